@@ -0,0 +1,4 @@
+-- Loudness analysis of files.
+-- Integrated loudness in LUFS and the gain in dB needed to reach the reference loudness.
+ALTER TABLE files ADD COLUMN loudness REAL;
+ALTER TABLE files ADD COLUMN gain REAL;
@@ -0,0 +1,8 @@
+-- Waveform peaks of files, generated on first request.
+CREATE TABLE waveforms (
+    file_id INTEGER PRIMARY KEY NOT NULL REFERENCES files(id) ON DELETE CASCADE,
+
+    peaks BLOB NOT NULL,
+
+    created TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,2 @@
+-- Dominant color of the cover, used as placeholder while the cover loads.
+ALTER TABLE books ADD COLUMN cover_color TEXT;
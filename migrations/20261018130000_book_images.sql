@@ -0,0 +1,14 @@
+-- Images of a book, stored on disk in the content-addressed image store.
+-- `books.cover` is only kept until existing covers are moved into the store on startup.
+CREATE TABLE book_images (
+    book_id INTEGER NOT NULL REFERENCES books(id) ON DELETE CASCADE,
+    kind TEXT NOT NULL CHECK (kind IN ('front', 'back', 'author')),
+
+    -- SHA-256 of the image data, as lowercase hex.
+    hash TEXT NOT NULL,
+
+    created TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+
+    PRIMARY KEY (book_id, kind)
+);
+CREATE INDEX book_images_hash ON book_images(hash);
@@ -0,0 +1,3 @@
+-- Unguessable token authenticating podcast feeds, apps can't send an Authorization header.
+ALTER TABLE users ADD COLUMN feed_token TEXT;
+CREATE UNIQUE INDEX users_feed_token ON users(feed_token);
@@ -0,0 +1,8 @@
+-- Server wide settings that are generated instead of configured, like signing keys.
+CREATE TABLE settings (
+    key TEXT PRIMARY KEY NOT NULL,
+
+    value TEXT NOT NULL,
+
+    created TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
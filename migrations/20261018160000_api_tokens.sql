@@ -0,0 +1,14 @@
+-- Named API tokens for scripts, used instead of logging in with username and password.
+CREATE TABLE api_tokens (
+    id INTEGER PRIMARY KEY NOT NULL,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+
+    name TEXT NOT NULL,
+    -- SHA-256 of the token, as lowercase hex. The token itself is only shown once.
+    hash TEXT NOT NULL UNIQUE,
+    scope TEXT NOT NULL CHECK (scope IN ('read', 'progress', 'admin')),
+
+    last_used TIMESTAMP,
+    created TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX api_tokens_user_id ON api_tokens(user_id);
@@ -0,0 +1,3 @@
+-- Client of a session, shown to users so they can recognize and revoke their sessions.
+ALTER TABLE sessions ADD COLUMN user_agent TEXT;
+ALTER TABLE sessions ADD COLUMN ip TEXT;
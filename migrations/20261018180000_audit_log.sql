@@ -0,0 +1,12 @@
+-- Security relevant events, like failed logins.
+CREATE TABLE audit_log (
+    id INTEGER PRIMARY KEY NOT NULL,
+    event TEXT NOT NULL,
+    -- Not a foreign key, entries are kept after users are deleted.
+    user_id INTEGER,
+    username TEXT,
+    ip TEXT,
+
+    created TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE INDEX audit_log_created ON audit_log(created);
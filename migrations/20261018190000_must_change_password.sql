@@ -0,0 +1,8 @@
+-- Users that have to change their password before they can use fela.
+ALTER TABLE users ADD COLUMN must_change_password BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- The default admin account still using the password from the init migration.
+UPDATE users
+SET must_change_password = TRUE
+WHERE name = 'admin'
+  AND password = '$argon2i$v=19$m=4096,t=3,p=1$c2FsdEl0V2l0aFNhbHQ$xTGvQNICqetaNA0Wu1GwFmYhQjAreRcjBz6ornhaFXA';
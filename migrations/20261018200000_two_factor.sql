@@ -0,0 +1,25 @@
+-- TOTP secrets of users. Two-factor authentication is only required once the secret is confirmed
+-- with a code.
+CREATE TABLE two_factor (
+    user_id INTEGER PRIMARY KEY NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+
+    -- Base32 encoded secret.
+    secret TEXT NOT NULL,
+    confirmed BOOLEAN NOT NULL DEFAULT FALSE,
+    -- Last accepted time step, codes can only be used once.
+    last_step INTEGER,
+
+    created TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+-- Single use codes to log in without the authenticator.
+CREATE TABLE recovery_codes (
+    id INTEGER PRIMARY KEY NOT NULL,
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+
+    -- SHA-256 of the normalized code, as lowercase hex.
+    hash TEXT NOT NULL,
+    used TIMESTAMP,
+
+    UNIQUE (user_id, hash)
+);
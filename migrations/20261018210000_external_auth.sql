@@ -0,0 +1,8 @@
+-- Where users authenticate, `local` users log in with the password stored here.
+-- Users of an OpenID Connect provider or a trusted reverse proxy have no usable password.
+ALTER TABLE users ADD COLUMN auth_source TEXT NOT NULL DEFAULT 'local';
+
+-- Identifier of the user at the external source, e.g. the `sub` claim.
+ALTER TABLE users ADD COLUMN external_id TEXT;
+
+CREATE UNIQUE INDEX users_external_id ON users (auth_source, external_id);
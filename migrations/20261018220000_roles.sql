@@ -0,0 +1,31 @@
+-- Roles grant permissions to users, replacing the admin flag.
+-- Permissions are a bit set, see `Permission` in `server/database/role.rs`:
+-- 1 = update progress and lists, 2 = manage the library, 4 = manage users and roles.
+CREATE TABLE roles (
+    id INTEGER PRIMARY KEY NOT NULL,
+
+    name TEXT NOT NULL UNIQUE,
+    permissions INTEGER NOT NULL DEFAULT 0,
+
+    created TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    modified TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+CREATE TRIGGER update_roles_modified
+AFTER UPDATE ON roles
+BEGIN
+    UPDATE roles SET modified = CURRENT_TIMESTAMP WHERE id = NEW.id;
+END;
+
+-- The admin role can't be changed, so there is always a role with all permissions.
+INSERT INTO roles (id, name, permissions)
+VALUES
+    (1, 'admin', 7),
+    (2, 'library_manager', 3),
+    (3, 'user_manager', 5),
+    (4, 'listener', 1),
+    (5, 'guest', 0);
+
+-- New users are listeners by default, existing admins keep all their rights.
+ALTER TABLE users ADD COLUMN role_id INTEGER NOT NULL DEFAULT 4;
+UPDATE users SET role_id = 1 WHERE admin;
+ALTER TABLE users DROP COLUMN admin;
@@ -0,0 +1,2 @@
+-- Fingerprint of the file the peaks were generated from, so replaced files get new peaks.
+ALTER TABLE waveforms ADD COLUMN file_hash TEXT NOT NULL DEFAULT '';
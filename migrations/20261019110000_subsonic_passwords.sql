@@ -0,0 +1,2 @@
+-- Password for Subsonic apps, stored as it is since token authentication needs the plain password.
+ALTER TABLE users ADD COLUMN subsonic_password TEXT;
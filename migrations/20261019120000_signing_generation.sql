@@ -0,0 +1,2 @@
+-- Generation of the signed URLs of a user, bumped to invalidate all of them at once.
+ALTER TABLE users ADD COLUMN signing_generation INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,5 @@
+-- Random id that identifies a session in the list of devices, the session id itself is a secret
+-- and row ids can be reused after a session is deleted.
+ALTER TABLE sessions ADD COLUMN public_id TEXT NOT NULL DEFAULT '';
+UPDATE sessions SET public_id = lower(hex(randomblob(16)));
+CREATE UNIQUE INDEX sessions_public_id ON sessions(public_id);
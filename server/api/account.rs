@@ -1,19 +1,32 @@
+use anyhow::Context;
 use axum::{
     Json, Router,
     extract::{Path, State},
-    routing::get,
+    routing::{delete, get, post},
 };
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-use super::response::{ApiResult, DataResponse, SuccessResponse};
+use super::response::{ApiFileResult, ApiResult, DataResponse, SuccessResponse};
 use crate::{
     api_bail, api_response,
     auth::{
-        password::hash_password,
-        session::{AdminSession, Session},
+        api_token::{generate_api_token, hash_api_token},
+        password::{PASSWORD_POLICY, hash_password},
+        random::random_string,
+        session::{AccountSession, PasswordChangeSession, Session, UserManagerSession},
+        totp::{
+            base32_decode, base32_encode, generate_recovery_codes, generate_secret,
+            hash_recovery_code, otpauth_uri, verify_totp, verify_two_factor_code,
+        },
     },
     data_response,
-    database::user::User,
+    database::{
+        api_token::{ApiToken, TokenScope},
+        audit::AuditEvent,
+        role::{DEFAULT_ROLE, Permission, Role},
+        session::SessionInfo,
+        user::{AuthSource, User},
+    },
     state::FelaState,
 };
 
@@ -22,7 +35,23 @@ use crate::{
 pub fn router() -> Router<FelaState> {
     Router::new()
         .route("/", get(get_users).post(create_user))
+        .route("/feed-token", get(get_feed_token).post(reset_feed_token))
+        .route(
+            "/subsonic-password",
+            get(get_subsonic_password).post(reset_subsonic_password),
+        )
+        .route("/tokens", get(get_api_tokens).post(create_api_token))
+        .route("/tokens/{id}", delete(delete_api_token))
+        .route(
+            "/two-factor",
+            get(get_two_factor)
+                .post(enable_two_factor)
+                .delete(disable_two_factor),
+        )
+        .route("/two-factor/confirm", post(confirm_two_factor))
+        .route("/two-factor/recovery-codes", post(reset_recovery_codes))
         .route("/{id}", get(get_user).patch(update_user))
+        .route("/{id}/two-factor", delete(reset_two_factor))
 }
 
 /// Get all users.
@@ -47,18 +76,37 @@ pub async fn get_user(
     data_response!(user)
 }
 
+/// Get a role that `session` may assign to users.
+/// Users can't give others permissions they don't have themselves.
+async fn assignable_role(
+    state: &FelaState,
+    session: &SessionInfo,
+    role_id: i64,
+) -> ApiFileResult<Role> {
+    let Some(role) = state.database.get_role(role_id).await? else {
+        api_bail!(NotFound)
+    };
+
+    if !session.permissions.contains(role.permissions) {
+        api_bail!(MissingPermission)
+    }
+
+    Ok(role)
+}
+
 /// Request data for a new user.
 #[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct CreateUserRequest {
     name: String,
     password: String,
-    admin: bool,
+    role_id: Option<i64>,
 }
 
-/// Create a new user.
-/// Admin only route.
+/// Create a new user, with the default role if none is given.
+/// Needs the permission to manage users.
 pub async fn create_user(
-    AdminSession(_): AdminSession,
+    UserManagerSession(session): UserManagerSession,
     State(state): State<FelaState>,
     Json(body): Json<CreateUserRequest>,
 ) -> ApiResult<SuccessResponse> {
@@ -70,13 +118,16 @@ pub async fn create_user(
         api_bail!(DataMissing)
     }
 
-    // Hash password.
+    // Check the password against the policy and hash it.
+    PASSWORD_POLICY.check(&body.password, &username)?;
     let password = hash_password(&body.password)?;
+    let role = assignable_role(&state, &session, body.role_id.unwrap_or(DEFAULT_ROLE)).await?;
 
     // Insert user into database.
+    // The password is chosen by a user manager, so the user has to replace it on first login.
     state
         .database
-        .create_user(&username, &password, body.admin)
+        .create_user(&username, &password, role.id, true)
         .await?;
 
     api_response!("user-create--success")
@@ -84,41 +135,440 @@ pub async fn create_user(
 
 /// Data to update a user.
 #[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
 pub struct UpdateUserRequest {
     name: Option<String>,
     password: Option<String>,
-    admin: Option<bool>,
+    role_id: Option<i64>,
 }
 
 /// Update a user account.
-/// Normal users are only allowed to update their own account.
-/// Admins are allowed to update any account.
+/// Normal users are only allowed to update their own account, but not their role.
+/// User managers are allowed to update accounts with no permissions beyond their own.
+/// Changing the password or a role that loses permissions logs the user out everywhere and revokes
+/// their API tokens. The last user that can manage users can't get a role without that permission.
+/// Users that have to change their password can only do that, passwords set by user managers for other
+/// users have to be changed again by them.
+/// Users of external sources like LDAP have no password in fela, it can't be set.
 pub async fn update_user(
-    Session(session): Session,
+    PasswordChangeSession(session): PasswordChangeSession,
     State(state): State<FelaState>,
     Path(id): Path<i64>,
     Json(body): Json<UpdateUserRequest>,
 ) -> ApiResult<SuccessResponse> {
+    // Like `AccountSession`, API tokens need the admin scope.
+    if session.scope < TokenScope::Admin {
+        api_bail!(InsufficientScope)
+    }
+
     // Check if user is allowed to update user.
-    if session.user_id != id && !session.admin {
-        api_bail!(NotAdmin);
+    let manages_users = session.permissions.has(Permission::ManageUsers);
+    if (session.user_id != id || body.role_id.is_some()) && !manages_users {
+        api_bail!(MissingPermission);
+    }
+
+    // Users that have to change their password can only change their own.
+    if session.must_change_password && (session.user_id != id || body.password.is_none()) {
+        api_bail!(PasswordChangeRequired)
     }
 
     // Normalize username if it's provided.
     let username = body.name.map(|name| name.trim().to_lowercase());
 
-    // Hash password if it's provided.
+    // Users can't edit accounts with more permissions than their own, like admins.
+    let user = state.database.get_user(id).await?;
+    if !session.permissions.contains(user.permissions) {
+        api_bail!(MissingPermission)
+    }
+    let role = match body.role_id {
+        Some(role_id) => Some(assignable_role(&state, &session, role_id).await?),
+        None => None,
+    };
+
+    // Passwords of users logging in with LDAP or another external source are managed there.
+    if body.password.is_some() && user.auth_source != AuthSource::Local {
+        api_bail!(ExternalAccount)
+    }
+
+    // Check the password against the policy and hash it if it's provided.
     let password = if let Some(password) = body.password {
+        PASSWORD_POLICY.check(&password, username.as_deref().unwrap_or(&user.name))?;
         Some(hash_password(&password)?)
     } else {
         None
     };
 
+    // Sessions and API tokens are revoked when the password changes or the user loses permissions.
+    let demoted = role
+        .as_ref()
+        .is_some_and(|role| !role.permissions.contains(user.permissions));
+    let password_changed = password.is_some();
+    let must_change_password = password_changed.then_some(session.user_id != id);
+
     // Update user by id.
-    state
+    // The last user that can manage users can't give that up, nobody could take it over.
+    let updated = state
         .database
-        .update_user(id, username, password, body.admin)
+        .update_user(
+            id,
+            username,
+            password,
+            role.map(|role| role.id),
+            must_change_password,
+        )
         .await?;
+    if !updated {
+        api_bail!(LastUserManager)
+    }
+
+    if demoted {
+        state.database.delete_user_sessions(id, None).await?;
+    } else if password_changed {
+        // Users changing their own password stay logged in on the current device.
+        let keep = (session.user_id == id).then_some(session.session_id.as_str());
+        state.database.delete_user_sessions(id, keep).await?;
+    }
+    if demoted || password_changed {
+        state.database.delete_user_api_tokens(id).await?;
+    }
 
     api_response!("user-edit--success")
 }
+
+/// Length of feed tokens, long enough to be unguessable.
+const FEED_TOKEN_LENGTH: usize = 32;
+
+/// Feed token of a user, used in the URLs of podcast feeds.
+#[derive(Serialize)]
+pub struct FeedTokenResponse {
+    token: String,
+}
+
+/// Get the feed token of the current user, it is created on first request.
+pub async fn get_feed_token(
+    Session(session): Session,
+    State(state): State<FelaState>,
+) -> ApiResult<DataResponse<FeedTokenResponse>> {
+    let token = match state.database.get_feed_token(session.user_id).await? {
+        Some(token) => token,
+        None => {
+            let token = random_string(FEED_TOKEN_LENGTH);
+            state
+                .database
+                .set_feed_token(session.user_id, &token)
+                .await?;
+            token
+        }
+    };
+
+    data_response!(FeedTokenResponse { token })
+}
+
+/// Replace the feed token of the current user.
+/// Feeds subscribed with the old token stop working.
+pub async fn reset_feed_token(
+    AccountSession(session): AccountSession,
+    State(state): State<FelaState>,
+) -> ApiResult<DataResponse<FeedTokenResponse>> {
+    let token = random_string(FEED_TOKEN_LENGTH);
+    state
+        .database
+        .set_feed_token(session.user_id, &token)
+        .await?;
+
+    data_response!(FeedTokenResponse { token })
+}
+
+/// Length of Subsonic passwords.
+const SUBSONIC_PASSWORD_LENGTH: usize = 24;
+
+/// Subsonic password of a user.
+#[derive(Serialize)]
+pub struct SubsonicPasswordResponse {
+    password: String,
+}
+
+/// Get the Subsonic password of the current user, it is created on first request.
+/// Subsonic apps using token authentication need it in place of the account password.
+pub async fn get_subsonic_password(
+    AccountSession(session): AccountSession,
+    State(state): State<FelaState>,
+) -> ApiResult<DataResponse<SubsonicPasswordResponse>> {
+    let password = match state
+        .database
+        .get_subsonic_password(session.user_id)
+        .await?
+    {
+        Some(password) => password,
+        None => {
+            let password = random_string(SUBSONIC_PASSWORD_LENGTH);
+            state
+                .database
+                .set_subsonic_password(session.user_id, &password)
+                .await?;
+            password
+        }
+    };
+
+    data_response!(SubsonicPasswordResponse { password })
+}
+
+/// Replace the Subsonic password of the current user.
+/// Apps logged in with the old password have to log in again.
+pub async fn reset_subsonic_password(
+    AccountSession(session): AccountSession,
+    State(state): State<FelaState>,
+) -> ApiResult<DataResponse<SubsonicPasswordResponse>> {
+    let password = random_string(SUBSONIC_PASSWORD_LENGTH);
+    state
+        .database
+        .set_subsonic_password(session.user_id, &password)
+        .await?;
+
+    data_response!(SubsonicPasswordResponse { password })
+}
+
+/// Get the API tokens of the current user, without the tokens themselves.
+pub async fn get_api_tokens(
+    AccountSession(session): AccountSession,
+    State(state): State<FelaState>,
+) -> ApiResult<DataResponse<Vec<ApiToken>>> {
+    let tokens = state.database.get_api_tokens(session.user_id).await?;
+
+    data_response!(tokens)
+}
+
+/// Request data for a new API token.
+#[derive(Deserialize)]
+pub struct CreateApiTokenRequest {
+    name: String,
+    scope: TokenScope,
+}
+
+/// A newly created API token.
+/// The token is only returned once, the server only stores its hash.
+#[derive(Serialize)]
+pub struct CreateApiTokenResponse {
+    id: i64,
+    token: String,
+}
+
+/// Create an API token for the current user.
+pub async fn create_api_token(
+    AccountSession(session): AccountSession,
+    State(state): State<FelaState>,
+    Json(body): Json<CreateApiTokenRequest>,
+) -> ApiResult<DataResponse<CreateApiTokenResponse>> {
+    let name = body.name.trim();
+    if name.is_empty() {
+        api_bail!(DataMissing)
+    }
+
+    let token = generate_api_token();
+    let id = state
+        .database
+        .create_api_token(session.user_id, name, &hash_api_token(&token), body.scope)
+        .await?;
+
+    data_response!(CreateApiTokenResponse { id, token })
+}
+
+/// Revoke an API token of the current user.
+pub async fn delete_api_token(
+    AccountSession(session): AccountSession,
+    State(state): State<FelaState>,
+    Path(id): Path<i64>,
+) -> ApiResult<SuccessResponse> {
+    if !state.database.delete_api_token(session.user_id, id).await? {
+        api_bail!(NotFound)
+    }
+
+    api_response!("api-token-delete--success")
+}
+
+/// Two-factor authentication state of the current user.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TwoFactorStatus {
+    enabled: bool,
+    recovery_codes: i64,
+}
+
+/// Check if the current user has two-factor authentication enabled.
+pub async fn get_two_factor(
+    AccountSession(session): AccountSession,
+    State(state): State<FelaState>,
+) -> ApiResult<DataResponse<TwoFactorStatus>> {
+    let enabled = state.database.has_two_factor(session.user_id).await?;
+    let recovery_codes = state.database.count_recovery_codes(session.user_id).await?;
+
+    data_response!(TwoFactorStatus {
+        enabled,
+        recovery_codes
+    })
+}
+
+/// Secret to add to an authenticator app.
+#[derive(Serialize)]
+pub struct TwoFactorSecret {
+    secret: String,
+    uri: String,
+}
+
+/// Start enabling two-factor authentication.
+/// The secret is only used after it was confirmed with a code.
+pub async fn enable_two_factor(
+    AccountSession(session): AccountSession,
+    State(state): State<FelaState>,
+) -> ApiResult<DataResponse<TwoFactorSecret>> {
+    let secret = generate_secret();
+    let encoded = base32_encode(&secret);
+
+    if !state
+        .database
+        .create_two_factor(session.user_id, &encoded)
+        .await?
+    {
+        api_bail!(TwoFactorAlreadyEnabled)
+    }
+
+    data_response!(TwoFactorSecret {
+        uri: otpauth_uri(&session.username, &secret),
+        secret: encoded,
+    })
+}
+
+/// A TOTP or recovery code.
+#[derive(Deserialize)]
+pub struct TwoFactorCode {
+    code: String,
+}
+
+/// New recovery codes, only shown once.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecoveryCodes {
+    recovery_codes: Vec<String>,
+}
+
+/// Replace the recovery codes of a user and return the new ones.
+async fn create_recovery_codes(
+    state: &FelaState,
+    user_id: i64,
+) -> ApiResult<DataResponse<RecoveryCodes>> {
+    let recovery_codes = generate_recovery_codes();
+    let hashes: Vec<String> = recovery_codes
+        .iter()
+        .map(|code| hash_recovery_code(code))
+        .collect();
+    state.database.set_recovery_codes(user_id, &hashes).await?;
+
+    data_response!(RecoveryCodes { recovery_codes })
+}
+
+/// Confirm the secret with a code from the authenticator app, which enables two-factor
+/// authentication. Returns the recovery codes.
+pub async fn confirm_two_factor(
+    AccountSession(session): AccountSession,
+    State(state): State<FelaState>,
+    Json(body): Json<TwoFactorCode>,
+) -> ApiResult<DataResponse<RecoveryCodes>> {
+    let Some(two_factor) = state.database.get_two_factor(session.user_id).await? else {
+        api_bail!(NotFound)
+    };
+    if two_factor.confirmed {
+        api_bail!(TwoFactorAlreadyEnabled)
+    }
+
+    let secret = base32_decode(&two_factor.secret).context("Invalid TOTP secret")?;
+    let now = time::OffsetDateTime::now_utc().unix_timestamp();
+    let Some(step) = verify_totp(&secret, &body.code, now, None) else {
+        api_bail!(InvalidTwoFactorCode)
+    };
+
+    state
+        .database
+        .confirm_two_factor(session.user_id, step)
+        .await?;
+    state
+        .database
+        .add_audit_entry(
+            AuditEvent::TwoFactorEnabled,
+            Some(session.user_id),
+            Some(&session.username),
+            None,
+        )
+        .await?;
+
+    create_recovery_codes(&state, session.user_id).await
+}
+
+/// Replace the recovery codes, e.g. after most were used.
+pub async fn reset_recovery_codes(
+    AccountSession(session): AccountSession,
+    State(state): State<FelaState>,
+    Json(body): Json<TwoFactorCode>,
+) -> ApiResult<DataResponse<RecoveryCodes>> {
+    let now = time::OffsetDateTime::now_utc();
+    if !verify_two_factor_code(&state.database, session.user_id, &body.code, now).await? {
+        api_bail!(InvalidTwoFactorCode)
+    }
+
+    create_recovery_codes(&state, session.user_id).await
+}
+
+/// Disable two-factor authentication of the current user, needs a code.
+pub async fn disable_two_factor(
+    AccountSession(session): AccountSession,
+    State(state): State<FelaState>,
+    Json(body): Json<TwoFactorCode>,
+) -> ApiResult<SuccessResponse> {
+    let now = time::OffsetDateTime::now_utc();
+    if !verify_two_factor_code(&state.database, session.user_id, &body.code, now).await? {
+        api_bail!(InvalidTwoFactorCode)
+    }
+
+    state.database.delete_two_factor(session.user_id).await?;
+    state
+        .database
+        .add_audit_entry(
+            AuditEvent::TwoFactorDisabled,
+            Some(session.user_id),
+            Some(&session.username),
+            None,
+        )
+        .await?;
+
+    api_response!("two-factor-disable--success")
+}
+
+/// Reset two-factor authentication of a user that lost their authenticator and recovery codes.
+/// Needs the permission to manage users and every permission of the user.
+pub async fn reset_two_factor(
+    UserManagerSession(session): UserManagerSession,
+    State(state): State<FelaState>,
+    Path(id): Path<i64>,
+) -> ApiResult<SuccessResponse> {
+    let user = state.database.get_user(id).await?;
+    if !session.permissions.contains(user.permissions) {
+        api_bail!(MissingPermission)
+    }
+
+    state.database.delete_two_factor(user.id).await?;
+    state
+        .database
+        .add_audit_entry(
+            AuditEvent::TwoFactorDisabled,
+            Some(user.id),
+            Some(&user.name),
+            None,
+        )
+        .await?;
+    tracing::info!(
+        "Two-factor authentication of {} reset by {}",
+        user.name,
+        session.username
+    );
+
+    api_response!("two-factor-reset--success")
+}
@@ -1,23 +1,103 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use anyhow::Context;
-use axum::{Router, extract::State, routing::post};
+use axum::{
+    Json, Router,
+    extract::{self, State},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+};
+use serde::Deserialize;
 
-use super::response::{ApiResult, SuccessResponse};
+use super::response::{
+    ApiError, ApiFileResult, ApiResult, DataResponse, SuccessResponse, job_response,
+};
 use crate::{
-    api_response, auth::session::AdminSession, media::ffmpeg::ffprobe_chapters, state::FelaState,
+    api_bail, api_response,
+    auth::session::{LibraryManagerSession, SESSION_LIFETIME, UserManagerSession},
+    data_response,
+    database::{
+        audit::AuditEntry,
+        book::Book,
+        file::{File, FileData},
+        image::ImageKind,
+        session::SessionCounts,
+    },
+    fs::{
+        path::validate_path_within_bounds,
+        storage::{FELA_MEDIA_ROOT, PartialFile},
+    },
+    media::{
+        cover::get_book_image,
+        ffmpeg::{
+            Chapters, MetadataUpdate, book_tags, chapters_from_files, ffmetadata, ffmpeg_merge_m4b,
+            ffmpeg_write_metadata, ffprobe_chapters, ffprobe_duration,
+        },
+        jobs::JobStatus,
+        loudness::{analyze_book_in_background, analyze_files},
+        waveform::{generate_book_waveforms, waveform_job_key},
+    },
+    state::FelaState,
 };
 
 /// Build router for admin routes.
 /// Is attached to `/admin`.
 pub fn router() -> Router<FelaState> {
-    Router::new().route("/rediscover-chapters", post(rediscover_chapters))
+    Router::new()
+        .route("/rediscover-chapters", post(rediscover_chapters))
+        .route(
+            "/analyze-loudness",
+            get(get_loudness_status).post(analyze_loudness),
+        )
+        .route("/sessions", get(get_session_counts))
+        .route("/audit-log", get(get_audit_log))
+        .route(
+            "/book/{book_id}/merge",
+            get(get_merge_status).post(merge_book_files),
+        )
+        .route(
+            "/book/{book_id}/write-metadata",
+            get(get_write_metadata_status).post(write_book_metadata),
+        )
+}
+
+/// Number of active and expired sessions.
+/// Expired sessions are deleted periodically, so only recently expired ones are counted.
+pub async fn get_session_counts(
+    UserManagerSession(_): UserManagerSession,
+    State(state): State<FelaState>,
+) -> ApiResult<DataResponse<SessionCounts>> {
+    let expired_before = time::OffsetDateTime::now_utc() - *SESSION_LIFETIME;
+    let counts = state.database.count_sessions(expired_before).await?;
+
+    data_response!(counts)
+}
+
+/// Number of entries returned from the audit log if no limit is given.
+const AUDIT_LOG_LIMIT: i64 = 100;
+
+/// Query parameters of the audit log.
+#[derive(Deserialize)]
+pub struct AuditLogQuery {
+    limit: Option<i64>,
+}
+
+/// Get the latest entries of the audit log, like failed logins.
+pub async fn get_audit_log(
+    UserManagerSession(_): UserManagerSession,
+    State(state): State<FelaState>,
+    extract::Query(query): extract::Query<AuditLogQuery>,
+) -> ApiResult<DataResponse<Vec<AuditEntry>>> {
+    let limit = query.limit.unwrap_or(AUDIT_LOG_LIMIT).clamp(1, 1000);
+    let entries = state.database.get_audit_log(limit).await?;
+
+    data_response!(entries)
 }
 
 /// Utility function that iterates through audio files and creates new chapter markers.
-/// Run on request by an admin user.
+/// Run on request by a library manager.
 pub async fn rediscover_chapters(
-    AdminSession(_): AdminSession,
+    LibraryManagerSession(_): LibraryManagerSession,
     State(state): State<FelaState>,
 ) -> ApiResult<SuccessResponse> {
     // Get all books with only one file.
@@ -96,3 +176,344 @@ pub async fn rediscover_chapters(
 
     api_response!("admin--chapters-rediscovered")
 }
+
+/// Key of the job analyzing the loudness of the library.
+const LOUDNESS_JOB_KEY: &str = "analyze-loudness";
+
+/// Analyze loudness of all files that haven't been analyzed yet.
+/// The analysis decodes every file and runs as background job, its status is polled with `GET`
+/// on the same route. Requests while it runs get the status of the running analysis.
+pub async fn analyze_loudness(
+    LibraryManagerSession(_): LibraryManagerSession,
+    State(state): State<FelaState>,
+) -> ApiFileResult<Response> {
+    let database = state.database.clone();
+    state.jobs.start(LOUDNESS_JOB_KEY, async move {
+        let files = database.get_files_without_loudness().await?;
+        tracing::info!("Analyzing loudness of {} files", files.len());
+        analyze_files(&database, &files).await;
+        tracing::info!("Finished loudness analysis");
+        Ok(())
+    });
+
+    Ok(job_response(JobStatus::Running))
+}
+
+/// Get the status of the last loudness analysis.
+pub async fn get_loudness_status(
+    LibraryManagerSession(_): LibraryManagerSession,
+    State(state): State<FelaState>,
+) -> ApiFileResult<Response> {
+    job_status_response(&state, LOUDNESS_JOB_KEY)
+}
+
+/// Options for merging the files of a book.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MergeBookRequest {
+    #[serde(default)]
+    delete_originals: bool,
+}
+
+/// Key of the job merging the files of a book.
+fn merge_job_key(book_id: i64) -> String {
+    format!("merge-book-{book_id}")
+}
+
+/// Start merging all files of a book into a single M4B file next to the original files.
+/// Merging runs as background job, its status is polled with `GET` on the same route.
+/// Chapters are created from the file boundaries and the progress of every user is moved onto
+/// the new file. The original files are kept on disk unless `deleteOriginals` is set.
+pub async fn merge_book_files(
+    LibraryManagerSession(session): LibraryManagerSession,
+    State(state): State<FelaState>,
+    extract::Path(book_id): extract::Path<i64>,
+    Json(MergeBookRequest { delete_originals }): Json<MergeBookRequest>,
+) -> ApiFileResult<Response> {
+    let job_key = merge_job_key(book_id);
+    if state.jobs.status(&job_key) == Some(JobStatus::Running) {
+        return Ok(job_response(JobStatus::Running));
+    }
+
+    let book = state
+        .database
+        .get_book_details(book_id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+    let files = state.database.get_files_for_book(book_id).await?;
+    if files.len() < 2 {
+        api_bail!(BookHasSingleFile)
+    }
+
+    // Place the merged file in the directory of the first file.
+    let directory = Path::new(&files[0].path)
+        .parent()
+        .context(ApiError::InvalidPath)?;
+    let directory = validate_path_within_bounds(directory, &FELA_MEDIA_ROOT)?;
+    let file_name = sanitize_file_name(&book.title);
+    let output = directory.join(format!("{file_name}.m4b"));
+    if output.exists() {
+        api_bail!(FileAlreadyExists, output.to_string_lossy())
+    }
+
+    let merge = MergeJob {
+        state: state.clone(),
+        username: session.username,
+        book,
+        files,
+        partial_output: directory.join(format!(".{file_name}.m4b.part")),
+        output,
+        delete_originals,
+    };
+    state.jobs.start(&job_key, merge.run());
+
+    Ok(job_response(JobStatus::Running))
+}
+
+/// Get the status of the last merge of a book.
+pub async fn get_merge_status(
+    LibraryManagerSession(_): LibraryManagerSession,
+    State(state): State<FelaState>,
+    extract::Path(book_id): extract::Path<i64>,
+) -> ApiFileResult<Response> {
+    job_status_response(&state, &merge_job_key(book_id))
+}
+
+/// Respond with the status of a job, `202 Accepted` while it is running.
+fn job_status_response(state: &FelaState, job_key: &str) -> ApiFileResult<Response> {
+    match state.jobs.status(job_key) {
+        Some(JobStatus::Running) => Ok(job_response(JobStatus::Running)),
+        Some(status) => Ok(Json(DataResponse::new(status)).into_response()),
+        None => api_bail!(NotFound),
+    }
+}
+
+/// Merge of the files of a book, checked by `merge_book_files`.
+struct MergeJob {
+    state: FelaState,
+    username: String,
+    book: Book,
+    files: Vec<File>,
+    partial_output: PathBuf,
+    output: PathBuf,
+    delete_originals: bool,
+}
+
+impl MergeJob {
+    async fn run(self) -> anyhow::Result<()> {
+        let Self {
+            state,
+            username,
+            book,
+            files,
+            partial_output,
+            output,
+            delete_originals,
+        } = self;
+        let _lock = state.jobs.lock_book(book.id).await;
+
+        // ffmpeg writes into a hidden file that is moved into place once it is complete.
+        // It is removed on every other way out, including panics.
+        let partial_output = PartialFile::new(partial_output);
+        let paths = files
+            .iter()
+            .map(|file| PathBuf::from(&file.path))
+            .collect::<Vec<_>>();
+        let chapters = chapters_from_files(&files);
+        let metadata = ffmetadata(&book.title, &book.author, &chapters);
+        let cover =
+            get_book_image(&state.database, &state.images, book.id, ImageKind::Front).await?;
+
+        ffmpeg_merge_m4b(
+            &paths,
+            &metadata,
+            cover.as_deref(),
+            None,
+            partial_output.path(),
+        )
+        .await
+        .with_context(|| format!("Failed to merge files of book {}", book.id))?;
+        partial_output
+            .persist(&output)
+            .await
+            .context("Failed to move merged file into place")?;
+
+        // Swap the files in the database, remove the merged file again if that fails.
+        let result = async {
+            let duration = ffprobe_duration(&output)
+                .await
+                .with_context(|| ApiError::FFProbeFailed(output.to_string_lossy().into_owned()))?;
+            let file_data = FileData {
+                path: output.to_string_lossy().into_owned(),
+                name: book.title.clone(),
+                duration,
+            };
+            state
+                .database
+                .replace_book_files(book.id, &file_data, &chapters)
+                .await
+        }
+        .await;
+        if let Err(err) = result {
+            let _ = tokio::fs::remove_file(&output).await;
+            return Err(err);
+        }
+
+        // The merged file needs a new loudness analysis and waveform.
+        analyze_book_in_background(state.database.clone(), book.id);
+        state.jobs.start(
+            &waveform_job_key(book.id),
+            generate_book_waveforms(state.database.clone(), state.waveforms.clone(), book.id),
+        );
+
+        tracing::info!(
+            "{} merged {} files of book {} into {}",
+            username,
+            files.len(),
+            book.id,
+            output.display()
+        );
+
+        if delete_originals {
+            for path in paths {
+                if let Err(err) = tokio::fs::remove_file(&path).await {
+                    tracing::warn!("Failed to delete {}: {}", path.display(), err);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Key of the job writing the metadata of a book.
+fn write_metadata_job_key(book_id: i64) -> String {
+    format!("write-metadata-book-{book_id}")
+}
+
+/// Start writing title, author, cover and chapters from the database back into the audio files
+/// of a book. Writing runs as background job, its status is polled with `GET` on the same route.
+/// Every file is remuxed into a hidden file next to it, which then replaces the original.
+pub async fn write_book_metadata(
+    LibraryManagerSession(session): LibraryManagerSession,
+    State(state): State<FelaState>,
+    extract::Path(book_id): extract::Path<i64>,
+) -> ApiFileResult<Response> {
+    let job_key = write_metadata_job_key(book_id);
+    if state.jobs.status(&job_key) == Some(JobStatus::Running) {
+        return Ok(job_response(JobStatus::Running));
+    }
+
+    if state.database.get_book_details(book_id).await?.is_none() {
+        api_bail!(NotFound)
+    }
+    state.jobs.start(
+        &job_key,
+        write_metadata(state.clone(), session.username, book_id),
+    );
+
+    Ok(job_response(JobStatus::Running))
+}
+
+/// Get the status of the last metadata write of a book.
+pub async fn get_write_metadata_status(
+    LibraryManagerSession(_): LibraryManagerSession,
+    State(state): State<FelaState>,
+    extract::Path(book_id): extract::Path<i64>,
+) -> ApiFileResult<Response> {
+    job_status_response(&state, &write_metadata_job_key(book_id))
+}
+
+/// Write the metadata of a book into its files, see `write_book_metadata`.
+/// Holds the lock of the book, the files are only read once a merge of the book is done.
+async fn write_metadata(state: FelaState, username: String, book_id: i64) -> anyhow::Result<()> {
+    let _lock = state.jobs.lock_book(book_id).await;
+    let book = state
+        .database
+        .get_book_details(book_id)
+        .await?
+        .context("Book was deleted")?;
+    let files = state.database.get_files_for_book(book_id).await?;
+    let cover = get_book_image(&state.database, &state.images, book_id, ImageKind::Front).await?;
+
+    // Chapters span the whole book, so they are only written into books with a single file.
+    // Files of other books are tagged as tracks instead.
+    let replace_chapters = files.len() == 1;
+    let chapters = if replace_chapters {
+        state
+            .database
+            .get_chapters_for_book(book_id)
+            .await?
+            .into_iter()
+            .map(Chapters::from)
+            .collect()
+    } else {
+        Vec::new()
+    };
+    let metadata = ffmetadata(&book.title, &book.author, &chapters);
+
+    for file in &files {
+        let path = validate_path_within_bounds(Path::new(&file.path), &FELA_MEDIA_ROOT)?;
+        let file_name = path
+            .file_name()
+            .context(ApiError::InvalidPath)?
+            .to_string_lossy();
+        let temporary_file = PartialFile::new(path.with_file_name(format!(".fela-{file_name}")));
+
+        // Only the tags fela knows are replaced, everything else in the file is kept.
+        let mut tags = book_tags(&book.title, &book.author);
+        if !replace_chapters {
+            tags.retain(|(key, _)| *key != "title");
+            tags.push(("title", file.name.clone()));
+            tags.push(("track", format!("{}/{}", file.position, files.len())));
+        }
+        let update = MetadataUpdate {
+            metadata: &metadata,
+            tags,
+            cover: cover.as_deref(),
+            replace_chapters,
+        };
+
+        ffmpeg_write_metadata(&path, temporary_file.path(), &update)
+            .await
+            .with_context(|| format!("Failed to write metadata into {}", path.display()))?;
+        temporary_file
+            .persist(&path)
+            .await
+            .context("Failed to replace file")?;
+
+        // Remuxing can change the reported duration slightly.
+        let duration = ffprobe_duration(&path)
+            .await
+            .with_context(|| ApiError::FFProbeFailed(path.to_string_lossy().into_owned()))?;
+        state
+            .database
+            .update_file_duration(file.id, duration)
+            .await?;
+    }
+
+    state.database.touch_book(book_id).await?;
+
+    tracing::info!(
+        "{} wrote metadata into {} files of book {}",
+        username,
+        files.len(),
+        book_id
+    );
+
+    Ok(())
+}
+
+/// Replace characters that are not allowed in file names.
+fn sanitize_file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_control() || matches!(c, '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|') {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect()
+}
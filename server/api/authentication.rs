@@ -1,20 +1,38 @@
 use anyhow::Context;
+use std::net::{IpAddr, SocketAddr};
+
 use axum::{
     Json, Router, debug_handler,
-    extract::State,
+    extract::{ConnectInfo, Path, Query, State},
+    http::{HeaderMap, header},
+    response::{AppendHeaders, IntoResponse, Redirect, Response},
     routing::{delete, get, post},
 };
 use serde::{Deserialize, Serialize};
 
-use super::response::{ApiError, ApiResult, DataResponse, SuccessResponse};
+use super::{
+    response::{ApiError, ApiFileResult, ApiResult, DataResponse, SuccessResponse},
+    url::{API_PREFIX, ApiUrl, PUBLIC_URL},
+};
 use crate::{
     api_bail, api_response,
     auth::{
-        password::verify_password,
-        session::{Session, create_session_id},
+        cookie::{OIDC_COOKIE, clear_session_cookies, get_cookie, oidc_cookie, session_cookies},
+        ldap::ldap_user,
+        oidc::{LoginState, OIDC_CONFIG, oidc_user},
+        password::{upgrade_password_hash, verify_password_blocking},
+        proxy::{ClientIp, TRUSTED_PROXY, client_ip, proxy_user},
+        session::{AccountSession, PasswordChangeSession, Session, create_session_id},
+        signed_url::{MAX_SIGNED_URL_LIFETIME, SIGNED_URL_LIFETIME, SignedResource, SignedUser},
+        throttle::{record_failed_login, reserve_attempt},
+        totp::verify_two_factor_code,
     },
     data_response,
-    database::session::SessionInfo,
+    database::{
+        audit::AuditEvent,
+        session::{SessionInfo, UserSession},
+        user::{AuthSource, User},
+    },
     state::FelaState,
 };
 
@@ -23,10 +41,21 @@ use crate::{
 pub fn router() -> Router<FelaState> {
     Router::new()
         .route("/login", post(login))
+        .route("/login/two-factor", post(login_two_factor))
+        .route("/login/options", get(login_options))
+        .route("/login/proxy", post(login_proxy))
+        .route("/oidc/login", get(oidc_login))
+        .route("/oidc/callback", get(oidc_callback))
         .route("/logout", delete(logout))
         .route("/info", get(info))
+        .route("/signed-url", post(create_signed_url))
+        .route("/sessions", get(get_sessions).delete(revoke_other_sessions))
+        .route("/sessions/{id}", delete(revoke_session))
 }
 
+/// Longest user agent stored with a session.
+const MAX_USER_AGENT_LENGTH: usize = 256;
+
 /// Data expected during a login request.
 #[derive(Deserialize)]
 pub struct LoginRequest {
@@ -35,18 +64,140 @@ pub struct LoginRequest {
 }
 
 /// Reponse to login request handing over the session id.
+/// Browsers can use the session cookie instead, which needs `csrf_token` for state-changing
+/// requests.
 #[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct LoginResponse {
     token: String,
+    csrf_token: String,
+    must_change_password: bool,
+}
+
+/// First step of a login of a user with two-factor authentication.
+/// The challenge is sent back with a code to `/login/two-factor`.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TwoFactorChallenge {
+    two_factor_required: bool,
+    challenge: String,
+}
+
+/// Path two-factor challenges are signed for, it is no route so the tokens can't be used as
+/// signed URL.
+const TWO_FACTOR_CHALLENGE: &str = "two-factor-challenge";
+
+/// Time a user has to enter the code after entering the password.
+const TWO_FACTOR_CHALLENGE_LIFETIME: time::Duration = time::Duration::minutes(5);
+
+/// Create a new session and return its id.
+async fn create_session(
+    state: &FelaState,
+    user_id: i64,
+    headers: &HeaderMap,
+    ip: IpAddr,
+) -> ApiFileResult<String> {
+    // Remember the client so the user can recognize the session later.
+    let user_agent = headers
+        .get(header::USER_AGENT)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| {
+            value
+                .chars()
+                .take(MAX_USER_AGENT_LENGTH)
+                .collect::<String>()
+        });
+    let ip = ip.to_string();
+    let session_id = create_session_id();
+    state
+        .database
+        .create_session(user_id, &session_id, user_agent.as_deref(), Some(&ip))
+        .await
+        .context("Failed to create session in database")?;
+
+    Ok(session_id)
+}
+
+/// Create a new session and respond with the session id and cookies.
+async fn start_session(
+    state: &FelaState,
+    user_id: i64,
+    must_change_password: bool,
+    headers: &HeaderMap,
+    ip: IpAddr,
+) -> ApiFileResult<Response> {
+    let session_id = create_session(state, user_id, headers, ip).await?;
+    let csrf_token = state.signer.csrf_token(&session_id);
+    let cookies = session_cookies(&session_id, &csrf_token);
+    Ok((
+        AppendHeaders(cookies),
+        Json(DataResponse::new(LoginResponse {
+            token: session_id,
+            csrf_token,
+            must_change_password,
+        })),
+    )
+        .into_response())
+}
+
+/// Check the password of a user.
+/// Local users are checked against the stored hash. Unknown users and users created by LDAP are
+/// checked against the directory if LDAP is configured, unknown users are created then.
+/// Returns the id of the user, if it exists, when the credentials are wrong.
+async fn authenticate(
+    state: &FelaState,
+    username: &str,
+    password: &str,
+) -> ApiFileResult<Result<User, Option<i64>>> {
+    let user = state.database.get_user_with_password(username).await.ok();
+
+    match user {
+        Some(user) if user.auth_source == AuthSource::Local => {
+            let hash = user
+                .password
+                .clone()
+                .context("get_user_with_password did not return a password")?;
+            if !verify_password_blocking(&hash, password).await? {
+                return Ok(Err(Some(user.id)));
+            }
+
+            // Hashes created with older parameters are replaced while the password is known.
+            upgrade_password_hash(&state.database, user.id, &hash, password).await;
+            Ok(Ok(user))
+        }
+        user => {
+            let user_id = user.as_ref().map(|user| user.id);
+            let Some(config) = state.ldap.as_deref() else {
+                return Ok(Err(user_id));
+            };
+            if user.is_some_and(|user| user.auth_source != AuthSource::Ldap) {
+                return Ok(Err(user_id));
+            }
+
+            // An unreachable server is no failed attempt of the user.
+            match config.authenticate(username, password).await? {
+                Some(entry) => Ok(Ok(
+                    ldap_user(&state.database, config, username, &entry).await?
+                )),
+                None => Ok(Err(user_id)),
+            }
+        }
+    }
 }
 
 /// Create new session and get session token.
+/// Also sets the session and CSRF cookies.
+/// Users with two-factor authentication get a `TwoFactorChallenge` instead.
+/// Users can be authenticated by LDAP, see `authenticate`.
+/// Failed attempts are throttled per username and IP address, see `LoginThrottle`.
 #[debug_handler]
 pub async fn login(
     session: Option<Session>,
+    ClientIp(ip): ClientIp,
+    headers: HeaderMap,
     State(state): State<FelaState>,
     Json(data): Json<LoginRequest>,
-) -> ApiResult<DataResponse<LoginResponse>> {
+) -> ApiFileResult<Response> {
     // Check if the user is already logged in.
     if session.is_some() {
         api_bail!(AlreadyLoggedIn)
@@ -61,46 +212,342 @@ pub async fn login(
         api_bail!(InvalidCredentials)
     }
 
-    // Get the user from the database.
+    let attempt = reserve_attempt(&state, &username, Some(ip))?;
+
+    let user = match authenticate(&state, &username, &data.password).await {
+        Ok(Ok(user)) => user,
+        Ok(Err(user_id)) => {
+            record_failed_login(&state, AuditEvent::LoginFailed, attempt, user_id).await;
+            api_bail!(InvalidCredentials)
+        }
+        Err(err) => {
+            state.login_throttle.release(attempt);
+            return Err(err);
+        }
+    };
+
+    // The session is only created after the code was entered.
+    // Earlier failures are kept until then, so a known password doesn't allow guessing codes
+    // endlessly.
+    if state.database.has_two_factor(user.id).await? {
+        state.login_throttle.release(attempt);
+        let expires = time::OffsetDateTime::now_utc() + TWO_FACTOR_CHALLENGE_LIFETIME;
+        let signed = SignedUser::current(&state.database, user.id).await?;
+        let challenge = state.signer.sign(TWO_FACTOR_CHALLENGE, signed, expires);
+
+        return Ok(Json(DataResponse::new(TwoFactorChallenge {
+            two_factor_required: true,
+            challenge,
+        }))
+        .into_response());
+    }
+    state.login_throttle.record_success(attempt);
+
+    start_session(&state, user.id, user.must_change_password, &headers, ip).await
+}
+
+/// Second step of a login with two-factor authentication.
+#[derive(Deserialize)]
+pub struct TwoFactorLoginRequest {
+    challenge: String,
+    /// TOTP or recovery code.
+    code: String,
+}
+
+/// Create a new session with the challenge of `login` and a TOTP or recovery code.
+pub async fn login_two_factor(
+    ClientIp(ip): ClientIp,
+    headers: HeaderMap,
+    State(state): State<FelaState>,
+    Json(data): Json<TwoFactorLoginRequest>,
+) -> ApiFileResult<Response> {
+    let now = time::OffsetDateTime::now_utc();
+    let Some(signed) = state
+        .signer
+        .verify(TWO_FACTOR_CHALLENGE, &data.challenge, now)
+    else {
+        api_bail!(InvalidCredentials)
+    };
+    // A password change since the first step invalidates the challenge.
+    if !signed.is_current(&state.database).await {
+        api_bail!(InvalidCredentials)
+    }
     let user = state
         .database
-        .get_user_with_password(&username)
+        .get_user(signed.user_id)
         .await
         .context(ApiError::InvalidCredentials)?;
 
-    // Check if the password is correct.
-    if !verify_password(
-        &user
-            .password
-            .with_context(|| "get_user_with_password did not return a password")?,
-        &data.password,
-    ) {
+    let attempt = reserve_attempt(&state, &user.name, Some(ip))?;
+
+    if !verify_two_factor_code(&state.database, user.id, &data.code, now).await? {
+        record_failed_login(&state, AuditEvent::TwoFactorFailed, attempt, Some(user.id)).await;
+        api_bail!(InvalidTwoFactorCode)
+    }
+    state.login_throttle.record_success(attempt);
+
+    start_session(&state, user.id, user.must_change_password, &headers, ip).await
+}
+
+/// Ways to log in besides username and password, shown on the login page.
+#[derive(Serialize)]
+pub struct LoginOptions {
+    oidc: bool,
+    proxy: bool,
+}
+
+/// Get the enabled ways to log in.
+pub async fn login_options() -> ApiResult<DataResponse<LoginOptions>> {
+    data_response!(LoginOptions {
+        oidc: OIDC_CONFIG.is_some(),
+        proxy: TRUSTED_PROXY.is_some(),
+    })
+}
+
+/// Create a new session for the user named by a trusted reverse proxy, see `TrustedProxy`.
+/// Unknown users are created.
+pub async fn login_proxy(
+    session: Option<Session>,
+    ConnectInfo(address): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    State(state): State<FelaState>,
+) -> ApiFileResult<Response> {
+    if session.is_some() {
+        api_bail!(AlreadyLoggedIn)
+    }
+    let Some(proxy) = TRUSTED_PROXY.as_ref() else {
+        api_bail!(NotFound)
+    };
+
+    // The proxy itself has to be trusted, the session is listed with the address of its client.
+    let Some(username) = proxy.username(&headers, address.ip()) else {
+        api_bail!(InvalidCredentials)
+    };
+    let user = proxy_user(&state.database, &username).await?;
+    let ip = client_ip(&headers, address.ip());
+
+    start_session(&state, user.id, user.must_change_password, &headers, ip).await
+}
+
+/// Redirect URI of the OpenID Connect login, has to be registered at the provider.
+fn oidc_redirect_uri(api_url: &str) -> String {
+    format!("{api_url}/oidc/callback")
+}
+
+/// Start a login at the OpenID Connect provider, see `OidcConfig`.
+/// Redirects the browser to the provider, which redirects back to `/oidc/callback`.
+pub async fn oidc_login(ApiUrl(api_url): ApiUrl) -> ApiFileResult<Response> {
+    let Some(config) = OIDC_CONFIG.as_ref() else {
+        api_bail!(NotFound)
+    };
+
+    let metadata = config.discover().await?;
+    let login = LoginState::new();
+    let url = config.authorization_url(&metadata, &oidc_redirect_uri(&api_url), &login);
+
+    Ok((
+        AppendHeaders([oidc_cookie(Some(&login.to_cookie()))]),
+        Redirect::to(&url),
+    )
+        .into_response())
+}
+
+/// Parameters the provider redirects back with.
+#[derive(Deserialize)]
+pub struct OidcCallbackQuery {
+    code: Option<String>,
+    state: Option<String>,
+    error: Option<String>,
+}
+
+/// Finish a login at the OpenID Connect provider.
+/// Creates a session for the user of the ID token, the user is created on the first login.
+/// Redirects to the web interface with the session cookies set.
+pub async fn oidc_callback(
+    ClientIp(ip): ClientIp,
+    ApiUrl(api_url): ApiUrl,
+    headers: HeaderMap,
+    State(state): State<FelaState>,
+    Query(query): Query<OidcCallbackQuery>,
+) -> ApiFileResult<Response> {
+    let Some(config) = OIDC_CONFIG.as_ref() else {
+        api_bail!(NotFound)
+    };
+    if let Some(error) = query.error {
+        tracing::warn!("OpenID Connect login failed at the provider: {}", error);
         api_bail!(InvalidCredentials)
     }
 
-    // Create a new session.
-    let session_id = create_session_id();
-    state
-        .database
-        .create_session(user.id, &session_id)
+    // The state has to match the cookie of the browser that started the login.
+    let login = get_cookie(&headers, OIDC_COOKIE).and_then(LoginState::from_cookie);
+    let (Some(login), Some(code)) = (login, query.code) else {
+        api_bail!(InvalidCredentials)
+    };
+    if query.state.as_deref() != Some(login.state.as_str()) {
+        api_bail!(InvalidCredentials)
+    }
+
+    let metadata = config.discover().await?;
+    let now = time::OffsetDateTime::now_utc();
+    let claims = config
+        .exchange_code(&metadata, &code, &oidc_redirect_uri(&api_url), &login, now)
         .await
-        .context("Failed to create session in database")?;
+        .map_err(|err| {
+            tracing::warn!("OpenID Connect login failed: {:?}", err);
+            ApiError::InvalidCredentials
+        })?;
+    let user = oidc_user(&state.database, config, &claims).await?;
 
-    data_response!(LoginResponse { token: session_id })
+    let session_id = create_session(&state, user.id, &headers, ip).await?;
+    let csrf_token = state.signer.csrf_token(&session_id);
+    let [session_cookie, csrf_cookie] = session_cookies(&session_id, &csrf_token);
+    let web_url = PUBLIC_URL
+        .as_deref()
+        .map_or_else(|| "/".to_string(), |url| format!("{url}/"));
+
+    Ok((
+        AppendHeaders([session_cookie, csrf_cookie, oidc_cookie(None)]),
+        Redirect::to(&web_url),
+    )
+        .into_response())
 }
 
-/// Remove the session from the database.
+/// Remove the session from the database and clear the session cookies.
 pub async fn logout(
     State(state): State<FelaState>,
-    Session(session): Session,
-) -> ApiResult<SuccessResponse> {
+    PasswordChangeSession(session): PasswordChangeSession,
+) -> ApiFileResult<impl IntoResponse> {
     state.database.delete_session(&session.session_id).await?;
 
-    api_response!("server-authentication--logged-out")
+    Ok((
+        AppendHeaders(clear_session_cookies()),
+        Json(SuccessResponse::new(
+            "server-authentication--logged-out",
+            None,
+        )),
+    ))
 }
 
 /// Return session info to client.
-/// Used to check if session is still alive.
-pub async fn info(Session(session): Session) -> ApiResult<DataResponse<SessionInfo>> {
+/// Used to check if session is still alive and if the user has to change their password.
+pub async fn info(
+    PasswordChangeSession(session): PasswordChangeSession,
+) -> ApiResult<DataResponse<SessionInfo>> {
     data_response!(session)
 }
+
+/// Resource to create a signed URL for.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SignedUrlRequest {
+    #[serde(flatten)]
+    resource: SignedResource,
+    /// Lifetime in seconds, capped at `MAX_SIGNED_URL_LIFETIME`.
+    expires_in: Option<i64>,
+}
+
+/// Signed URL that works without a session until it expires.
+#[derive(Serialize)]
+pub struct SignedUrlResponse {
+    url: String,
+    token: String,
+    #[serde(with = "time::serde::iso8601")]
+    expires: time::OffsetDateTime,
+}
+
+/// Create a signed URL for an audio file, cover or download.
+/// Used for `<audio>` elements, casting devices and external players that can't send the session.
+pub async fn create_signed_url(
+    Session(session): Session,
+    ApiUrl(api_url): ApiUrl,
+    State(state): State<FelaState>,
+    Json(data): Json<SignedUrlRequest>,
+) -> ApiResult<DataResponse<SignedUrlResponse>> {
+    let lifetime = data
+        .expires_in
+        .map_or(SIGNED_URL_LIFETIME, time::Duration::seconds)
+        .clamp(time::Duration::ZERO, MAX_SIGNED_URL_LIFETIME);
+    let expires = time::OffsetDateTime::now_utc() + lifetime;
+
+    let path = data.resource.path();
+    let signed = SignedUser::current(&state.database, session.user_id).await?;
+    let token = state.signer.sign(&path, signed, expires);
+
+    // The path starts with the API prefix, which is already part of the API URL.
+    let url = format!(
+        "{api_url}{}?token={token}",
+        path.trim_start_matches(API_PREFIX)
+    );
+
+    data_response!(SignedUrlResponse {
+        url,
+        token,
+        expires
+    })
+}
+
+/// Session of the user, marked if it is the one making the request.
+#[derive(Serialize)]
+pub struct SessionResponse {
+    #[serde(flatten)]
+    session: UserSession,
+    current: bool,
+}
+
+/// Get the active sessions of the current user.
+pub async fn get_sessions(
+    AccountSession(session): AccountSession,
+    State(state): State<FelaState>,
+) -> ApiResult<DataResponse<Vec<SessionResponse>>> {
+    let sessions = state
+        .database
+        .get_user_sessions(session.user_id)
+        .await?
+        .into_iter()
+        .map(|user_session| SessionResponse {
+            current: user_session.session_id == session.session_id,
+            session: user_session,
+        })
+        .collect();
+
+    data_response!(sessions)
+}
+
+/// Revoke a session of the current user, e.g. of a lost device.
+/// Signed URLs can't be traced back to a session, all of them are revoked too.
+pub async fn revoke_session(
+    AccountSession(session): AccountSession,
+    State(state): State<FelaState>,
+    Path(id): Path<String>,
+) -> ApiResult<SuccessResponse> {
+    if !state
+        .database
+        .delete_user_session(session.user_id, &id)
+        .await?
+    {
+        api_bail!(NotFound)
+    }
+    state
+        .database
+        .rotate_signing_generation(session.user_id)
+        .await?;
+
+    api_response!("server-authentication--session-revoked")
+}
+
+/// Revoke all sessions of the current user, except the one making the request.
+pub async fn revoke_other_sessions(
+    AccountSession(session): AccountSession,
+    State(state): State<FelaState>,
+) -> ApiResult<SuccessResponse> {
+    state
+        .database
+        .delete_user_sessions(session.user_id, Some(&session.session_id))
+        .await?;
+    state
+        .database
+        .rotate_signing_generation(session.user_id)
+        .await?;
+
+    api_response!("server-authentication--sessions-revoked")
+}
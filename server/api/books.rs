@@ -1,20 +1,49 @@
 use std::fmt::{Display, Formatter};
 
-use super::response::{ApiError, ApiFileResult, ApiResult, DataResponse, SuccessResponse};
+use super::{
+    playlist::{Playlist, PlaylistEntry},
+    response::{ApiError, ApiFileResult, ApiResult, DataResponse, SuccessResponse, job_response},
+    url::ApiUrl,
+};
 use crate::{
     api_bail, api_response,
-    auth::session::{AdminSession, Session},
+    auth::{
+        session::{LibraryManagerSession, ProgressSession, Session},
+        signed_url::{SignedAccess, SignedResource, SignedUser},
+    },
     data_response,
     database::{
         book::Book,
         chapter::Chapter,
         file::{File, FileData},
+        image::{BookImage, ImageKind},
         library::LibraryEntry,
+        session::SessionInfo,
+    },
+    fs::{
+        archive::{ArchiveEntry, chapter_list, create_zip},
+        download_cache::{
+            DOWNLOAD_CACHE_PATH, DOWNLOAD_CACHE_SIZE, cached_download_name, download_key,
+            evict_downloads,
+        },
+        image_store::ImageStore,
+        path::validate_path_within_bounds,
+        send_file::send_file,
+        storage::{FELA_MEDIA_ROOT, PartialFile},
     },
-    fs::{path::validate_path_within_bounds, storage::FELA_MEDIA_ROOT},
     media::{
-        cover::get_cover_bytes,
-        ffmpeg::{ffprobe_chapters, ffprobe_duration},
+        cover::{
+            CoverFormat, CoverSize, cached_cover_variant, detect_image_type, dominant_color,
+            get_book_image as get_book_image_data, get_cover_bytes, remove_unused_image,
+        },
+        cover_candidates::discover_cover,
+        ffmpeg::{
+            Chapters, chapters_from_files, ffmetadata, ffmpeg_merge_m4b, ffprobe_chapters,
+            ffprobe_duration,
+        },
+        jobs::JobStatus,
+        loudness::{analyze_book_in_background, book_gain},
+        waveform::{generate_book_waveforms, waveform_job_key},
     },
     state::FelaState,
 };
@@ -22,11 +51,14 @@ use anyhow::Context;
 use axum::{
     Json, Router,
     body::Bytes,
-    extract::{Path, State},
+    extract::{Path, Query, State},
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
     routing::{get, put},
 };
 use axum_typed_multipart::{FieldData, TryFromMultipart, TypedMultipart};
 use std::sync::LazyLock;
+use time::{Duration, OffsetDateTime};
 
 use serde::{Deserialize, Serialize};
 
@@ -36,6 +68,13 @@ static PLACEHOLDER_COVER: LazyLock<Vec<u8>> = LazyLock::new(|| {
     placeholder_cover.to_vec()
 });
 
+/// How long the file URLs in a playlist stay valid, long enough to listen through most books.
+const PLAYLIST_TOKEN_LIFETIME: Duration = Duration::hours(24);
+
+/// Hash of the placeholder, so its resized variants are cached like other covers.
+static PLACEHOLDER_COVER_HASH: LazyLock<String> =
+    LazyLock::new(|| ImageStore::hash(&PLACEHOLDER_COVER));
+
 /// Build router for books and library.
 /// Is attached to `/book`.
 pub fn router() -> Router<FelaState> {
@@ -43,6 +82,15 @@ pub fn router() -> Router<FelaState> {
         .route("/", get(get_books).post(upload_book))
         .route("/{book_id}", get(get_book_details))
         .route("/{book_id}/cover", get(get_book_cover))
+        .route(
+            "/{book_id}/cover/{kind}",
+            get(get_book_image)
+                .put(set_book_image)
+                .delete(delete_book_image),
+        )
+        .route("/{book_id}/download", get(download_book))
+        .route("/{book_id}/playlist.m3u8", get(get_m3u8_playlist))
+        .route("/{book_id}/playlist.xspf", get(get_xspf_playlist))
         .route("/{book_id}/library", put(set_book_list))
         .route("/{book_id}/progress", put(update_progress))
 }
@@ -68,6 +116,8 @@ pub struct BookResponse {
     library: Option<LibraryEntry>,
     #[serde(skip_serializing_if = "Vec::is_empty")]
     chapters: Vec<Chapter>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    images: Vec<BookImage>,
 }
 
 /// Get info for a single book by id.
@@ -95,31 +145,530 @@ pub async fn get_book_details(
         .get_library_entry(session.user_id, book_id)
         .await?;
 
+    // Get images from the database.
+    let images = state.database.get_book_images(book_id).await?;
+
     data_response!(BookResponse {
         book,
         files,
         library,
         chapters,
+        images,
     })
 }
 
-/// Get cover for book.
+/// Query parameters for a cover request.
+#[derive(Deserialize)]
+pub struct CoverQuery {
+    #[serde(default)]
+    size: CoverSize,
+}
+
+/// Get the front cover of a book.
+/// Accepts signed URLs in place of a session.
 pub async fn get_book_cover(
-    Session(_): Session,
+    _: SignedAccess,
     Path(book_id): Path<i64>,
+    Query(CoverQuery { size }): Query<CoverQuery>,
+    headers: HeaderMap,
     State(state): State<FelaState>,
-) -> ApiFileResult<Vec<u8>> {
-    // Get cover image from the database.
-    let result = state.database.get_book_cover(book_id).await;
+) -> ApiFileResult<Response> {
+    serve_book_image(&state, book_id, ImageKind::Front, size, &headers).await
+}
 
-    match result {
-        Ok(Some(cover)) => Ok(cover),
-        Ok(None) => Ok(PLACEHOLDER_COVER.clone()),
+/// Get an image of a book.
+/// Accepts signed URLs in place of a session.
+pub async fn get_book_image(
+    _: SignedAccess,
+    Path((book_id, kind)): Path<(i64, ImageKind)>,
+    Query(CoverQuery { size }): Query<CoverQuery>,
+    headers: HeaderMap,
+    State(state): State<FelaState>,
+) -> ApiFileResult<Response> {
+    serve_book_image(&state, book_id, kind, size, &headers).await
+}
+
+/// Serve an image of a book from the image store.
+/// Resized images are encoded as WebP if the client accepts it, JPEG otherwise.
+/// A missing front cover is replaced by a placeholder.
+pub(super) async fn serve_book_image(
+    state: &FelaState,
+    book_id: i64,
+    kind: ImageKind,
+    size: CoverSize,
+    headers: &HeaderMap,
+) -> ApiFileResult<Response> {
+    let hash = match state.database.get_book_image(book_id, kind).await {
+        Ok(hash) => hash,
         Err(err) => {
             tracing::error!("Failed to get cover image from database: {:?}", err);
-            Ok(PLACEHOLDER_COVER.clone())
+            None
         }
+    };
+    let hash = match hash {
+        Some(hash) => hash,
+        None if kind == ImageKind::Front => PLACEHOLDER_COVER_HASH.clone(),
+        None => api_bail!(NotFound),
+    };
+
+    let accept = headers
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok());
+    let format = CoverFormat::from_accept(accept);
+
+    // Images never change for a hash, so the hash makes a strong ETag.
+    let etag = match size.pixels() {
+        Some(pixels) => format!("\"{}-{}.{}\"", hash, pixels, format.extension()),
+        None => format!("\"{}\"", hash),
+    };
+    let cache_headers = [
+        (header::ETAG, etag.clone()),
+        (header::CACHE_CONTROL, "private, max-age=86400".to_string()),
+        (header::VARY, "Accept".to_string()),
+    ];
+    let if_none_match = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok());
+    if if_none_match == Some(etag.as_str()) {
+        return Ok((StatusCode::NOT_MODIFIED, cache_headers).into_response());
     }
+
+    let cover = if hash == *PLACEHOLDER_COVER_HASH {
+        PLACEHOLDER_COVER.clone()
+    } else {
+        state.images.read(&hash).await?
+    };
+
+    let (cover, content_type) = match size.pixels() {
+        Some(pixels) => {
+            let variant = cached_cover_variant(&state.images, &hash, cover, pixels, format).await?;
+            (variant, format.content_type())
+        }
+        None => {
+            let content_type = detect_image_type(&cover)
+                .map(|image_type| image_type.content_type)
+                .unwrap_or("application/octet-stream");
+            (cover, content_type)
+        }
+    };
+
+    Ok((
+        [(header::CONTENT_TYPE, content_type.to_string())],
+        cache_headers,
+        cover,
+    )
+        .into_response())
+}
+
+/// Data needed to set an image of a book.
+#[derive(TryFromMultipart)]
+pub struct UploadBookImage {
+    cover: FieldData<Bytes>,
+}
+
+/// Set an image of a book.
+/// Accepts the same cover data as the book upload, either image data or a path.
+pub async fn set_book_image(
+    State(state): State<FelaState>,
+    LibraryManagerSession(_): LibraryManagerSession,
+    Path((book_id, kind)): Path<(i64, ImageKind)>,
+    TypedMultipart(UploadBookImage { cover }): TypedMultipart<UploadBookImage>,
+) -> ApiResult<SuccessResponse> {
+    if state.database.get_book_details(book_id).await?.is_none() {
+        api_bail!(NotFound)
+    }
+
+    let cover = get_cover_bytes(cover).await?;
+
+    let hash = state.images.store(&cover).await?;
+    let previous = state.database.set_book_image(book_id, kind, &hash).await?;
+    if let Some(previous) = previous {
+        remove_unused_image(&state.database, &state.images, &previous).await;
+    }
+
+    if kind == ImageKind::Front {
+        let color = tokio::task::spawn_blocking(move || dominant_color(&cover).ok())
+            .await
+            .ok()
+            .flatten();
+        state
+            .database
+            .set_cover_color(book_id, color.as_deref())
+            .await?;
+    }
+    state.database.touch_book(book_id).await?;
+
+    api_response!("books--image-updated")
+}
+
+/// Remove an image from a book.
+pub async fn delete_book_image(
+    State(state): State<FelaState>,
+    LibraryManagerSession(_): LibraryManagerSession,
+    Path((book_id, kind)): Path<(i64, ImageKind)>,
+) -> ApiResult<SuccessResponse> {
+    let Some(hash) = state.database.delete_book_image(book_id, kind).await? else {
+        api_bail!(NotFound)
+    };
+    remove_unused_image(&state.database, &state.images, &hash).await;
+
+    if kind == ImageKind::Front {
+        state.database.set_cover_color(book_id, None).await?;
+    }
+    state.database.touch_book(book_id).await?;
+
+    api_response!("books--image-deleted")
+}
+
+/// Formats a book can be downloaded in.
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum DownloadFormat {
+    /// Archive of all files, the cover and a chapter list.
+    #[default]
+    Zip,
+    /// All files merged into a single M4B with embedded chapters and cover.
+    M4b,
+}
+
+impl DownloadFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            DownloadFormat::Zip => "zip",
+            DownloadFormat::M4b => "m4b",
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            DownloadFormat::Zip => "application/zip",
+            DownloadFormat::M4b => "audio/mp4",
+        }
+    }
+}
+
+/// Querystring for a book download.
+#[derive(Deserialize)]
+pub struct DownloadQuery {
+    #[serde(default)]
+    format: DownloadFormat,
+    /// Apply the suggested gain of the book when creating an M4B.
+    #[serde(default)]
+    normalize: bool,
+    /// Answer with `202 Accepted` while the download is created instead of waiting for it.
+    #[serde(default)]
+    prepare: bool,
+}
+
+/// Download a book for offline listening, either as zip archive or merged M4B.
+/// Downloads are created by a background job on first request, and sent once they are ready.
+/// Clients that rather poll opt in with `?prepare` or `Accept: application/json`, and get
+/// `202 Accepted` with `Retry-After` until the download is ready. Downloads are cached by the content of the book, so clients can resume
+/// downloads with range requests, and edits to the book create a new download.
+/// Accepts signed URLs in place of a session.
+pub async fn download_book(
+    _: SignedAccess,
+    Path(book_id): Path<i64>,
+    Query(DownloadQuery {
+        format,
+        normalize,
+        prepare,
+    }): Query<DownloadQuery>,
+    headers: HeaderMap,
+    State(state): State<FelaState>,
+) -> ApiFileResult<Response> {
+    let book = state
+        .database
+        .get_book_details(book_id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    let files = state.database.get_files_for_book(book_id).await?;
+    if files.is_empty() {
+        api_bail!(NotFound)
+    }
+
+    // Normalization is only possible once all files have been analyzed.
+    let gain = match format {
+        DownloadFormat::M4b if normalize => book_gain(&files),
+        _ => None,
+    };
+
+    let extension = format.extension();
+    let key = download_key(&book, &files).await?;
+    let gain_suffix = gain
+        .map(|gain| format!("-{gain:+.2}dB"))
+        .unwrap_or_default();
+    let download_name = cached_download_name(book.id, &key, &gain_suffix, extension);
+    let download_path = DOWNLOAD_CACHE_PATH.join(&download_name);
+    let file_name = download_file_name(&format!("{} - {}", book.author, book.title));
+
+    if !download_path.exists() {
+        let job_key = format!("download-{download_name}");
+        match state.jobs.status(&job_key) {
+            Some(JobStatus::Failed { error }) => {
+                // Report the failure once, the next request tries again.
+                state.jobs.forget(&job_key);
+                api_bail!(JobFailed, error)
+            }
+            Some(JobStatus::Running) => {}
+            // Not started yet, or finished and evicted since.
+            Some(JobStatus::Finished) | None => {
+                state.jobs.start(
+                    &job_key,
+                    create_download(
+                        state.clone(),
+                        book,
+                        files,
+                        format,
+                        gain,
+                        download_path.clone(),
+                    ),
+                );
+            }
+        }
+
+        let prepare = prepare
+            || headers
+                .get(header::ACCEPT)
+                .and_then(|accept| accept.to_str().ok())
+                .is_some_and(|accept| accept.contains("application/json"));
+        if prepare {
+            return Ok(job_response(JobStatus::Running));
+        }
+
+        // The job keeps running if the client disconnects while waiting.
+        if let Some(JobStatus::Failed { error }) = state.jobs.wait(&job_key).await {
+            state.jobs.forget(&job_key);
+            api_bail!(JobFailed, error)
+        }
+    }
+
+    let mut response = send_file(&download_path.to_string_lossy(), Some(&headers))
+        .await
+        .into_response();
+
+    if matches!(
+        response.status(),
+        StatusCode::OK | StatusCode::PARTIAL_CONTENT
+    ) {
+        let response_headers = response.headers_mut();
+        response_headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static(format.content_type()),
+        );
+        response_headers.insert(
+            header::CONTENT_DISPOSITION,
+            HeaderValue::from_str(&format!("attachment; filename=\"{file_name}.{extension}\""))
+                .context("Failed to create Content-Disposition header")?,
+        );
+    }
+
+    Ok(response)
+}
+
+/// Create a book download at `download_path` and evict old downloads from the cache.
+async fn create_download(
+    state: FelaState,
+    book: Book,
+    files: Vec<File>,
+    format: DownloadFormat,
+    gain: Option<f64>,
+    download_path: std::path::PathBuf,
+) -> anyhow::Result<()> {
+    let cover =
+        get_book_image_data(&state.database, &state.images, book.id, ImageKind::Front).await?;
+
+    // Use the chapters of the book, fall back to file boundaries if there are none.
+    let chapters = state.database.get_chapters_for_book(book.id).await?;
+    let chapters = if chapters.is_empty() {
+        chapters_from_files(&files)
+    } else {
+        chapters.into_iter().map(Chapters::from).collect()
+    };
+
+    // Write into a partial file first, so requests never see an incomplete download.
+    // It is removed if creating the download fails.
+    let partial = PartialFile::temporary();
+
+    match format {
+        DownloadFormat::Zip => {
+            // Prefix file names with their position to keep them in order.
+            let width = files.len().to_string().len();
+            let mut entries = files
+                .iter()
+                .map(|file| {
+                    let path = std::path::PathBuf::from(&file.path);
+                    let extension = path
+                        .extension()
+                        .map(|extension| format!(".{}", extension.to_string_lossy()))
+                        .unwrap_or_default();
+                    ArchiveEntry::File {
+                        name: format!(
+                            "{:0width$} - {}{}",
+                            file.position,
+                            download_file_name(&file.name),
+                            extension
+                        ),
+                        path,
+                    }
+                })
+                .collect::<Vec<_>>();
+
+            if let Some(cover) = cover {
+                let extension = detect_image_type(&cover).map_or("jpg", |image| image.extension);
+                entries.push(ArchiveEntry::Data {
+                    name: format!("cover.{extension}"),
+                    data: cover,
+                });
+            }
+
+            entries.push(ArchiveEntry::Data {
+                name: "chapters.txt".to_string(),
+                data: chapter_list(&chapters).into_bytes(),
+            });
+
+            create_zip(entries, partial.path().to_path_buf()).await
+        }
+        DownloadFormat::M4b => {
+            let paths = files
+                .iter()
+                .map(|file| std::path::PathBuf::from(&file.path))
+                .collect::<Vec<_>>();
+            let metadata = ffmetadata(&book.title, &book.author, &chapters);
+            ffmpeg_merge_m4b(&paths, &metadata, cover.as_deref(), gain, partial.path()).await
+        }
+    }
+    .with_context(|| format!("Failed to create download for book {}", book.id))?;
+
+    partial.persist(&download_path).await?;
+
+    evict_downloads(
+        &DOWNLOAD_CACHE_PATH,
+        book.id,
+        &download_path,
+        *DOWNLOAD_CACHE_SIZE,
+    )
+    .await
+}
+
+/// Make a name safe to use as file name in archives and the Content-Disposition header.
+fn download_file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii() && !c.is_ascii_control() && !matches!(c, '"' | '\\' | '/' | ':') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Playlist of the files of a book, in `position` order.
+/// Players can't send the session, so every file URL carries a token signed for the user.
+async fn book_playlist(
+    state: &FelaState,
+    session: &SessionInfo,
+    book_id: i64,
+    api_url: &str,
+) -> ApiFileResult<(Book, Playlist)> {
+    let book = state
+        .database
+        .get_book_details(book_id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    let files = state.database.get_files_for_book(book_id).await?;
+
+    let expires = OffsetDateTime::now_utc() + PLAYLIST_TOKEN_LIFETIME;
+    let signed = SignedUser::current(&state.database, session.user_id).await?;
+    let entries = files
+        .into_iter()
+        .map(|file| {
+            let path = SignedResource::Audio { id: file.id }.path();
+            let token = state.signer.sign(&path, signed, expires);
+            PlaylistEntry {
+                title: file.name,
+                duration: file.duration,
+                url: format!("{api_url}/fs/audio/{}?token={token}", file.id),
+            }
+        })
+        .collect();
+
+    let cover = SignedResource::Cover {
+        id: book.id,
+        kind: None,
+    }
+    .path();
+    let cover_token = state.signer.sign(&cover, signed, expires);
+
+    let playlist = Playlist {
+        title: book.title.clone(),
+        author: book.author.clone(),
+        image: Some(format!(
+            "{api_url}/book/{}/cover?token={cover_token}",
+            book.id
+        )),
+        entries,
+    };
+
+    Ok((book, playlist))
+}
+
+/// Send a playlist as attachment, so browsers hand it to a player.
+fn playlist_response(
+    book: &Book,
+    body: String,
+    extension: &str,
+    content_type: &'static str,
+) -> Response {
+    let file_name = download_file_name(&format!("{} - {}", book.author, book.title));
+
+    (
+        [
+            (header::CONTENT_TYPE, content_type.to_string()),
+            (
+                header::CONTENT_DISPOSITION,
+                format!("attachment; filename=\"{file_name}.{extension}\""),
+            ),
+            (header::CACHE_CONTROL, "private, no-store".to_string()),
+        ],
+        body,
+    )
+        .into_response()
+}
+
+/// Get the files of a book as M3U8 playlist.
+pub async fn get_m3u8_playlist(
+    Session(session): Session,
+    Path(book_id): Path<i64>,
+    ApiUrl(api_url): ApiUrl,
+    State(state): State<FelaState>,
+) -> ApiFileResult<Response> {
+    let (book, playlist) = book_playlist(&state, &session, book_id, &api_url).await?;
+
+    Ok(playlist_response(
+        &book,
+        playlist.m3u8(),
+        "m3u8",
+        "audio/x-mpegurl; charset=utf-8",
+    ))
+}
+
+/// Get the files of a book as XSPF playlist.
+pub async fn get_xspf_playlist(
+    Session(session): Session,
+    Path(book_id): Path<i64>,
+    ApiUrl(api_url): ApiUrl,
+    State(state): State<FelaState>,
+) -> ApiFileResult<Response> {
+    let (book, playlist) = book_playlist(&state, &session, book_id, &api_url).await?;
+
+    Ok(playlist_response(
+        &book,
+        playlist.xspf(),
+        "xspf",
+        "application/xspf+xml",
+    ))
 }
 
 /// Data needed for a book upload.
@@ -140,10 +689,10 @@ pub struct UploadBookResponse {
 
 /// "Upload" a new book.
 /// No actual audio data is send, the server reads from the servers disk.
-/// This is more a "registering". An admin sets the author, title, and cover for a book.
+/// This is more a "registering". A library manager sets the author, title, and cover for a book.
 pub async fn upload_book(
     State(state): State<FelaState>,
-    AdminSession(_): AdminSession,
+    LibraryManagerSession(_): LibraryManagerSession,
     TypedMultipart(UploadBook {
         title,
         author,
@@ -169,15 +718,19 @@ pub async fn upload_book(
 
     // Extract cover image.
     let cover = if let Some(cover) = cover {
-        let cover_data = get_cover_bytes(cover).await;
-        if let Ok(cover_data) = cover_data {
-            Some(cover_data)
-        } else {
-            tracing::debug!("Failed to get cover image bytes: {:?}", cover_data);
-            api_bail!(FailedToGetCoverImage)
-        }
+        Some(get_cover_bytes(cover).await?)
     } else {
-        None
+        // Pick a cover from the book's directory or the files' embedded artwork.
+        discover_cover(&files).await
+    };
+
+    // Compute the dominant cover color, shown by clients while the cover loads.
+    let cover_color = match cover.clone() {
+        Some(cover) => tokio::task::spawn_blocking(move || dominant_color(&cover).ok())
+            .await
+            .ok()
+            .flatten(),
+        None => None,
     };
 
     // Extract chapters if only one file is uploaded.
@@ -216,19 +769,39 @@ pub async fn upload_book(
     // Sort file data by name.
     file_data.sort_by(|a, b| a.name.cmp(&b.name));
 
+    // Move the cover into the image store.
+    let cover = match cover {
+        Some(cover) => Some(state.images.store(&cover).await?),
+        None => None,
+    };
+
     // Insert book into the database.
     let book_id = state
         .database
-        .create_book(title, author, cover.as_ref(), &file_data, chapters.as_ref())
+        .create_book(
+            title,
+            author,
+            cover.as_deref(),
+            cover_color.as_deref(),
+            &file_data,
+            chapters.as_ref(),
+        )
         .await?;
 
+    // Analyze loudness and generate waveforms in the background, both decode every file.
+    analyze_book_in_background(state.database.clone(), book_id);
+    state.jobs.start(
+        &waveform_job_key(book_id),
+        generate_book_waveforms(state.database.clone(), state.waveforms.clone(), book_id),
+    );
+
     data_response!(UploadBookResponse { book_id })
 }
 
 /// Define the library lists.
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
-enum LibraryLists {
+pub enum LibraryLists {
     // TODO: Maybe allow users to create their own lists?
     Listening,
     WantToListen,
@@ -236,6 +809,15 @@ enum LibraryLists {
     Abandoned,
 }
 
+impl LibraryLists {
+    pub const ALL: [LibraryLists; 4] = [
+        LibraryLists::Listening,
+        LibraryLists::WantToListen,
+        LibraryLists::Finished,
+        LibraryLists::Abandoned,
+    ];
+}
+
 impl Display for LibraryLists {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
@@ -258,7 +840,7 @@ pub struct SetBookList {
 
 /// Move book into library.
 pub async fn set_book_list(
-    Session(session): Session,
+    ProgressSession(session): ProgressSession,
     Path(book_id): Path<i64>,
     State(state): State<FelaState>,
     Json(SetBookList {
@@ -286,7 +868,7 @@ pub struct UpdateProgress {
 
 /// Update progress data.
 pub async fn update_progress(
-    Session(session): Session,
+    ProgressSession(session): ProgressSession,
     Path(book_id): Path<i64>,
     State(state): State<FelaState>,
     Json(UpdateProgress { file_id, progress }): Json<UpdateProgress>,
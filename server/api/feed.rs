@@ -0,0 +1,400 @@
+use anyhow::Context;
+use axum::{
+    Router,
+    extract::{Path, Query, State},
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use serde::Deserialize;
+use time::{Duration, OffsetDateTime, format_description::well_known::Rfc2822};
+
+use super::{
+    books::serve_book_image,
+    response::{ApiError, ApiFileResult},
+    url::ApiUrl,
+    xml::XmlWriter,
+};
+use crate::{
+    api_bail,
+    auth::random::random_string,
+    database::{book::Book, image::ImageKind, user::User},
+    fs::{list_fs::audio_content_type, send_file::send_file, storage::TMP_PATH},
+    media::{
+        cover::{CoverSize, RANDOM_FILE_NAME_LENGTH},
+        ffmpeg::{ffmpeg_transcode_segment, locate_segment},
+    },
+    state::FelaState,
+};
+
+const RSS_TYPE: &str = "application/rss+xml; charset=utf-8";
+
+/// Build router for private podcast feeds.
+/// Is attached to `/feed`.
+///
+/// Podcast apps can't send an `Authorization` header, so every route authenticates with the
+/// feed token of a user in the path instead.
+pub fn router() -> Router<FelaState> {
+    Router::new()
+        .route("/{token}/book/{book_id}", get(book_feed))
+        .route("/{token}/listening", get(listening_feed))
+        .route("/{token}/file/{file_id}", get(feed_file))
+        .route("/{token}/chapter/{chapter_id}", get(feed_chapter))
+        .route("/{token}/cover/{book_id}", get(feed_cover))
+}
+
+/// Get the user a feed token belongs to.
+/// Unknown tokens are reported as missing, so feeds can't be told apart from invalid tokens.
+async fn feed_user(state: &FelaState, token: &str) -> Result<User, ApiError> {
+    state
+        .database
+        .get_user_by_feed_token(token)
+        .await?
+        .ok_or(ApiError::NotFound)
+}
+
+/// A single episode of a feed.
+struct Episode {
+    guid: String,
+    title: String,
+    url: String,
+    content_type: &'static str,
+    length: u64,
+    duration: f64,
+    published: OffsetDateTime,
+    image: String,
+}
+
+/// Start a feed with the channel metadata.
+/// Feeds are marked as blocked, so podcast directories never list them.
+fn open_channel(
+    title: &str,
+    author: &str,
+    description: &str,
+    link: &str,
+    image: &str,
+) -> XmlWriter {
+    let mut xml = XmlWriter::new();
+    xml.open(
+        "rss",
+        &[
+            ("version", "2.0"),
+            ("xmlns:itunes", "http://www.itunes.com/dtds/podcast-1.0.dtd"),
+        ],
+    )
+    .open("channel", &[])
+    .text("title", &[], title)
+    .text("link", &[], link)
+    .text("description", &[], description)
+    .text("itunes:author", &[], author)
+    .text("itunes:summary", &[], description)
+    .empty("itunes:image", &[("href", image)])
+    .open("image", &[])
+    .text("url", &[], image)
+    .text("title", &[], title)
+    .text("link", &[], link)
+    .close()
+    .text("itunes:type", &[], "serial")
+    .text("itunes:explicit", &[], "false")
+    .text("itunes:block", &[], "Yes");
+
+    xml
+}
+
+/// Add an item for an episode.
+fn episode_item(xml: &mut XmlWriter, episode: &Episode, number: usize) {
+    xml.open("item", &[])
+        .text("guid", &[("isPermaLink", "false")], &episode.guid)
+        .text("title", &[], &episode.title)
+        .text(
+            "pubDate",
+            &[],
+            &episode.published.format(&Rfc2822).unwrap_or_default(),
+        )
+        .empty(
+            "enclosure",
+            &[
+                ("url", &episode.url),
+                ("length", &episode.length.to_string()),
+                ("type", episode.content_type),
+            ],
+        )
+        .text(
+            "itunes:duration",
+            &[],
+            &(episode.duration.round() as i64).to_string(),
+        )
+        .text("itunes:episode", &[], &number.to_string())
+        .text("itunes:episodeType", &[], "full")
+        .empty("itunes:image", &[("href", &episode.image)])
+        .close();
+}
+
+/// Episodes for the files of a book.
+async fn file_episodes(
+    state: &FelaState,
+    feed_url: &str,
+    book: &Book,
+) -> ApiFileResult<Vec<Episode>> {
+    let files = state.database.get_files_for_book(book.id).await?;
+    let single_file = files.len() == 1;
+
+    let mut episodes = Vec::with_capacity(files.len());
+    for file in files {
+        let path = std::path::Path::new(&file.path);
+        let length = tokio::fs::metadata(path)
+            .await
+            .map(|metadata| metadata.len())
+            .unwrap_or_default();
+
+        episodes.push(Episode {
+            guid: format!("urn:fela:file:{}", file.id),
+            title: if single_file {
+                book.title.clone()
+            } else {
+                file.name.clone()
+            },
+            url: format!("{feed_url}/file/{}", file.id),
+            content_type: audio_content_type(path),
+            length,
+            duration: file.duration,
+            // Offset episodes by their position, so apps sorting by date keep the book in order.
+            published: book.created + Duration::seconds(file.position),
+            image: format!("{feed_url}/cover/{}", book.id),
+        });
+    }
+
+    Ok(episodes)
+}
+
+/// Episodes for the chapters of a book, transcoded on first request.
+async fn chapter_episodes(
+    state: &FelaState,
+    feed_url: &str,
+    book: &Book,
+) -> ApiFileResult<Vec<Episode>> {
+    let chapters = state.database.get_chapters_for_book(book.id).await?;
+
+    Ok(chapters
+        .into_iter()
+        .enumerate()
+        .map(|(index, chapter)| Episode {
+            guid: format!("urn:fela:chapter:{}", chapter.id),
+            title: chapter.name,
+            url: format!("{feed_url}/chapter/{}", chapter.id),
+            content_type: "audio/mp4",
+            // The size of a transcoded chapter isn't known before it is created.
+            length: 0,
+            duration: chapter.end - chapter.start,
+            published: book.created + Duration::seconds(index as i64 + 1),
+            image: format!("{feed_url}/cover/{}", book.id),
+        })
+        .collect())
+}
+
+fn feed_response(xml: XmlWriter) -> Response {
+    (
+        [
+            (header::CONTENT_TYPE, RSS_TYPE),
+            (header::CACHE_CONTROL, "private, no-cache"),
+        ],
+        xml.finish(),
+    )
+        .into_response()
+}
+
+/// Query parameters for book feeds.
+#[derive(Deserialize)]
+pub struct BookFeedQuery {
+    #[serde(default)]
+    chapters: bool,
+}
+
+/// Feed of a single book.
+/// Has one episode per file, or one episode per chapter if `chapters=true` is set.
+/// Books without chapters always use their files.
+pub async fn book_feed(
+    Path((token, book_id)): Path<(String, i64)>,
+    Query(query): Query<BookFeedQuery>,
+    ApiUrl(api_url): ApiUrl,
+    State(state): State<FelaState>,
+) -> ApiFileResult<Response> {
+    feed_user(&state, &token).await?;
+    let book = state
+        .database
+        .get_book_details(book_id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+    let feed_url = format!("{api_url}/feed/{token}");
+
+    let mut episodes = Vec::new();
+    if query.chapters {
+        episodes = chapter_episodes(&state, &feed_url, &book).await?;
+    }
+    if episodes.is_empty() {
+        episodes = file_episodes(&state, &feed_url, &book).await?;
+    }
+
+    let mut xml = open_channel(
+        &book.title,
+        &book.author,
+        &format!("{} by {}", book.title, book.author),
+        &format!("{api_url}/book/{}", book.id),
+        &format!("{feed_url}/cover/{}", book.id),
+    );
+    for (index, episode) in episodes.iter().enumerate() {
+        episode_item(&mut xml, episode, index + 1);
+    }
+
+    Ok(feed_response(xml))
+}
+
+/// Feed of all books on the listening list of the user, one episode per file.
+pub async fn listening_feed(
+    Path(token): Path<String>,
+    ApiUrl(api_url): ApiUrl,
+    State(state): State<FelaState>,
+) -> ApiFileResult<Response> {
+    let user = feed_user(&state, &token).await?;
+    let books = state
+        .database
+        .get_library_books(user.id, "listening")
+        .await?;
+
+    let feed_url = format!("{api_url}/feed/{token}");
+    // Books without a cover get the placeholder, which also covers an empty list.
+    let image = format!(
+        "{feed_url}/cover/{}",
+        books.first().map_or(0, |book| book.id)
+    );
+
+    let mut xml = open_channel(
+        "Listening",
+        "Fela",
+        &format!("Books {} is listening to", user.name),
+        &api_url,
+        &image,
+    );
+    let mut number = 0;
+    for book in &books {
+        for mut episode in file_episodes(&state, &feed_url, book).await? {
+            number += 1;
+            episode.title = format!("{} - {}", book.title, episode.title);
+            episode_item(&mut xml, &episode, number);
+        }
+    }
+
+    Ok(feed_response(xml))
+}
+
+/// Send an audio file of a book.
+pub async fn feed_file(
+    Path((token, file_id)): Path<(String, i64)>,
+    headers: HeaderMap,
+    State(state): State<FelaState>,
+) -> ApiFileResult<Response> {
+    feed_user(&state, &token).await?;
+    let path = state
+        .database
+        .get_file_path(&file_id.to_string())
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+    let content_type = audio_content_type(std::path::Path::new(&path));
+    Ok(audio_response(&path, content_type, &headers).await)
+}
+
+/// Send a chapter of a book as AAC audio.
+/// The chapter is transcoded into the temporary directory on first request and reused
+/// afterwards. Chapters spanning multiple files end at the end of the first file.
+pub async fn feed_chapter(
+    Path((token, chapter_id)): Path<(String, i64)>,
+    headers: HeaderMap,
+    State(state): State<FelaState>,
+) -> ApiFileResult<Response> {
+    feed_user(&state, &token).await?;
+    let chapter = state
+        .database
+        .get_chapter(chapter_id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    let book = state
+        .database
+        .get_book_details(chapter.book_id)
+        .await?
+        .ok_or(ApiError::NotFound)?;
+
+    // Segments are keyed by modification time, so edits to a book create new segments.
+    let segment_path = TMP_PATH.join(format!(
+        "chapter-{}-{}.m4a",
+        chapter.id,
+        book.modified.unix_timestamp()
+    ));
+
+    if !segment_path.exists() {
+        let files = state.database.get_files_for_book(book.id).await?;
+        let Some((file, offset, duration)) = locate_segment(&files, chapter.start, chapter.end)
+        else {
+            api_bail!(NotFound)
+        };
+
+        // Write into a partial file first, so concurrent requests never see an incomplete segment.
+        let partial_path =
+            TMP_PATH.join(format!("{}.part", random_string(RANDOM_FILE_NAME_LENGTH)));
+        let result = ffmpeg_transcode_segment(
+            std::path::Path::new(&file.path),
+            offset,
+            duration,
+            &partial_path,
+        )
+        .await;
+
+        if let Err(err) = result {
+            let _ = tokio::fs::remove_file(&partial_path).await;
+            return Err(err
+                .context(format!("Failed to transcode chapter {}", chapter.id))
+                .into());
+        }
+
+        tokio::fs::rename(&partial_path, &segment_path)
+            .await
+            .context("Failed to move chapter into place")?;
+    }
+
+    Ok(audio_response(&segment_path.to_string_lossy(), "audio/mp4", &headers).await)
+}
+
+/// Send a file with range support and the given content type.
+async fn audio_response(path: &str, content_type: &'static str, headers: &HeaderMap) -> Response {
+    let mut response = send_file(path, Some(headers)).await.into_response();
+
+    if matches!(
+        response.status(),
+        StatusCode::OK | StatusCode::PARTIAL_CONTENT
+    ) {
+        response
+            .headers_mut()
+            .insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    }
+
+    response
+}
+
+/// Send the front cover of a book.
+pub async fn feed_cover(
+    Path((token, book_id)): Path<(String, i64)>,
+    headers: HeaderMap,
+    State(state): State<FelaState>,
+) -> ApiFileResult<Response> {
+    feed_user(&state, &token).await?;
+
+    serve_book_image(
+        &state,
+        book_id,
+        ImageKind::Front,
+        CoverSize::Original,
+        &headers,
+    )
+    .await
+}
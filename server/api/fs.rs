@@ -1,11 +1,14 @@
-use std::path::PathBuf;
+use std::{
+    hash::{DefaultHasher, Hash, Hasher},
+    path::PathBuf,
+};
 
 use anyhow::Context;
 use axum::{
     Router,
     extract::{Path, Query, State},
-    http::HeaderMap,
-    response::IntoResponse,
+    http::{HeaderMap, HeaderName, StatusCode, header},
+    response::{IntoResponse, Response},
     routing::get,
 };
 use serde::{Deserialize, Serialize};
@@ -13,15 +16,19 @@ use serde::{Deserialize, Serialize};
 use super::response::{ApiError, ApiFileResult, ApiResult, DataResponse};
 use crate::{
     api_bail,
-    auth::session::{AdminSession, Session},
+    auth::{session::LibraryManagerSession, signed_url::SignedAccess},
     data_response,
     fs::{
         list_fs::{Entry, IMAGE_EXTENSIONS, get_file_system_list},
         path::validate_path_within_bounds,
         send_file::send_file,
-        storage::{FELA_MEDIA_ROOT, TMP_PATH},
+        storage::{FELA_MEDIA_ROOT, TMP_PATH, file_fingerprint},
+    },
+    media::{
+        cover_candidates::{discover_covers, sibling_audio_files},
+        ffmpeg::{FileInfo, ffprobe_book_details},
+        waveform::{PEAKS_PER_SECOND, file_waveform},
     },
-    media::ffmpeg::{FileInfo, ffprobe_book_details},
     state::FelaState,
 };
 
@@ -33,6 +40,7 @@ pub fn router() -> Router<FelaState> {
         .route("/info", get(ffprobe))
         .route("/tmp-cover", get(get_tmp_cover))
         .route("/audio/{file_id}", get(get_audio_file))
+        .route("/audio/{file_id}/waveform", get(get_waveform))
 }
 
 /// Query for the file system list.
@@ -52,7 +60,7 @@ pub struct FsResponse {
 
 /// List a directory in the file system.
 pub async fn fs(
-    AdminSession(_): AdminSession,
+    LibraryManagerSession(_): LibraryManagerSession,
     Query(FsQuery { path }): Query<FsQuery>,
 ) -> ApiResult<DataResponse<FsResponse>> {
     let path = path
@@ -96,11 +104,13 @@ pub async fn fs(
 pub struct FfprobeResponse {
     path: String,
     info: FileInfo,
+    /// Cover candidates from the file's directory and embedded artwork, best first.
+    covers: Vec<String>,
 }
 
 /// Get ffprobe info for a file.
 pub async fn ffprobe(
-    AdminSession(_): AdminSession,
+    LibraryManagerSession(_): LibraryManagerSession,
     Query(FsQuery { path }): Query<FsQuery>,
 ) -> ApiResult<DataResponse<FfprobeResponse>> {
     let path = path.context(ApiError::InvalidPath)?;
@@ -112,9 +122,14 @@ pub async fn ffprobe(
         .await
         .with_context(|| ApiError::FFProbeFailed(path.to_string_lossy().into_owned()))?;
 
+    // Offer covers for the whole book, the other audio files in the directory belong to it.
+    let files = sibling_audio_files(&path).await;
+    let covers = discover_covers(&files).await;
+
     data_response!(FfprobeResponse {
         path: path.to_string_lossy().into_owned(),
-        info
+        info,
+        covers,
     })
 }
 
@@ -127,7 +142,7 @@ pub struct TemporaryCoverQuery {
 /// Return a temporary cover.
 /// The path is handed via querystring `&name=`.
 pub async fn get_tmp_cover(
-    AdminSession(_): AdminSession,
+    LibraryManagerSession(_): LibraryManagerSession,
     Query(TemporaryCoverQuery { name }): Query<TemporaryCoverQuery>,
 ) -> ApiFileResult<Vec<u8>> {
     // Join name with TMP_PATH before validating — name is a bare filename, not a full path.
@@ -161,8 +176,9 @@ pub async fn get_tmp_cover(
 
 /// Send user the requested audio file.
 /// We use the path stored in the database to get the file.
+/// Players without a session can use a signed `token`, like the URLs in playlists.
 pub async fn get_audio_file(
-    Session(_): Session,
+    _: SignedAccess,
     Path(file_id): Path<String>,
     headers: HeaderMap,
     State(state): State<FelaState>,
@@ -176,3 +192,55 @@ pub async fn get_audio_file(
 
     Ok(send_file(&file, Some(&headers)).await)
 }
+
+/// Send waveform peaks of an audio file, used to draw an overview of the audio on seek bars.
+///
+/// The body is `application/octet-stream`. Every byte is the peak amplitude of
+/// `1 / PEAKS_PER_SECOND` seconds of audio, linearly scaled to 0-255. The last byte may cover a
+/// shorter window. The window length in seconds is also sent in the `X-Waveform-Interval` header.
+/// Peaks are generated in the background when a book is added, files without them get them on
+/// first request. They are stored in the database until the file changes.
+/// Everyone who can stream the file can read its waveform, with a session or a signed `token`.
+pub async fn get_waveform(
+    _: SignedAccess,
+    Path(file_id): Path<i64>,
+    headers: HeaderMap,
+    State(state): State<FelaState>,
+) -> ApiFileResult<Response> {
+    let path = state
+        .database
+        .get_file_path(&file_id.to_string())
+        .await?
+        .context(ApiError::NotFound)?;
+    let path = std::path::Path::new(&path);
+    let file_hash = file_fingerprint(path)
+        .await
+        .context(ApiError::FileNotFound)?;
+    let peaks = file_waveform(&state.database, &state.waveforms, file_id, path, &file_hash).await?;
+
+    // File ids can be reused after a file is removed, so the peaks themselves are the ETag.
+    let mut hasher = DefaultHasher::new();
+    peaks.hash(&mut hasher);
+    let etag = format!("\"{:x}\"", hasher.finish());
+
+    if headers
+        .get(header::IF_NONE_MATCH)
+        .is_some_and(|value| value.as_bytes() == etag.as_bytes())
+    {
+        return Ok((StatusCode::NOT_MODIFIED, [(header::ETAG, etag)]).into_response());
+    }
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, "application/octet-stream".to_string()),
+            (header::CACHE_CONTROL, "private, max-age=604800".to_string()),
+            (header::ETAG, etag),
+            (
+                HeaderName::from_static("x-waveform-interval"),
+                (1.0 / PEAKS_PER_SECOND as f64).to_string(),
+            ),
+        ],
+        peaks,
+    )
+        .into_response())
+}
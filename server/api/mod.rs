@@ -2,9 +2,16 @@ mod account;
 mod admin;
 mod authentication;
 mod books;
+mod feed;
 mod fs;
+mod opds;
+mod playlist;
 pub mod response;
+mod role;
+mod subsonic;
+pub mod url;
 mod user;
+mod xml;
 
 use axum::{Router, middleware, routing::get};
 use tower_http::trace::TraceLayer;
@@ -25,7 +32,11 @@ pub async fn build_router(state: FelaState) -> Router {
         .nest("/user", user::router())
         .nest("/fs", fs::router())
         .nest("/account", account::router())
+        .nest("/role", role::router())
         .nest("/admin", admin::router())
+        .nest("/opds", opds::router())
+        .nest("/feed", feed::router())
+        .nest("/rest", subsonic::router())
         .route_layer(middleware::from_fn_with_state(state.clone(), get_session))
         .layer(TraceLayer::new_for_http())
         .with_state(state)
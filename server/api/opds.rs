@@ -0,0 +1,359 @@
+use axum::{
+    Router,
+    extract::{FromRequestParts, Path, State},
+    http::{HeaderValue, header, request::Parts},
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use percent_encoding::{NON_ALPHANUMERIC, utf8_percent_encode};
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+
+use super::{
+    books::LibraryLists,
+    response::{ApiError, ApiFileResult},
+    url::API_PREFIX,
+    xml::XmlWriter,
+};
+use crate::{
+    api_bail,
+    auth::session::BasicAuthSession,
+    database::{book::Book, session::SessionInfo},
+    state::FelaState,
+};
+
+/// Number of books in the recently added feed.
+const RECENT_BOOKS: i64 = 50;
+
+const NAVIGATION_TYPE: &str = "application/atom+xml;profile=opds-catalog;kind=navigation";
+const ACQUISITION_TYPE: &str = "application/atom+xml;profile=opds-catalog;kind=acquisition";
+
+/// Build router for the OPDS 1.2 catalog.
+/// Is attached to `/opds`.
+pub fn router() -> Router<FelaState> {
+    Router::new()
+        .route("/", get(root))
+        .route("/recent", get(recent))
+        .route("/books", get(all_books))
+        .route("/authors", get(authors))
+        .route("/authors/{author}", get(author_books))
+        .route("/library/{list}", get(library_list))
+}
+
+/// Session of a catalog client.
+/// Rejects with an HTTP Basic challenge, so reader apps ask for credentials.
+pub struct OpdsSession(pub SessionInfo);
+
+impl FromRequestParts<FelaState> for OpdsSession {
+    type Rejection = Response;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &FelaState,
+    ) -> Result<Self, Self::Rejection> {
+        match BasicAuthSession::from_request_parts(parts, state).await {
+            Ok(BasicAuthSession(session)) => Ok(OpdsSession(session)),
+            // Throttled clients are told when to try again instead of being asked for credentials.
+            Err(err @ ApiError::TooManyAttempts(_)) => Err(err.into_response()),
+            Err(err) => {
+                // Wrong credentials make the reader ask for new ones.
+                let err = match err {
+                    ApiError::InvalidCredentials => ApiError::NotLoggedIn,
+                    err => err,
+                };
+                let mut response = err.into_response();
+                response.headers_mut().insert(
+                    header::WWW_AUTHENTICATE,
+                    HeaderValue::from_static(r#"Basic realm="Fela", charset="UTF-8""#),
+                );
+                Err(response)
+            }
+        }
+    }
+}
+
+fn timestamp(time: OffsetDateTime) -> String {
+    time.format(&Rfc3339).unwrap_or_default()
+}
+
+fn list_title(list: &LibraryLists) -> &'static str {
+    match list {
+        LibraryLists::Listening => "Listening",
+        LibraryLists::WantToListen => "Want to listen",
+        LibraryLists::Finished => "Finished",
+        LibraryLists::Abandoned => "Abandoned",
+    }
+}
+
+/// Start a feed with its metadata and navigation links.
+fn open_feed(id: &str, title: &str, href: &str, kind: &str, updated: OffsetDateTime) -> XmlWriter {
+    let root = format!("{API_PREFIX}/opds");
+
+    let mut xml = XmlWriter::new();
+    xml.open(
+        "feed",
+        &[
+            ("xmlns", "http://www.w3.org/2005/Atom"),
+            ("xmlns:opds", "http://opds-spec.org/2010/catalog"),
+        ],
+    )
+    .text("id", &[], id)
+    .text("title", &[], title)
+    .text("updated", &[], &timestamp(updated))
+    .open("author", &[])
+    .text("name", &[], "Fela")
+    .close()
+    .empty("link", &[("rel", "self"), ("href", href), ("type", kind)])
+    .empty(
+        "link",
+        &[("rel", "start"), ("href", &root), ("type", NAVIGATION_TYPE)],
+    );
+
+    xml
+}
+
+/// Add an entry linking to another feed.
+fn navigation_entry(
+    xml: &mut XmlWriter,
+    id: &str,
+    title: &str,
+    href: &str,
+    kind: &str,
+    content: &str,
+    updated: OffsetDateTime,
+) {
+    xml.open("entry", &[])
+        .text("id", &[], id)
+        .text("title", &[], title)
+        .text("updated", &[], &timestamp(updated))
+        .text("content", &[("type", "text")], content)
+        .empty(
+            "link",
+            &[("rel", "subsection"), ("href", href), ("type", kind)],
+        )
+        .close();
+}
+
+/// Add an entry for a book with cover and acquisition links.
+fn book_entry(xml: &mut XmlWriter, book: &Book) {
+    let book_path = format!("{API_PREFIX}/book/{}", book.id);
+
+    xml.open("entry", &[])
+        .text("id", &[], &format!("urn:fela:book:{}", book.id))
+        .text("title", &[], &book.title)
+        .text("updated", &[], &timestamp(book.modified))
+        .open("author", &[])
+        .text("name", &[], &book.author)
+        .close()
+        .empty(
+            "link",
+            &[
+                ("rel", "http://opds-spec.org/image"),
+                ("href", &format!("{book_path}/cover")),
+                ("type", "image/jpeg"),
+            ],
+        )
+        .empty(
+            "link",
+            &[
+                ("rel", "http://opds-spec.org/image/thumbnail"),
+                ("href", &format!("{book_path}/cover?size=256")),
+                ("type", "image/jpeg"),
+            ],
+        )
+        .empty(
+            "link",
+            &[
+                ("rel", "http://opds-spec.org/acquisition"),
+                ("href", &format!("{book_path}/download?format=m4b")),
+                ("type", "audio/mp4"),
+                ("title", "M4B"),
+            ],
+        )
+        .empty(
+            "link",
+            &[
+                ("rel", "http://opds-spec.org/acquisition"),
+                ("href", &format!("{book_path}/download?format=zip")),
+                ("type", "application/zip"),
+                ("title", "ZIP"),
+            ],
+        )
+        .close();
+}
+
+fn feed_response(xml: XmlWriter, kind: &'static str) -> Response {
+    ([(header::CONTENT_TYPE, kind)], xml.finish()).into_response()
+}
+
+/// Build an acquisition feed listing books.
+fn acquisition_feed(id: &str, title: &str, href: &str, books: &[Book]) -> Response {
+    let updated = books
+        .iter()
+        .map(|book| book.modified)
+        .max()
+        .unwrap_or_else(OffsetDateTime::now_utc);
+
+    let mut xml = open_feed(id, title, href, ACQUISITION_TYPE, updated);
+    for book in books {
+        book_entry(&mut xml, book);
+    }
+
+    feed_response(xml, ACQUISITION_TYPE)
+}
+
+/// Root of the catalog, links to all other feeds.
+pub async fn root(OpdsSession(_): OpdsSession) -> ApiFileResult<Response> {
+    let root = format!("{API_PREFIX}/opds");
+    let now = OffsetDateTime::now_utc();
+
+    let mut xml = open_feed("urn:fela:root", "Fela", &root, NAVIGATION_TYPE, now);
+    navigation_entry(
+        &mut xml,
+        "urn:fela:recent",
+        "Recently added",
+        &format!("{root}/recent"),
+        ACQUISITION_TYPE,
+        "Books added most recently",
+        now,
+    );
+    navigation_entry(
+        &mut xml,
+        "urn:fela:books",
+        "All books",
+        &format!("{root}/books"),
+        ACQUISITION_TYPE,
+        "All books by title",
+        now,
+    );
+    navigation_entry(
+        &mut xml,
+        "urn:fela:authors",
+        "Authors",
+        &format!("{root}/authors"),
+        NAVIGATION_TYPE,
+        "Books by author",
+        now,
+    );
+    for list in LibraryLists::ALL {
+        navigation_entry(
+            &mut xml,
+            &format!("urn:fela:library:{list}"),
+            list_title(&list),
+            &format!("{root}/library/{list}"),
+            ACQUISITION_TYPE,
+            &format!("Your {} list", list_title(&list).to_lowercase()),
+            now,
+        );
+    }
+
+    Ok(feed_response(xml, NAVIGATION_TYPE))
+}
+
+/// Books added most recently.
+pub async fn recent(
+    OpdsSession(_): OpdsSession,
+    State(state): State<FelaState>,
+) -> ApiFileResult<Response> {
+    let books = state.database.get_recent_books(RECENT_BOOKS).await?;
+
+    Ok(acquisition_feed(
+        "urn:fela:recent",
+        "Recently added",
+        &format!("{API_PREFIX}/opds/recent"),
+        &books,
+    ))
+}
+
+/// All books by title.
+pub async fn all_books(
+    OpdsSession(_): OpdsSession,
+    State(state): State<FelaState>,
+) -> ApiFileResult<Response> {
+    let books = state.database.get_all_books().await?;
+
+    Ok(acquisition_feed(
+        "urn:fela:books",
+        "All books",
+        &format!("{API_PREFIX}/opds/books"),
+        &books,
+    ))
+}
+
+/// Authors, each linking to a feed of their books.
+pub async fn authors(
+    OpdsSession(_): OpdsSession,
+    State(state): State<FelaState>,
+) -> ApiFileResult<Response> {
+    let authors = state.database.get_authors().await?;
+    let href = format!("{API_PREFIX}/opds/authors");
+    let updated = authors
+        .iter()
+        .map(|author| author.modified)
+        .max()
+        .unwrap_or_else(OffsetDateTime::now_utc);
+
+    let mut xml = open_feed(
+        "urn:fela:authors",
+        "Authors",
+        &href,
+        NAVIGATION_TYPE,
+        updated,
+    );
+    for author in &authors {
+        let encoded = utf8_percent_encode(&author.name, NON_ALPHANUMERIC).to_string();
+        let books = match author.books {
+            1 => "1 book".to_string(),
+            books => format!("{books} books"),
+        };
+        navigation_entry(
+            &mut xml,
+            &format!("urn:fela:author:{encoded}"),
+            &author.name,
+            &format!("{href}/{encoded}"),
+            ACQUISITION_TYPE,
+            &books,
+            author.modified,
+        );
+    }
+
+    Ok(feed_response(xml, NAVIGATION_TYPE))
+}
+
+/// Books of a single author.
+pub async fn author_books(
+    OpdsSession(_): OpdsSession,
+    Path(author): Path<String>,
+    State(state): State<FelaState>,
+) -> ApiFileResult<Response> {
+    let books = state.database.get_books_by_author(&author).await?;
+    if books.is_empty() {
+        api_bail!(NotFound)
+    }
+
+    let encoded = utf8_percent_encode(&author, NON_ALPHANUMERIC).to_string();
+    Ok(acquisition_feed(
+        &format!("urn:fela:author:{encoded}"),
+        &author,
+        &format!("{API_PREFIX}/opds/authors/{encoded}"),
+        &books,
+    ))
+}
+
+/// Books in one of the user's library lists.
+pub async fn library_list(
+    OpdsSession(session): OpdsSession,
+    Path(list): Path<LibraryLists>,
+    State(state): State<FelaState>,
+) -> ApiFileResult<Response> {
+    let books = state
+        .database
+        .get_library_books(session.user_id, &list.to_string())
+        .await?;
+
+    Ok(acquisition_feed(
+        &format!("urn:fela:library:{list}"),
+        list_title(&list),
+        &format!("{API_PREFIX}/opds/library/{list}"),
+        &books,
+    ))
+}
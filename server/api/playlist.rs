@@ -0,0 +1,135 @@
+use super::xml::XmlWriter;
+
+/// Entry of a playlist.
+pub struct PlaylistEntry {
+    pub title: String,
+    pub duration: f64,
+    pub url: String,
+}
+
+/// Playlist of the files of a book.
+pub struct Playlist {
+    pub title: String,
+    pub author: String,
+    pub image: Option<String>,
+    pub entries: Vec<PlaylistEntry>,
+}
+
+/// Remove line breaks, every line of an M3U file is a directive or a URL.
+fn single_line(text: &str) -> String {
+    text.replace(['\r', '\n'], " ")
+}
+
+impl Playlist {
+    /// Extended M3U playlist, encoded as UTF-8.
+    pub fn m3u8(&self) -> String {
+        let mut playlist = String::from("#EXTM3U\n");
+        playlist.push_str(&format!("#PLAYLIST:{}\n", single_line(&self.title)));
+        playlist.push_str(&format!("#EXTALB:{}\n", single_line(&self.title)));
+        playlist.push_str(&format!("#EXTART:{}\n", single_line(&self.author)));
+
+        for entry in &self.entries {
+            playlist.push_str(&format!(
+                "#EXTINF:{},{} - {}\n{}\n",
+                entry.duration.round() as i64,
+                single_line(&self.author),
+                single_line(&entry.title),
+                single_line(&entry.url)
+            ));
+        }
+
+        playlist
+    }
+
+    /// XSPF playlist, durations are in milliseconds.
+    pub fn xspf(&self) -> String {
+        let mut xml = XmlWriter::new();
+        xml.open(
+            "playlist",
+            &[("version", "1"), ("xmlns", "http://xspf.org/ns/0/")],
+        )
+        .text("title", &[], &self.title)
+        .text("creator", &[], &self.author);
+        if let Some(image) = &self.image {
+            xml.text("image", &[], image);
+        }
+        xml.open("trackList", &[]);
+
+        for (index, entry) in self.entries.iter().enumerate() {
+            xml.open("track", &[])
+                .text("location", &[], &entry.url)
+                .text("title", &[], &entry.title)
+                .text("creator", &[], &self.author)
+                .text("album", &[], &self.title)
+                .text("trackNum", &[], &(index + 1).to_string())
+                .text(
+                    "duration",
+                    &[],
+                    &((entry.duration * 1000.0).round() as i64).to_string(),
+                )
+                .close();
+        }
+
+        xml.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn playlist() -> Playlist {
+        Playlist {
+            title: "The Long Walk".to_string(),
+            author: "Stephen King".to_string(),
+            image: Some("http://fela.local/api/book/20/cover?token=c".to_string()),
+            entries: vec![
+                PlaylistEntry {
+                    title: "01".to_string(),
+                    duration: 100.4,
+                    url: "http://fela.local/api/fs/audio/500?token=a".to_string(),
+                },
+                PlaylistEntry {
+                    title: "Part\nTwo".to_string(),
+                    duration: 200.0,
+                    url: "http://fela.local/api/fs/audio/501?token=b".to_string(),
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_m3u8() {
+        // Test case: Verify that the M3U playlist lists entries with durations on single lines
+        assert_eq!(
+            playlist().m3u8(),
+            concat!(
+                "#EXTM3U\n",
+                "#PLAYLIST:The Long Walk\n",
+                "#EXTALB:The Long Walk\n",
+                "#EXTART:Stephen King\n",
+                "#EXTINF:100,Stephen King - 01\n",
+                "http://fela.local/api/fs/audio/500?token=a\n",
+                "#EXTINF:200,Stephen King - Part Two\n",
+                "http://fela.local/api/fs/audio/501?token=b\n",
+            )
+        );
+    }
+
+    #[test]
+    fn test_xspf() {
+        // Test case: Verify that the XSPF playlist escapes URLs and numbers tracks
+        let xspf = playlist().xspf();
+
+        assert!(xspf.starts_with(
+            r#"<?xml version="1.0" encoding="UTF-8"?><playlist version="1" xmlns="http://xspf.org/ns/0/"><title>The Long Walk</title>"#
+        ));
+        assert!(xspf.contains("<image>http://fela.local/api/book/20/cover?token=c</image>"));
+        assert!(xspf.contains(concat!(
+            "<track><location>http://fela.local/api/fs/audio/500?token=a</location>",
+            "<title>01</title><creator>Stephen King</creator><album>The Long Walk</album>",
+            "<trackNum>1</trackNum><duration>100400</duration></track>"
+        )));
+        assert!(xspf.ends_with("</trackList></playlist>"));
+    }
+}
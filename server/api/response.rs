@@ -1,11 +1,16 @@
 use axum::{
     Json,
-    http::StatusCode,
+    http::{StatusCode, header},
     response::{IntoResponse, Response},
 };
 use serde::Serialize;
 use thiserror::Error;
 
+use crate::media::jobs::JobStatus;
+
+/// Seconds clients wait before polling a running job again.
+const JOB_RETRY_AFTER: &str = "5";
+
 extern crate proc_macro;
 
 pub type ApiResult<T> = Result<Json<T>, ApiError>;
@@ -29,8 +34,45 @@ pub enum ApiError {
     #[error("server-authentication--not-logged-in")]
     NotLoggedIn,
 
-    #[error("server-authentication--not-admin")]
-    NotAdmin,
+    #[error("server-authentication--missing-permission")]
+    MissingPermission,
+
+    #[error("server-authentication--invalid-csrf-token")]
+    InvalidCsrfToken,
+
+    #[error("server-authentication--insufficient-scope")]
+    InsufficientScope,
+
+    #[error("server-authentication--password-change-required")]
+    PasswordChangeRequired,
+
+    /// Holds the minimum length.
+    #[error("server-authentication--password-too-short")]
+    PasswordTooShort(String),
+
+    /// Holds the maximum length.
+    #[error("server-authentication--password-too-long")]
+    PasswordTooLong(String),
+
+    #[error("server-authentication--password-breached")]
+    PasswordBreached,
+
+    #[error("server-authentication--invalid-two-factor-code")]
+    InvalidTwoFactorCode,
+
+    #[error("server-authentication--two-factor-already-enabled")]
+    TwoFactorAlreadyEnabled,
+
+    /// Holds the name of the existing user.
+    #[error("server-authentication--username-taken")]
+    UsernameTaken(String),
+
+    #[error("server-authentication--external-account")]
+    ExternalAccount,
+
+    /// Too many failed logins, holds the seconds until the next attempt.
+    #[error("server-authentication--too-many-attempts")]
+    TooManyAttempts(u64),
 
     // File system errors.
     #[error("server-fs--could-not-list-directory")]
@@ -48,6 +90,9 @@ pub enum ApiError {
     #[error("server-fs--not-found")]
     FileNotFound,
 
+    #[error("server-fs--file-already-exists")]
+    FileAlreadyExists(String),
+
     // Book errors.
     #[error("server-upload--missing-data")]
     UploadMissingData,
@@ -55,6 +100,33 @@ pub enum ApiError {
     #[error("server-books--failed-to-get-cover-image")]
     FailedToGetCoverImage,
 
+    #[error("server-books--invalid-cover-image")]
+    InvalidCoverImage(String),
+
+    // Role errors.
+    #[error("server-role--protected")]
+    RoleProtected,
+
+    #[error("server-role--in-use")]
+    RoleInUse,
+
+    /// The change would leave no user that can manage users.
+    #[error("server-role--last-user-manager")]
+    LastUserManager,
+
+    /// Holds the name of the existing role.
+    #[error("server-role--name-taken")]
+    RoleNameTaken(String),
+
+    // Admin errors.
+    #[error("server-admin--book-has-single-file")]
+    BookHasSingleFile,
+
+    // Job errors.
+    /// Holds the error of the failed job.
+    #[error("server-job--failed")]
+    JobFailed(String),
+
     // Internal server errors.
     #[error(transparent)]
     AnyhowError(#[from] anyhow::Error),
@@ -84,15 +156,34 @@ impl IntoResponse for ApiError {
             Self::DataMissing
             | Self::InvalidCredentials
             | Self::AlreadyLoggedIn
+            | Self::PasswordTooShort(_)
+            | Self::PasswordTooLong(_)
+            | Self::PasswordBreached
+            | Self::InvalidTwoFactorCode
+            | Self::ExternalAccount
             | Self::UploadMissingData
             | Self::PathDoesNotExist(_)
+            | Self::BookHasSingleFile
+            | Self::RoleProtected
             | Self::InvalidPath => StatusCode::BAD_REQUEST,
-            Self::CouldNotListDirectory | Self::FailedToGetCoverImage | Self::FFProbeFailed(_) => {
-                StatusCode::UNPROCESSABLE_ENTITY
-            }
+            Self::FileAlreadyExists(_)
+            | Self::TwoFactorAlreadyEnabled
+            | Self::UsernameTaken(_)
+            | Self::RoleInUse
+            | Self::LastUserManager
+            | Self::RoleNameTaken(_) => StatusCode::CONFLICT,
+            Self::CouldNotListDirectory
+            | Self::FailedToGetCoverImage
+            | Self::InvalidCoverImage(_)
+            | Self::FFProbeFailed(_) => StatusCode::UNPROCESSABLE_ENTITY,
             Self::FileNotFound | Self::NotFound => StatusCode::NOT_FOUND,
-            Self::NotLoggedIn | Self::NotAdmin => StatusCode::UNAUTHORIZED,
-            Self::AnyhowError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::NotLoggedIn => StatusCode::UNAUTHORIZED,
+            Self::MissingPermission
+            | Self::InvalidCsrfToken
+            | Self::InsufficientScope
+            | Self::PasswordChangeRequired => StatusCode::FORBIDDEN,
+            Self::TooManyAttempts(_) => StatusCode::TOO_MANY_REQUESTS,
+            Self::JobFailed(_) | Self::AnyhowError(_) => StatusCode::INTERNAL_SERVER_ERROR,
         };
 
         // Create the response
@@ -103,20 +194,49 @@ impl IntoResponse for ApiError {
             | Self::CouldNotListDirectory
             | Self::UploadMissingData
             | Self::FailedToGetCoverImage
+            | Self::BookHasSingleFile
             | Self::InvalidPath
             | Self::NotLoggedIn
-            | Self::NotAdmin
+            | Self::MissingPermission
+            | Self::InvalidCsrfToken
+            | Self::InsufficientScope
+            | Self::PasswordChangeRequired
+            | Self::PasswordBreached
+            | Self::InvalidTwoFactorCode
+            | Self::TwoFactorAlreadyEnabled
+            | Self::ExternalAccount
+            | Self::RoleProtected
+            | Self::RoleInUse
+            | Self::LastUserManager
             | Self::FileNotFound
             | Self::NotFound => ErrorResponse::new(api_error.to_string(), None),
 
-            Self::PathDoesNotExist(value) | Self::FFProbeFailed(value) => {
+            Self::PathDoesNotExist(value)
+            | Self::PasswordTooShort(value)
+            | Self::PasswordTooLong(value)
+            | Self::UsernameTaken(value)
+            | Self::RoleNameTaken(value)
+            | Self::FFProbeFailed(value)
+            | Self::InvalidCoverImage(value)
+            | Self::JobFailed(value)
+            | Self::FileAlreadyExists(value) => {
                 ErrorResponse::new(api_error.to_string(), Some(value.to_string()))
             }
 
+            Self::TooManyAttempts(seconds) => {
+                ErrorResponse::new(api_error.to_string(), Some(seconds.to_string()))
+            }
+
             Self::AnyhowError(err) => {
                 ErrorResponse::new("server-error--internal".to_string(), Some(err.to_string()))
             }
         };
+
+        // Tell clients when to try again.
+        if let Self::TooManyAttempts(seconds) = api_error {
+            return (status, [(header::RETRY_AFTER, seconds.to_string())], body).into_response();
+        }
+
         (status, body).into_response()
     }
 }
@@ -162,6 +282,16 @@ where
     }
 }
 
+/// Tell the client that a background job is running and to poll again.
+pub fn job_response(status: JobStatus) -> Response {
+    (
+        StatusCode::ACCEPTED,
+        [(header::RETRY_AFTER, JOB_RETRY_AFTER)],
+        Json(DataResponse::new(status)),
+    )
+        .into_response()
+}
+
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
 struct ErrorResponse {
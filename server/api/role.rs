@@ -0,0 +1,181 @@
+use anyhow::Context;
+use axum::{
+    Json, Router,
+    extract::{Path, State},
+    routing::{get, patch},
+};
+use serde::Deserialize;
+
+use super::response::{ApiError, ApiFileResult, ApiResult, DataResponse, SuccessResponse};
+use crate::{
+    api_bail, api_response,
+    auth::session::{Session, UserManagerSession},
+    data_response,
+    database::{
+        role::{ADMIN_ROLE, DEFAULT_ROLE, Permissions, Role},
+        session::SessionInfo,
+    },
+    state::FelaState,
+};
+
+/// Build router for role routes.
+/// Is attached to `/role`.
+pub fn router() -> Router<FelaState> {
+    Router::new()
+        .route("/", get(get_roles).post(create_role))
+        .route("/{id}", patch(update_role).delete(delete_role))
+}
+
+/// Get all roles.
+pub async fn get_roles(
+    Session(_): Session,
+    State(state): State<FelaState>,
+) -> ApiResult<DataResponse<Vec<Role>>> {
+    let roles = state.database.get_roles().await?;
+
+    data_response!(roles)
+}
+
+/// Normalize a role name.
+fn normalize_role_name(name: &str) -> ApiFileResult<String> {
+    let name = name.trim().to_lowercase();
+    if name.is_empty() {
+        api_bail!(DataMissing)
+    }
+
+    Ok(name)
+}
+
+/// Turn the error of creating or renaming a role into `RoleNameTaken` if another role has the
+/// name, role names are unique in the database.
+fn role_name_error(error: anyhow::Error, name: &str) -> ApiError {
+    match error.downcast_ref::<sqlx::Error>() {
+        Some(sqlx::Error::Database(database_error)) if database_error.is_unique_violation() => {
+            ApiError::RoleNameTaken(name.to_string())
+        }
+        _ => error.into(),
+    }
+}
+
+/// Get a role that `session` may change.
+/// The admin role always has every permission, and users can't change roles with permissions
+/// they don't have themselves.
+async fn changeable_role(
+    state: &FelaState,
+    session: &SessionInfo,
+    role_id: i64,
+) -> ApiFileResult<Role> {
+    if role_id == ADMIN_ROLE {
+        api_bail!(RoleProtected)
+    }
+
+    let Some(role) = state.database.get_role(role_id).await? else {
+        api_bail!(NotFound)
+    };
+    if !session.permissions.contains(role.permissions) {
+        api_bail!(MissingPermission)
+    }
+
+    Ok(role)
+}
+
+/// Request data for a new role.
+#[derive(Deserialize)]
+pub struct CreateRoleRequest {
+    name: String,
+    permissions: Permissions,
+}
+
+/// Create a new role.
+/// Needs the permission to manage users and every permission of the role.
+pub async fn create_role(
+    UserManagerSession(session): UserManagerSession,
+    State(state): State<FelaState>,
+    Json(body): Json<CreateRoleRequest>,
+) -> ApiResult<DataResponse<Role>> {
+    if !session.permissions.contains(body.permissions) {
+        api_bail!(MissingPermission)
+    }
+    let name = normalize_role_name(&body.name)?;
+
+    let id = state
+        .database
+        .create_role(&name, body.permissions)
+        .await
+        .map_err(|error| role_name_error(error, &name))?;
+    tracing::info!("Role {} created by {}", name, session.username);
+
+    let role = state
+        .database
+        .get_role(id)
+        .await?
+        .context("Created role not found")?;
+
+    data_response!(role)
+}
+
+/// Data to update a role.
+#[derive(Deserialize)]
+pub struct UpdateRoleRequest {
+    name: Option<String>,
+    permissions: Option<Permissions>,
+}
+
+/// Rename a role or change its permissions.
+/// Permissions are looked up on every request, so changes apply to logged in users right away.
+/// Removing the permission to manage users fails if no other user has it.
+pub async fn update_role(
+    UserManagerSession(session): UserManagerSession,
+    State(state): State<FelaState>,
+    Path(id): Path<i64>,
+    Json(body): Json<UpdateRoleRequest>,
+) -> ApiResult<DataResponse<Role>> {
+    let role = changeable_role(&state, &session, id).await?;
+    if body
+        .permissions
+        .is_some_and(|permissions| !session.permissions.contains(permissions))
+    {
+        api_bail!(MissingPermission)
+    }
+    let name = body.name.as_deref().map(normalize_role_name).transpose()?;
+
+    // The role was found, so it's only not updated if no user could manage users anymore.
+    let updated = state
+        .database
+        .update_role(role.id, name.clone(), body.permissions)
+        .await
+        .map_err(|error| role_name_error(error, name.as_deref().unwrap_or_default()))?;
+    if !updated {
+        api_bail!(LastUserManager)
+    }
+    tracing::info!("Role {} updated by {}", role.name, session.username);
+
+    let role = state
+        .database
+        .get_role(id)
+        .await?
+        .context("Updated role not found")?;
+
+    data_response!(role)
+}
+
+/// Delete a role that no user has.
+/// The default role of new users can't be deleted.
+pub async fn delete_role(
+    UserManagerSession(session): UserManagerSession,
+    State(state): State<FelaState>,
+    Path(id): Path<i64>,
+) -> ApiResult<SuccessResponse> {
+    if id == DEFAULT_ROLE {
+        api_bail!(RoleProtected)
+    }
+    let role = changeable_role(&state, &session, id).await?;
+    if state.database.count_role_users(role.id).await? > 0 {
+        api_bail!(RoleInUse)
+    }
+
+    state.database.delete_role(role.id).await?;
+    tracing::info!("Role {} deleted by {}", role.name, session.username);
+
+    api_response!("role-delete--success")
+}
@@ -0,0 +1,831 @@
+use axum::{
+    Router,
+    extract::{FromRequestParts, Query, Request, State},
+    handler::Handler,
+    http::{HeaderMap, HeaderValue, StatusCode, Uri, header, request::Parts},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::get,
+};
+use md5::Md5;
+use rand::seq::SliceRandom;
+use serde_json::{Map, Value, json};
+use sha2::{Digest, Sha256};
+use std::time::Instant;
+use time::{OffsetDateTime, format_description::well_known::Rfc3339};
+
+use super::{books::serve_book_image, response::ApiError, xml::XmlWriter};
+use crate::{
+    auth::{
+        api_token::API_TOKEN_PREFIX,
+        proxy::request_client_ip,
+        session::{credentials_session, session_from_api_token, session_from_password},
+        throttle::{record_failed_login, reserve_attempt, too_many_attempts},
+    },
+    database::{
+        api_token::TokenScope, audit::AuditEvent, book::Book, file::File, image::ImageKind,
+        role::Permission, session::SessionInfo, user::User,
+    },
+    fs::{list_fs::audio_content_type, send_file::send_file},
+    media::cover::CoverSize,
+    state::FelaState,
+};
+
+/// Version of the Subsonic API that is implemented.
+const API_VERSION: &str = "1.16.1";
+
+/// Maximum number of albums in a single album list.
+const MAX_ALBUM_LIST_SIZE: usize = 500;
+
+/// Build router for the Subsonic API.
+/// Is attached to `/rest`.
+///
+/// Implements the subset of the Subsonic and OpenSubsonic API needed to browse and stream books.
+/// Books are albums and their files are tracks. Every method is available with and without the
+/// `.view` suffix, parameters are read from the query string.
+pub fn router() -> Router<FelaState> {
+    let router = Router::new();
+    let router = endpoint(router, "ping", ping);
+    let router = endpoint(router, "getLicense", get_license);
+    let router = endpoint(router, "getMusicDirectory", get_music_directory);
+    let router = endpoint(router, "getAlbumList2", get_album_list2);
+    let router = endpoint(router, "getAlbum", get_album);
+    let router = endpoint(router, "stream", stream);
+    let router = endpoint(router, "getCoverArt", get_cover_art);
+    let router = endpoint(router, "savePlayQueue", save_play_queue);
+    let router = endpoint(router, "getPlayQueue", get_play_queue);
+    let router = endpoint(router, "scrobble", scrobble);
+
+    router
+        .fallback(not_implemented)
+        .layer(middleware::from_fn(render_response))
+}
+
+/// Register a method of the API, with and without `.view` suffix.
+fn endpoint<H, T>(router: Router<FelaState>, name: &str, handler: H) -> Router<FelaState>
+where
+    H: Handler<T, FelaState>,
+    T: 'static,
+{
+    router
+        .route(
+            &format!("/{name}"),
+            get(handler.clone()).post(handler.clone()),
+        )
+        .route(&format!("/{name}.view"), get(handler.clone()).post(handler))
+}
+
+/// Body of a Subsonic response, rendered as XML or JSON by `render_response`.
+#[derive(Clone)]
+struct SubsonicBody {
+    status: &'static str,
+    fields: Map<String, Value>,
+}
+
+/// Successful response with the fields to add to `subsonic-response`.
+pub struct SubsonicResponse(Map<String, Value>);
+
+impl SubsonicResponse {
+    fn empty() -> Self {
+        SubsonicResponse(Map::new())
+    }
+
+    fn with(name: &str, value: Value) -> Self {
+        let mut fields = Map::new();
+        fields.insert(name.to_string(), value);
+        SubsonicResponse(fields)
+    }
+}
+
+impl IntoResponse for SubsonicResponse {
+    fn into_response(self) -> Response {
+        let mut response = Response::default();
+        response.extensions_mut().insert(SubsonicBody {
+            status: "ok",
+            fields: self.0,
+        });
+        response
+    }
+}
+
+/// Error as defined by the Subsonic API.
+/// Errors are sent with status 200, clients only look at the error code. Throttled logins are
+/// the exception, they are sent with status 429 and `Retry-After`.
+#[derive(Debug)]
+pub struct SubsonicError {
+    code: u16,
+    message: String,
+    /// Seconds until the next login attempt.
+    retry_after: Option<u64>,
+}
+
+impl SubsonicError {
+    fn new(code: u16, message: String) -> Self {
+        SubsonicError {
+            code,
+            message,
+            retry_after: None,
+        }
+    }
+
+    fn generic(message: &str) -> Self {
+        SubsonicError::new(0, message.to_string())
+    }
+
+    fn too_many_attempts(seconds: u64) -> Self {
+        SubsonicError {
+            retry_after: Some(seconds),
+            ..SubsonicError::generic("Too many failed logins, try again later")
+        }
+    }
+
+    fn missing_parameter(name: &str) -> Self {
+        SubsonicError::new(10, format!("Required parameter is missing: {name}"))
+    }
+
+    fn wrong_credentials() -> Self {
+        SubsonicError::new(40, "Wrong username or password".to_string())
+    }
+
+    fn token_authentication() -> Self {
+        SubsonicError::new(
+            41,
+            "Token authentication needs the Subsonic password of the account".to_string(),
+        )
+    }
+
+    fn not_authorized() -> Self {
+        SubsonicError::new(
+            50,
+            "User is not authorized for the given operation".to_string(),
+        )
+    }
+
+    fn not_found() -> Self {
+        SubsonicError::new(70, "The requested data was not found".to_string())
+    }
+}
+
+impl From<anyhow::Error> for SubsonicError {
+    fn from(err: anyhow::Error) -> Self {
+        tracing::error!("Subsonic request failed: {:?}", err);
+        SubsonicError::generic("Internal server error")
+    }
+}
+
+impl From<ApiError> for SubsonicError {
+    fn from(err: ApiError) -> Self {
+        match err {
+            ApiError::NotFound => SubsonicError::not_found(),
+            ApiError::InsufficientScope => SubsonicError::not_authorized(),
+            ApiError::TooManyAttempts(seconds) => SubsonicError::too_many_attempts(seconds),
+            err => {
+                tracing::error!("Subsonic request failed: {:?}", err);
+                SubsonicError::generic("Internal server error")
+            }
+        }
+    }
+}
+
+impl IntoResponse for SubsonicError {
+    fn into_response(self) -> Response {
+        let mut response = Response::default();
+        if let Some(seconds) = self.retry_after {
+            *response.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(seconds));
+        }
+        response.extensions_mut().insert(SubsonicBody {
+            status: "failed",
+            fields: SubsonicResponse::with(
+                "error",
+                json!({ "code": self.code, "message": self.message }),
+            )
+            .0,
+        });
+        response
+    }
+}
+
+type SubsonicResult<T = SubsonicResponse> = Result<T, SubsonicError>;
+
+/// Parameters of a request, names can repeat.
+pub struct SubsonicParams(Vec<(String, String)>);
+
+impl SubsonicParams {
+    fn from_uri(uri: &Uri) -> Self {
+        let params = Query::<Vec<(String, String)>>::try_from_uri(uri)
+            .map(|Query(params)| params)
+            .unwrap_or_default();
+
+        SubsonicParams(params)
+    }
+
+    /// Last value of a parameter.
+    fn get(&self, name: &str) -> Option<&str> {
+        self.0
+            .iter()
+            .rev()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn require(&self, name: &str) -> SubsonicResult<&str> {
+        self.get(name)
+            .ok_or_else(|| SubsonicError::missing_parameter(name))
+    }
+
+    fn parse<T: std::str::FromStr>(&self, name: &str) -> SubsonicResult<Option<T>> {
+        self.get(name)
+            .map(|value| {
+                value
+                    .parse()
+                    .map_err(|_| SubsonicError::generic(&format!("Invalid parameter: {name}")))
+            })
+            .transpose()
+    }
+}
+
+impl FromRequestParts<FelaState> for SubsonicParams {
+    type Rejection = SubsonicError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _state: &FelaState,
+    ) -> Result<Self, Self::Rejection> {
+        Ok(SubsonicParams::from_uri(&parts.uri))
+    }
+}
+
+/// Session of a Subsonic client.
+/// Clients send the username in `u` and either the password in `p`, plain or hex encoded with an
+/// `enc:` prefix, or a token `t` and salt `s` with `t = md5(password + s)`. Token authentication
+/// needs the plain password on the server, so it only works with the Subsonic password of the
+/// user, which the account routes create. `p` accepts the Subsonic password, the account password
+/// and API tokens. An API token can also be sent as `Bearer` token.
+/// Session cookies are never accepted, Subsonic requests change state with `GET`, so a cookie
+/// would let other sites scrobble or save play queues in the name of the user.
+pub struct SubsonicSession(pub SessionInfo);
+
+impl FromRequestParts<FelaState> for SubsonicSession {
+    type Rejection = SubsonicError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &FelaState,
+    ) -> Result<Self, Self::Rejection> {
+        let bearer_token = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .filter(|token| token.starts_with(API_TOKEN_PREFIX));
+        let session = match bearer_token {
+            Some(token) => session_from_api_token(state, token)
+                .await
+                .ok_or_else(SubsonicError::wrong_credentials)?,
+            None => subsonic_login(parts, state).await?,
+        };
+
+        // Subsonic clients can't change passwords, the web interface has to be used first.
+        if session.must_change_password {
+            return Err(SubsonicError::not_authorized());
+        }
+
+        Ok(SubsonicSession(session))
+    }
+}
+
+/// Authenticate with the `u` and `p` or `t` and `s` parameters.
+/// The Subsonic password and API tokens skip Argon2, account passwords are only verified again
+/// after a few minutes.
+async fn subsonic_login(parts: &Parts, state: &FelaState) -> Result<SessionInfo, SubsonicError> {
+    let params = SubsonicParams::from_uri(&parts.uri);
+    let username = params.require("u")?.trim().to_lowercase();
+    let ip = request_client_ip(parts);
+
+    let password = match (params.get("p"), params.get("t"), params.get("s")) {
+        (Some(password), _, _) => {
+            decode_password(password).ok_or_else(SubsonicError::wrong_credentials)?
+        }
+        (None, Some(token), Some(salt)) => {
+            state
+                .login_throttle
+                .check(&username, ip, Instant::now())
+                .map_err(too_many_attempts)?;
+
+            // Without a Subsonic password there is nothing to check the token against.
+            let Some((user, password)) = subsonic_password(state, &username).await? else {
+                return Err(SubsonicError::token_authentication());
+            };
+            let expected = Md5::digest(format!("{password}{salt}"))
+                .iter()
+                .map(|byte| format!("{byte:02x}"))
+                .collect::<String>();
+
+            // The token is checked right away, so the attempt is resolved before other requests
+            // of the client reserve theirs.
+            let attempt = reserve_attempt(state, &username, ip)?;
+            if !secure_eq(&expected, &token.to_lowercase()) {
+                record_failed_login(state, AuditEvent::LoginFailed, attempt, Some(user.id)).await;
+                return Err(SubsonicError::wrong_credentials());
+            }
+            state.login_throttle.record_success(attempt);
+
+            return Ok(credentials_session(user));
+        }
+        (None, Some(_), None) => return Err(SubsonicError::missing_parameter("s")),
+        (None, None, _) => return Err(SubsonicError::missing_parameter("p")),
+    };
+
+    if password.starts_with(API_TOKEN_PREFIX) {
+        return session_from_api_token(state, &password)
+            .await
+            .filter(|session| session.username == username)
+            .ok_or_else(SubsonicError::wrong_credentials);
+    }
+
+    if let Some((user, subsonic_password)) = subsonic_password(state, &username).await?
+        && secure_eq(&subsonic_password, &password)
+    {
+        return Ok(credentials_session(user));
+    }
+
+    session_from_password(state, &username, password, ip)
+        .await
+        .map_err(|err| match err {
+            ApiError::TooManyAttempts(_) => err.into(),
+            _ => SubsonicError::wrong_credentials(),
+        })
+}
+
+/// User with their Subsonic password, `None` if the user doesn't exist or has none.
+async fn subsonic_password(
+    state: &FelaState,
+    username: &str,
+) -> SubsonicResult<Option<(User, String)>> {
+    let Some(user) = state.database.get_user_by_name(username).await? else {
+        return Ok(None);
+    };
+
+    Ok(state
+        .database
+        .get_subsonic_password(user.id)
+        .await?
+        .map(|password| (user, password)))
+}
+
+/// Compare secrets without revealing through timing how much of them matched.
+/// Comparing their hashes leaks nothing useful about the secrets themselves.
+fn secure_eq(a: &str, b: &str) -> bool {
+    Sha256::digest(a) == Sha256::digest(b)
+}
+
+/// Decode a password sent as `p` parameter.
+fn decode_password(password: &str) -> Option<String> {
+    let Some(hex) = password.strip_prefix("enc:") else {
+        return Some(password.to_string());
+    };
+
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(hex.get(index..index + 2)?, 16).ok())
+        .collect::<Option<Vec<_>>>()?;
+
+    String::from_utf8(bytes).ok()
+}
+
+/// Render Subsonic responses in the format requested with the `f` parameter.
+/// Responses without a Subsonic body, like streams and covers, are passed through.
+async fn render_response(request: Request, next: Next) -> Response {
+    let json = matches!(
+        SubsonicParams::from_uri(request.uri()).get("f"),
+        Some("json" | "jsonp")
+    );
+
+    let mut response = next.run(request).await;
+    let Some(body) = response.extensions_mut().remove::<SubsonicBody>() else {
+        return response;
+    };
+
+    let mut fields = Map::new();
+    fields.insert("status".to_string(), json!(body.status));
+    fields.insert("version".to_string(), json!(API_VERSION));
+    fields.insert("type".to_string(), json!("fela"));
+    fields.insert(
+        "serverVersion".to_string(),
+        json!(env!("CARGO_PKG_VERSION")),
+    );
+    fields.insert("openSubsonic".to_string(), json!(true));
+    fields.extend(body.fields);
+
+    let (content_type, body) = if json {
+        let body = json!({ "subsonic-response": fields }).to_string();
+        ("application/json", body)
+    } else {
+        fields.insert("xmlns".to_string(), json!("http://subsonic.org/restapi"));
+        let mut xml = XmlWriter::new();
+        xml.value("subsonic-response", &Value::Object(fields));
+        ("text/xml; charset=utf-8", xml.finish())
+    };
+
+    // Keep the status and headers of the handler, like `Retry-After` of throttled logins.
+    let (mut parts, _) = response.into_parts();
+    parts
+        .headers
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    Response::from_parts(parts, body.into())
+}
+
+/// Unknown methods.
+async fn not_implemented() -> SubsonicError {
+    SubsonicError::generic("Method not implemented")
+}
+
+fn timestamp(time: OffsetDateTime) -> String {
+    time.format(&Rfc3339).unwrap_or_default()
+}
+
+/// Parse an id with the given prefix, like `al-15`.
+fn parse_id(id: &str, prefix: &str) -> SubsonicResult<i64> {
+    id.strip_prefix(prefix)
+        .and_then(|id| id.strip_prefix('-'))
+        .and_then(|id| id.parse().ok())
+        .ok_or_else(SubsonicError::not_found)
+}
+
+fn album_id(book_id: i64) -> String {
+    format!("al-{book_id}")
+}
+
+fn track_id(file_id: i64) -> String {
+    format!("tr-{file_id}")
+}
+
+/// Album for a book.
+fn album(book: &Book, files: &[File]) -> Map<String, Value> {
+    let duration: f64 = files.iter().map(|file| file.duration).sum();
+
+    let Value::Object(album) = json!({
+        "id": album_id(book.id),
+        "name": book.title,
+        "title": book.title,
+        "album": book.title,
+        "artist": book.author,
+        "isDir": true,
+        "coverArt": album_id(book.id),
+        "songCount": files.len(),
+        "duration": duration.round() as i64,
+        "created": timestamp(book.created),
+    }) else {
+        unreachable!("json! with braces creates an object")
+    };
+
+    album
+}
+
+/// Track for a file of a book.
+async fn track(book: &Book, file: &File) -> Value {
+    let path = std::path::Path::new(&file.path);
+    let size = tokio::fs::metadata(path)
+        .await
+        .map(|metadata| metadata.len())
+        .unwrap_or_default();
+    let suffix = path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+
+    json!({
+        "id": track_id(file.id),
+        "parent": album_id(book.id),
+        "isDir": false,
+        "title": file.name,
+        "album": book.title,
+        "artist": book.author,
+        "track": file.position,
+        "coverArt": album_id(book.id),
+        "size": size,
+        "contentType": audio_content_type(path),
+        "suffix": suffix,
+        "duration": file.duration.round() as i64,
+        "created": timestamp(file.created),
+        "albumId": album_id(book.id),
+        "type": "audiobook",
+        "mediaType": "song",
+    })
+}
+
+async fn tracks(book: &Book, files: &[File]) -> Vec<Value> {
+    let mut tracks = Vec::with_capacity(files.len());
+    for file in files {
+        tracks.push(track(book, file).await);
+    }
+
+    tracks
+}
+
+/// Get a book by album id, with its files.
+async fn get_book(state: &FelaState, id: &str) -> SubsonicResult<(Book, Vec<File>)> {
+    let book_id = parse_id(id, "al")?;
+    let book = state
+        .database
+        .get_book_details(book_id)
+        .await?
+        .ok_or_else(SubsonicError::not_found)?;
+    let files = state.database.get_files_for_book(book_id).await?;
+
+    Ok((book, files))
+}
+
+/// Get a file by track id.
+async fn get_file(state: &FelaState, id: &str) -> SubsonicResult<File> {
+    let file_id = parse_id(id, "tr")?;
+    state
+        .database
+        .get_file(file_id)
+        .await?
+        .ok_or_else(SubsonicError::not_found)
+}
+
+/// Check the connection and credentials.
+pub async fn ping(SubsonicSession(_): SubsonicSession) -> SubsonicResult {
+    Ok(SubsonicResponse::empty())
+}
+
+/// Fela has no license, it is always valid.
+pub async fn get_license(SubsonicSession(_): SubsonicSession) -> SubsonicResult {
+    Ok(SubsonicResponse::with("license", json!({ "valid": true })))
+}
+
+/// Directory of a book, listing its files.
+pub async fn get_music_directory(
+    SubsonicSession(_): SubsonicSession,
+    params: SubsonicParams,
+    State(state): State<FelaState>,
+) -> SubsonicResult {
+    let (book, files) = get_book(&state, params.require("id")?).await?;
+
+    Ok(SubsonicResponse::with(
+        "directory",
+        json!({
+            "id": album_id(book.id),
+            "name": book.title,
+            "child": tracks(&book, &files).await,
+        }),
+    ))
+}
+
+/// List of books, sorted by the `type` parameter.
+/// `recent` and `frequent` list the books the user is listening to. Fela has no ratings, years
+/// or genres, so lists based on them are empty.
+pub async fn get_album_list2(
+    SubsonicSession(session): SubsonicSession,
+    params: SubsonicParams,
+    State(state): State<FelaState>,
+) -> SubsonicResult {
+    let size = params
+        .parse::<usize>("size")?
+        .unwrap_or(10)
+        .min(MAX_ALBUM_LIST_SIZE);
+    let offset = params.parse::<usize>("offset")?.unwrap_or(0);
+
+    let books = match params.require("type")? {
+        "recent" | "frequent" => {
+            state
+                .database
+                .get_library_books(session.user_id, "listening")
+                .await?
+        }
+        "starred" | "highest" | "byYear" | "byGenre" => Vec::new(),
+        list_type => {
+            let mut books = state.database.get_all_books().await?;
+            match list_type {
+                "random" => books.shuffle(&mut rand::rng()),
+                "newest" => books.sort_by_key(|book| std::cmp::Reverse(book.created)),
+                "alphabeticalByName" => {}
+                "alphabeticalByArtist" => books.sort_by(|a, b| {
+                    a.author
+                        .to_lowercase()
+                        .cmp(&b.author.to_lowercase())
+                        .then_with(|| a.title.cmp(&b.title))
+                }),
+                _ => return Err(SubsonicError::generic("Unknown album list type")),
+            }
+            books
+        }
+    };
+
+    let mut albums = Vec::new();
+    for book in books.iter().skip(offset).take(size) {
+        let files = state.database.get_files_for_book(book.id).await?;
+        albums.push(Value::Object(album(book, &files)));
+    }
+
+    Ok(SubsonicResponse::with(
+        "albumList2",
+        json!({ "album": albums }),
+    ))
+}
+
+/// A book with its files.
+pub async fn get_album(
+    SubsonicSession(_): SubsonicSession,
+    params: SubsonicParams,
+    State(state): State<FelaState>,
+) -> SubsonicResult {
+    let (book, files) = get_book(&state, params.require("id")?).await?;
+
+    let mut album = album(&book, &files);
+    album.insert(
+        "song".to_string(),
+        Value::Array(tracks(&book, &files).await),
+    );
+
+    Ok(SubsonicResponse::with("album", Value::Object(album)))
+}
+
+/// Stream a file with range support.
+/// Files are always sent as they are, requested formats and bitrates are ignored.
+pub async fn stream(
+    SubsonicSession(_): SubsonicSession,
+    params: SubsonicParams,
+    headers: HeaderMap,
+    State(state): State<FelaState>,
+) -> SubsonicResult<Response> {
+    let file = get_file(&state, params.require("id")?).await?;
+
+    let mut response = send_file(&file.path, Some(&headers)).await.into_response();
+    if matches!(
+        response.status(),
+        StatusCode::OK | StatusCode::PARTIAL_CONTENT
+    ) {
+        response.headers_mut().insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static(audio_content_type(std::path::Path::new(&file.path))),
+        );
+    }
+
+    Ok(response)
+}
+
+/// Front cover of a book, `id` can be an album or a track.
+pub async fn get_cover_art(
+    SubsonicSession(_): SubsonicSession,
+    params: SubsonicParams,
+    headers: HeaderMap,
+    State(state): State<FelaState>,
+) -> SubsonicResult<Response> {
+    let id = params.require("id")?;
+    let book_id = match parse_id(id, "tr") {
+        Ok(_) => get_file(&state, id).await?.book_id,
+        Err(_) => parse_id(id, "al")?,
+    };
+    let size = params
+        .parse::<u32>("size")?
+        .map_or(CoverSize::Original, CoverSize::fit);
+
+    Ok(serve_book_image(&state, book_id, ImageKind::Front, size, &headers).await?)
+}
+
+/// Changing the library needs the progress permission, API tokens also need at least the
+/// progress scope.
+fn require_progress_scope(session: &SessionInfo) -> SubsonicResult<()> {
+    if !session.permissions.has(Permission::Progress) || session.scope < TokenScope::Progress {
+        return Err(SubsonicError::not_authorized());
+    }
+
+    Ok(())
+}
+
+/// Store the position in a file, adding the book to the listening list if needed.
+async fn record_progress(
+    state: &FelaState,
+    user_id: i64,
+    file: &File,
+    progress: f64,
+) -> SubsonicResult<()> {
+    let entry = state
+        .database
+        .get_library_entry(user_id, file.book_id)
+        .await?;
+
+    match entry {
+        Some(_) => {
+            state
+                .database
+                .update_progress(user_id, file.book_id, file.id, progress)
+                .await?
+        }
+        None => {
+            state
+                .database
+                .manage_library_entry(
+                    user_id,
+                    file.book_id,
+                    "listening",
+                    Some(file.id),
+                    Some(progress),
+                )
+                .await?
+        }
+    }
+
+    Ok(())
+}
+
+/// Save the playing file and position, `position` is in milliseconds.
+/// Only the current file is stored, the queue itself is always the book it belongs to.
+pub async fn save_play_queue(
+    SubsonicSession(session): SubsonicSession,
+    params: SubsonicParams,
+    State(state): State<FelaState>,
+) -> SubsonicResult {
+    require_progress_scope(&session)?;
+
+    let Some(current) = params.get("current") else {
+        return Ok(SubsonicResponse::empty());
+    };
+    let file = get_file(&state, current).await?;
+    let position = params.parse::<i64>("position")?.unwrap_or(0);
+
+    record_progress(&state, session.user_id, &file, position as f64 / 1000.0).await?;
+
+    Ok(SubsonicResponse::empty())
+}
+
+/// Files of the book the user listened to last, with the stored position.
+pub async fn get_play_queue(
+    SubsonicSession(session): SubsonicSession,
+    State(state): State<FelaState>,
+) -> SubsonicResult {
+    let Some(entry) = state
+        .database
+        .get_latest_library_entry(session.user_id, "listening")
+        .await?
+    else {
+        return Ok(SubsonicResponse::empty());
+    };
+    let (book, files) = get_book(&state, &album_id(entry.book_id)).await?;
+
+    Ok(SubsonicResponse::with(
+        "playQueue",
+        json!({
+            "current": track_id(entry.file_id),
+            "position": (entry.progress * 1000.0).round() as i64,
+            "username": session.username,
+            "changed": timestamp(entry.modified),
+            "changedBy": "fela",
+            "entry": tracks(&book, &files).await,
+        }),
+    ))
+}
+
+/// Register a file as playing (`submission=false`) or played.
+/// A played file moves the progress to the start of the next file, or marks the book as
+/// finished after its last file.
+pub async fn scrobble(
+    SubsonicSession(session): SubsonicSession,
+    params: SubsonicParams,
+    State(state): State<FelaState>,
+) -> SubsonicResult {
+    require_progress_scope(&session)?;
+
+    let file = get_file(&state, params.require("id")?).await?;
+    let submission = params.parse::<bool>("submission")?.unwrap_or(true);
+
+    if !submission {
+        // Keep the position if the file is already the current one.
+        let entry = state
+            .database
+            .get_library_entry(session.user_id, file.book_id)
+            .await?;
+        if entry.is_none_or(|entry| entry.file_id != file.id) {
+            record_progress(&state, session.user_id, &file, 0.0).await?;
+        }
+        return Ok(SubsonicResponse::empty());
+    }
+
+    let files = state.database.get_files_for_book(file.book_id).await?;
+    match files.iter().find(|next| next.position > file.position) {
+        Some(next) => record_progress(&state, session.user_id, next, 0.0).await?,
+        None => {
+            state
+                .database
+                .manage_library_entry(
+                    session.user_id,
+                    file.book_id,
+                    "finished",
+                    Some(file.id),
+                    None,
+                )
+                .await?
+        }
+    }
+
+    Ok(SubsonicResponse::empty())
+}
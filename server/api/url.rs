@@ -0,0 +1,123 @@
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+    sync::LazyLock,
+};
+
+use axum::{
+    extract::{ConnectInfo, FromRequestParts},
+    http::{HeaderMap, header, request::Parts},
+};
+
+use crate::auth::proxy::{IpNetwork, TRUSTED_PROXIES, is_trusted_proxy};
+
+/// Path the API is mounted at.
+pub const API_PREFIX: &str = "/api";
+
+/// Public URL of the server, e.g. `https://fela.example.com`.
+/// Controlled by the `PUBLIC_URL` environment variable. Needed for absolute links that are
+/// opened outside of the web interface, like enclosures in podcast feeds.
+pub static PUBLIC_URL: LazyLock<Option<String>> = LazyLock::new(|| {
+    std::env::var("PUBLIC_URL")
+        .ok()
+        .map(|url| url.trim_end_matches('/').to_string())
+        .filter(|url| !url.is_empty())
+});
+
+/// Origin of the request, taken from the `Host` header.
+/// The reverse proxy headers take precedence on requests from one of the `proxies`, other clients
+/// could point links at any host with them.
+fn request_origin(headers: &HeaderMap, peer: Option<IpAddr>, proxies: &[IpNetwork]) -> String {
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(|value| value.split(',').next().unwrap_or_default().trim())
+    };
+
+    let forwarded = |name: &str| {
+        peer.filter(|peer| is_trusted_proxy(proxies, *peer))
+            .and_then(|_| header(name))
+    };
+
+    let scheme = forwarded("x-forwarded-proto").unwrap_or("http");
+    let host = forwarded("x-forwarded-host")
+        .or_else(|| header(header::HOST.as_str()))
+        .unwrap_or("localhost");
+
+    format!("{scheme}://{host}")
+}
+
+/// Absolute URL of the API.
+/// Uses `PUBLIC_URL` if it is set, the origin of the request from `peer` otherwise.
+pub fn absolute_api_url(headers: &HeaderMap, peer: Option<IpAddr>) -> String {
+    let origin = PUBLIC_URL
+        .clone()
+        .unwrap_or_else(|| request_origin(headers, peer, &TRUSTED_PROXIES));
+
+    format!("{origin}{API_PREFIX}")
+}
+
+/// Absolute URL of the API for the current request, see `absolute_api_url`.
+pub struct ApiUrl(pub String);
+
+impl<S: Send + Sync> FromRequestParts<S> for ApiUrl {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let peer = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(address)| address.ip());
+
+        Ok(Self(absolute_api_url(&parts.headers, peer)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+    use axum::http::HeaderValue;
+
+    #[test]
+    fn test_request_origin() {
+        // Test case: Verify that the origin is built from the Host header
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, HeaderValue::from_static("fela.local:3000"));
+
+        assert_eq!(
+            request_origin(&headers, None, &[]),
+            "http://fela.local:3000"
+        );
+    }
+
+    #[test]
+    fn test_request_origin_forwarded() {
+        // Test case: Verify that reverse proxy headers take precedence on requests of trusted proxies
+        let proxies = [IpNetwork::from_str("10.0.0.0/8").unwrap()];
+        let mut headers = HeaderMap::new();
+        headers.insert(header::HOST, HeaderValue::from_static("127.0.0.1:3000"));
+        headers.insert("x-forwarded-proto", HeaderValue::from_static("https"));
+        headers.insert(
+            "x-forwarded-host",
+            HeaderValue::from_static("fela.example.com, proxy.local"),
+        );
+
+        assert_eq!(
+            request_origin(&headers, Some("10.0.0.2".parse().unwrap()), &proxies),
+            "https://fela.example.com"
+        );
+
+        // Anyone else could point links at another host.
+        assert_eq!(
+            request_origin(&headers, Some("192.168.1.2".parse().unwrap()), &proxies),
+            "http://127.0.0.1:3000"
+        );
+        assert_eq!(
+            request_origin(&headers, None, &proxies),
+            "http://127.0.0.1:3000"
+        );
+    }
+}
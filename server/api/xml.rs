@@ -0,0 +1,205 @@
+use serde_json::Value;
+
+/// Escape text for use in XML content and attribute values.
+pub fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            // Control characters other than whitespace aren't allowed in XML 1.0.
+            c if c.is_control() && !matches!(c, '\n' | '\r' | '\t') => {}
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// Minimal XML writer, enough for the feeds served by the API.
+/// Text and attribute values are escaped, element names are trusted.
+pub struct XmlWriter {
+    buffer: String,
+    open: Vec<String>,
+}
+
+impl XmlWriter {
+    pub fn new() -> Self {
+        Self {
+            buffer: String::from(r#"<?xml version="1.0" encoding="UTF-8"?>"#),
+            open: Vec::new(),
+        }
+    }
+
+    fn start_tag(&mut self, name: &str, attributes: &[(&str, &str)]) {
+        self.buffer.push('<');
+        self.buffer.push_str(name);
+        for (key, value) in attributes {
+            self.buffer.push(' ');
+            self.buffer.push_str(key);
+            self.buffer.push_str("=\"");
+            self.buffer.push_str(&escape_xml(value));
+            self.buffer.push('"');
+        }
+    }
+
+    /// Open an element, it stays open until `close` is called.
+    pub fn open(&mut self, name: &str, attributes: &[(&str, &str)]) -> &mut Self {
+        self.start_tag(name, attributes);
+        self.buffer.push('>');
+        self.open.push(name.to_string());
+        self
+    }
+
+    /// Close the most recently opened element.
+    pub fn close(&mut self) -> &mut Self {
+        if let Some(name) = self.open.pop() {
+            self.buffer.push_str("</");
+            self.buffer.push_str(&name);
+            self.buffer.push('>');
+        }
+        self
+    }
+
+    /// Write an element containing only text.
+    pub fn text(&mut self, name: &str, attributes: &[(&str, &str)], text: &str) -> &mut Self {
+        self.start_tag(name, attributes);
+        self.buffer.push('>');
+        self.buffer.push_str(&escape_xml(text));
+        self.buffer.push_str("</");
+        self.buffer.push_str(name);
+        self.buffer.push('>');
+        self
+    }
+
+    /// Write an element without content.
+    pub fn empty(&mut self, name: &str, attributes: &[(&str, &str)]) -> &mut Self {
+        self.start_tag(name, attributes);
+        self.buffer.push_str("/>");
+        self
+    }
+
+    /// Write a JSON value as element.
+    /// Scalar fields of objects become attributes, nested objects become child elements and
+    /// arrays repeat the element once per item. This is the mapping used by the Subsonic API.
+    pub fn value(&mut self, name: &str, value: &Value) -> &mut Self {
+        match value {
+            Value::Object(fields) => {
+                let attributes = fields
+                    .iter()
+                    .filter_map(|(key, value)| Some((key.as_str(), scalar(value)?)))
+                    .collect::<Vec<_>>();
+                let attributes = attributes
+                    .iter()
+                    .map(|(key, value)| (*key, value.as_str()))
+                    .collect::<Vec<_>>();
+                let children = fields
+                    .iter()
+                    .filter(|(_, value)| scalar(value).is_none())
+                    .collect::<Vec<_>>();
+
+                if children.is_empty() {
+                    self.empty(name, &attributes);
+                } else {
+                    self.open(name, &attributes);
+                    for (key, value) in children {
+                        self.value(key, value);
+                    }
+                    self.close();
+                }
+            }
+            Value::Array(items) => {
+                for item in items {
+                    self.value(name, item);
+                }
+            }
+            Value::Null => {}
+            value => {
+                self.text(name, &[], &scalar(value).unwrap_or_default());
+            }
+        }
+        self
+    }
+
+    /// Close all open elements and return the document.
+    pub fn finish(mut self) -> String {
+        while !self.open.is_empty() {
+            self.close();
+        }
+        self.buffer
+    }
+}
+
+/// Text of a JSON value that fits into an attribute.
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(text) => Some(text.clone()),
+        Value::Number(number) => Some(number.to_string()),
+        Value::Bool(boolean) => Some(boolean.to_string()),
+        _ => None,
+    }
+}
+
+impl Default for XmlWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape_xml() {
+        // Test case: Verify that markup characters and invalid control characters are escaped
+        assert_eq!(
+            escape_xml(r#"Tom & Jerry's <"Best"> of"#),
+            "Tom &amp; Jerry&apos;s &lt;&quot;Best&quot;&gt; of"
+        );
+        assert_eq!(escape_xml("line\nbreak\u{0}"), "line\nbreak");
+    }
+
+    #[test]
+    fn test_xml_writer() {
+        // Test case: Verify that the writer nests elements and escapes text and attributes
+        let mut xml = XmlWriter::new();
+        xml.open("feed", &[("xmlns", "http://www.w3.org/2005/Atom")])
+            .text("title", &[], "A & B")
+            .empty("link", &[("href", "/book?a=1&b=2")]);
+
+        assert_eq!(
+            xml.finish(),
+            concat!(
+                r#"<?xml version="1.0" encoding="UTF-8"?>"#,
+                r#"<feed xmlns="http://www.w3.org/2005/Atom"><title>A &amp; B</title>"#,
+                r#"<link href="/book?a=1&amp;b=2"/></feed>"#
+            )
+        );
+    }
+
+    #[test]
+    fn test_xml_writer_value() {
+        // Test case: Verify that JSON values map scalars to attributes and arrays to repeated elements
+        let value = serde_json::json!({
+            "id": "al-1",
+            "songCount": 2,
+            "missing": null,
+            "song": [{ "id": "tr-1" }, { "id": "tr-2" }],
+        });
+
+        let mut xml = XmlWriter::new();
+        xml.value("album", &value);
+
+        assert_eq!(
+            xml.finish(),
+            concat!(
+                r#"<?xml version="1.0" encoding="UTF-8"?>"#,
+                r#"<album id="al-1" songCount="2"><song id="tr-1"/><song id="tr-2"/></album>"#
+            )
+        );
+    }
+}
@@ -0,0 +1,47 @@
+use sha2::{Digest, Sha256};
+
+use super::random::random_string;
+
+/// Prefix of API tokens, tells them apart from session ids and makes leaked tokens easy to find.
+pub const API_TOKEN_PREFIX: &str = "fela_";
+
+/// Length of the random part of an API token.
+const API_TOKEN_LENGTH: usize = 40;
+
+/// Create a new API token.
+pub fn generate_api_token() -> String {
+    format!("{API_TOKEN_PREFIX}{}", random_string(API_TOKEN_LENGTH))
+}
+
+/// Hash of an API token as stored in the database.
+/// Tokens are long and random, so a fast hash is enough.
+pub fn hash_api_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_api_token() {
+        // Test case: Verify that tokens carry the prefix and differ from each other
+        let token = generate_api_token();
+
+        assert!(token.starts_with(API_TOKEN_PREFIX));
+        assert_eq!(token.len(), API_TOKEN_PREFIX.len() + API_TOKEN_LENGTH);
+        assert_ne!(token, generate_api_token());
+    }
+
+    #[test]
+    fn test_hash_api_token() {
+        // Test case: Verify that tokens are hashed with SHA-256 as lowercase hex
+        assert_eq!(
+            hash_api_token("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}
@@ -0,0 +1,160 @@
+use std::sync::LazyLock;
+
+use axum::http::{HeaderMap, HeaderValue, Method, header};
+
+use super::session::SESSION_LIFETIME;
+
+/// Cookie holding the session id, hidden from scripts.
+pub const SESSION_COOKIE: &str = "fela_session";
+
+/// Cookie holding the CSRF token, readable by the web interface.
+pub const CSRF_COOKIE: &str = "fela_csrf";
+
+/// Cookie tying an OpenID Connect login to the browser that started it.
+pub const OIDC_COOKIE: &str = "fela_oidc";
+
+/// Time a user has to log in at the OpenID Connect provider.
+const OIDC_COOKIE_LIFETIME: time::Duration = time::Duration::minutes(10);
+
+/// Header the CSRF token has to be sent in for state-changing requests.
+pub const CSRF_HEADER: &str = "x-csrf-token";
+
+/// Only send cookies over HTTPS.
+/// Controlled by the `COOKIE_SECURE` environment variable, off by default so fela keeps working
+/// over plain HTTP in a local network.
+pub static COOKIE_SECURE: LazyLock<bool> = LazyLock::new(|| {
+    std::env::var("COOKIE_SECURE").is_ok_and(|value| matches!(value.as_str(), "1" | "true"))
+});
+
+/// Get the value of a cookie sent by the client.
+pub fn get_cookie<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+/// Build a `Set-Cookie` header value, `None` clears the cookie.
+fn cookie(
+    name: &str,
+    value: Option<&str>,
+    lifetime: time::Duration,
+    http_only: bool,
+) -> HeaderValue {
+    let max_age = match value {
+        Some(_) => lifetime.whole_seconds(),
+        None => 0,
+    };
+
+    let mut cookie = format!(
+        "{name}={}; Path=/; Max-Age={max_age}; SameSite=Lax",
+        value.unwrap_or_default()
+    );
+    if http_only {
+        cookie.push_str("; HttpOnly");
+    }
+    if *COOKIE_SECURE {
+        cookie.push_str("; Secure");
+    }
+
+    HeaderValue::from_str(&cookie).expect("Cookie values should be valid header values")
+}
+
+/// `Set-Cookie` values for a new session.
+pub fn session_cookies(
+    session_id: &str,
+    csrf_token: &str,
+) -> [(header::HeaderName, HeaderValue); 2] {
+    [
+        (
+            header::SET_COOKIE,
+            cookie(SESSION_COOKIE, Some(session_id), *SESSION_LIFETIME, true),
+        ),
+        (
+            header::SET_COOKIE,
+            cookie(CSRF_COOKIE, Some(csrf_token), *SESSION_LIFETIME, false),
+        ),
+    ]
+}
+
+/// `Set-Cookie` values removing the session.
+pub fn clear_session_cookies() -> [(header::HeaderName, HeaderValue); 2] {
+    [
+        (
+            header::SET_COOKIE,
+            cookie(SESSION_COOKIE, None, *SESSION_LIFETIME, true),
+        ),
+        (
+            header::SET_COOKIE,
+            cookie(CSRF_COOKIE, None, *SESSION_LIFETIME, false),
+        ),
+    ]
+}
+
+/// `Set-Cookie` value for the state of an OpenID Connect login, `None` clears it.
+pub fn oidc_cookie(value: Option<&str>) -> (header::HeaderName, HeaderValue) {
+    (
+        header::SET_COOKIE,
+        cookie(OIDC_COOKIE, value, OIDC_COOKIE_LIFETIME, true),
+    )
+}
+
+/// Requests that can change state and need a CSRF token when authenticated by cookie.
+pub fn needs_csrf_token(method: &Method) -> bool {
+    !matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_cookie() {
+        // Test case: Verify that cookies are found in one or more Cookie headers
+        let mut headers = HeaderMap::new();
+        headers.append(
+            header::COOKIE,
+            HeaderValue::from_static("theme=dark; fela_session=abc"),
+        );
+        headers.append(header::COOKIE, HeaderValue::from_static("fela_csrf=def"));
+
+        assert_eq!(get_cookie(&headers, SESSION_COOKIE), Some("abc"));
+        assert_eq!(get_cookie(&headers, CSRF_COOKIE), Some("def"));
+        assert_eq!(get_cookie(&headers, "missing"), None);
+    }
+
+    #[test]
+    fn test_session_cookies() {
+        // Test case: Verify that the session cookie is HttpOnly and the CSRF cookie isn't
+        let [(_, session), (_, csrf)] = session_cookies("abc", "def");
+        let session = session.to_str().unwrap();
+        let csrf = csrf.to_str().unwrap();
+
+        assert!(session.starts_with("fela_session=abc; Path=/; Max-Age="));
+        assert!(session.contains("SameSite=Lax"));
+        assert!(session.contains("HttpOnly"));
+        assert!(csrf.starts_with("fela_csrf=def;"));
+        assert!(!csrf.contains("HttpOnly"));
+
+        let [(_, session), _] = clear_session_cookies();
+        assert!(
+            session
+                .to_str()
+                .unwrap()
+                .starts_with("fela_session=; Path=/; Max-Age=0")
+        );
+    }
+
+    #[test]
+    fn test_needs_csrf_token() {
+        // Test case: Verify that only state-changing methods need a CSRF token
+        assert!(!needs_csrf_token(&Method::GET));
+        assert!(!needs_csrf_token(&Method::HEAD));
+        assert!(needs_csrf_token(&Method::POST));
+        assert!(needs_csrf_token(&Method::DELETE));
+    }
+}
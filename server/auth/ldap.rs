@@ -0,0 +1,1309 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::{Context, Result, bail};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpStream,
+};
+use tokio_rustls::{
+    TlsConnector,
+    client::TlsStream,
+    rustls::{
+        ClientConfig, RootCertStore,
+        pki_types::{CertificateDer, ServerName, pem::PemObject},
+    },
+};
+
+use crate::database::{
+    Database,
+    role::{DEFAULT_ROLE, group_role},
+    user::{AuthSource, User},
+};
+
+/// Time the LDAP server has to answer a login.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest message accepted from the server.
+const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+/// Result code of successful operations.
+const RESULT_SUCCESS: i64 = 0;
+
+/// Result code of a bind with a wrong password or unknown DN.
+const RESULT_INVALID_CREDENTIALS: i64 = 49;
+
+/// Login against an LDAP directory, configured with environment variables:
+/// - `LDAP_URL`: Server, e.g. `ldaps://ldap.example.com` or `ldap://ldap.example.com:389`.
+///   Enables the login. `ldap://` connections are upgraded with StartTLS.
+/// - `LDAP_ALLOW_PLAIN`: Skip StartTLS on `ldap://` connections, sending passwords in clear text.
+///   Only meant for servers on the same host or a trusted network.
+/// - `LDAP_CA_FILE`: PEM file with certificates trusted besides the Mozilla root certificates,
+///   for directories with their own CA.
+/// - `LDAP_BASE_DN`: Where users are searched, e.g. `ou=people,dc=example,dc=com`.
+/// - `LDAP_BIND_DN` and `LDAP_BIND_PASSWORD`: Account used for the search, anonymous if not set.
+/// - `LDAP_USER_FILTER`: Filter finding the user, `{username}` is replaced with the escaped
+///   username. Defaults to `(uid={username})`.
+/// - `LDAP_ADMIN_GROUP`: DN of the group whose members get the admin role, admins that left it
+///   the default role. Other roles are managed in fela, as are all roles if not set.
+/// - `LDAP_GROUP_ATTRIBUTE`: Attribute of users listing their groups, defaults to `memberOf`.
+pub struct LdapConfig {
+    /// Host and port.
+    pub address: String,
+    pub security: LdapSecurity,
+    /// Checks the certificate of the server, unused for plain connections.
+    pub tls: TlsConnector,
+    pub base_dn: String,
+    pub bind_dn: Option<String>,
+    pub bind_password: Option<String>,
+    pub user_filter: String,
+    pub admin_group: Option<String>,
+    pub group_attribute: String,
+}
+
+/// How the connection to the LDAP server is secured.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LdapSecurity {
+    /// `ldaps://`, TLS from the start.
+    Tls,
+    /// `ldap://`, upgraded with the StartTLS operation before anything else is sent.
+    StartTls,
+    /// `ldap://` with `LDAP_ALLOW_PLAIN`, binds are sent in clear text.
+    Plain,
+}
+
+/// Split an `LDAP_URL` into the security and address of the server, with the default port if it
+/// has none.
+fn parse_url(url: &str, allow_plain: bool) -> Result<(LdapSecurity, String)> {
+    let (security, address, port) = if let Some(address) = url.strip_prefix("ldaps://") {
+        (LdapSecurity::Tls, address, 636)
+    } else if let Some(address) = url.strip_prefix("ldap://") {
+        let security = if allow_plain {
+            LdapSecurity::Plain
+        } else {
+            LdapSecurity::StartTls
+        };
+        (security, address, 389)
+    } else {
+        bail!("LDAP_URL should start with ldaps:// or ldap://: {url}");
+    };
+
+    let address = address.trim_end_matches('/');
+    let has_port = address
+        .rsplit_once(':')
+        .is_some_and(|(_, port)| !port.contains(']'));
+    let address = if has_port {
+        address.to_string()
+    } else {
+        format!("{address}:{port}")
+    };
+
+    Ok((security, address))
+}
+
+/// Mozilla root certificates, and the certificates in `ca_file` if given.
+fn root_certificates(ca_file: Option<&str>) -> Result<RootCertStore> {
+    let mut roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+
+    if let Some(ca_file) = ca_file {
+        let pem = std::fs::read(ca_file)
+            .with_context(|| format!("Unable to read LDAP_CA_FILE {ca_file}"))?;
+        for certificate in CertificateDer::pem_slice_iter(&pem) {
+            let certificate =
+                certificate.with_context(|| format!("LDAP_CA_FILE {ca_file} is invalid"))?;
+            roots
+                .add(certificate)
+                .with_context(|| format!("LDAP_CA_FILE {ca_file} has an invalid certificate"))?;
+        }
+    }
+
+    Ok(roots)
+}
+
+/// TLS client trusting `roots`.
+fn tls_connector(roots: RootCertStore) -> TlsConnector {
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    TlsConnector::from(Arc::new(config))
+}
+
+impl LdapConfig {
+    /// Read the configuration from the environment, `None` if the login isn't enabled.
+    pub fn from_env() -> Result<Option<Self>> {
+        let Ok(url) = std::env::var("LDAP_URL") else {
+            return Ok(None);
+        };
+        let var = |name: &str| std::env::var(name).ok().filter(|value| !value.is_empty());
+
+        let allow_plain =
+            var("LDAP_ALLOW_PLAIN").is_some_and(|value| matches!(value.as_str(), "1" | "true"));
+        let (security, address) = parse_url(&url, allow_plain)?;
+        if security == LdapSecurity::Plain {
+            tracing::warn!("LDAP passwords are sent in clear text to {}", address);
+        }
+
+        let config = LdapConfig {
+            address,
+            security,
+            tls: tls_connector(root_certificates(var("LDAP_CA_FILE").as_deref())?),
+            base_dn: var("LDAP_BASE_DN").context("LDAP_BASE_DN should be set with LDAP_URL")?,
+            bind_dn: var("LDAP_BIND_DN"),
+            bind_password: var("LDAP_BIND_PASSWORD"),
+            user_filter: var("LDAP_USER_FILTER").unwrap_or_else(|| "(uid={username})".to_string()),
+            admin_group: var("LDAP_ADMIN_GROUP"),
+            group_attribute: var("LDAP_GROUP_ATTRIBUTE").unwrap_or_else(|| "memberOf".to_string()),
+        };
+        config.server_name()?;
+        config
+            .filter("username")
+            .context("LDAP_USER_FILTER is invalid")?;
+
+        Ok(Some(config))
+    }
+
+    /// Name the certificate of the server has to be issued for, the host of `address`.
+    fn server_name(&self) -> Result<ServerName<'static>> {
+        let host = self
+            .address
+            .rsplit_once(':')
+            .map_or(self.address.as_str(), |(host, _)| host)
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+
+        ServerName::try_from(host.to_string())
+            .with_context(|| format!("LDAP_URL has an invalid host {host}"))
+    }
+}
+
+/// Minimal BER encoding, as far as LDAP needs it.
+mod ber {
+    use anyhow::{Context, Result, bail};
+
+    pub const INTEGER: u8 = 0x02;
+    pub const OCTET_STRING: u8 = 0x04;
+    pub const ENUMERATED: u8 = 0x0a;
+    pub const BOOLEAN: u8 = 0x01;
+    pub const SEQUENCE: u8 = 0x30;
+    pub const SET: u8 = 0x31;
+
+    /// Encode a value with its tag and length.
+    pub fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+        let mut encoded = vec![tag];
+        let length = content.len();
+        if length < 0x80 {
+            encoded.push(length as u8);
+        } else {
+            let bytes = length.to_be_bytes();
+            let skip = bytes.iter().take_while(|byte| **byte == 0).count();
+            encoded.push(0x80 | (bytes.len() - skip) as u8);
+            encoded.extend_from_slice(&bytes[skip..]);
+        }
+        encoded.extend_from_slice(content);
+        encoded
+    }
+
+    /// Encode an integer in the fewest bytes of two's complement.
+    pub fn integer(tag: u8, value: i64) -> Vec<u8> {
+        let bytes = value.to_be_bytes();
+        let mut start = 0;
+        while start < bytes.len() - 1
+            && ((bytes[start] == 0 && bytes[start + 1] & 0x80 == 0)
+                || (bytes[start] == 0xff && bytes[start + 1] & 0x80 != 0))
+        {
+            start += 1;
+        }
+        tlv(tag, &bytes[start..])
+    }
+
+    /// Length of a message from its first bytes, `None` if more bytes are needed.
+    pub fn message_length(header: &[u8]) -> Result<Option<usize>> {
+        let Some(&first) = header.get(1) else {
+            return Ok(None);
+        };
+        if first < 0x80 {
+            return Ok(Some(2 + first as usize));
+        }
+
+        let length_bytes = (first & 0x7f) as usize;
+        if length_bytes == 0 || length_bytes > 4 {
+            bail!("Unsupported BER length");
+        }
+        let Some(bytes) = header.get(2..2 + length_bytes) else {
+            return Ok(None);
+        };
+        let length = bytes
+            .iter()
+            .fold(0usize, |length, byte| (length << 8) | *byte as usize);
+
+        Ok(Some(2 + length_bytes + length))
+    }
+
+    /// Reads values from encoded data.
+    pub struct Reader<'a> {
+        data: &'a [u8],
+    }
+
+    impl<'a> Reader<'a> {
+        pub fn new(data: &'a [u8]) -> Self {
+            Self { data }
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.data.is_empty()
+        }
+
+        /// Read the next value, returns its tag and content.
+        pub fn read(&mut self) -> Result<(u8, &'a [u8])> {
+            let length = message_length(self.data)?.context("Truncated BER value")?;
+            if length > self.data.len() {
+                bail!("Truncated BER value");
+            }
+
+            let (value, rest) = self.data.split_at(length);
+            self.data = rest;
+            let content_start = if value[1] < 0x80 {
+                2
+            } else {
+                2 + (value[1] & 0x7f) as usize
+            };
+
+            Ok((value[0], &value[content_start..]))
+        }
+
+        /// Read the next value, which must have `tag`.
+        pub fn expect(&mut self, tag: u8) -> Result<&'a [u8]> {
+            let (actual, content) = self.read()?;
+            if actual != tag {
+                bail!("Expected BER tag {tag:#x}, got {actual:#x}");
+            }
+            Ok(content)
+        }
+
+        pub fn integer(&mut self, tag: u8) -> Result<i64> {
+            let content = self.expect(tag)?;
+            if content.is_empty() || content.len() > 8 {
+                bail!("Invalid BER integer");
+            }
+
+            let negative = content[0] & 0x80 != 0;
+            let initial = if negative { -1 } else { 0 };
+            Ok(content
+                .iter()
+                .fold(initial, |value: i64, byte| (value << 8) | i64::from(*byte)))
+        }
+    }
+}
+
+/// Application tags of LDAP operations.
+const BIND_REQUEST: u8 = 0x60;
+const BIND_RESPONSE: u8 = 0x61;
+const UNBIND_REQUEST: u8 = 0x42;
+const SEARCH_REQUEST: u8 = 0x63;
+const SEARCH_RESULT_ENTRY: u8 = 0x64;
+const SEARCH_RESULT_DONE: u8 = 0x65;
+const SEARCH_RESULT_REFERENCE: u8 = 0x73;
+const EXTENDED_REQUEST: u8 = 0x77;
+const EXTENDED_RESPONSE: u8 = 0x78;
+
+/// Name of the extended request in an extended operation.
+const EXTENDED_REQUEST_NAME: u8 = 0x80;
+
+/// OID of the StartTLS extended operation, RFC 4511.
+const START_TLS_OID: &[u8] = b"1.3.6.1.4.1.1466.20037";
+
+/// Simple authentication in a bind request.
+const SIMPLE_AUTHENTICATION: u8 = 0x80;
+
+/// Search filter, RFC 4515.
+#[derive(PartialEq, Debug)]
+enum Filter {
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+    Not(Box<Filter>),
+    Equal(String, Vec<u8>),
+    Substrings {
+        attribute: String,
+        initial: Option<Vec<u8>>,
+        any: Vec<Vec<u8>>,
+        last: Option<Vec<u8>>,
+    },
+    GreaterOrEqual(String, Vec<u8>),
+    LessOrEqual(String, Vec<u8>),
+    Approximate(String, Vec<u8>),
+    Present(String),
+}
+
+/// Escape a value for use in a filter, so usernames can't change the filter.
+pub fn escape_filter_value(value: &str) -> String {
+    value
+        .chars()
+        .map(|char| match char {
+            '*' | '(' | ')' | '\\' | '\0' => format!("\\{:02x}", char as u32),
+            _ => char.to_string(),
+        })
+        .collect()
+}
+
+/// Decode the `\XX` escapes of a filter value.
+fn unescape_filter_value(value: &str) -> Result<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut rest = value.as_bytes();
+
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'\\' {
+            let hex = tail
+                .get(..2)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .context("Invalid escape in filter value")?;
+            bytes.push(hex);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+
+    Ok(bytes)
+}
+
+impl Filter {
+    fn parse(filter: &str) -> Result<Filter> {
+        let (filter, rest) = Self::parse_next(filter.trim())?;
+        if !rest.is_empty() {
+            bail!("Unexpected {rest} after filter");
+        }
+        Ok(filter)
+    }
+
+    /// Parse a parenthesized filter, returns it and the remaining input.
+    fn parse_next(input: &str) -> Result<(Filter, &str)> {
+        let input = input
+            .strip_prefix('(')
+            .context("Filter should start with (")?;
+
+        let (filter, rest) = match input.chars().next() {
+            Some(operator @ ('&' | '|')) => {
+                let mut rest = &input[1..];
+                let mut filters = Vec::new();
+                while rest.starts_with('(') {
+                    let (filter, remaining) = Self::parse_next(rest)?;
+                    filters.push(filter);
+                    rest = remaining;
+                }
+                if operator == '&' {
+                    (Filter::And(filters), rest)
+                } else {
+                    (Filter::Or(filters), rest)
+                }
+            }
+            Some('!') => {
+                let (filter, rest) = Self::parse_next(&input[1..])?;
+                (Filter::Not(Box::new(filter)), rest)
+            }
+            _ => {
+                let end = input.find(')').context("Filter should end with )")?;
+                (Self::parse_item(&input[..end])?, &input[end..])
+            }
+        };
+
+        let rest = rest.strip_prefix(')').context("Filter should end with )")?;
+        Ok((filter, rest))
+    }
+
+    /// Parse a comparison like `uid=jane`.
+    fn parse_item(item: &str) -> Result<Filter> {
+        let equals = item.find('=').context("Filter item without =")?;
+        let (attribute, operator) = match item[..equals].chars().last() {
+            Some(operator @ ('~' | '>' | '<')) => (&item[..equals - 1], Some(operator)),
+            _ => (&item[..equals], None),
+        };
+        let value = &item[equals + 1..];
+
+        if attribute.is_empty() || attribute.contains(':') {
+            bail!("Unsupported attribute in filter: {attribute}");
+        }
+        let attribute = attribute.to_string();
+
+        Ok(match operator {
+            Some('~') => Filter::Approximate(attribute, unescape_filter_value(value)?),
+            Some('>') => Filter::GreaterOrEqual(attribute, unescape_filter_value(value)?),
+            Some('<') => Filter::LessOrEqual(attribute, unescape_filter_value(value)?),
+            _ if value == "*" => Filter::Present(attribute),
+            _ if value.contains('*') => {
+                let mut parts = value.split('*').map(unescape_filter_value);
+                let initial = parts.next().transpose()?.filter(|part| !part.is_empty());
+                let mut any = parts.collect::<Result<Vec<_>>>()?;
+                let last = any.pop().filter(|part| !part.is_empty());
+                any.retain(|part| !part.is_empty());
+                Filter::Substrings {
+                    attribute,
+                    initial,
+                    any,
+                    last,
+                }
+            }
+            _ => Filter::Equal(attribute, unescape_filter_value(value)?),
+        })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let assertion = |tag: u8, attribute: &str, value: &[u8]| {
+            ber::tlv(
+                tag,
+                &[
+                    ber::tlv(ber::OCTET_STRING, attribute.as_bytes()),
+                    ber::tlv(ber::OCTET_STRING, value),
+                ]
+                .concat(),
+            )
+        };
+
+        match self {
+            Filter::And(filters) => ber::tlv(
+                0xa0,
+                &filters
+                    .iter()
+                    .map(Filter::encode)
+                    .collect::<Vec<_>>()
+                    .concat(),
+            ),
+            Filter::Or(filters) => ber::tlv(
+                0xa1,
+                &filters
+                    .iter()
+                    .map(Filter::encode)
+                    .collect::<Vec<_>>()
+                    .concat(),
+            ),
+            Filter::Not(filter) => ber::tlv(0xa2, &filter.encode()),
+            Filter::Equal(attribute, value) => assertion(0xa3, attribute, value),
+            Filter::Substrings {
+                attribute,
+                initial,
+                any,
+                last,
+            } => {
+                let substrings = initial
+                    .iter()
+                    .map(|value| ber::tlv(0x80, value))
+                    .chain(any.iter().map(|value| ber::tlv(0x81, value)))
+                    .chain(last.iter().map(|value| ber::tlv(0x82, value)))
+                    .collect::<Vec<_>>()
+                    .concat();
+                ber::tlv(
+                    0xa4,
+                    &[
+                        ber::tlv(ber::OCTET_STRING, attribute.as_bytes()),
+                        ber::tlv(ber::SEQUENCE, &substrings),
+                    ]
+                    .concat(),
+                )
+            }
+            Filter::GreaterOrEqual(attribute, value) => assertion(0xa5, attribute, value),
+            Filter::LessOrEqual(attribute, value) => assertion(0xa6, attribute, value),
+            Filter::Present(attribute) => ber::tlv(0x87, attribute.as_bytes()),
+            Filter::Approximate(attribute, value) => assertion(0xa8, attribute, value),
+        }
+    }
+}
+
+/// User found in the directory.
+#[derive(Debug)]
+pub struct LdapEntry {
+    pub dn: String,
+    /// Groups from the group attribute.
+    pub groups: Vec<String>,
+}
+
+/// Entry of a search result with the requested attributes.
+struct SearchEntry {
+    dn: String,
+    attributes: Vec<(String, Vec<String>)>,
+}
+
+/// Connection to the LDAP server, requests are sent one after the other.
+struct Connection<S> {
+    stream: S,
+    message_id: i64,
+    buffer: Vec<u8>,
+}
+
+/// Result of an operation, the result code and diagnostic message.
+struct LdapResult {
+    code: i64,
+    message: String,
+}
+
+impl LdapResult {
+    fn parse(content: &[u8]) -> Result<Self> {
+        let mut reader = ber::Reader::new(content);
+        let code = reader.integer(ber::ENUMERATED)?;
+        reader.expect(ber::OCTET_STRING)?;
+        let message = String::from_utf8_lossy(reader.expect(ber::OCTET_STRING)?).to_string();
+
+        Ok(Self { code, message })
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Connection<S> {
+    fn new(stream: S) -> Self {
+        Self {
+            stream,
+            message_id: 0,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Send an operation in a new message.
+    async fn send(&mut self, operation: &[u8]) -> Result<()> {
+        self.message_id += 1;
+        let message = ber::tlv(
+            ber::SEQUENCE,
+            &[
+                ber::integer(ber::INTEGER, self.message_id),
+                operation.to_vec(),
+            ]
+            .concat(),
+        );
+
+        self.stream
+            .write_all(&message)
+            .await
+            .context("Unable to send LDAP request")
+    }
+
+    /// Receive the next operation of the current message, returns its tag and content.
+    async fn receive(&mut self) -> Result<(u8, Vec<u8>)> {
+        let message = read_message(&mut self.stream, &mut self.buffer).await?;
+
+        let mut reader = ber::Reader::new(&message);
+        let mut message = ber::Reader::new(reader.expect(ber::SEQUENCE)?);
+        let message_id = message.integer(ber::INTEGER)?;
+        if message_id != self.message_id {
+            bail!("Unexpected LDAP message {message_id}");
+        }
+        let (tag, content) = message.read()?;
+
+        Ok((tag, content.to_vec()))
+    }
+
+    /// Simple bind, returns false if the credentials are wrong.
+    async fn bind(&mut self, dn: &str, password: &str) -> Result<bool> {
+        self.send(&ber::tlv(
+            BIND_REQUEST,
+            &[
+                ber::integer(ber::INTEGER, 3),
+                ber::tlv(ber::OCTET_STRING, dn.as_bytes()),
+                ber::tlv(SIMPLE_AUTHENTICATION, password.as_bytes()),
+            ]
+            .concat(),
+        ))
+        .await?;
+
+        let (tag, content) = self.receive().await?;
+        if tag != BIND_RESPONSE {
+            bail!("Unexpected LDAP response {tag:#x} to bind");
+        }
+        match LdapResult::parse(&content)? {
+            LdapResult {
+                code: RESULT_SUCCESS,
+                ..
+            } => Ok(true),
+            LdapResult {
+                code: RESULT_INVALID_CREDENTIALS,
+                ..
+            } => Ok(false),
+            LdapResult { code, message } => bail!("LDAP bind failed with {code}: {message}"),
+        }
+    }
+
+    /// Search the subtree of `base_dn`, returns at most two entries with `attributes`.
+    async fn search(
+        &mut self,
+        base_dn: &str,
+        filter: &Filter,
+        attributes: &[&str],
+    ) -> Result<Vec<SearchEntry>> {
+        let attributes = attributes
+            .iter()
+            .map(|attribute| ber::tlv(ber::OCTET_STRING, attribute.as_bytes()))
+            .collect::<Vec<_>>()
+            .concat();
+        self.send(&ber::tlv(
+            SEARCH_REQUEST,
+            &[
+                ber::tlv(ber::OCTET_STRING, base_dn.as_bytes()),
+                // Whole subtree.
+                ber::integer(ber::ENUMERATED, 2),
+                // Never dereference aliases.
+                ber::integer(ber::ENUMERATED, 0),
+                // Two entries are enough to notice ambiguous filters.
+                ber::integer(ber::INTEGER, 2),
+                ber::integer(ber::INTEGER, REQUEST_TIMEOUT.as_secs() as i64),
+                ber::tlv(ber::BOOLEAN, &[0]),
+                filter.encode(),
+                ber::tlv(ber::SEQUENCE, &attributes),
+            ]
+            .concat(),
+        ))
+        .await?;
+
+        let mut entries = Vec::new();
+        loop {
+            let (tag, content) = self.receive().await?;
+            match tag {
+                SEARCH_RESULT_ENTRY => entries.push(parse_entry(&content)?),
+                SEARCH_RESULT_REFERENCE => {}
+                SEARCH_RESULT_DONE => {
+                    let result = LdapResult::parse(&content)?;
+                    // Size limit exceeded still returns the entries found so far.
+                    if result.code != RESULT_SUCCESS && result.code != 4 {
+                        bail!(
+                            "LDAP search failed with {}: {}",
+                            result.code,
+                            result.message
+                        );
+                    }
+                    return Ok(entries);
+                }
+                tag => bail!("Unexpected LDAP response {tag:#x} to search"),
+            }
+        }
+    }
+
+    /// Upgrade the connection with StartTLS, the server certificate has to be valid for `name`.
+    async fn start_tls(
+        mut self,
+        connector: &TlsConnector,
+        name: ServerName<'static>,
+    ) -> Result<Connection<TlsStream<S>>> {
+        self.send(&ber::tlv(
+            EXTENDED_REQUEST,
+            &ber::tlv(EXTENDED_REQUEST_NAME, START_TLS_OID),
+        ))
+        .await?;
+
+        let (tag, content) = self.receive().await?;
+        if tag != EXTENDED_RESPONSE {
+            bail!("Unexpected LDAP response {tag:#x} to StartTLS");
+        }
+        let result = LdapResult::parse(&content)?;
+        if result.code != RESULT_SUCCESS {
+            bail!(
+                "LDAP StartTLS failed with {}: {}",
+                result.code,
+                result.message
+            );
+        }
+        // Anything sent before the handshake could have been injected.
+        if !self.buffer.is_empty() {
+            bail!("Unexpected LDAP data before the TLS handshake");
+        }
+
+        let stream = connector
+            .connect(name, self.stream)
+            .await
+            .context("Unable to start TLS with LDAP server")?;
+        Ok(Connection {
+            stream,
+            message_id: self.message_id,
+            buffer: Vec::new(),
+        })
+    }
+
+    async fn unbind(&mut self) -> Result<()> {
+        self.send(&ber::tlv(UNBIND_REQUEST, &[])).await?;
+        self.stream.shutdown().await.ok();
+        Ok(())
+    }
+}
+
+/// Read a whole BER message from the stream.
+async fn read_message<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buffer: &mut Vec<u8>,
+) -> Result<Vec<u8>> {
+    loop {
+        if let Some(length) = ber::message_length(buffer)? {
+            if length > MAX_MESSAGE_SIZE {
+                bail!("LDAP message is too large");
+            }
+            if buffer.len() >= length {
+                return Ok(buffer.drain(..length).collect());
+            }
+        }
+
+        let mut chunk = [0; 4096];
+        let read = stream
+            .read(&mut chunk)
+            .await
+            .context("Unable to read LDAP response")?;
+        if read == 0 {
+            bail!("LDAP server closed the connection");
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+    }
+}
+
+/// Parse a search result entry into its DN and attributes.
+fn parse_entry(content: &[u8]) -> Result<SearchEntry> {
+    let mut reader = ber::Reader::new(content);
+    let dn = String::from_utf8_lossy(reader.expect(ber::OCTET_STRING)?).to_string();
+
+    let mut attributes = Vec::new();
+    let mut list = ber::Reader::new(reader.expect(ber::SEQUENCE)?);
+    while !list.is_empty() {
+        let mut attribute = ber::Reader::new(list.expect(ber::SEQUENCE)?);
+        let name = String::from_utf8_lossy(attribute.expect(ber::OCTET_STRING)?).to_string();
+        let mut values = ber::Reader::new(attribute.expect(ber::SET)?);
+        let mut parsed = Vec::new();
+        while !values.is_empty() {
+            parsed.push(String::from_utf8_lossy(values.expect(ber::OCTET_STRING)?).to_string());
+        }
+        attributes.push((name, parsed));
+    }
+
+    Ok(SearchEntry { dn, attributes })
+}
+
+impl LdapConfig {
+    /// Filter finding the user `username`.
+    fn filter(&self, username: &str) -> Result<Filter> {
+        Filter::parse(
+            &self
+                .user_filter
+                .replace("{username}", &escape_filter_value(username)),
+        )
+    }
+
+    /// Check the credentials of a user: find the user with the search account and bind as the
+    /// user with `password`. Returns `None` if the user doesn't exist or the password is wrong.
+    pub async fn authenticate(&self, username: &str, password: &str) -> Result<Option<LdapEntry>> {
+        // An empty password would be an unauthenticated bind, which servers accept.
+        if password.is_empty() {
+            return Ok(None);
+        }
+
+        tokio::time::timeout(REQUEST_TIMEOUT, async {
+            let stream = TcpStream::connect(&self.address)
+                .await
+                .with_context(|| format!("Unable to connect to LDAP server {}", self.address))?;
+
+            match self.security {
+                LdapSecurity::Tls => {
+                    let stream = self
+                        .tls
+                        .connect(self.server_name()?, stream)
+                        .await
+                        .context("Unable to start TLS with LDAP server")?;
+                    self.authenticate_with(Connection::new(stream), username, password)
+                        .await
+                }
+                LdapSecurity::StartTls => {
+                    let connection = Connection::new(stream)
+                        .start_tls(&self.tls, self.server_name()?)
+                        .await?;
+                    self.authenticate_with(connection, username, password).await
+                }
+                LdapSecurity::Plain => {
+                    self.authenticate_with(Connection::new(stream), username, password)
+                        .await
+                }
+            }
+        })
+        .await
+        .context("LDAP server did not respond in time")?
+    }
+
+    /// Authenticate on a new connection and close it.
+    async fn authenticate_with<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        mut connection: Connection<S>,
+        username: &str,
+        password: &str,
+    ) -> Result<Option<LdapEntry>> {
+        let entry = self
+            .authenticate_on(&mut connection, username, password)
+            .await;
+        connection.unbind().await?;
+        entry
+    }
+
+    async fn authenticate_on<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        connection: &mut Connection<S>,
+        username: &str,
+        password: &str,
+    ) -> Result<Option<LdapEntry>> {
+        let bind_dn = self.bind_dn.as_deref().unwrap_or_default();
+        let bind_password = self.bind_password.as_deref().unwrap_or_default();
+        if !connection.bind(bind_dn, bind_password).await? {
+            bail!("LDAP_BIND_DN or LDAP_BIND_PASSWORD is wrong");
+        }
+
+        let mut entries = connection
+            .search(
+                &self.base_dn,
+                &self.filter(username)?,
+                &[&self.group_attribute],
+            )
+            .await?;
+        if entries.len() > 1 {
+            tracing::warn!("LDAP_USER_FILTER matches several users named {}", username);
+            return Ok(None);
+        }
+        let Some(SearchEntry { dn, attributes }) = entries.pop() else {
+            return Ok(None);
+        };
+
+        if !connection.bind(&dn, password).await? {
+            return Ok(None);
+        }
+
+        let groups = attributes
+            .into_iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case(&self.group_attribute))
+            .flat_map(|(_, values)| values)
+            .collect();
+        Ok(Some(LdapEntry { dn, groups }))
+    }
+
+    /// Whether the user is in the admin group, `None` if admin rights aren't mapped.
+    pub fn is_admin(&self, entry: &LdapEntry) -> Option<bool> {
+        let admin_group = self.admin_group.as_ref()?;
+
+        Some(
+            entry
+                .groups
+                .iter()
+                .any(|group| group.eq_ignore_ascii_case(admin_group)),
+        )
+    }
+}
+
+/// Get the user of an authenticated LDAP entry, creating it on the first login.
+/// Only called for unknown names and users created by LDAP logins.
+pub async fn ldap_user(
+    database: &Database,
+    config: &LdapConfig,
+    username: &str,
+    entry: &LdapEntry,
+) -> Result<User> {
+    let admin = config.is_admin(entry);
+
+    if let Some(user) = database.get_user_by_name(username).await? {
+        if user.auth_source != AuthSource::Ldap {
+            bail!("User {} is no LDAP user", username);
+        }
+        let role_id = group_role(user.role_id, admin);
+        if role_id != user.role_id {
+            database
+                .update_user(user.id, None, None, Some(role_id), None)
+                .await?;
+            return database.get_user(user.id).await;
+        }
+        return Ok(user);
+    }
+
+    let id = database
+        .create_external_user(
+            username,
+            AuthSource::Ldap,
+            &entry.dn,
+            group_role(DEFAULT_ROLE, admin),
+        )
+        .await?;
+    tracing::info!("Created user {} from LDAP login", username);
+
+    database.get_user(id).await
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+    use tokio_rustls::{
+        TlsAcceptor,
+        rustls::{ServerConfig, pki_types::PrivatePkcs8KeyDer},
+    };
+
+    use super::*;
+    use crate::database::role::ADMIN_ROLE;
+
+    const SERVICE_DN: &str = "cn=fela,dc=example,dc=com";
+    const JANE_DN: &str = "uid=jane,ou=people,dc=example,dc=com";
+    const ADMINS_DN: &str = "cn=admins,ou=groups,dc=example,dc=com";
+
+    fn config(address: String) -> LdapConfig {
+        LdapConfig {
+            address,
+            security: LdapSecurity::Plain,
+            tls: tls_connector(root_certificates(None).unwrap()),
+            base_dn: "ou=people,dc=example,dc=com".to_string(),
+            bind_dn: Some(SERVICE_DN.to_string()),
+            bind_password: Some("service".to_string()),
+            user_filter: "(&(objectClass=person)(uid={username}))".to_string(),
+            admin_group: Some(ADMINS_DN.to_string()),
+            group_attribute: "memberOf".to_string(),
+        }
+    }
+
+    /// Respond to one message of a client.
+    fn mock_response(message: &[u8]) -> Option<Vec<u8>> {
+        let mut reader = ber::Reader::new(message);
+        let mut message = ber::Reader::new(reader.expect(ber::SEQUENCE).unwrap());
+        let message_id = message.integer(ber::INTEGER).unwrap();
+        let (tag, content) = message.read().unwrap();
+        let respond = |operations: Vec<Vec<u8>>| {
+            operations
+                .into_iter()
+                .map(|operation| {
+                    ber::tlv(
+                        ber::SEQUENCE,
+                        &[ber::integer(ber::INTEGER, message_id), operation].concat(),
+                    )
+                })
+                .collect::<Vec<_>>()
+                .concat()
+        };
+        let result = |tag: u8, code: i64| {
+            ber::tlv(
+                tag,
+                &[
+                    ber::integer(ber::ENUMERATED, code),
+                    ber::tlv(ber::OCTET_STRING, b""),
+                    ber::tlv(ber::OCTET_STRING, b""),
+                ]
+                .concat(),
+            )
+        };
+
+        match tag {
+            BIND_REQUEST => {
+                let mut bind = ber::Reader::new(content);
+                assert_eq!(bind.integer(ber::INTEGER).unwrap(), 3);
+                let dn = bind.expect(ber::OCTET_STRING).unwrap();
+                let password = bind.expect(SIMPLE_AUTHENTICATION).unwrap();
+                let valid = matches!(
+                    (dn, password),
+                    (b"cn=fela,dc=example,dc=com", b"service")
+                        | (b"uid=jane,ou=people,dc=example,dc=com", b"secret")
+                );
+                let code = if valid {
+                    RESULT_SUCCESS
+                } else {
+                    RESULT_INVALID_CREDENTIALS
+                };
+                Some(respond(vec![result(BIND_RESPONSE, code)]))
+            }
+            SEARCH_REQUEST => {
+                let mut search = ber::Reader::new(content);
+                assert_eq!(
+                    search.expect(ber::OCTET_STRING).unwrap(),
+                    b"ou=people,dc=example,dc=com"
+                );
+                for _ in 0..5 {
+                    search.read().unwrap();
+                }
+                let (_, filter) = search.read().unwrap();
+
+                let expected = Filter::parse("(&(objectClass=person)(uid=jane))")
+                    .unwrap()
+                    .encode();
+                let mut entries = Vec::new();
+                if filter == &expected[2..] {
+                    let group = ber::tlv(ber::OCTET_STRING, ADMINS_DN.as_bytes());
+                    let attribute = ber::tlv(
+                        ber::SEQUENCE,
+                        &[
+                            ber::tlv(ber::OCTET_STRING, b"memberOf"),
+                            ber::tlv(ber::SET, &group),
+                        ]
+                        .concat(),
+                    );
+                    entries.push(ber::tlv(
+                        SEARCH_RESULT_ENTRY,
+                        &[
+                            ber::tlv(ber::OCTET_STRING, JANE_DN.as_bytes()),
+                            ber::tlv(ber::SEQUENCE, &attribute),
+                        ]
+                        .concat(),
+                    ));
+                }
+                entries.push(result(SEARCH_RESULT_DONE, RESULT_SUCCESS));
+                Some(respond(entries))
+            }
+            EXTENDED_REQUEST => {
+                let mut extended = ber::Reader::new(content);
+                assert_eq!(
+                    extended.expect(EXTENDED_REQUEST_NAME).unwrap(),
+                    START_TLS_OID
+                );
+                Some(respond(vec![result(EXTENDED_RESPONSE, RESULT_SUCCESS)]))
+            }
+            _ => None,
+        }
+    }
+
+    /// Answer messages until the client closes the connection or starts TLS.
+    /// Returns true if TLS was started.
+    async fn mock_session<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S) -> bool {
+        let mut buffer = Vec::new();
+        while let Ok(message) = read_message(stream, &mut buffer).await {
+            let Some(response) = mock_response(&message) else {
+                break;
+            };
+            stream.write_all(&response).await.unwrap();
+
+            let mut reader = ber::Reader::new(&message);
+            let mut message = ber::Reader::new(reader.expect(ber::SEQUENCE).unwrap());
+            message.integer(ber::INTEGER).unwrap();
+            if message.read().unwrap().0 == EXTENDED_REQUEST {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Start an LDAP server with `security` on a random port, returns its address and a TLS
+    /// client trusting its certificate for `localhost`.
+    async fn mock_server(security: LdapSecurity) -> (String, TlsConnector) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("localhost:{}", listener.local_addr().unwrap().port());
+
+        let certificate =
+            rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let key = PrivatePkcs8KeyDer::from(certificate.signing_key.serialize_der());
+        let server_config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_single_cert(vec![certificate.cert.der().clone()], key.into())
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+        let mut roots = RootCertStore::empty();
+        roots.add(certificate.cert.der().clone()).unwrap();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let started = match security {
+                        LdapSecurity::Plain => return mock_session(&mut stream).await,
+                        LdapSecurity::StartTls => mock_session(&mut stream).await,
+                        LdapSecurity::Tls => true,
+                    };
+                    if started && let Ok(mut stream) = acceptor.accept(stream).await {
+                        mock_session(&mut stream).await;
+                    }
+                    false
+                });
+            }
+        });
+
+        (address, tls_connector(roots))
+    }
+
+    #[tokio::test]
+    async fn test_mock_server() {
+        // Test case: Verify the bind and search against a local LDAP server
+        let config = config(mock_server(LdapSecurity::Plain).await.0);
+
+        let entry = config
+            .authenticate("jane", "secret")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(entry.dn, JANE_DN);
+        assert_eq!(config.is_admin(&entry), Some(true));
+
+        assert!(
+            config
+                .authenticate("jane", "wrong")
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert!(config.authenticate("jane", "").await.unwrap().is_none());
+        assert!(
+            config
+                .authenticate("john", "secret")
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert!(config.authenticate("*", "secret").await.unwrap().is_none());
+
+        let wrong_service = LdapConfig {
+            bind_password: Some("wrong".to_string()),
+            ..config
+        };
+        assert!(wrong_service.authenticate("jane", "secret").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_mock_server_tls() {
+        // Test case: Verify that TLS and StartTLS connections check the server certificate
+        for security in [LdapSecurity::Tls, LdapSecurity::StartTls] {
+            let (address, tls) = mock_server(security).await;
+            let config = LdapConfig {
+                security,
+                tls,
+                ..config(address)
+            };
+
+            let entry = config
+                .authenticate("jane", "secret")
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(entry.dn, JANE_DN);
+            assert!(
+                config
+                    .authenticate("jane", "wrong")
+                    .await
+                    .unwrap()
+                    .is_none()
+            );
+
+            // The self-signed certificate isn't trusted by default.
+            let untrusted = LdapConfig {
+                tls: tls_connector(root_certificates(None).unwrap()),
+                ..config
+            };
+            assert!(untrusted.authenticate("jane", "secret").await.is_err());
+        }
+    }
+
+    #[test]
+    fn test_parse_url() {
+        // Test case: Verify that plain LDAP is only used when allowed
+        assert_eq!(
+            parse_url("ldaps://ldap.example.com", false).unwrap(),
+            (LdapSecurity::Tls, "ldap.example.com:636".to_string())
+        );
+        assert_eq!(
+            parse_url("ldap://ldap.example.com/", false).unwrap(),
+            (LdapSecurity::StartTls, "ldap.example.com:389".to_string())
+        );
+        assert_eq!(
+            parse_url("ldap://ldap.example.com:3890", true).unwrap(),
+            (LdapSecurity::Plain, "ldap.example.com:3890".to_string())
+        );
+        assert_eq!(
+            parse_url("ldaps://[::1]:6360", true).unwrap(),
+            (LdapSecurity::Tls, "[::1]:6360".to_string())
+        );
+        assert_eq!(
+            parse_url("ldaps://[::1]", false).unwrap(),
+            (LdapSecurity::Tls, "[::1]:636".to_string())
+        );
+        assert!(parse_url("ldapi:///run/slapd.sock", true).is_err());
+        assert!(parse_url("ldap.example.com", false).is_err());
+
+        let config = config("[::1]:636".to_string());
+        assert_eq!(
+            config.server_name().unwrap(),
+            ServerName::try_from("::1").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_escape_filter_value() {
+        // Test case: Verify that special characters can't change the filter
+        assert_eq!(escape_filter_value("jane"), "jane");
+        assert_eq!(escape_filter_value("*"), "\\2a");
+        assert_eq!(escape_filter_value("jane)(uid=*"), "jane\\29\\28uid=\\2a");
+        assert_eq!(escape_filter_value("a\\b\0"), "a\\5cb\\00");
+        assert_eq!(escape_filter_value("jänè"), "jänè");
+
+        let config = config(String::new());
+        assert_eq!(
+            config.filter("jane)(uid=*").unwrap(),
+            Filter::And(vec![
+                Filter::Equal("objectClass".to_string(), b"person".to_vec()),
+                Filter::Equal("uid".to_string(), b"jane)(uid=*".to_vec()),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_parse_filter() {
+        // Test case: Verify that the filter types of RFC 4515 are parsed
+        assert_eq!(
+            Filter::parse("(|(!(cn=a*b*c))(mail=*)(age>=3)(age<=5)(sn~=x))").unwrap(),
+            Filter::Or(vec![
+                Filter::Not(Box::new(Filter::Substrings {
+                    attribute: "cn".to_string(),
+                    initial: Some(b"a".to_vec()),
+                    any: vec![b"b".to_vec()],
+                    last: Some(b"c".to_vec()),
+                })),
+                Filter::Present("mail".to_string()),
+                Filter::GreaterOrEqual("age".to_string(), b"3".to_vec()),
+                Filter::LessOrEqual("age".to_string(), b"5".to_vec()),
+                Filter::Approximate("sn".to_string(), b"x".to_vec()),
+            ])
+        );
+
+        assert!(Filter::parse("uid=jane").is_err());
+        assert!(Filter::parse("(uid=jane").is_err());
+        assert!(Filter::parse("(uid=jane))").is_err());
+        assert!(Filter::parse("(uid=\\zz)").is_err());
+    }
+
+    #[test]
+    fn test_ber() {
+        // Test case: Verify the encoding of lengths and integers
+        assert_eq!(ber::integer(ber::INTEGER, 0), [0x02, 0x01, 0x00]);
+        assert_eq!(ber::integer(ber::INTEGER, 128), [0x02, 0x02, 0x00, 0x80]);
+        assert_eq!(ber::integer(ber::INTEGER, -1), [0x02, 0x01, 0xff]);
+
+        let long = ber::tlv(ber::OCTET_STRING, &[1; 300]);
+        assert_eq!(long[..4], [0x04, 0x82, 0x01, 0x2c]);
+
+        let mut reader = ber::Reader::new(&long);
+        assert_eq!(reader.expect(ber::OCTET_STRING).unwrap().len(), 300);
+        assert!(reader.is_empty());
+
+        for value in [0, 1, 127, 128, -128, -129, 65536, i64::MAX, i64::MIN] {
+            let encoded = ber::integer(ber::INTEGER, value);
+            assert_eq!(
+                ber::Reader::new(&encoded).integer(ber::INTEGER).unwrap(),
+                value
+            );
+        }
+    }
+
+    #[sqlx::test(fixtures(path = "../database/fixtures", scripts("user")))]
+    async fn test_ldap_user(pool: sqlx::Pool<sqlx::Sqlite>) {
+        // Test case: Verify that users are created on the first login and the admin role follows the group
+        let db = Database::new_test(pool);
+        let config = config(String::new());
+        let mut entry = LdapEntry {
+            dn: JANE_DN.to_string(),
+            groups: vec![ADMINS_DN.to_uppercase()],
+        };
+
+        let user = ldap_user(&db, &config, "jane", &entry).await.unwrap();
+        assert_eq!(user.auth_source, AuthSource::Ldap);
+        assert_eq!(user.role_id, ADMIN_ROLE);
+
+        entry.groups.clear();
+        let same = ldap_user(&db, &config, "jane", &entry).await.unwrap();
+        assert_eq!(same.id, user.id);
+        assert_eq!(same.role_id, DEFAULT_ROLE);
+
+        // Roles other than admin are managed in fela.
+        db.update_user(user.id, None, None, Some(2), None)
+            .await
+            .unwrap();
+        let same = ldap_user(&db, &config, "jane", &entry).await.unwrap();
+        assert_eq!(same.role_id, 2);
+
+        // Local users are never taken over.
+        assert!(ldap_user(&db, &config, "user", &entry).await.is_err());
+    }
+}
@@ -1,3 +1,12 @@
+pub mod api_token;
+pub mod cookie;
+pub mod ldap;
+pub mod oidc;
 pub mod password;
+pub mod password_cache;
+pub mod proxy;
 pub mod random;
 pub mod session;
+pub mod signed_url;
+pub mod throttle;
+pub mod totp;
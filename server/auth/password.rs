@@ -1,11 +1,26 @@
+use std::{collections::HashSet, sync::LazyLock};
+
 use anyhow::Result;
 use argon2::{
-    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+    Algorithm, Argon2, Params, PasswordHash, PasswordHasher, PasswordVerifier, Version,
     password_hash::{SaltString, rand_core::OsRng},
 };
+use sha1::{Digest, Sha1};
+
+use crate::{api::response::ApiError, database::Database};
+
+/// Longest accepted password, hashing very long inputs only costs time.
+const MAX_PASSWORD_LENGTH: usize = 1024;
+
+/// Argon2 instance new hashes are created with.
+/// Stored hashes with other parameters are replaced on the next login.
+fn argon2() -> Argon2<'static> {
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, Params::default())
+}
 
 pub fn verify_password(hash: &str, password: &str) -> bool {
     if let Ok(parsed_hash) = PasswordHash::new(hash) {
+        // The parameters are read from the hash, so this also verifies older hashes.
         Argon2::default()
             .verify_password(password.as_bytes(), &parsed_hash)
             .is_ok()
@@ -14,18 +29,154 @@ pub fn verify_password(hash: &str, password: &str) -> bool {
     }
 }
 
+/// Verify a password on a blocking thread.
+/// Argon2 is slow on purpose and would hold up other requests on the async workers.
+pub async fn verify_password_blocking(hash: &str, password: &str) -> Result<bool> {
+    let (hash, password) = (hash.to_string(), password.to_string());
+    Ok(tokio::task::spawn_blocking(move || verify_password(&hash, &password)).await?)
+}
+
 pub fn hash_password(password: &str) -> Result<String> {
     let salt = SaltString::generate(&mut OsRng);
-    let hash = Argon2::default()
+    let hash = argon2()
         .hash_password(password.as_bytes(), &salt)
         .map_err(|err| anyhow::anyhow!("Failed to hash password: {}", err))?;
     Ok(hash.to_string())
 }
 
+/// Check if a hash was created with another algorithm, version or parameters than new hashes.
+/// The seeded admin account for example uses `argon2i` with less memory.
+pub fn needs_rehash(hash: &str) -> bool {
+    let Ok(hash) = PasswordHash::new(hash) else {
+        return true;
+    };
+    let Ok(params) = Params::try_from(&hash) else {
+        return true;
+    };
+    let current = Params::default();
+
+    hash.algorithm != Algorithm::Argon2id.ident()
+        || hash.version != Some(Version::V0x13.into())
+        || params.m_cost() != current.m_cost()
+        || params.t_cost() != current.t_cost()
+        || params.p_cost() != current.p_cost()
+}
+
+/// Replace an outdated hash after the password was verified, the plain password is only known
+/// at this point.
+/// The password stays the same, so signed URLs stay valid. A password changed in the meantime is
+/// kept.
+pub async fn upgrade_password_hash(database: &Database, user_id: i64, hash: &str, password: &str) {
+    if !needs_rehash(hash) {
+        return;
+    }
+
+    let password = password.to_string();
+    let new_hash = match tokio::task::spawn_blocking(move || hash_password(&password)).await {
+        Ok(Ok(hash)) => hash,
+        Ok(Err(err)) => return tracing::error!("{:?}", err),
+        Err(err) => return tracing::error!("{:?}", err),
+    };
+
+    match database
+        .replace_password_hash(user_id, hash, &new_hash)
+        .await
+    {
+        Ok(true) => tracing::info!("Upgraded password hash of user {}", user_id),
+        Ok(false) => {}
+        Err(err) => tracing::error!("{:?}", err),
+    }
+}
+
+/// Rules new passwords have to follow.
+pub struct PasswordPolicy {
+    min_length: usize,
+    /// Breached or common passwords, as plain text or upper case SHA-1 hex.
+    blocklist: HashSet<String>,
+}
+
+/// Password policy, configured with environment variables:
+/// - `PASSWORD_MIN_LENGTH`: Minimum number of characters, defaults to 8.
+/// - `PASSWORD_BLOCKLIST`: File of passwords that are rejected, one per line. Lines can also be
+///   SHA-1 hashes in the format of the Have I Been Pwned downloads (`HASH:COUNT`).
+pub static PASSWORD_POLICY: LazyLock<PasswordPolicy> = LazyLock::new(|| {
+    let min_length = std::env::var("PASSWORD_MIN_LENGTH").map_or(8, |length| {
+        length
+            .parse()
+            .expect("PASSWORD_MIN_LENGTH environment variable should be an integer")
+    });
+
+    let blocklist = std::env::var("PASSWORD_BLOCKLIST").map_or_else(
+        |_| HashSet::new(),
+        |path| {
+            let list = std::fs::read_to_string(&path).unwrap_or_else(|err| {
+                panic!("PASSWORD_BLOCKLIST should be a readable file ({path}): {err}")
+            });
+            PasswordPolicy::parse_blocklist(&list)
+        },
+    );
+
+    PasswordPolicy {
+        min_length,
+        blocklist,
+    }
+});
+
+impl PasswordPolicy {
+    /// Parse a blocklist file, hashes are normalized to upper case without the count.
+    fn parse_blocklist(list: &str) -> HashSet<String> {
+        list.lines()
+            .map(|line| line.trim_end_matches('\r'))
+            .filter(|line| !line.is_empty())
+            .map(|line| match line.split_once(':') {
+                Some((hash, count))
+                    if is_sha1(hash) && count.chars().all(|c| c.is_ascii_digit()) =>
+                {
+                    hash.to_uppercase()
+                }
+                _ if is_sha1(line) => line.to_uppercase(),
+                _ => line.to_string(),
+            })
+            .collect()
+    }
+
+    /// Check a new password of the user `username`.
+    pub fn check(&self, password: &str, username: &str) -> Result<(), ApiError> {
+        let length = password.chars().count();
+        if length < self.min_length {
+            return Err(ApiError::PasswordTooShort(self.min_length.to_string()));
+        }
+        if length > MAX_PASSWORD_LENGTH {
+            return Err(ApiError::PasswordTooLong(MAX_PASSWORD_LENGTH.to_string()));
+        }
+
+        let sha1 = Sha1::digest(password.as_bytes())
+            .iter()
+            .map(|byte| format!("{byte:02X}"))
+            .collect::<String>();
+        if password.eq_ignore_ascii_case(username)
+            || self.blocklist.contains(password)
+            || self.blocklist.contains(&sha1)
+        {
+            return Err(ApiError::PasswordBreached);
+        }
+
+        Ok(())
+    }
+}
+
+/// Check if a string is a SHA-1 hash in hex.
+fn is_sha1(value: &str) -> bool {
+    value.len() == 40 && value.chars().all(|c| c.is_ascii_hexdigit())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    /// Password `admin` as hashed in the init migration.
+    const SEEDED_HASH: &str = "$argon2i$v=19$m=4096,t=3,p=1$c2FsdEl0V2l0aFNhbHQ$xTGvQNICqetaNA0Wu1GwFmYhQjAreRcjBz6ornhaFXA";
+
     #[test]
     fn test_hash_password() {
         let password = "password123";
@@ -53,4 +204,68 @@ mod tests {
         // Ensure a wrong password is not verified
         assert!(!verify_password(&hashed_password, "wrongpassword"));
     }
+
+    #[test]
+    fn test_needs_rehash() {
+        // Test case: Verify that the seeded argon2i hash is upgraded, new hashes aren't
+        assert!(verify_password(SEEDED_HASH, "admin"));
+        assert!(needs_rehash(SEEDED_HASH));
+        assert!(needs_rehash("not a hash"));
+
+        assert!(!needs_rehash(&hash_password("password123").unwrap()));
+    }
+
+    fn policy(blocklist: &str) -> PasswordPolicy {
+        PasswordPolicy {
+            min_length: 8,
+            blocklist: PasswordPolicy::parse_blocklist(blocklist),
+        }
+    }
+
+    #[test]
+    fn test_policy_length() {
+        // Test case: Verify that the length is counted in characters
+        let policy = policy("");
+
+        assert!(matches!(
+            policy.check("short", "user"),
+            Err(ApiError::PasswordTooShort(_))
+        ));
+        assert!(policy.check("längerés", "user").is_ok());
+        assert!(matches!(
+            policy.check(&"a".repeat(MAX_PASSWORD_LENGTH + 1), "user"),
+            Err(ApiError::PasswordTooLong(_))
+        ));
+    }
+
+    #[test]
+    fn test_policy_blocklist() {
+        // Test case: Verify that listed passwords, their SHA-1 hashes and the username are rejected
+        let policy = policy("password123\r\nletmein\n");
+
+        assert!(matches!(
+            policy.check("password123", "user"),
+            Err(ApiError::PasswordBreached)
+        ));
+        assert!(matches!(
+            policy.check("Username1", "username1"),
+            Err(ApiError::PasswordBreached)
+        ));
+        assert!(policy.check("password1234", "user").is_ok());
+    }
+
+    #[test]
+    fn test_policy_blocklist_hash() {
+        // Test case: Verify that hashed entries are matched case-insensitively
+        let hash = Sha1::digest(b"correct horse")
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect::<String>();
+        let policy = policy(&format!("{hash}:42"));
+
+        assert!(matches!(
+            policy.check("correct horse", "user"),
+            Err(ApiError::PasswordBreached)
+        ));
+    }
 }
@@ -0,0 +1,107 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use sha2::{Digest, Sha256};
+
+use super::random::random_string;
+
+/// How long a verified password is accepted without running Argon2 again.
+const VERIFIED_LIFETIME: Duration = Duration::from_secs(5 * 60);
+
+/// Number of cached passwords, expired ones are pruned once it is reached.
+const MAX_ENTRIES: usize = 1024;
+
+/// Passwords recently verified for clients that send them with every request, like OPDS readers
+/// and Subsonic apps.
+/// Only a hash of username, password and the stored password hash is kept, keyed with a secret
+/// of the process. A changed password never matches, and the cache reveals no passwords.
+#[derive(Clone)]
+pub struct PasswordCache {
+    secret: Arc<str>,
+    verified: Arc<Mutex<HashMap<[u8; 32], Instant>>>,
+}
+
+impl Default for PasswordCache {
+    fn default() -> Self {
+        Self {
+            secret: random_string(32).into(),
+            verified: Arc::default(),
+        }
+    }
+}
+
+impl PasswordCache {
+    fn key(&self, username: &str, password: &str, hash: &str) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        for part in [&*self.secret, username, password, hash] {
+            hasher.update(part.len().to_le_bytes());
+            hasher.update(part);
+        }
+        hasher.finalize().into()
+    }
+
+    /// Check if `password` was verified against the stored `hash` recently.
+    pub fn is_verified(&self, username: &str, password: &str, hash: &str, now: Instant) -> bool {
+        let key = self.key(username, password, hash);
+        self.verified
+            .lock()
+            .expect("Password cache lock should not be poisoned")
+            .get(&key)
+            .is_some_and(|verified| now.duration_since(*verified) < VERIFIED_LIFETIME)
+    }
+
+    /// Remember that `password` matches the stored `hash`.
+    pub fn insert(&self, username: &str, password: &str, hash: &str, now: Instant) {
+        let key = self.key(username, password, hash);
+        let mut verified = self
+            .verified
+            .lock()
+            .expect("Password cache lock should not be poisoned");
+
+        if verified.len() >= MAX_ENTRIES {
+            verified.retain(|_, verified| now.duration_since(*verified) < VERIFIED_LIFETIME);
+        }
+        // Still full of fresh entries, start over rather than growing without bound.
+        if verified.len() >= MAX_ENTRIES {
+            verified.clear();
+        }
+        verified.insert(key, now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_password_cache() {
+        // Test case: Verify that only the same credentials and hash are accepted until they expire
+        let cache = PasswordCache::default();
+        let now = Instant::now();
+
+        assert!(!cache.is_verified("user", "password", "hash", now));
+        cache.insert("user", "password", "hash", now);
+        assert!(cache.is_verified("user", "password", "hash", now));
+
+        assert!(!cache.is_verified("user", "other", "hash", now));
+        assert!(!cache.is_verified("other", "password", "hash", now));
+        // The password was changed.
+        assert!(!cache.is_verified("user", "password", "new hash", now));
+        assert!(!cache.is_verified("user", "password", "hash", now + VERIFIED_LIFETIME));
+    }
+
+    #[test]
+    fn test_password_cache_limit() {
+        // Test case: Verify that the cache doesn't grow past its limit
+        let cache = PasswordCache::default();
+        let now = Instant::now();
+
+        for index in 0..MAX_ENTRIES * 2 {
+            cache.insert(&format!("user{index}"), "password", "hash", now);
+        }
+        assert!(cache.verified.lock().unwrap().len() <= MAX_ENTRIES);
+    }
+}
@@ -0,0 +1,319 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::LazyLock,
+};
+
+use anyhow::Result;
+use axum::{
+    extract::{ConnectInfo, FromRequestParts, rejection::ExtensionRejection},
+    http::{HeaderMap, HeaderName, request::Parts},
+};
+
+use crate::database::{
+    Database,
+    role::DEFAULT_ROLE,
+    user::{AuthSource, User},
+};
+
+/// Range of IP addresses, e.g. `10.0.0.0/8` or a single address.
+#[derive(PartialEq, Debug)]
+pub struct IpNetwork {
+    address: IpAddr,
+    prefix: u8,
+}
+
+impl FromStr for IpNetwork {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let (address, prefix) = match value.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (value, None),
+        };
+        let address: IpAddr = address
+            .trim()
+            .parse()
+            .map_err(|_| format!("Invalid IP address {address}"))?;
+        let max_prefix = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .trim()
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= max_prefix)
+                .ok_or_else(|| format!("Invalid prefix length {prefix}"))?,
+            None => max_prefix,
+        };
+
+        Ok(Self { address, prefix })
+    }
+}
+
+impl IpNetwork {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // Clients connecting over IPv6 to an IPv4 address show up as mapped addresses.
+        match (self.address, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Parse comma separated addresses or ranges, e.g. `127.0.0.1,172.16.0.0/12`.
+pub fn parse_networks(networks: &str) -> Result<Vec<IpNetwork>, String> {
+    networks
+        .split(',')
+        .filter(|network| !network.trim().is_empty())
+        .map(IpNetwork::from_str)
+        .collect()
+}
+
+/// Reverse proxies in front of fela, from the `TRUSTED_PROXIES` environment variable.
+/// Only requests from them can set the client address with `X-Forwarded-For`, the origin with
+/// `X-Forwarded-Proto` and `X-Forwarded-Host`, or log users in with `TRUSTED_PROXY_HEADER`.
+pub static TRUSTED_PROXIES: LazyLock<Vec<IpNetwork>> = LazyLock::new(|| {
+    std::env::var("TRUSTED_PROXIES").map_or_else(
+        |_| Vec::new(),
+        |proxies| {
+            parse_networks(&proxies)
+                .unwrap_or_else(|err| panic!("TRUSTED_PROXIES is invalid: {err}"))
+        },
+    )
+});
+
+/// Check if a request from `ip` comes from one of the `proxies`.
+pub fn is_trusted_proxy(proxies: &[IpNetwork], ip: IpAddr) -> bool {
+    proxies.iter().any(|proxy| proxy.contains(ip))
+}
+
+/// Address of the client of a request from `peer`.
+/// Requests of trusted `proxies` come from the right-most address in `X-Forwarded-For` that isn't
+/// a trusted proxy. Proxies append the address they got the request from, everything left of it
+/// could be set by the client.
+fn forwarded_client(headers: &HeaderMap, peer: IpAddr, proxies: &[IpNetwork]) -> IpAddr {
+    if !is_trusted_proxy(proxies, peer) {
+        return peer;
+    }
+
+    let hops = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect::<Vec<_>>();
+
+    let mut client = peer;
+    for hop in hops.into_iter().rev() {
+        let Ok(ip) = hop.trim().parse::<IpAddr>() else {
+            break;
+        };
+        client = ip;
+        if !is_trusted_proxy(proxies, ip) {
+            break;
+        }
+    }
+
+    client.to_canonical()
+}
+
+/// Address of the client of a request from `peer`, behind the `TRUSTED_PROXIES`.
+/// Used for the login throttle, the audit log and the devices of sessions.
+pub fn client_ip(headers: &HeaderMap, peer: IpAddr) -> IpAddr {
+    forwarded_client(headers, peer, &TRUSTED_PROXIES)
+}
+
+/// Address of the client of a request that has its peer address, see `client_ip`.
+pub fn request_client_ip(parts: &Parts) -> Option<IpAddr> {
+    parts
+        .extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(address)| client_ip(&parts.headers, address.ip()))
+}
+
+/// Address of the client of the current request, see `client_ip`.
+pub struct ClientIp(pub IpAddr);
+
+impl<S: Send + Sync> FromRequestParts<S> for ClientIp {
+    type Rejection = ExtensionRejection;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let ConnectInfo(address) =
+            ConnectInfo::<SocketAddr>::from_request_parts(parts, state).await?;
+
+        Ok(Self(client_ip(&parts.headers, address.ip())))
+    }
+}
+
+/// Login with a header set by a reverse proxy that already authenticated the user, configured
+/// with environment variables:
+/// - `TRUSTED_PROXY_HEADER`: Header holding the username, e.g. `Remote-User`. Enables the login.
+/// - `TRUSTED_PROXIES`: Comma separated addresses or ranges of the proxies, e.g.
+///   `127.0.0.1,172.16.0.0/12`. The header is ignored on requests from other addresses, so
+///   clients can't set it themselves.
+pub struct TrustedProxy {
+    header: HeaderName,
+    proxies: Vec<IpNetwork>,
+}
+
+/// Trusted proxy configuration, `None` if the login isn't enabled.
+pub static TRUSTED_PROXY: LazyLock<Option<TrustedProxy>> = LazyLock::new(|| {
+    let header = std::env::var("TRUSTED_PROXY_HEADER").ok()?;
+    let proxies = std::env::var("TRUSTED_PROXIES")
+        .expect("TRUSTED_PROXIES should be set with TRUSTED_PROXY_HEADER");
+
+    Some(TrustedProxy::new(&header, &proxies).unwrap_or_else(|err| panic!("{err}")))
+});
+
+impl TrustedProxy {
+    pub fn new(header: &str, proxies: &str) -> Result<Self, String> {
+        let header = HeaderName::from_str(header.trim())
+            .map_err(|_| format!("TRUSTED_PROXY_HEADER is no valid header name: {header}"))?;
+        let proxies =
+            parse_networks(proxies).map_err(|err| format!("TRUSTED_PROXIES is invalid: {err}"))?;
+
+        Ok(Self { header, proxies })
+    }
+
+    /// Normalized username of a request, if it came from a trusted proxy.
+    pub fn username(&self, headers: &HeaderMap, ip: IpAddr) -> Option<String> {
+        if !is_trusted_proxy(&self.proxies, ip) {
+            return None;
+        }
+
+        headers
+            .get(&self.header)
+            .and_then(|value| value.to_str().ok())
+            .map(|name| name.trim().to_lowercase())
+            .filter(|name| !name.is_empty())
+    }
+}
+
+/// Get the user named by the proxy, creating it if it doesn't exist yet.
+/// The proxy is trusted, so existing local accounts are used as well.
+pub async fn proxy_user(database: &Database, username: &str) -> Result<User> {
+    if let Some(user) = database.get_user_by_name(username).await? {
+        return Ok(user);
+    }
+
+    let id = database
+        .create_external_user(username, AuthSource::Proxy, username, DEFAULT_ROLE)
+        .await?;
+    tracing::info!("Created user {} from trusted proxy login", username);
+
+    database.get_user(id).await
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn test_ip_network() {
+        // Test case: Verify that addresses are matched by prefix, including mapped IPv4 addresses
+        let network: IpNetwork = "172.16.0.0/12".parse().unwrap();
+        assert!(network.contains("172.20.1.2".parse().unwrap()));
+        assert!(network.contains("::ffff:172.20.1.2".parse().unwrap()));
+        assert!(!network.contains("172.32.0.1".parse().unwrap()));
+        assert!(!network.contains("::1".parse().unwrap()));
+
+        let single: IpNetwork = "::1".parse().unwrap();
+        assert!(single.contains("::1".parse().unwrap()));
+        assert!(!single.contains("::2".parse().unwrap()));
+
+        let any: IpNetwork = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains("8.8.8.8".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<IpNetwork>().is_err());
+        assert!("localhost".parse::<IpNetwork>().is_err());
+    }
+
+    #[test]
+    fn test_forwarded_client() {
+        // Test case: Verify that X-Forwarded-For is only used on requests of trusted proxies
+        let proxies = parse_networks("127.0.0.1,10.0.0.0/8").unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            HeaderValue::from_static("1.1.1.1, 203.0.113.7"),
+        );
+        headers.append("x-forwarded-for", HeaderValue::from_static("10.0.0.5"));
+
+        // The client could have set 1.1.1.1 itself, the trusted proxies only vouch for the rest.
+        let trusted = "127.0.0.1".parse().unwrap();
+        assert_eq!(
+            forwarded_client(&headers, trusted, &proxies),
+            "203.0.113.7".parse::<IpAddr>().unwrap()
+        );
+
+        let untrusted = "192.0.2.1".parse().unwrap();
+        assert_eq!(forwarded_client(&headers, untrusted, &proxies), untrusted);
+        assert_eq!(forwarded_client(&headers, trusted, &[]), trusted);
+        assert_eq!(
+            forwarded_client(&HeaderMap::new(), trusted, &proxies),
+            trusted
+        );
+
+        // Invalid hops end the search at the last trusted address.
+        headers.insert(
+            "x-forwarded-for",
+            HeaderValue::from_static("unknown, 10.0.0.5"),
+        );
+        assert_eq!(
+            forwarded_client(&headers, trusted, &proxies),
+            "10.0.0.5".parse::<IpAddr>().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_trusted_proxy_username() {
+        // Test case: Verify that the header is only used on requests of trusted proxies
+        let proxy = TrustedProxy::new("Remote-User", "127.0.0.1, 10.0.0.0/8").unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert("remote-user", HeaderValue::from_static(" Jane "));
+
+        assert_eq!(
+            proxy.username(&headers, "10.1.2.3".parse().unwrap()),
+            Some("jane".to_string())
+        );
+        assert_eq!(
+            proxy.username(&headers, "192.168.1.2".parse().unwrap()),
+            None
+        );
+        assert_eq!(
+            proxy.username(&HeaderMap::new(), "127.0.0.1".parse().unwrap()),
+            None
+        );
+
+        assert!(TrustedProxy::new("Remote User", "127.0.0.1").is_err());
+    }
+
+    #[sqlx::test(fixtures(path = "../database/fixtures", scripts("user")))]
+    async fn test_proxy_user(pool: sqlx::Pool<sqlx::Sqlite>) {
+        // Test case: Verify that existing users are used and unknown ones are created
+        let db = Database::new_test(pool);
+
+        let user = proxy_user(&db, "user").await.unwrap();
+        assert_eq!(user.id, 2);
+        assert_eq!(user.auth_source, AuthSource::Local);
+
+        let created = proxy_user(&db, "jane").await.unwrap();
+        assert_eq!(created.name, "jane");
+        assert_eq!(created.auth_source, AuthSource::Proxy);
+        assert_eq!(created.role_id, DEFAULT_ROLE);
+        assert_eq!(proxy_user(&db, "jane").await.unwrap().id, created.id);
+    }
+}
@@ -1,24 +1,43 @@
 use axum::{
     extract::{FromRequestParts, OptionalFromRequestParts, Request, State},
-    http::request::Parts,
+    http::{header, request::Parts},
     middleware::Next,
-    response::Response,
+    response::{IntoResponse, Response},
 };
 
-use crate::{api_bail, database::session::SessionInfo, state::FelaState};
+use crate::{
+    api_bail,
+    database::{
+        Database, api_token::TokenScope, audit::AuditEvent, role::Permission, session::SessionInfo,
+        user::User,
+    },
+    state::FelaState,
+};
+
+use base64::{Engine, prelude::BASE64_STANDARD};
+use std::{net::IpAddr, time::Instant};
 
-use super::random::random_string;
+use super::{
+    api_token::{API_TOKEN_PREFIX, hash_api_token},
+    cookie::{CSRF_HEADER, SESSION_COOKIE, get_cookie, needs_csrf_token},
+    password::{upgrade_password_hash, verify_password_blocking},
+    proxy::request_client_ip,
+    random::random_string,
+    throttle::{record_failed_login, reserve_attempt, too_many_attempts},
+};
 use crate::api::response::ApiError;
 
 // Bytes of entropy in the session id.
 pub static SESSION_ID_ENTROPY: usize = 32;
 
 // Session lifetime as a time::Duration.
-// Read FELA_SESSION_LIFETIME from environment variable using a lazy once_cell.
-// FELA_SESSION_LIFETIME is in hours.
+// Read SESSION_LIFETIME from environment variable, in hours.
+// FELA_SESSION_LIFETIME is still read for setups configured before it was renamed.
 // Default to 30 days.
 pub static SESSION_LIFETIME: std::sync::LazyLock<time::Duration> = std::sync::LazyLock::new(|| {
-    if let Ok(session_lifetime) = std::env::var("FELA_SESSION_LIFETIME") {
+    if let Ok(session_lifetime) =
+        std::env::var("SESSION_LIFETIME").or_else(|_| std::env::var("FELA_SESSION_LIFETIME"))
+    {
         time::Duration::hours(
             session_lifetime
                 .parse::<i64>()
@@ -29,71 +48,274 @@ pub static SESSION_LIFETIME: std::sync::LazyLock<time::Duration> = std::sync::La
     }
 });
 
+// How often the last access of a session is written, in hours.
+// To minimize writes it is only updated if it is older than this.
+// Default to 6 hours.
+pub static SESSION_REFRESH_INTERVAL: std::sync::LazyLock<time::Duration> =
+    std::sync::LazyLock::new(|| {
+        if let Ok(interval) = std::env::var("SESSION_REFRESH_INTERVAL") {
+            time::Duration::hours(
+                interval
+                    .parse::<i64>()
+                    .expect("SESSION_REFRESH_INTERVAL environment variable should be an integer"),
+            )
+        } else {
+            time::Duration::hours(6)
+        }
+    });
+
+// How often expired sessions are deleted.
+const SESSION_CLEANUP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+/// Credentials sent in the `Authorization` header.
+#[derive(PartialEq, Debug)]
+enum Credentials {
+    Bearer(String),
+    Basic { username: String, password: String },
+}
+
+/// Parse an `Authorization` header value.
+fn parse_authorization(value: &str) -> Option<Credentials> {
+    let (auth_type, credentials) = value.split_once(' ')?;
+
+    match auth_type {
+        "Bearer" => Some(Credentials::Bearer(credentials.to_string())),
+        "Basic" => {
+            let decoded = BASE64_STANDARD.decode(credentials.trim()).ok()?;
+            let decoded = String::from_utf8(decoded).ok()?;
+            let (username, password) = decoded.split_once(':')?;
+            Some(Credentials::Basic {
+                username: username.to_string(),
+                password: password.to_string(),
+            })
+        }
+        _ => None,
+    }
+}
+
 /// Middleware function to insert SessionInfo into the request extensions.
+/// Accepts session ids and API tokens as `Bearer` and the session cookie. Cookies are sent by the
+/// browser on its own, so state-changing requests authenticated by cookie also need the CSRF token
+/// in the `X-CSRF-Token` header.
+/// HTTP `Basic` auth is only accepted by the routes extracting a `BasicAuthSession`.
 pub async fn get_session(
     State(state): State<FelaState>,
     mut request: Request,
     next: Next,
 ) -> Response {
-    // Get session id from auth header.
-    let session_id = {
-        let Some(auth_header) = request.headers().get("Authorization") else {
-            return next.run(request).await;
-        };
+    let credentials = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_authorization);
 
-        let Ok(auth_value) = auth_header.to_str() else {
-            return next.run(request).await;
-        };
-
-        let Some((auth_type, session_id)) = auth_value.split_once(" ") else {
-            return next.run(request).await;
-        };
-
-        if auth_type != "Bearer" {
-            return next.run(request).await;
+    let session = match credentials {
+        Some(Credentials::Bearer(token)) if token.starts_with(API_TOKEN_PREFIX) => {
+            session_from_api_token(&state, &token).await
+        }
+        Some(Credentials::Bearer(session_id)) => session_from_id(&state, &session_id).await,
+        Some(Credentials::Basic { .. }) | None => {
+            match get_cookie(request.headers(), SESSION_COOKIE) {
+                Some(session_id) => {
+                    let session = session_from_id(&state, session_id).await;
+                    if session.is_some() && needs_csrf_token(request.method()) {
+                        let csrf_token = request
+                            .headers()
+                            .get(CSRF_HEADER)
+                            .and_then(|value| value.to_str().ok())
+                            .unwrap_or_default();
+                        if !state.signer.verify_csrf_token(session_id, csrf_token) {
+                            return ApiError::InvalidCsrfToken.into_response();
+                        }
+                    }
+                    session
+                }
+                None => None,
+            }
         }
-
-        session_id
     };
 
+    // Insert session into request extensions.
+    if let Some(session) = session {
+        request.extensions_mut().insert(session);
+    }
+
+    // Call next middleware.
+    next.run(request).await
+}
+
+/// Get a stored session by its id.
+async fn session_from_id(state: &FelaState, session_id: &str) -> Option<SessionInfo> {
     // Get session from database.
-    let Ok(session) = state.database.get_session(session_id).await else {
-        return next.run(request).await;
-    };
+    let session = state.database.get_session(session_id).await.ok()?;
 
     // Check if the session is expired.
     // If it is, delete it from the database and return.
     if session.last_accessed < time::OffsetDateTime::now_utc() - *SESSION_LIFETIME {
+        let database = state.database.clone();
         tokio::spawn(async move {
-            if let Err(err) = state.database.delete_session(&session.session_id).await {
+            if let Err(err) = database.delete_session(&session.session_id).await {
                 tracing::error!("{}", err)
             }
         });
 
-        return next.run(request).await;
+        return None;
     }
 
     // Update session last access time in background.
-    // To minimize writes we only update if the session was last accessed more than 6 hours ago.
-    if session.last_accessed < time::OffsetDateTime::now_utc() - time::Duration::hours(6) {
+    // To minimize writes we only update if the session was last accessed before the refresh interval.
+    if session.last_accessed < time::OffsetDateTime::now_utc() - *SESSION_REFRESH_INTERVAL {
         // Spawn task to update session last access time.
+        let database = state.database.clone();
         let session_id = session.session_id.clone();
-        tokio::spawn(async move { state.database.update_session_timestamp(&session_id).await });
+        tokio::spawn(async move { database.update_session_timestamp(&session_id).await });
     }
 
-    // Insert session into request extensions.
-    request.extensions_mut().insert(session);
+    Some(session)
+}
 
-    // Call next middleware.
-    next.run(request).await
+/// Delete expired sessions periodically.
+/// Sessions are also deleted when they are presented after expiring, but abandoned ones never are.
+pub async fn cleanup_expired_sessions(database: Database) {
+    let mut interval = tokio::time::interval(SESSION_CLEANUP_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let expired_before = time::OffsetDateTime::now_utc() - *SESSION_LIFETIME;
+        match database.delete_expired_sessions(expired_before).await {
+            Ok(0) => {}
+            Ok(deleted) => tracing::info!("Deleted {} expired sessions", deleted),
+            Err(err) => tracing::error!("{:?}", err),
+        }
+    }
+}
+
+/// Authenticate with username and password, for clients that only support HTTP Basic auth or
+/// send the password with every request like Subsonic apps.
+/// No session is stored. Recently verified passwords are cached, so Argon2 only runs once every
+/// few minutes per client.
+/// Failed attempts are throttled like logins, throttled attempts are rejected without checking
+/// the password. Users with two-factor authentication can't log in this way.
+pub async fn session_from_password(
+    state: &FelaState,
+    username: &str,
+    password: String,
+    ip: Option<IpAddr>,
+) -> Result<SessionInfo, ApiError> {
+    let username = username.trim().to_lowercase();
+    state
+        .login_throttle
+        .check(&username, ip, Instant::now())
+        .map_err(too_many_attempts)?;
+
+    let Ok(mut user) = state.database.get_user_with_password(&username).await else {
+        let attempt = reserve_attempt(state, &username, ip)?;
+        record_failed_login(state, AuditEvent::LoginFailed, attempt, None).await;
+        api_bail!(InvalidCredentials)
+    };
+    let Some(hash) = user.password.take() else {
+        api_bail!(InvalidCredentials)
+    };
+
+    // The password alone isn't enough for users with two-factor authentication, they can use
+    // API tokens instead.
+    if state.database.has_two_factor(user.id).await.unwrap_or(true) {
+        api_bail!(InvalidCredentials)
+    }
+
+    // Cached passwords were verified already, only new ones count as attempts.
+    if !state
+        .password_cache
+        .is_verified(&username, &password, &hash, Instant::now())
+    {
+        let attempt = reserve_attempt(state, &username, ip)?;
+        let valid = match verify_password_blocking(&hash, &password).await {
+            Ok(valid) => valid,
+            Err(err) => {
+                state.login_throttle.release(attempt);
+                return Err(err.into());
+            }
+        };
+        if !valid {
+            record_failed_login(state, AuditEvent::LoginFailed, attempt, Some(user.id)).await;
+            api_bail!(InvalidCredentials)
+        }
+        state.login_throttle.record_success(attempt);
+        state
+            .password_cache
+            .insert(&username, &password, &hash, Instant::now());
+        upgrade_password_hash(&state.database, user.id, &hash, &password).await;
+    }
+
+    Ok(credentials_session(user))
+}
+
+/// Session of a user authenticated by credentials sent with the request instead of a login.
+/// It gets the `Progress` scope, management and account routes need a login.
+pub fn credentials_session(user: User) -> SessionInfo {
+    SessionInfo {
+        session_id: String::new(),
+        user_id: user.id,
+        last_accessed: time::OffsetDateTime::now_utc(),
+        username: user.name,
+        role_id: user.role_id,
+        permissions: user.permissions,
+        scope: TokenScope::Progress,
+        must_change_password: user.must_change_password,
+    }
+}
+
+/// Authenticate with an API token.
+pub async fn session_from_api_token(state: &FelaState, token: &str) -> Option<SessionInfo> {
+    let token = state
+        .database
+        .get_api_token_session(&hash_api_token(token))
+        .await
+        .ok()??;
+
+    // Like sessions, only write the last use every few minutes.
+    let now = time::OffsetDateTime::now_utc();
+    if token
+        .last_used
+        .is_none_or(|last_used| last_used < now - time::Duration::minutes(5))
+    {
+        let database = state.database.clone();
+        tokio::spawn(async move { database.touch_api_token(token.id).await });
+    }
+
+    Some(token.session)
 }
 
 /// Extract the session from the request.
+/// Rejects users that have to change their password first.
 pub struct Session(pub SessionInfo);
 
 impl FromRequestParts<FelaState> for Session {
     type Rejection = ApiError;
 
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &FelaState,
+    ) -> Result<Self, Self::Rejection> {
+        let PasswordChangeSession(session) =
+            PasswordChangeSession::from_request_parts(parts, state).await?;
+
+        if session.must_change_password {
+            api_bail!(PasswordChangeRequired)
+        }
+
+        Ok(Session(session))
+    }
+}
+
+/// Extract the session from the request, even if the user has to change their password.
+/// Only used by the routes needed to change it.
+pub struct PasswordChangeSession(pub SessionInfo);
+
+impl FromRequestParts<FelaState> for PasswordChangeSession {
+    type Rejection = ApiError;
+
     async fn from_request_parts(
         parts: &mut Parts,
         _state: &FelaState,
@@ -101,7 +323,7 @@ impl FromRequestParts<FelaState> for Session {
         // Get the session from the request extensions.
         parts.extensions.get::<SessionInfo>().map_or_else(
             || api_bail!(NotLoggedIn),
-            |session| Ok(Session(session.to_owned())),
+            |session| Ok(PasswordChangeSession(session.to_owned())),
         )
     }
 }
@@ -120,30 +342,159 @@ impl OptionalFromRequestParts<FelaState> for Session {
     }
 }
 
-/// Extract the session from the request if user is an admin.
-pub struct AdminSession(pub SessionInfo);
+/// Extract the session from the request, also accepting username and password as HTTP `Basic`
+/// auth. Only used by the routes of clients that support nothing else, like OPDS readers.
+/// Rejects users that have to change their password first.
+pub struct BasicAuthSession(pub SessionInfo);
 
-impl FromRequestParts<FelaState> for AdminSession {
+impl FromRequestParts<FelaState> for BasicAuthSession {
     type Rejection = ApiError;
 
     async fn from_request_parts(
         parts: &mut Parts,
-        _state: &FelaState,
+        state: &FelaState,
+    ) -> Result<Self, Self::Rejection> {
+        let credentials = parts
+            .headers
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_authorization);
+
+        let Some(Credentials::Basic { username, password }) = credentials else {
+            return <self::Session as axum::extract::FromRequestParts<FelaState>>::from_request_parts(
+                parts, state,
+            )
+            .await
+            .map(|Session(session)| BasicAuthSession(session));
+        };
+
+        let ip = request_client_ip(parts);
+        let session = session_from_password(state, &username, password, ip).await?;
+
+        if session.must_change_password {
+            api_bail!(PasswordChangeRequired)
+        }
+
+        Ok(BasicAuthSession(session))
+    }
+}
+
+/// Check that the role of the user has a permission needed by a management route.
+/// API tokens also need the admin scope for these routes.
+fn require_permission(
+    session: SessionInfo,
+    permission: Permission,
+) -> Result<SessionInfo, ApiError> {
+    if !session.permissions.has(permission) {
+        api_bail!(MissingPermission)
+    }
+
+    if session.scope < TokenScope::Admin {
+        api_bail!(InsufficientScope)
+    }
+
+    Ok(session)
+}
+
+/// Extract the session from the request if the user may manage the library.
+pub struct LibraryManagerSession(pub SessionInfo);
+
+impl FromRequestParts<FelaState> for LibraryManagerSession {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &FelaState,
     ) -> Result<Self, Self::Rejection> {
         // Get session from Session extractor.
         let session =
             <self::Session as axum::extract::FromRequestParts<FelaState>>::from_request_parts(
-                parts, _state,
+                parts, state,
             )
             .await?
             .0;
 
-        // Check if user is an admin.
-        if !session.admin {
-            api_bail!(NotAdmin)
+        require_permission(session, Permission::ManageLibrary).map(LibraryManagerSession)
+    }
+}
+
+/// Extract the session from the request if the user may manage users and roles.
+pub struct UserManagerSession(pub SessionInfo);
+
+impl FromRequestParts<FelaState> for UserManagerSession {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &FelaState,
+    ) -> Result<Self, Self::Rejection> {
+        // Get session from Session extractor.
+        let session =
+            <self::Session as axum::extract::FromRequestParts<FelaState>>::from_request_parts(
+                parts, state,
+            )
+            .await?
+            .0;
+
+        require_permission(session, Permission::ManageUsers).map(UserManagerSession)
+    }
+}
+
+/// Extract the session from the request if it may update progress and library lists.
+/// Needs the progress permission, guests can only listen.
+pub struct ProgressSession(pub SessionInfo);
+
+impl FromRequestParts<FelaState> for ProgressSession {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &FelaState,
+    ) -> Result<Self, Self::Rejection> {
+        // Get session from Session extractor.
+        let session =
+            <self::Session as axum::extract::FromRequestParts<FelaState>>::from_request_parts(
+                parts, state,
+            )
+            .await?
+            .0;
+
+        if !session.permissions.has(Permission::Progress) {
+            api_bail!(MissingPermission)
+        }
+
+        if session.scope < TokenScope::Progress {
+            api_bail!(InsufficientScope)
+        }
+
+        Ok(ProgressSession(session))
+    }
+}
+
+/// Extract the session from the request if it may manage the account of the user.
+/// API tokens need the admin scope, so a leaked read token can't change the password.
+pub struct AccountSession(pub SessionInfo);
+
+impl FromRequestParts<FelaState> for AccountSession {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &FelaState,
+    ) -> Result<Self, Self::Rejection> {
+        // Get session from Session extractor.
+        let session =
+            <self::Session as axum::extract::FromRequestParts<FelaState>>::from_request_parts(
+                parts, state,
+            )
+            .await?
+            .0;
+
+        if session.scope < TokenScope::Admin {
+            api_bail!(InsufficientScope)
         }
 
-        Ok(AdminSession(session))
+        Ok(AccountSession(session))
     }
 }
 
@@ -151,3 +502,40 @@ impl FromRequestParts<FelaState> for AdminSession {
 pub fn create_session_id() -> String {
     random_string(SESSION_ID_ENTROPY)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_authorization_bearer() {
+        // Test case: Verify that bearer tokens are parsed as session ids
+        assert_eq!(
+            parse_authorization("Bearer abc123"),
+            Some(Credentials::Bearer("abc123".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_parse_authorization_basic() {
+        // Test case: Verify that basic credentials are decoded, passwords may contain colons
+        let encoded = BASE64_STANDARD.encode("admin:pass:word");
+        assert_eq!(
+            parse_authorization(&format!("Basic {encoded}")),
+            Some(Credentials::Basic {
+                username: "admin".to_string(),
+                password: "pass:word".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_authorization_invalid() {
+        // Test case: Verify that unknown schemes and malformed credentials are ignored
+        assert_eq!(parse_authorization("Digest abc"), None);
+        assert_eq!(parse_authorization("Basic not-base64!"), None);
+        let encoded = BASE64_STANDARD.encode("no-colon");
+        assert_eq!(parse_authorization(&format!("Basic {encoded}")), None);
+        assert_eq!(parse_authorization("Bearer"), None);
+    }
+}
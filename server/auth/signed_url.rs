@@ -0,0 +1,328 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use axum::{
+    extract::{FromRequestParts, OriginalUri, Query},
+    http::request::Parts,
+};
+use base64::{Engine, prelude::BASE64_URL_SAFE_NO_PAD};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use time::OffsetDateTime;
+
+use super::random::random_string;
+use crate::{
+    api::{response::ApiError, url::API_PREFIX},
+    api_bail,
+    database::{Database, image::ImageKind, session::SessionInfo},
+    state::FelaState,
+};
+
+/// Key of the setting the signing key is stored in.
+const SIGNING_KEY_SETTING: &str = "url_signing_key";
+
+/// Length of a generated signing key.
+const SIGNING_KEY_LENGTH: usize = 64;
+
+/// Default lifetime of signed URLs.
+pub const SIGNED_URL_LIFETIME: time::Duration = time::Duration::hours(1);
+
+/// Longest lifetime a client can request for a signed URL.
+pub const MAX_SIGNED_URL_LIFETIME: time::Duration = time::Duration::days(1);
+
+/// Routes that accept signed URLs.
+#[derive(Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(tag = "resource", rename_all = "lowercase")]
+pub enum SignedResource {
+    /// An audio file, `/fs/audio/{id}`.
+    Audio { id: i64 },
+    /// An image of a book, `/book/{id}/cover` or `/book/{id}/cover/{kind}`.
+    Cover { id: i64, kind: Option<ImageKind> },
+    /// The download of a book, `/book/{id}/download`.
+    Download { id: i64 },
+}
+
+impl SignedResource {
+    /// Full path of the resource, tokens are only valid for this path.
+    pub fn path(self) -> String {
+        match self {
+            SignedResource::Audio { id } => format!("{API_PREFIX}/fs/audio/{id}"),
+            SignedResource::Cover { id, kind: None } => format!("{API_PREFIX}/book/{id}/cover"),
+            SignedResource::Cover {
+                id,
+                kind: Some(kind),
+            } => format!("{API_PREFIX}/book/{id}/cover/{}", kind.as_str()),
+            SignedResource::Download { id } => format!("{API_PREFIX}/book/{id}/download"),
+        }
+    }
+}
+
+/// User a token is signed for, with the signing generation of the user at that time.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct SignedUser {
+    pub user_id: i64,
+    pub generation: i64,
+}
+
+impl SignedUser {
+    /// Current generation of a user, for signing new tokens.
+    pub async fn current(database: &Database, user_id: i64) -> Result<Self> {
+        Ok(Self {
+            user_id,
+            generation: database.get_signing_generation(user_id).await?,
+        })
+    }
+
+    /// Check that the token wasn't revoked since it was signed.
+    pub async fn is_current(self, database: &Database) -> bool {
+        database
+            .get_signing_generation(self.user_id)
+            .await
+            .is_ok_and(|generation| generation == self.generation)
+    }
+}
+
+/// Signs URLs, so clients without a session can fetch a single path for a limited time.
+/// The same key derives the CSRF tokens of cookie sessions.
+///
+/// Tokens have the form `{user_id}.{generation}.{expires}.{signature}`, where the signature is an
+/// HMAC-SHA256 over the user, the signing generation of the user, the expiry as unix timestamp
+/// and the path. The generation is bumped when the user changes their password, revokes sessions
+/// or loses permissions, which invalidates all their tokens before they expire.
+#[derive(Clone)]
+pub struct UrlSigner {
+    key: Arc<[u8]>,
+}
+
+impl UrlSigner {
+    pub fn new(key: &[u8]) -> Self {
+        Self { key: key.into() }
+    }
+
+    /// Load the signing key from the database, it is generated on first start.
+    pub async fn from_database(database: &Database) -> Result<Self> {
+        let key = database
+            .get_or_insert_setting(SIGNING_KEY_SETTING, &random_string(SIGNING_KEY_LENGTH))
+            .await?;
+
+        Ok(Self::new(key.as_bytes()))
+    }
+
+    fn mac(&self, path: &str, signed: SignedUser, expires: i64) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC should accept keys of any size");
+        mac.update(format!("{}.{}.{expires}.{path}", signed.user_id, signed.generation).as_bytes());
+        mac
+    }
+
+    /// Create a token allowing the user to fetch `path` until `expires`.
+    pub fn sign(&self, path: &str, signed: SignedUser, expires: OffsetDateTime) -> String {
+        let expires = expires.unix_timestamp();
+        let signature = self.mac(path, signed, expires).finalize().into_bytes();
+
+        format!(
+            "{}.{}.{expires}.{}",
+            signed.user_id,
+            signed.generation,
+            BASE64_URL_SAFE_NO_PAD.encode(signature)
+        )
+    }
+
+    /// Check a token for `path`, returns the user and generation it was created for.
+    /// Callers have to compare the generation with the current one of the user.
+    pub fn verify(&self, path: &str, token: &str, now: OffsetDateTime) -> Option<SignedUser> {
+        let mut parts = token.splitn(4, '.');
+        let signed = SignedUser {
+            user_id: parts.next()?.parse().ok()?,
+            generation: parts.next()?.parse().ok()?,
+        };
+        let expires = parts.next()?.parse().ok()?;
+        let signature = BASE64_URL_SAFE_NO_PAD.decode(parts.next()?).ok()?;
+
+        if expires < now.unix_timestamp() {
+            return None;
+        }
+
+        self.mac(path, signed, expires)
+            .verify_slice(&signature)
+            .ok()
+            .map(|_| signed)
+    }
+
+    fn csrf_mac(&self, session_id: &str) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC should accept keys of any size");
+        mac.update(format!("csrf.{session_id}").as_bytes());
+        mac
+    }
+
+    /// CSRF token of a cookie session, derived from the session id so it needs no storage.
+    pub fn csrf_token(&self, session_id: &str) -> String {
+        BASE64_URL_SAFE_NO_PAD.encode(self.csrf_mac(session_id).finalize().into_bytes())
+    }
+
+    /// Check the CSRF token sent with a request of a cookie session.
+    pub fn verify_csrf_token(&self, session_id: &str, token: &str) -> bool {
+        BASE64_URL_SAFE_NO_PAD
+            .decode(token)
+            .is_ok_and(|token| self.csrf_mac(session_id).verify_slice(&token).is_ok())
+    }
+}
+
+/// Query parameter carrying a signed token.
+#[derive(Deserialize)]
+struct SignedQuery {
+    token: Option<String>,
+}
+
+/// Allow access with a session, or with a signed `token` in the query for the requested path.
+/// Used by routes that are opened by players which can't send an `Authorization` header.
+pub struct SignedAccess;
+
+impl FromRequestParts<FelaState> for SignedAccess {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &FelaState,
+    ) -> Result<Self, Self::Rejection> {
+        if let Some(session) = parts.extensions.get::<SessionInfo>() {
+            if session.must_change_password {
+                api_bail!(PasswordChangeRequired)
+            }
+            return Ok(SignedAccess);
+        }
+
+        let Ok(Query(SignedQuery { token: Some(token) })) =
+            Query::<SignedQuery>::try_from_uri(&parts.uri)
+        else {
+            api_bail!(NotLoggedIn)
+        };
+
+        // Nested routers only see the rest of the path, tokens are signed for the full path.
+        let path = parts
+            .extensions
+            .get::<OriginalUri>()
+            .map_or_else(|| parts.uri.path(), |uri| uri.path());
+        let Some(signed) = state.signer.verify(path, &token, OffsetDateTime::now_utc()) else {
+            api_bail!(NotLoggedIn)
+        };
+        if !signed.is_current(&state.database).await {
+            api_bail!(NotLoggedIn)
+        }
+
+        // Tokens of deleted users stop working.
+        let user = state
+            .database
+            .get_user(signed.user_id)
+            .await
+            .map_err(|_| ApiError::NotLoggedIn)?;
+        if user.must_change_password {
+            api_bail!(PasswordChangeRequired)
+        }
+
+        Ok(SignedAccess)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::Duration;
+
+    const USER: SignedUser = SignedUser {
+        user_id: 2,
+        generation: 0,
+    };
+
+    #[test]
+    fn test_signed_resource_path() {
+        // Test case: Verify that resources map to the paths of the routes accepting tokens
+        assert_eq!(SignedResource::Audio { id: 1 }.path(), "/api/fs/audio/1");
+        assert_eq!(
+            SignedResource::Cover { id: 2, kind: None }.path(),
+            "/api/book/2/cover"
+        );
+        assert_eq!(
+            SignedResource::Cover {
+                id: 2,
+                kind: Some(ImageKind::Back)
+            }
+            .path(),
+            "/api/book/2/cover/back"
+        );
+        assert_eq!(
+            SignedResource::Download { id: 3 }.path(),
+            "/api/book/3/download"
+        );
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        // Test case: Verify that a signed token is accepted for its path and user
+        let signer = UrlSigner::new(b"key");
+        let now = OffsetDateTime::now_utc();
+
+        let token = signer.sign("/api/fs/audio/1", USER, now + Duration::hours(1));
+
+        assert_eq!(signer.verify("/api/fs/audio/1", &token, now), Some(USER));
+    }
+
+    #[test]
+    fn test_verify_rejects_other_path() {
+        // Test case: Verify that a token can't be used for another path or with another key
+        let signer = UrlSigner::new(b"key");
+        let now = OffsetDateTime::now_utc();
+
+        let token = signer.sign("/api/fs/audio/1", USER, now + Duration::hours(1));
+
+        assert_eq!(signer.verify("/api/fs/audio/2", &token, now), None);
+        assert_eq!(
+            UrlSigner::new(b"other").verify("/api/fs/audio/1", &token, now),
+            None
+        );
+    }
+
+    #[test]
+    fn test_verify_rejects_expired() {
+        // Test case: Verify that expired tokens are rejected
+        let signer = UrlSigner::new(b"key");
+        let now = OffsetDateTime::now_utc();
+
+        let token = signer.sign("/api/fs/audio/1", USER, now - Duration::seconds(1));
+
+        assert_eq!(signer.verify("/api/fs/audio/1", &token, now), None);
+    }
+
+    #[test]
+    fn test_csrf_token() {
+        // Test case: Verify that CSRF tokens are bound to the session
+        let signer = UrlSigner::new(b"key");
+
+        let token = signer.csrf_token("session");
+
+        assert!(signer.verify_csrf_token("session", &token));
+        assert!(!signer.verify_csrf_token("other", &token));
+        assert!(!signer.verify_csrf_token("session", "garbage"));
+    }
+
+    #[test]
+    fn test_verify_rejects_tampered() {
+        // Test case: Verify that changing the user, generation or expiry invalidates the signature
+        let signer = UrlSigner::new(b"key");
+        let now = OffsetDateTime::now_utc();
+        let expires = now + Duration::hours(1);
+
+        let token = signer.sign("/api/fs/audio/1", USER, expires);
+        let other_user = token.replacen("2.", "1.", 1);
+        let other_generation = token.replacen("2.0.", "2.1.", 1);
+
+        assert_eq!(signer.verify("/api/fs/audio/1", &other_user, now), None);
+        assert_eq!(
+            signer.verify("/api/fs/audio/1", &other_generation, now),
+            None
+        );
+        assert_eq!(signer.verify("/api/fs/audio/1", "garbage", now), None);
+    }
+}
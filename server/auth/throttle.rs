@@ -0,0 +1,476 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    api::response::ApiError,
+    database::{Database, audit::AuditEvent},
+    state::FelaState,
+};
+
+/// How failed logins of one username or IP address slow down further attempts.
+struct ThrottlePolicy {
+    /// Failures allowed before attempts are delayed.
+    free_attempts: u32,
+    /// Delay after the first failure past the free attempts, doubled with every further failure.
+    base_delay: Duration,
+    /// Longest delay before the lockout.
+    max_delay: Duration,
+    /// Failures after which attempts are rejected for `lockout`.
+    lockout_after: u32,
+    lockout: Duration,
+}
+
+impl ThrottlePolicy {
+    /// Time attempts are blocked after `failures` failed logins.
+    fn delay(&self, failures: u32) -> Duration {
+        if failures >= self.lockout_after {
+            self.lockout
+        } else if failures >= self.free_attempts {
+            let exponent = (failures - self.free_attempts).min(16);
+            (self.base_delay * 2u32.pow(exponent)).min(self.max_delay)
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+/// Policy for a single username, only its owner should fail to log in.
+const USERNAME_POLICY: ThrottlePolicy = ThrottlePolicy {
+    free_attempts: 3,
+    base_delay: Duration::from_secs(1),
+    max_delay: Duration::from_secs(60),
+    lockout_after: 10,
+    lockout: Duration::from_secs(15 * 60),
+};
+
+/// Policy for an IP address, more lenient as several users can share one.
+const IP_POLICY: ThrottlePolicy = ThrottlePolicy {
+    free_attempts: 10,
+    base_delay: Duration::from_secs(1),
+    max_delay: Duration::from_secs(60),
+    lockout_after: 50,
+    lockout: Duration::from_secs(15 * 60),
+};
+
+/// Failures are forgotten after this long without another failure.
+const FORGET_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// Most keys tracked at once, forgotten and then the oldest failures are dropped beyond that.
+/// Keeps attackers with many addresses or usernames from filling the memory, forgotten failures
+/// are also pruned periodically by `cleanup_login_records`.
+const MAX_KEYS: usize = 100_000;
+
+/// Interval failures and old audit log entries are pruned in.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// How long audit log entries are kept.
+const AUDIT_LOG_LIFETIME: time::Duration = time::Duration::days(90);
+
+/// Most entries kept in the audit log, older ones are deleted first.
+const MAX_AUDIT_ENTRIES: i64 = 100_000;
+
+/// What failed logins are counted for.
+#[derive(Hash, PartialEq, Eq, Clone, Debug)]
+enum ThrottleKey {
+    Username(String),
+    Ip(IpAddr),
+}
+
+impl ThrottleKey {
+    fn policy(&self) -> &'static ThrottlePolicy {
+        match self {
+            ThrottleKey::Username(_) => &USERNAME_POLICY,
+            ThrottleKey::Ip(_) => &IP_POLICY,
+        }
+    }
+}
+
+/// Failed logins of a key.
+struct Failures {
+    count: u32,
+    last: Instant,
+}
+
+impl Failures {
+    /// Time the key is blocked for at `now`.
+    fn blocked_for(&self, key: &ThrottleKey, now: Instant) -> Duration {
+        (self.last + key.policy().delay(self.count)).saturating_duration_since(now)
+    }
+}
+
+/// Login attempt reserved with `LoginThrottle::reserve`.
+/// It is counted as failure until it is resolved with `record_success` or `release`, so parallel
+/// attempts can't get past the throttle while the credentials are checked.
+#[derive(Debug)]
+#[must_use = "attempts stay counted as failure until resolved"]
+pub struct LoginAttempt {
+    username: String,
+    ip: Option<IpAddr>,
+    /// The attempt reached the lockout of the username or IP address, if it fails.
+    locks: bool,
+}
+
+/// Throttles logins with exponential backoff per username and per IP address.
+/// Failures are only kept in memory, a restart resets them.
+///
+/// The current time is passed in, so tests don't have to wait for delays to pass.
+#[derive(Clone, Default)]
+pub struct LoginThrottle {
+    failures: Arc<Mutex<HashMap<ThrottleKey, Failures>>>,
+}
+
+/// Keys a login attempt is counted for.
+fn keys(username: &str, ip: Option<IpAddr>) -> impl Iterator<Item = ThrottleKey> {
+    std::iter::once(ThrottleKey::Username(username.to_string())).chain(ip.map(ThrottleKey::Ip))
+}
+
+impl LoginThrottle {
+    /// Check if a login may be attempted, returns how long to wait otherwise.
+    /// Doesn't count the attempt, credentials have to be checked after `reserve`.
+    pub fn check(&self, username: &str, ip: Option<IpAddr>, now: Instant) -> Result<(), Duration> {
+        let failures = self
+            .failures
+            .lock()
+            .expect("Throttle lock should not be poisoned");
+
+        let wait = keys(username, ip)
+            .filter_map(|key| {
+                failures
+                    .get(&key)
+                    .map(|failures| failures.blocked_for(&key, now))
+            })
+            .max()
+            .unwrap_or_default();
+
+        if wait.is_zero() { Ok(()) } else { Err(wait) }
+    }
+
+    /// Check if a login may be attempted and count it as failure under the same lock, returns
+    /// how long to wait otherwise. The attempt has to be resolved once the credentials are checked.
+    pub fn reserve(
+        &self,
+        username: &str,
+        ip: Option<IpAddr>,
+        now: Instant,
+    ) -> Result<LoginAttempt, Duration> {
+        let mut failures = self
+            .failures
+            .lock()
+            .expect("Throttle lock should not be poisoned");
+
+        let wait = keys(username, ip)
+            .filter_map(|key| {
+                failures
+                    .get(&key)
+                    .map(|failures| failures.blocked_for(&key, now))
+            })
+            .max()
+            .unwrap_or_default();
+        if !wait.is_zero() {
+            return Err(wait);
+        }
+
+        if failures.len() >= MAX_KEYS {
+            prune(&mut failures, now);
+        }
+
+        let mut locks = false;
+        for key in keys(username, ip) {
+            let entry = failures.entry(key.clone()).or_insert(Failures {
+                count: 0,
+                last: now,
+            });
+            if now.duration_since(entry.last) >= FORGET_AFTER {
+                entry.count = 0;
+            }
+            entry.count += 1;
+            entry.last = now;
+
+            if entry.count == key.policy().lockout_after {
+                locks = true;
+            }
+        }
+
+        Ok(LoginAttempt {
+            username: username.to_string(),
+            ip,
+            locks,
+        })
+    }
+
+    /// Give back the attempt of an IP address.
+    fn release_ip(failures: &mut HashMap<ThrottleKey, Failures>, ip: Option<IpAddr>) {
+        if let Some(ip) = ip
+            && let Some(entry) = failures.get_mut(&ThrottleKey::Ip(ip))
+        {
+            entry.count = entry.count.saturating_sub(1);
+        }
+    }
+
+    /// Forget the failures of a username after a successful login.
+    /// Earlier failures of the IP address are kept, so one valid account can't be used to reset
+    /// them.
+    pub fn record_success(&self, attempt: LoginAttempt) {
+        let mut failures = self
+            .failures
+            .lock()
+            .expect("Throttle lock should not be poisoned");
+
+        failures.remove(&ThrottleKey::Username(attempt.username));
+        Self::release_ip(&mut failures, attempt.ip);
+    }
+
+    /// Give back an attempt that was neither a success nor a failure of the user, like a correct
+    /// password that still needs a two-factor code or an unreachable LDAP server.
+    pub fn release(&self, attempt: LoginAttempt) {
+        let mut failures = self
+            .failures
+            .lock()
+            .expect("Throttle lock should not be poisoned");
+
+        if let Some(entry) = failures.get_mut(&ThrottleKey::Username(attempt.username)) {
+            entry.count = entry.count.saturating_sub(1);
+        }
+        Self::release_ip(&mut failures, attempt.ip);
+    }
+
+    /// Drop forgotten failures, and the oldest ones if too many keys are tracked.
+    pub fn prune(&self, now: Instant) {
+        prune(
+            &mut self
+                .failures
+                .lock()
+                .expect("Throttle lock should not be poisoned"),
+            now,
+        );
+    }
+}
+
+fn prune(failures: &mut HashMap<ThrottleKey, Failures>, now: Instant) {
+    failures.retain(|_, failures| now.duration_since(failures.last) < FORGET_AFTER);
+
+    // Make room for a quarter of the keys at once, so this doesn't run on every attempt.
+    if failures.len() >= MAX_KEYS {
+        let mut last: Vec<_> = failures.values().map(|failures| failures.last).collect();
+        let (_, oldest, _) = last.select_nth_unstable(MAX_KEYS / 4);
+        let oldest = *oldest;
+        failures.retain(|_, failures| failures.last > oldest);
+    }
+}
+
+/// Error for an attempt that has to wait for `wait`.
+pub fn too_many_attempts(wait: Duration) -> ApiError {
+    // Round up, so clients don't retry a moment too early.
+    ApiError::TooManyAttempts(wait.as_secs() + u64::from(wait.subsec_nanos() > 0))
+}
+
+/// Reserve a login attempt before checking credentials, rejects it if there were too many
+/// failures.
+pub fn reserve_attempt(
+    state: &FelaState,
+    username: &str,
+    ip: Option<IpAddr>,
+) -> Result<LoginAttempt, ApiError> {
+    state
+        .login_throttle
+        .reserve(username, ip, Instant::now())
+        .map_err(too_many_attempts)
+}
+
+/// Add a failed login to the audit log as `event`.
+/// The attempt was already counted when it was reserved.
+pub async fn record_failed_login(
+    state: &FelaState,
+    event: AuditEvent,
+    attempt: LoginAttempt,
+    user_id: Option<i64>,
+) {
+    let username = attempt.username.as_str();
+    let ip = attempt.ip.map(|ip| ip.to_string());
+    let mut events = vec![event];
+    if attempt.locks {
+        tracing::warn!("Locked out logins for {} from {:?}", username, ip);
+        events.push(AuditEvent::LoginLocked);
+    }
+
+    for event in events {
+        if let Err(err) = state
+            .database
+            .add_audit_entry(event, user_id, Some(username), ip.as_deref())
+            .await
+        {
+            tracing::error!("{:?}", err);
+        }
+    }
+}
+
+/// Prune failed logins and old audit log entries periodically.
+pub async fn cleanup_login_records(database: Database, throttle: LoginThrottle) {
+    let mut interval = tokio::time::interval(CLEANUP_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        throttle.prune(Instant::now());
+
+        let created_before = time::OffsetDateTime::now_utc() - AUDIT_LOG_LIFETIME;
+        match database
+            .delete_old_audit_entries(created_before, MAX_AUDIT_ENTRIES)
+            .await
+        {
+            Ok(0) => {}
+            Ok(deleted) => tracing::info!("Deleted {} old audit log entries", deleted),
+            Err(err) => tracing::error!("{:?}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IP: Option<IpAddr> = Some(IpAddr::V4(std::net::Ipv4Addr::LOCALHOST));
+
+    #[test]
+    fn test_policy_delay() {
+        // Test case: Verify that delays start after the free attempts, double and end in a lockout
+        assert_eq!(USERNAME_POLICY.delay(2), Duration::ZERO);
+        assert_eq!(USERNAME_POLICY.delay(3), Duration::from_secs(1));
+        assert_eq!(USERNAME_POLICY.delay(5), Duration::from_secs(4));
+        assert_eq!(USERNAME_POLICY.delay(9), Duration::from_secs(60));
+        assert_eq!(USERNAME_POLICY.delay(10), Duration::from_secs(15 * 60));
+    }
+
+    /// Reserve an attempt that fails.
+    fn fail(throttle: &LoginThrottle, username: &str, ip: Option<IpAddr>, now: Instant) -> bool {
+        throttle.reserve(username, ip, now).unwrap().locks
+    }
+
+    #[test]
+    fn test_throttle_backoff() {
+        // Test case: Verify that failures block attempts until the delay passed
+        let throttle = LoginThrottle::default();
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            assert_eq!(throttle.check("user", IP, now), Ok(()));
+            fail(&throttle, "user", IP, now);
+        }
+
+        assert_eq!(throttle.check("user", IP, now), Err(Duration::from_secs(1)));
+        assert_eq!(
+            throttle.reserve("user", IP, now).unwrap_err(),
+            Duration::from_secs(1)
+        );
+        assert_eq!(
+            throttle.check("user", IP, now + Duration::from_secs(1)),
+            Ok(())
+        );
+
+        // Other users from another address aren't affected.
+        assert_eq!(throttle.check("other", None, now), Ok(()));
+    }
+
+    #[test]
+    fn test_throttle_parallel_attempts() {
+        // Test case: Verify that attempts count before they are resolved, so parallel ones are throttled
+        let throttle = LoginThrottle::default();
+        let now = Instant::now();
+
+        let attempts: Vec<_> = (0..3)
+            .map(|_| throttle.reserve("user", IP, now).unwrap())
+            .collect();
+        assert!(throttle.reserve("user", IP, now).is_err());
+
+        // Resolved attempts give their reservation back.
+        let mut attempts = attempts.into_iter();
+        throttle.release(attempts.next().unwrap());
+        assert_eq!(throttle.check("user", IP, now), Ok(()));
+        throttle.record_success(attempts.next().unwrap());
+        assert_eq!(throttle.check("user", IP, now), Ok(()));
+    }
+
+    #[test]
+    fn test_throttle_lockout() {
+        // Test case: Verify that repeated failures lock the username out
+        let throttle = LoginThrottle::default();
+        let now = Instant::now();
+
+        for attempt in 1..=10 {
+            // Wait for the delay of the previous failure.
+            let now = now + Duration::from_secs(60) * (attempt - 1);
+            assert_eq!(fail(&throttle, "user", IP, now), attempt == 10);
+        }
+
+        let locked = now + Duration::from_secs(9 * 60);
+        assert_eq!(
+            throttle.check("user", None, locked + Duration::from_secs(60)),
+            Err(Duration::from_secs(14 * 60))
+        );
+        assert_eq!(
+            throttle.check("user", None, locked + Duration::from_secs(15 * 60)),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_throttle_per_ip() {
+        // Test case: Verify that guessing many usernames from one address is throttled
+        let throttle = LoginThrottle::default();
+        let now = Instant::now();
+
+        for index in 0..10 {
+            fail(&throttle, &format!("user{index}"), IP, now);
+        }
+
+        assert!(throttle.check("new", IP, now).is_err());
+        assert_eq!(throttle.check("new", None, now), Ok(()));
+    }
+
+    #[test]
+    fn test_throttle_success_and_forget() {
+        // Test case: Verify that a successful login or an hour without failures resets the username
+        let throttle = LoginThrottle::default();
+        let now = Instant::now();
+
+        for _ in 0..3 {
+            fail(&throttle, "user", None, now);
+        }
+        let later = now + Duration::from_secs(1);
+        let attempt = throttle.reserve("user", None, later).unwrap();
+        throttle.record_success(attempt);
+        assert_eq!(throttle.check("user", None, later), Ok(()));
+
+        for _ in 0..3 {
+            fail(&throttle, "user", None, later);
+        }
+        let later = later + FORGET_AFTER;
+        fail(&throttle, "user", None, later);
+        assert_eq!(throttle.check("user", None, later), Ok(()));
+    }
+
+    #[test]
+    fn test_throttle_limit() {
+        // Test case: Verify that the oldest failures are dropped once too many keys are tracked
+        let throttle = LoginThrottle::default();
+        let now = Instant::now();
+
+        for index in 0..MAX_KEYS {
+            let now = now + Duration::from_millis(index as u64);
+            fail(&throttle, &format!("user{index}"), None, now);
+        }
+        let last = now + Duration::from_millis(MAX_KEYS as u64);
+        fail(&throttle, "new", None, last);
+
+        let failures = throttle.failures.lock().unwrap();
+        assert!(failures.len() < MAX_KEYS);
+        assert!(failures.contains_key(&ThrottleKey::Username("new".to_string())));
+        assert!(!failures.contains_key(&ThrottleKey::Username("user0".to_string())));
+        let newest = format!("user{}", MAX_KEYS - 1);
+        assert!(failures.contains_key(&ThrottleKey::Username(newest)));
+    }
+}
@@ -0,0 +1,344 @@
+use hmac::{Hmac, Mac};
+use percent_encoding::{NON_ALPHANUMERIC, utf8_percent_encode};
+use rand::{Rng, RngCore};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+
+use crate::database::Database;
+
+/// Issuer shown in authenticator apps.
+const ISSUER: &str = "Fela";
+
+/// Length of generated secrets in bytes, as recommended by RFC 4226.
+const SECRET_LENGTH: usize = 20;
+
+/// Seconds a code is valid for.
+pub const TOTP_STEP: i64 = 30;
+
+/// Digits of a code.
+const TOTP_DIGITS: u32 = 6;
+
+/// Steps before and after the current one that are accepted, to allow for clock drift.
+const TOTP_WINDOW: i64 = 1;
+
+/// Number of recovery codes created at once.
+pub const RECOVERY_CODE_COUNT: usize = 10;
+
+/// Alphabet of recovery codes, without characters that are easily confused.
+const RECOVERY_CODE_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+const BASE32_ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// Encode bytes as base32 without padding, as used by authenticator apps.
+pub fn base32_encode(data: &[u8]) -> String {
+    let mut encoded = String::with_capacity(data.len().div_ceil(5) * 8);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+
+    for &byte in data {
+        buffer = (buffer << 8) | u32::from(byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+
+    encoded
+}
+
+/// Decode base32, ignoring case, spaces and padding.
+pub fn base32_decode(encoded: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(encoded.len() * 5 / 8);
+    let mut buffer = 0u32;
+    let mut bits = 0;
+
+    for char in encoded.chars().filter(|char| !matches!(char, ' ' | '=')) {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|&letter| letter as char == char.to_ascii_uppercase())?;
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((buffer >> bits) as u8);
+        }
+    }
+
+    Some(decoded)
+}
+
+/// Create a new random secret.
+pub fn generate_secret() -> Vec<u8> {
+    let mut secret = vec![0; SECRET_LENGTH];
+    rand::rng().fill_bytes(&mut secret);
+    secret
+}
+
+/// HOTP value of a counter, RFC 4226.
+fn hotp(secret: &[u8], counter: u64, digits: u32) -> u32 {
+    let mut mac =
+        Hmac::<Sha1>::new_from_slice(secret).expect("HMAC should accept keys of any size");
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    // Dynamic truncation.
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let value = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+
+    value % 10u32.pow(digits)
+}
+
+/// Time step of a unix timestamp.
+pub fn totp_step(unix_time: i64) -> i64 {
+    unix_time.div_euclid(TOTP_STEP)
+}
+
+/// TOTP code of a time step, RFC 6238.
+fn totp(secret: &[u8], step: i64, digits: u32) -> String {
+    format!(
+        "{:0width$}",
+        hotp(secret, step as u64, digits),
+        width = digits as usize
+    )
+}
+
+/// Check a code at `unix_time`, returns the matched time step.
+/// Steps up to `last_step` were already used and are rejected, so a code works only once.
+pub fn verify_totp(
+    secret: &[u8],
+    code: &str,
+    unix_time: i64,
+    last_step: Option<i64>,
+) -> Option<i64> {
+    let code: String = code.chars().filter(|char| !char.is_whitespace()).collect();
+    if code.len() != TOTP_DIGITS as usize || !code.chars().all(|char| char.is_ascii_digit()) {
+        return None;
+    }
+
+    let current = totp_step(unix_time);
+    (current - TOTP_WINDOW..=current + TOTP_WINDOW)
+        .filter(|&step| last_step.is_none_or(|last_step| step > last_step))
+        .find(|&step| totp(secret, step, TOTP_DIGITS) == code)
+}
+
+/// URI to add the secret to an authenticator app, usually shown as QR code.
+pub fn otpauth_uri(username: &str, secret: &[u8]) -> String {
+    let label = utf8_percent_encode(&format!("{ISSUER}:{username}"), NON_ALPHANUMERIC).to_string();
+
+    format!(
+        "otpauth://totp/{label}?secret={}&issuer={ISSUER}&algorithm=SHA1&digits={TOTP_DIGITS}&period={TOTP_STEP}",
+        base32_encode(secret)
+    )
+}
+
+/// Create recovery codes, formatted as `xxxxx-xxxxx`.
+pub fn generate_recovery_codes() -> Vec<String> {
+    let mut rng = rand::rng();
+
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let mut code: String = (0..10)
+                .map(|_| {
+                    RECOVERY_CODE_ALPHABET[rng.random_range(0..RECOVERY_CODE_ALPHABET.len())]
+                        as char
+                })
+                .collect();
+            code.insert(5, '-');
+            code
+        })
+        .collect()
+}
+
+/// Hash of a recovery code as stored in the database.
+/// Case and dashes are ignored, so codes can be typed in any form.
+pub fn hash_recovery_code(code: &str) -> String {
+    let normalized: String = code
+        .chars()
+        .filter(|char| char.is_ascii_alphanumeric())
+        .map(|char| char.to_ascii_lowercase())
+        .collect();
+
+    Sha256::digest(normalized.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Check a TOTP or recovery code of a user with confirmed two-factor authentication.
+/// Used codes are stored, so they can't be used again.
+pub async fn verify_two_factor_code(
+    database: &Database,
+    user_id: i64,
+    code: &str,
+    now: OffsetDateTime,
+) -> anyhow::Result<bool> {
+    let Some(two_factor) = database.get_two_factor(user_id).await? else {
+        return Ok(false);
+    };
+    if !two_factor.confirmed {
+        return Ok(false);
+    }
+
+    let secret = base32_decode(&two_factor.secret)
+        .ok_or_else(|| anyhow::anyhow!("Invalid TOTP secret of user {}", user_id))?;
+    if let Some(step) = verify_totp(&secret, code, now.unix_timestamp(), two_factor.last_step) {
+        return database.use_totp_step(user_id, step).await;
+    }
+
+    database
+        .use_recovery_code(user_id, &hash_recovery_code(code))
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Secret of the SHA-1 test vectors in RFC 6238.
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    #[sqlx::test(fixtures(path = "../database/fixtures", scripts("user")))]
+    async fn test_verify_two_factor_code(pool: sqlx::Pool<sqlx::Sqlite>) {
+        // Test case: Verify that TOTP and recovery codes are accepted once
+        let db = Database::new_test(pool);
+        let now = OffsetDateTime::from_unix_timestamp(1111111111).unwrap();
+        db.create_two_factor(2, &base32_encode(RFC_SECRET))
+            .await
+            .unwrap();
+
+        // Unconfirmed secrets aren't used.
+        assert!(!verify_two_factor_code(&db, 2, "050471", now).await.unwrap());
+
+        db.confirm_two_factor(2, 0).await.unwrap();
+        db.set_recovery_codes(2, &[hash_recovery_code("abcde-fghjk")])
+            .await
+            .unwrap();
+
+        assert!(verify_two_factor_code(&db, 2, "050471", now).await.unwrap());
+        assert!(!verify_two_factor_code(&db, 2, "050471", now).await.unwrap());
+        assert!(
+            verify_two_factor_code(&db, 2, "ABCDE-FGHJK", now)
+                .await
+                .unwrap()
+        );
+        assert!(
+            !verify_two_factor_code(&db, 2, "abcde-fghjk", now)
+                .await
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_totp_rfc6238() {
+        // Test case: Verify the SHA-1 test vectors of RFC 6238, appendix B
+        let vectors = [
+            (59, "94287082"),
+            (1111111109, "07081804"),
+            (1111111111, "14050471"),
+            (1234567890, "89005924"),
+            (2000000000, "69279037"),
+            (20000000000, "65353130"),
+        ];
+
+        for (time, code) in vectors {
+            assert_eq!(totp(RFC_SECRET, totp_step(time), 8), code, "time {time}");
+        }
+    }
+
+    #[test]
+    fn test_hotp_rfc4226() {
+        // Test case: Verify the first test vectors of RFC 4226, appendix D
+        let codes = [755224, 287082, 359152, 969429, 338314];
+
+        for (counter, code) in codes.into_iter().enumerate() {
+            assert_eq!(hotp(RFC_SECRET, counter as u64, 6), code);
+        }
+    }
+
+    #[test]
+    fn test_verify_totp() {
+        // Test case: Verify that codes of adjacent steps are accepted and can't be reused
+        let time = 1111111111;
+        let code = totp(RFC_SECRET, totp_step(time), 6);
+        assert_eq!(code, "050471");
+
+        let step = verify_totp(RFC_SECRET, &code, time, None).unwrap();
+        assert_eq!(step, totp_step(time));
+        assert_eq!(
+            verify_totp(RFC_SECRET, "050 471", time + TOTP_STEP, None),
+            Some(step)
+        );
+
+        // Already used or too old.
+        assert_eq!(verify_totp(RFC_SECRET, &code, time, Some(step)), None);
+        assert_eq!(
+            verify_totp(RFC_SECRET, &code, time + 2 * TOTP_STEP, None),
+            None
+        );
+
+        assert_eq!(verify_totp(RFC_SECRET, "123", time, None), None);
+        assert_eq!(verify_totp(RFC_SECRET, "abcdef", time, None), None);
+    }
+
+    #[test]
+    fn test_base32() {
+        // Test case: Verify the test vectors of RFC 4648 and decoding of user input
+        let vectors = [
+            ("", ""),
+            ("f", "MY"),
+            ("fo", "MZXQ"),
+            ("foo", "MZXW6"),
+            ("foob", "MZXW6YQ"),
+            ("fooba", "MZXW6YTB"),
+            ("foobar", "MZXW6YTBOI"),
+        ];
+
+        for (data, encoded) in vectors {
+            assert_eq!(base32_encode(data.as_bytes()), encoded);
+            assert_eq!(base32_decode(encoded).unwrap(), data.as_bytes());
+        }
+
+        assert_eq!(base32_decode("mzxw 6ytb oi======").unwrap(), b"foobar");
+        assert_eq!(base32_decode("MZXW1"), None);
+    }
+
+    #[test]
+    fn test_otpauth_uri() {
+        // Test case: Verify that the label is encoded and the secret is base32
+        assert_eq!(
+            otpauth_uri("jane doe", b"foobar"),
+            "otpauth://totp/Fela%3Ajane%20doe?secret=MZXW6YTBOI&issuer=Fela&algorithm=SHA1&digits=6&period=30"
+        );
+    }
+
+    #[test]
+    fn test_recovery_codes() {
+        // Test case: Verify the format of recovery codes and that hashing ignores case and dashes
+        let codes = generate_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+        assert!(
+            codes
+                .iter()
+                .all(|code| code.len() == 11 && &code[5..6] == "-")
+        );
+
+        assert_eq!(
+            hash_recovery_code("abcde-fghjk"),
+            hash_recovery_code("ABCDEFGHJK")
+        );
+        assert_ne!(
+            hash_recovery_code("abcde-fghjk"),
+            hash_recovery_code("abcde-fghjm")
+        );
+    }
+}
@@ -0,0 +1,244 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+
+use super::{Database, role::Permissions, session::SessionInfo};
+
+/// What a session is allowed to do.
+/// Sessions created by logging in have the `Admin` scope, which allows everything the user can do.
+/// API tokens can be limited to reading or to reading and updating progress.
+#[derive(
+    sqlx::Type, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug,
+)]
+#[sqlx(rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum TokenScope {
+    /// Read books, files and the library.
+    Read,
+    /// Everything of `Read`, plus updating progress and library lists.
+    Progress,
+    /// Full access, including account routes and the routes the role of the user permits.
+    Admin,
+}
+
+/// API token of a user, without the token itself.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiToken {
+    pub id: i64,
+    pub name: String,
+    pub scope: TokenScope,
+
+    #[serde(with = "time::serde::iso8601::option")]
+    pub last_used: Option<OffsetDateTime>,
+    #[serde(with = "time::serde::iso8601")]
+    pub created: OffsetDateTime,
+}
+
+/// Session of an API token, with the time it was last used.
+pub struct ApiTokenSession {
+    pub id: i64,
+    pub session: SessionInfo,
+    pub last_used: Option<OffsetDateTime>,
+}
+
+impl Database {
+    // Store a new API token, returns its id.
+    pub async fn create_api_token(
+        &self,
+        user_id: i64,
+        name: &str,
+        hash: &str,
+        scope: TokenScope,
+    ) -> Result<i64> {
+        sqlx::query_scalar!(
+            r#"
+                INSERT INTO api_tokens (user_id, name, hash, scope)
+                VALUES (?, ?, ?, ?)
+                RETURNING id
+            "#,
+            user_id,
+            name,
+            hash,
+            scope
+        )
+        .fetch_one(&self.pool)
+        .await
+        .context("Unable to create API token")
+    }
+
+    // Get all API tokens of a user.
+    pub async fn get_api_tokens(&self, user_id: i64) -> Result<Vec<ApiToken>> {
+        sqlx::query_as!(
+            ApiToken,
+            r#"
+                SELECT
+                    id,
+                    name,
+                    scope AS "scope: TokenScope",
+                    last_used,
+                    created
+                FROM api_tokens
+                WHERE user_id = ?
+                ORDER BY created DESC, id DESC
+            "#,
+            user_id
+        )
+        .fetch_all(&self.pool)
+        .await
+        .context("Unable to get API tokens")
+    }
+
+    // Delete an API token of a user, returns false if the user has no such token.
+    pub async fn delete_api_token(&self, user_id: i64, token_id: i64) -> Result<bool> {
+        sqlx::query!(
+            r#"
+                DELETE FROM api_tokens
+                WHERE id = ? AND user_id = ?
+            "#,
+            token_id,
+            user_id
+        )
+        .execute(&self.pool)
+        .await
+        .context("Unable to delete API token")
+        .map(|result| result.rows_affected() > 0)
+    }
+
+    // Delete all API tokens of a user.
+    pub async fn delete_user_api_tokens(&self, user_id: i64) -> Result<()> {
+        sqlx::query!(
+            r#"
+                DELETE FROM api_tokens
+                WHERE user_id = ?
+            "#,
+            user_id
+        )
+        .execute(&self.pool)
+        .await
+        .context("Unable to delete API tokens of user")
+        .map(|_| ())
+    }
+
+    // Get the session of an API token by its hash.
+    pub async fn get_api_token_session(&self, hash: &str) -> Result<Option<ApiTokenSession>> {
+        let row = sqlx::query!(
+            r#"
+                SELECT
+                    api_tokens.id,
+                    api_tokens.scope AS "scope: TokenScope",
+                    api_tokens.last_used,
+                    api_tokens.user_id,
+                    users.name AS username,
+                    users.role_id,
+                    roles.permissions AS "permissions: Permissions",
+                    users.must_change_password
+                FROM api_tokens
+                INNER JOIN users ON api_tokens.user_id = users.id
+                INNER JOIN roles ON users.role_id = roles.id
+                WHERE api_tokens.hash = ?
+            "#,
+            hash
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .context("Unable to get API token session")?;
+
+        Ok(row.map(|row| ApiTokenSession {
+            id: row.id,
+            last_used: row.last_used,
+            session: SessionInfo {
+                session_id: String::new(),
+                user_id: row.user_id,
+                last_accessed: OffsetDateTime::now_utc(),
+                username: row.username,
+                role_id: row.role_id,
+                permissions: row.permissions,
+                scope: row.scope,
+                must_change_password: row.must_change_password,
+            },
+        }))
+    }
+
+    // Update the last use of an API token.
+    pub async fn touch_api_token(&self, token_id: i64) -> Result<()> {
+        sqlx::query!(
+            r#"
+                UPDATE api_tokens
+                SET last_used = CURRENT_TIMESTAMP
+                WHERE id = ?
+            "#,
+            token_id
+        )
+        .execute(&self.pool)
+        .await
+        .context("Unable to update API token")
+        .map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[sqlx::test(fixtures("user"))]
+    async fn test_api_token_session(pool: sqlx::Pool<sqlx::Sqlite>) {
+        // Test case: Verify that a token resolves to its user and scope
+        let db = Database::new_test(pool);
+        let id = db
+            .create_api_token(2, "script", "hash", TokenScope::Progress)
+            .await
+            .unwrap();
+
+        let token = db.get_api_token_session("hash").await.unwrap().unwrap();
+        assert_eq!(token.id, id);
+        assert_eq!(token.last_used, None);
+        assert_eq!(token.session.user_id, 2);
+        assert_eq!(token.session.username, "user");
+        assert_eq!(token.session.scope, TokenScope::Progress);
+
+        db.touch_api_token(id).await.unwrap();
+        let token = db.get_api_token_session("hash").await.unwrap().unwrap();
+        assert!(token.last_used.is_some());
+
+        assert!(db.get_api_token_session("other").await.unwrap().is_none());
+    }
+
+    #[sqlx::test(fixtures("user"))]
+    async fn test_delete_api_token(pool: sqlx::Pool<sqlx::Sqlite>) {
+        // Test case: Verify that users can only delete their own tokens
+        let db = Database::new_test(pool);
+        let id = db
+            .create_api_token(2, "script", "hash", TokenScope::Read)
+            .await
+            .unwrap();
+        assert_eq!(db.get_api_tokens(2).await.unwrap().len(), 1);
+
+        assert!(!db.delete_api_token(1, id).await.unwrap());
+        assert!(db.delete_api_token(2, id).await.unwrap());
+        assert!(db.get_api_tokens(2).await.unwrap().is_empty());
+    }
+
+    #[sqlx::test(fixtures("user"))]
+    async fn test_delete_user_api_tokens(pool: sqlx::Pool<sqlx::Sqlite>) {
+        // Test case: Verify that all tokens of a user are deleted, tokens of others are kept
+        let db = Database::new_test(pool);
+        for (user_id, hash) in [(2, "first"), (2, "second"), (1, "admin")] {
+            db.create_api_token(user_id, "script", hash, TokenScope::Read)
+                .await
+                .unwrap();
+        }
+
+        db.delete_user_api_tokens(2).await.unwrap();
+        assert!(db.get_api_tokens(2).await.unwrap().is_empty());
+        assert!(db.get_api_token_session("first").await.unwrap().is_none());
+        assert_eq!(db.get_api_tokens(1).await.unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_token_scope_order() {
+        // Test case: Verify that wider scopes compare greater
+        assert!(TokenScope::Read < TokenScope::Progress);
+        assert!(TokenScope::Progress < TokenScope::Admin);
+    }
+}
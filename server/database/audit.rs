@@ -0,0 +1,166 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use time::OffsetDateTime;
+
+use super::Database;
+
+/// Kind of an audit log entry.
+#[derive(sqlx::Type, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[sqlx(rename_all = "snake_case")]
+#[serde(rename_all = "camelCase")]
+pub enum AuditEvent {
+    /// Wrong username or password.
+    LoginFailed,
+    /// Too many failed logins, further attempts are rejected for a while.
+    LoginLocked,
+    /// Wrong two-factor code after a correct password.
+    TwoFactorFailed,
+    /// Two-factor authentication was enabled.
+    TwoFactorEnabled,
+    /// Two-factor authentication was disabled by the user or reset by a user manager.
+    TwoFactorDisabled,
+}
+
+/// Entry of the audit log.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditEntry {
+    pub id: i64,
+    pub event: AuditEvent,
+    pub user_id: Option<i64>,
+    pub username: Option<String>,
+    pub ip: Option<String>,
+
+    #[serde(with = "time::serde::iso8601")]
+    pub created: OffsetDateTime,
+}
+
+impl Database {
+    // Add an entry to the audit log.
+    pub async fn add_audit_entry(
+        &self,
+        event: AuditEvent,
+        user_id: Option<i64>,
+        username: Option<&str>,
+        ip: Option<&str>,
+    ) -> Result<()> {
+        sqlx::query!(
+            r#"
+                INSERT INTO audit_log (event, user_id, username, ip)
+                VALUES (?, ?, ?, ?)
+            "#,
+            event,
+            user_id,
+            username,
+            ip
+        )
+        .execute(&self.pool)
+        .await
+        .context("Unable to add audit log entry")
+        .map(|_| ())
+    }
+
+    // Get the latest entries of the audit log.
+    pub async fn get_audit_log(&self, limit: i64) -> Result<Vec<AuditEntry>> {
+        sqlx::query_as!(
+            AuditEntry,
+            r#"
+                SELECT
+                    id,
+                    event AS "event: AuditEvent",
+                    user_id,
+                    username,
+                    ip,
+                    created
+                FROM audit_log
+                ORDER BY id DESC
+                LIMIT ?
+            "#,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await
+        .context("Unable to get audit log")
+    }
+
+    // Delete entries created before `created_before` and all but the newest `keep` entries,
+    // returns the number of deleted entries.
+    pub async fn delete_old_audit_entries(
+        &self,
+        created_before: OffsetDateTime,
+        keep: i64,
+    ) -> Result<u64> {
+        sqlx::query!(
+            r#"
+                DELETE FROM audit_log
+                WHERE datetime(created) < datetime(?)
+                    OR id <= (
+                        SELECT id
+                        FROM audit_log
+                        ORDER BY id DESC
+                        LIMIT 1 OFFSET ?
+                    )
+            "#,
+            created_before,
+            keep
+        )
+        .execute(&self.pool)
+        .await
+        .context("Unable to delete old audit log entries")
+        .map(|result| result.rows_affected())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[sqlx::test]
+    async fn test_audit_log(pool: sqlx::Pool<sqlx::Sqlite>) {
+        // Test case: Verify that entries are returned newest first and limited
+        let db = Database::new_test(pool);
+
+        db.add_audit_entry(
+            AuditEvent::LoginFailed,
+            None,
+            Some("nobody"),
+            Some("10.0.0.1"),
+        )
+        .await
+        .unwrap();
+        db.add_audit_entry(AuditEvent::LoginLocked, Some(1), Some("admin"), None)
+            .await
+            .unwrap();
+
+        let entries = db.get_audit_log(10).await.unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].event, AuditEvent::LoginLocked);
+        assert_eq!(entries[0].user_id, Some(1));
+        assert_eq!(entries[1].event, AuditEvent::LoginFailed);
+        assert_eq!(entries[1].ip.as_deref(), Some("10.0.0.1"));
+
+        assert_eq!(db.get_audit_log(1).await.unwrap().len(), 1);
+    }
+
+    #[sqlx::test]
+    async fn test_delete_old_audit_entries(pool: sqlx::Pool<sqlx::Sqlite>) {
+        // Test case: Verify that expired entries and entries beyond the limit are deleted
+        let db = Database::new_test(pool);
+        for _ in 0..5 {
+            db.add_audit_entry(AuditEvent::LoginFailed, None, Some("nobody"), None)
+                .await
+                .unwrap();
+        }
+        let now = OffsetDateTime::now_utc();
+
+        let long_ago = now - time::Duration::days(1);
+        assert_eq!(db.delete_old_audit_entries(long_ago, 10).await.unwrap(), 0);
+        assert_eq!(db.delete_old_audit_entries(long_ago, 3).await.unwrap(), 2);
+        let entries = db.get_audit_log(10).await.unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[2].id, 3);
+
+        let later = now + time::Duration::days(1);
+        assert_eq!(db.delete_old_audit_entries(later, 10).await.unwrap(), 3);
+    }
+}
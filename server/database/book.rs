@@ -15,6 +15,8 @@ pub struct Book {
     pub author: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub duration: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cover_color: Option<String>,
 
     #[serde(with = "time::serde::iso8601")]
     pub created: OffsetDateTime,
@@ -22,6 +24,13 @@ pub struct Book {
     pub modified: OffsetDateTime,
 }
 
+/// Author with the number of their books.
+pub struct Author {
+    pub name: String,
+    pub books: i64,
+    pub modified: OffsetDateTime,
+}
+
 impl Database {
     // Get all books.
     pub async fn get_all_books(&self) -> Result<Vec<Book>> {
@@ -33,6 +42,7 @@ impl Database {
                     id,
                     title,
                     author,
+                    cover_color,
                     created,
                     modified,
                     NULL AS "duration: f64"
@@ -45,6 +55,73 @@ impl Database {
         .context("Unable to get all books")
     }
 
+    // Get the most recently added books.
+    pub async fn get_recent_books(&self, limit: i64) -> Result<Vec<Book>> {
+        sqlx::query_as!(
+            Book,
+            r#"
+                SELECT
+                    id,
+                    title,
+                    author,
+                    cover_color,
+                    created,
+                    modified,
+                    NULL AS "duration: f64"
+                FROM books
+                ORDER BY created DESC, id DESC
+                LIMIT ?
+            "#,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await
+        .context("Unable to get recent books")
+    }
+
+    // Get all authors with the number of their books.
+    pub async fn get_authors(&self) -> Result<Vec<Author>> {
+        sqlx::query_as!(
+            Author,
+            r#"
+                SELECT
+                    author AS "name!",
+                    COUNT(*) AS books,
+                    MAX(modified) AS "modified!: OffsetDateTime"
+                FROM books
+                GROUP BY author
+                ORDER BY author ASC
+            "#
+        )
+        .fetch_all(&self.pool)
+        .await
+        .context("Unable to get authors")
+    }
+
+    // Get all books of an author.
+    pub async fn get_books_by_author(&self, author: &str) -> Result<Vec<Book>> {
+        sqlx::query_as!(
+            Book,
+            r#"
+                SELECT
+                    id,
+                    title,
+                    author,
+                    cover_color,
+                    created,
+                    modified,
+                    NULL AS "duration: f64"
+                FROM books
+                WHERE author = ?
+                ORDER BY title ASC
+            "#,
+            author
+        )
+        .fetch_all(&self.pool)
+        .await
+        .context("Unable to get books by author")
+    }
+
     // Get book details.
     pub async fn get_book_details(&self, book_id: i64) -> Result<Option<Book>> {
         sqlx::query_as!(
@@ -54,6 +131,7 @@ impl Database {
                     id,
                     title,
                     author,
+                    cover_color,
                     created,
                     modified,
                     (
@@ -71,12 +149,61 @@ impl Database {
         .context("Unable to get book details")
     }
 
+    // Mark a book as modified.
+    pub async fn touch_book(&self, book_id: i64) -> Result<()> {
+        sqlx::query!(
+            r#"
+                UPDATE books
+                SET modified = CURRENT_TIMESTAMP
+                WHERE id = ?
+            "#,
+            book_id
+        )
+        .execute(&self.pool)
+        .await
+        .context("Unable to update book")
+        .map(|_| ())
+    }
+
+    // Get ids of books that have a front cover but no cover color.
+    pub async fn get_books_without_cover_color(&self) -> Result<Vec<i64>> {
+        sqlx::query_scalar!(
+            r#"
+                SELECT books.id
+                FROM books
+                JOIN book_images ON book_images.book_id = books.id AND book_images.kind = 'front'
+                WHERE books.cover_color IS NULL
+            "#
+        )
+        .fetch_all(&self.pool)
+        .await
+        .context("Unable to get books without cover color")
+    }
+
+    // Set the cover color of a book.
+    pub async fn set_cover_color(&self, book_id: i64, color: Option<&str>) -> Result<()> {
+        sqlx::query!(
+            r#"
+                UPDATE books
+                SET cover_color = ?
+                WHERE id = ?
+            "#,
+            color,
+            book_id
+        )
+        .execute(&self.pool)
+        .await
+        .context("Unable to update cover color")
+        .map(|_| ())
+    }
+
     // Create new book.
     pub async fn create_book(
         &self,
         title: &str,
         author: &str,
-        cover: Option<&Vec<u8>>,
+        cover: Option<&str>,
+        cover_color: Option<&str>,
         file_data: &[FileData],
         chapters: Option<&Vec<Chapters>>,
     ) -> Result<i64> {
@@ -89,19 +216,34 @@ impl Database {
         // Insert book.
         let book_id = sqlx::query!(
             r#"
-                INSERT INTO books (title, author, cover)
+                INSERT INTO books (title, author, cover_color)
                 VALUES (?, ?, ?)
                 RETURNING id
             "#,
             title,
             author,
-            cover,
+            cover_color,
         )
         .fetch_one(&mut *trx)
         .await
         .context("Failed to insert book into database")?
         .id;
 
+        // Reference the cover in the image store.
+        if let Some(cover) = cover {
+            sqlx::query!(
+                r#"
+                    INSERT INTO book_images (book_id, kind, hash)
+                    VALUES (?, 'front', ?)
+                "#,
+                book_id,
+                cover,
+            )
+            .execute(&mut *trx)
+            .await
+            .context("Failed to insert cover into database")?;
+        }
+
         // Insert files into the database.
         for (position, file) in file_data.iter().enumerate() {
             let position = position as i64 + 1;
@@ -146,3 +288,57 @@ impl Database {
         Ok(book_id)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::image::ImageKind;
+
+    #[sqlx::test(fixtures("book"))]
+    async fn test_set_cover_color(pool: sqlx::Pool<sqlx::Sqlite>) {
+        // Test case: Verify that set_cover_color stores the color and removes the book from the backfill list
+        let db = Database::new_test(pool);
+        db.set_book_image(15, ImageKind::Front, &"a".repeat(64))
+            .await
+            .unwrap();
+
+        let before = db.get_books_without_cover_color().await.unwrap();
+        assert!(before.contains(&15));
+
+        db.set_cover_color(15, Some("#102030")).await.unwrap();
+
+        let after = db.get_books_without_cover_color().await.unwrap();
+        assert!(!after.contains(&15));
+
+        let book = db.get_book_details(15).await.unwrap().unwrap();
+        assert_eq!(book.cover_color.as_deref(), Some("#102030"));
+    }
+
+    #[sqlx::test(fixtures("user", "book", "multi_file_book"))]
+    async fn test_get_authors(pool: sqlx::Pool<sqlx::Sqlite>) {
+        // Test case: Verify that get_authors groups books by author
+        let db = Database::new_test(pool);
+
+        let authors = db.get_authors().await.unwrap();
+        let names: Vec<_> = authors.iter().map(|author| author.name.as_str()).collect();
+        assert_eq!(names, vec!["Daniel B. Greene", "Stephen King"]);
+        assert!(authors.iter().all(|author| author.books == 1));
+
+        let books = db.get_books_by_author("Stephen King").await.unwrap();
+        assert_eq!(books.len(), 1);
+        assert_eq!(books[0].id, 20);
+    }
+
+    #[sqlx::test(fixtures("user", "book", "multi_file_book"))]
+    async fn test_get_recent_books(pool: sqlx::Pool<sqlx::Sqlite>) {
+        // Test case: Verify that get_recent_books returns the newest books first
+        let db = Database::new_test(pool);
+
+        let books = db.get_recent_books(10).await.unwrap();
+        let ids: Vec<_> = books.iter().map(|book| book.id).collect();
+        assert_eq!(ids, vec![20, 15]);
+
+        let books = db.get_recent_books(1).await.unwrap();
+        assert_eq!(books.len(), 1);
+    }
+}
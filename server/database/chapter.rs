@@ -2,6 +2,7 @@ use anyhow::{Context, Result};
 use serde::Serialize;
 
 use super::Database;
+use crate::media::ffmpeg::Chapters;
 
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
@@ -16,7 +17,37 @@ pub struct Chapter {
     pub end: f64,
 }
 
+impl From<Chapter> for Chapters {
+    fn from(chapter: Chapter) -> Self {
+        Chapters {
+            name: chapter.name,
+            start: chapter.start,
+            end: chapter.end,
+        }
+    }
+}
+
 impl Database {
+    pub async fn get_chapter(&self, chapter_id: i64) -> Result<Option<Chapter>> {
+        sqlx::query_as!(
+            Chapter,
+            r#"
+                SELECT
+                    id,
+                    book_id,
+                    name,
+                    start,
+                    end
+                FROM chapters
+                WHERE id = ?
+            "#,
+            chapter_id
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .context("Unable to get chapter")
+    }
+
     pub async fn get_chapters_for_book(&self, book_id: i64) -> Result<Vec<Chapter>> {
         sqlx::query_as!(
             Chapter,
@@ -80,4 +111,16 @@ mod tests {
 
         assert_eq!(chapters.len(), 0);
     }
+
+    #[sqlx::test(fixtures("book"))]
+    async fn test_get_chapter(pool: sqlx::Pool<sqlx::Sqlite>) {
+        // Test case: Verify that get_chapter fetches a single chapter by id
+        let db = Database::new_test(pool);
+
+        let chapter = db.get_chapter(1175).await.unwrap().unwrap();
+        assert_eq!(chapter.book_id, 15);
+        assert_eq!(chapter.name, "Chapter 1: Deserved");
+
+        assert!(db.get_chapter(999_999).await.unwrap().is_none());
+    }
 }
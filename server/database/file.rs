@@ -1,4 +1,5 @@
 use super::Database;
+use crate::media::ffmpeg::Chapters;
 use anyhow::{Context, Result};
 use serde::Serialize;
 use time::OffsetDateTime;
@@ -16,6 +17,11 @@ pub struct File {
     pub position: i64,
     pub duration: f64,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub loudness: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gain: Option<f64>,
+
     #[serde(with = "time::serde::iso8601")]
     pub created: OffsetDateTime,
     #[serde(with = "time::serde::iso8601")]
@@ -41,6 +47,8 @@ impl Database {
                     name,
                     position,
                     duration,
+                    loudness,
+                    gain,
                     created,
                     modified
                 FROM files
@@ -54,6 +62,32 @@ impl Database {
         .context("Unable to get files for book")
     }
 
+    // Get a single file.
+    pub async fn get_file(&self, file_id: i64) -> Result<Option<File>> {
+        sqlx::query_as!(
+            File,
+            r#"
+                SELECT
+                    id,
+                    book_id,
+                    path,
+                    name,
+                    position,
+                    duration,
+                    loudness,
+                    gain,
+                    created,
+                    modified
+                FROM files
+                WHERE id = ?
+            "#,
+            file_id
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .context("Unable to get file")
+    }
+
     // Get path of file.
     pub async fn get_file_path(&self, file_id: &str) -> Result<Option<String>> {
         sqlx::query!("SELECT path FROM files WHERE id = $1", file_id)
@@ -63,7 +97,8 @@ impl Database {
             .map(|result| result.map(|result| result.path))
     }
 
-    // Get cover of book.
+    // Get cover BLOB of book.
+    // Covers live in the image store now, this is only read to move old covers there.
     pub async fn get_book_cover(&self, book_id: i64) -> Result<Option<Vec<u8>>> {
         sqlx::query!(
             r#"
@@ -80,6 +115,206 @@ impl Database {
     }
 }
 
+impl Database {
+    // Update duration of a file.
+    pub async fn update_file_duration(&self, file_id: i64, duration: f64) -> Result<()> {
+        sqlx::query!(
+            r#"
+                UPDATE files
+                SET duration = ?,
+                    modified = CURRENT_TIMESTAMP
+                WHERE id = ?
+            "#,
+            duration,
+            file_id,
+        )
+        .execute(&self.pool)
+        .await
+        .context("Unable to update file duration")
+        .map(|_| ())
+    }
+
+    // Get files that have not been analyzed for loudness yet.
+    pub async fn get_files_without_loudness(&self) -> Result<Vec<File>> {
+        sqlx::query_as!(
+            File,
+            r#"
+                SELECT
+                    id,
+                    book_id,
+                    path,
+                    name,
+                    position,
+                    duration,
+                    loudness,
+                    gain,
+                    created,
+                    modified
+                FROM files
+                WHERE loudness IS NULL
+                ORDER BY book_id ASC, position ASC
+            "#
+        )
+        .fetch_all(&self.pool)
+        .await
+        .context("Unable to get files without loudness")
+    }
+
+    // Store loudness analysis of a file.
+    pub async fn update_file_loudness(&self, file_id: i64, loudness: f64, gain: f64) -> Result<()> {
+        sqlx::query!(
+            r#"
+                UPDATE files
+                SET loudness = ?,
+                    gain = ?,
+                    modified = CURRENT_TIMESTAMP
+                WHERE id = ?
+            "#,
+            loudness,
+            gain,
+            file_id,
+        )
+        .execute(&self.pool)
+        .await
+        .context("Unable to update file loudness")
+        .map(|_| ())
+    }
+
+    // Replace all files of a book with a single file.
+    // Progress of every user is moved onto the new file and the chapters are replaced.
+    pub async fn replace_book_files(
+        &self,
+        book_id: i64,
+        file: &FileData,
+        chapters: &[Chapters],
+    ) -> Result<i64> {
+        let mut trx = self
+            .pool
+            .begin()
+            .await
+            .context("Failed to start transaction")?;
+
+        // Insert the new file.
+        let file_id = sqlx::query!(
+            r#"
+                INSERT INTO files (book_id, path, name, position, duration)
+                VALUES (?, ?, ?, 1, ?)
+                RETURNING id
+            "#,
+            book_id,
+            file.path,
+            file.name,
+            file.duration,
+        )
+        .fetch_one(&mut *trx)
+        .await
+        .context("Failed to insert file into database")?
+        .id;
+
+        // Translate progress into an offset on the new file.
+        // The new offset is the duration of all files before the current one plus the progress.
+        sqlx::query!(
+            r#"
+                UPDATE library_entries
+                SET progress = progress + COALESCE((
+                        SELECT SUM(previous.duration)
+                        FROM files previous
+                        JOIN files current ON current.id = library_entries.file_id
+                        WHERE previous.book_id = current.book_id
+                        AND previous.position < current.position
+                        AND previous.id != $2
+                    ), 0),
+                    file_id = $2
+                WHERE book_id = $1
+                AND file_id != $2
+            "#,
+            book_id,
+            file_id,
+        )
+        .execute(&mut *trx)
+        .await
+        .context("Failed to translate library progress")?;
+
+        // Remove the old files.
+        sqlx::query!(
+            r#"
+                DELETE FROM files
+                WHERE book_id = ?
+                AND id != ?
+            "#,
+            book_id,
+            file_id,
+        )
+        .execute(&mut *trx)
+        .await
+        .context("Failed to delete old files")?;
+
+        // Replace chapters.
+        sqlx::query!(
+            r#"
+                DELETE FROM chapters
+                WHERE book_id = ?
+            "#,
+            book_id,
+        )
+        .execute(&mut *trx)
+        .await
+        .context("Failed to delete old chapters")?;
+
+        for chapter in chapters {
+            sqlx::query!(
+                r#"
+                    INSERT INTO chapters (book_id, name, start, end)
+                    VALUES (?, ?, ?, ?)
+                "#,
+                book_id,
+                chapter.name,
+                chapter.start,
+                chapter.end,
+            )
+            .execute(&mut *trx)
+            .await
+            .context("Failed to insert chapter into database")?;
+        }
+
+        // Touch the book, so anything derived from its files is recreated.
+        sqlx::query!(
+            r#"
+                UPDATE books
+                SET modified = CURRENT_TIMESTAMP
+                WHERE id = ?
+            "#,
+            book_id,
+        )
+        .execute(&mut *trx)
+        .await
+        .context("Failed to update book")?;
+
+        trx.commit().await.context("Failed to commit transaction")?;
+
+        Ok(file_id)
+    }
+}
+
+#[cfg(test)]
+impl File {
+    /// A file of book 1 at `position`, for tests that don't need a database.
+    pub fn test(position: i64, name: &str, duration: f64) -> Self {
+        Self {
+            id: position - 1,
+            book_id: 1,
+            path: format!("/{name}.mp3"),
+            name: name.to_string(),
+            position,
+            duration,
+            loudness: None,
+            gain: None,
+            created: OffsetDateTime::UNIX_EPOCH,
+            modified: OffsetDateTime::UNIX_EPOCH,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -115,6 +350,18 @@ mod tests {
         assert_eq!(files.len(), 0);
     }
 
+    #[sqlx::test(fixtures("book"))]
+    async fn test_get_file(pool: sqlx::Pool<sqlx::Sqlite>) {
+        // Test case: Verify that get_file fetches a single file by id
+        let db = Database::new_test(pool);
+
+        let file = db.get_file(336).await.unwrap().unwrap();
+        assert_eq!(file.book_id, 15);
+        assert_eq!(file.name, "A Witch's Sin");
+
+        assert!(db.get_file(999).await.unwrap().is_none());
+    }
+
     #[sqlx::test(fixtures("book"))]
     async fn test_get_file_path(pool: sqlx::Pool<sqlx::Sqlite>) {
         // Test case: Verify that get_file_path fetches the file path correctly
@@ -170,4 +417,113 @@ mod tests {
 
         assert!(cover.is_none());
     }
+
+    #[sqlx::test(fixtures("book"))]
+    async fn test_update_file_duration(pool: sqlx::Pool<sqlx::Sqlite>) {
+        // Test case: Verify that update_file_duration stores the new duration
+        let db = Database::new_test(pool);
+
+        db.update_file_duration(336, 42.5)
+            .await
+            .expect("Should be able to update file duration");
+
+        let files = db
+            .get_files_for_book(15)
+            .await
+            .expect("Should be able to get files");
+        assert_eq!(files[0].duration, 42.5);
+    }
+
+    #[sqlx::test(fixtures("user", "book", "multi_file_book"))]
+    async fn test_update_file_loudness(pool: sqlx::Pool<sqlx::Sqlite>) {
+        // Test case: Verify that analyzed files are no longer returned by get_files_without_loudness
+        let db = Database::new_test(pool);
+
+        let files = db
+            .get_files_without_loudness()
+            .await
+            .expect("Should be able to get files");
+        assert_eq!(files.len(), 4);
+
+        db.update_file_loudness(336, -20.5, 2.5)
+            .await
+            .expect("Should be able to update loudness");
+
+        let files = db
+            .get_files_without_loudness()
+            .await
+            .expect("Should be able to get files");
+        assert_eq!(files.len(), 3);
+        assert!(files.iter().all(|file| file.id != 336));
+
+        let files = db
+            .get_files_for_book(15)
+            .await
+            .expect("Should be able to get files");
+        assert_eq!(files[0].loudness, Some(-20.5));
+        assert_eq!(files[0].gain, Some(2.5));
+    }
+
+    #[sqlx::test(fixtures("user", "multi_file_book"))]
+    async fn test_replace_book_files(pool: sqlx::Pool<sqlx::Sqlite>) {
+        // Test case: Verify that replace_book_files swaps the files and translates progress
+        let db = Database::new_test(pool);
+
+        let file = FileData {
+            path: "/media/Stephen King - The Long Walk/The Long Walk.m4b".to_string(),
+            name: "The Long Walk".to_string(),
+            duration: 600.0,
+        };
+        let chapters = vec![
+            Chapters {
+                name: "01".to_string(),
+                start: 0.0,
+                end: 100.0,
+            },
+            Chapters {
+                name: "02".to_string(),
+                start: 100.0,
+                end: 300.0,
+            },
+        ];
+
+        let file_id = db
+            .replace_book_files(20, &file, &chapters)
+            .await
+            .expect("Should be able to replace files");
+
+        let files = db
+            .get_files_for_book(20)
+            .await
+            .expect("Should be able to get files");
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].id, file_id);
+        assert_eq!(files[0].path, file.path);
+        assert_eq!(files[0].position, 1);
+
+        // Progress in the third file is moved behind the first two files.
+        let entry = db
+            .get_library_entry(1, 20)
+            .await
+            .expect("Should be able to get library entry")
+            .expect("Library entry should exist");
+        assert_eq!(entry.file_id, file_id);
+        assert_eq!(entry.progress, 350.0);
+
+        // Progress in the first file stays the same.
+        let entry = db
+            .get_library_entry(2, 20)
+            .await
+            .expect("Should be able to get library entry")
+            .expect("Library entry should exist");
+        assert_eq!(entry.file_id, file_id);
+        assert_eq!(entry.progress, 10.0);
+
+        let chapters = db
+            .get_chapters_for_book(20)
+            .await
+            .expect("Should be able to get chapters");
+        assert_eq!(chapters.len(), 2);
+        assert_eq!(chapters[1].name, "02");
+    }
 }
@@ -1,5 +1,5 @@
-INSERT INTO books VALUES(15,'A Witch''s Sin','Daniel B. Greene',NULL,'2024-02-13 07:22:43','2024-02-13 07:22:43');
-INSERT INTO files VALUES(336,15,'/media/Daniel B. Greene - A Witch''s Sin/A Witch''s Sin.m4b','A Witch''s Sin',1,59252.703332999997654,'2024-02-13 07:22:43','2024-02-13 07:22:43');
+INSERT INTO books (id, title, author, cover, created, modified) VALUES(15,'A Witch''s Sin','Daniel B. Greene',NULL,'2024-02-13 07:22:43','2024-02-13 07:22:43');
+INSERT INTO files (id, book_id, path, name, position, duration, created, modified) VALUES(336,15,'/media/Daniel B. Greene - A Witch''s Sin/A Witch''s Sin.m4b','A Witch''s Sin',1,59252.703332999997654,'2024-02-13 07:22:43','2024-02-13 07:22:43');
 INSERT INTO chapters VALUES(1174,15,'Opening Credits',0.0,18.506000000000000227);
 INSERT INTO chapters VALUES(1175,15,'Chapter 1: Deserved',18.506000000000000227,2189.2689999999997781);
 INSERT INTO chapters VALUES(1176,15,'Chapter 2: Can You Afford It?',2189.2689999999997781,5570.3029999999998833);
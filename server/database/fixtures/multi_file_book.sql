@@ -0,0 +1,7 @@
+-- A book split into one file per chapter, listened to by both users.
+INSERT INTO books (id, title, author, cover, created, modified) VALUES(20,'The Long Walk','Stephen King',NULL,'2024-03-01 10:00:00','2024-03-01 10:00:00');
+INSERT INTO files (id, book_id, path, name, position, duration, created, modified) VALUES(500,20,'/media/Stephen King - The Long Walk/01.mp3','01',1,100.0,'2024-03-01 10:00:00','2024-03-01 10:00:00');
+INSERT INTO files (id, book_id, path, name, position, duration, created, modified) VALUES(501,20,'/media/Stephen King - The Long Walk/02.mp3','02',2,200.0,'2024-03-01 10:00:00','2024-03-01 10:00:00');
+INSERT INTO files (id, book_id, path, name, position, duration, created, modified) VALUES(502,20,'/media/Stephen King - The Long Walk/03.mp3','03',3,300.0,'2024-03-01 10:00:00','2024-03-01 10:00:00');
+INSERT INTO library_entries VALUES(1,1,20,502,50.0,'listening','2024-03-02 10:00:00','2024-03-02 10:00:00');
+INSERT INTO library_entries VALUES(2,2,20,500,10.0,'listening','2024-03-02 10:00:00','2024-03-02 10:00:00');
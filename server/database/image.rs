@@ -0,0 +1,251 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use super::Database;
+
+/// Kinds of images a book can have.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageKind {
+    Front,
+    Back,
+    Author,
+}
+
+impl ImageKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ImageKind::Front => "front",
+            ImageKind::Back => "back",
+            ImageKind::Author => "author",
+        }
+    }
+}
+
+/// Image attached to a book.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BookImage {
+    pub kind: String,
+    pub hash: String,
+}
+
+impl Database {
+    // Get the hash of an image of a book.
+    pub async fn get_book_image(&self, book_id: i64, kind: ImageKind) -> Result<Option<String>> {
+        let kind = kind.as_str();
+        sqlx::query_scalar!(
+            r#"
+                SELECT hash
+                FROM book_images
+                WHERE book_id = ? AND kind = ?
+            "#,
+            book_id,
+            kind
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .context("Unable to get book image")
+    }
+
+    // Get all images of a book.
+    pub async fn get_book_images(&self, book_id: i64) -> Result<Vec<BookImage>> {
+        sqlx::query_as!(
+            BookImage,
+            r#"
+                SELECT kind, hash
+                FROM book_images
+                WHERE book_id = ?
+                ORDER BY kind ASC
+            "#,
+            book_id
+        )
+        .fetch_all(&self.pool)
+        .await
+        .context("Unable to get book images")
+    }
+
+    // Set an image of a book, returns the hash of the replaced image.
+    pub async fn set_book_image(
+        &self,
+        book_id: i64,
+        kind: ImageKind,
+        hash: &str,
+    ) -> Result<Option<String>> {
+        let previous = self.get_book_image(book_id, kind).await?;
+
+        let kind = kind.as_str();
+        sqlx::query!(
+            r#"
+                INSERT OR REPLACE INTO book_images (book_id, kind, hash)
+                VALUES (?, ?, ?)
+            "#,
+            book_id,
+            kind,
+            hash
+        )
+        .execute(&self.pool)
+        .await
+        .context("Unable to set book image")?;
+
+        Ok(previous.filter(|previous| previous != hash))
+    }
+
+    // Remove an image from a book, returns the hash of the removed image.
+    pub async fn delete_book_image(&self, book_id: i64, kind: ImageKind) -> Result<Option<String>> {
+        let kind = kind.as_str();
+        sqlx::query_scalar!(
+            r#"
+                DELETE FROM book_images
+                WHERE book_id = ? AND kind = ?
+                RETURNING hash
+            "#,
+            book_id,
+            kind
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .context("Unable to delete book image")
+    }
+
+    // Check if any book still references an image.
+    pub async fn is_image_referenced(&self, hash: &str) -> Result<bool> {
+        sqlx::query_scalar!(
+            r#"
+                SELECT EXISTS(SELECT 1 FROM book_images WHERE hash = ?) AS "referenced: bool"
+            "#,
+            hash
+        )
+        .fetch_one(&self.pool)
+        .await
+        .context("Unable to check image references")
+    }
+
+    // Get ids of books that still store their cover in the database.
+    pub async fn get_books_with_cover_blob(&self) -> Result<Vec<i64>> {
+        sqlx::query_scalar!(
+            r#"
+                SELECT id
+                FROM books
+                WHERE cover IS NOT NULL
+            "#
+        )
+        .fetch_all(&self.pool)
+        .await
+        .context("Unable to get books with cover blob")
+    }
+
+    // Replace the cover BLOB of a book with a reference into the image store.
+    pub async fn move_cover_blob(&self, book_id: i64, hash: &str) -> Result<()> {
+        let mut trx = self
+            .pool
+            .begin()
+            .await
+            .context("Failed to start transaction")?;
+
+        // Keep a front cover that was set in the meantime.
+        sqlx::query!(
+            r#"
+                INSERT OR IGNORE INTO book_images (book_id, kind, hash)
+                VALUES (?, 'front', ?)
+            "#,
+            book_id,
+            hash
+        )
+        .execute(&mut *trx)
+        .await
+        .context("Failed to insert book image")?;
+
+        sqlx::query!(
+            r#"
+                UPDATE books
+                SET cover = NULL
+                WHERE id = ?
+            "#,
+            book_id
+        )
+        .execute(&mut *trx)
+        .await
+        .context("Failed to remove cover blob")?;
+
+        trx.commit().await.context("Failed to commit transaction")
+    }
+
+    // Rebuild the database file to release unused pages.
+    pub async fn vacuum(&self) -> Result<()> {
+        sqlx::query("VACUUM")
+            .execute(&self.pool)
+            .await
+            .context("Unable to vacuum database")
+            .map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(c: char) -> String {
+        c.to_string().repeat(64)
+    }
+
+    #[sqlx::test(fixtures("book"))]
+    async fn test_set_book_image(pool: sqlx::Pool<sqlx::Sqlite>) {
+        // Test case: Verify that set_book_image stores the hash and returns the replaced one
+        let db = Database::new_test(pool);
+
+        let previous = db
+            .set_book_image(15, ImageKind::Front, &hash('a'))
+            .await
+            .unwrap();
+        assert_eq!(previous, None);
+
+        let previous = db
+            .set_book_image(15, ImageKind::Front, &hash('b'))
+            .await
+            .unwrap();
+        assert_eq!(previous, Some(hash('a')));
+
+        let image = db.get_book_image(15, ImageKind::Front).await.unwrap();
+        assert_eq!(image, Some(hash('b')));
+        let image = db.get_book_image(15, ImageKind::Back).await.unwrap();
+        assert_eq!(image, None);
+    }
+
+    #[sqlx::test(fixtures("book"))]
+    async fn test_delete_book_image(pool: sqlx::Pool<sqlx::Sqlite>) {
+        // Test case: Verify that delete_book_image removes the image and returns its hash
+        let db = Database::new_test(pool);
+        db.set_book_image(15, ImageKind::Author, &hash('a'))
+            .await
+            .unwrap();
+        db.set_book_image(15, ImageKind::Back, &hash('a'))
+            .await
+            .unwrap();
+
+        let removed = db.delete_book_image(15, ImageKind::Author).await.unwrap();
+        assert_eq!(removed, Some(hash('a')));
+        assert!(db.is_image_referenced(&hash('a')).await.unwrap());
+
+        db.delete_book_image(15, ImageKind::Back).await.unwrap();
+        assert!(!db.is_image_referenced(&hash('a')).await.unwrap());
+        assert!(db.get_book_images(15).await.unwrap().is_empty());
+    }
+
+    #[sqlx::test(fixtures("book"))]
+    async fn test_move_cover_blob(pool: sqlx::Pool<sqlx::Sqlite>) {
+        // Test case: Verify that move_cover_blob references the image and clears the BLOB
+        let db = Database::new_test(pool);
+        sqlx::query!("UPDATE books SET cover = x'00' WHERE id = 15")
+            .execute(&db.pool)
+            .await
+            .unwrap();
+        assert_eq!(db.get_books_with_cover_blob().await.unwrap(), vec![15]);
+
+        db.move_cover_blob(15, &hash('c')).await.unwrap();
+
+        assert!(db.get_books_with_cover_blob().await.unwrap().is_empty());
+        let image = db.get_book_image(15, ImageKind::Front).await.unwrap();
+        assert_eq!(image, Some(hash('c')));
+    }
+}
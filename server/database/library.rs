@@ -1,4 +1,4 @@
-use super::Database;
+use super::{Database, book::Book};
 
 use anyhow::{Context, Result};
 use serde::Serialize;
@@ -65,6 +65,38 @@ impl Database {
         .context("Unable to get library entry")
     }
 
+    // Get the library entry of the list the user changed most recently.
+    pub async fn get_latest_library_entry(
+        &self,
+        user_id: i64,
+        list: &str,
+    ) -> Result<Option<LibraryEntry>> {
+        sqlx::query_as!(
+            LibraryEntry,
+            r#"
+                SELECT
+                    id,
+                    user_id,
+                    book_id,
+                    file_id,
+                    progress,
+                    list,
+                    created,
+                    modified
+                FROM library_entries
+                WHERE user_id = ?
+                AND list = ?
+                ORDER BY modified DESC, id DESC
+                LIMIT 1
+            "#,
+            user_id,
+            list
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .context("Unable to get latest library entry")
+    }
+
     /// Updates a users progress.
     pub async fn update_progress(
         &self,
@@ -140,6 +172,32 @@ impl Database {
         .map(|_| ())
     }
 
+    // Get the books in one of the user's library lists.
+    pub async fn get_library_books(&self, user_id: i64, list: &str) -> Result<Vec<Book>> {
+        sqlx::query_as!(
+            Book,
+            r#"
+                SELECT
+                    books.id,
+                    books.title,
+                    books.author,
+                    books.cover_color,
+                    books.created,
+                    books.modified,
+                    NULL AS "duration: f64"
+                FROM books
+                JOIN library_entries ON library_entries.book_id = books.id
+                WHERE library_entries.user_id = ? AND library_entries.list = ?
+                ORDER BY library_entries.modified DESC
+            "#,
+            user_id,
+            list
+        )
+        .fetch_all(&self.pool)
+        .await
+        .context("Unable to get library books")
+    }
+
     // Get user library.
     pub async fn get_user_library(&self, user_id: i64) -> Result<Vec<LibraryResponse>> {
         sqlx::query_as!(
@@ -177,3 +235,41 @@ impl Database {
         .context("Unable to get user library")
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[sqlx::test(fixtures("user", "book", "multi_file_book"))]
+    async fn test_get_library_books(pool: sqlx::Pool<sqlx::Sqlite>) {
+        // Test case: Verify that get_library_books only returns books in the given list
+        let db = Database::new_test(pool);
+
+        let books = db.get_library_books(1, "listening").await.unwrap();
+        assert_eq!(books.len(), 1);
+        assert_eq!(books[0].id, 20);
+
+        let books = db.get_library_books(1, "finished").await.unwrap();
+        assert!(books.is_empty());
+    }
+
+    #[sqlx::test(fixtures("user", "book", "multi_file_book"))]
+    async fn test_get_latest_library_entry(pool: sqlx::Pool<sqlx::Sqlite>) {
+        // Test case: Verify that get_latest_library_entry returns the most recent entry of a list
+        let db = Database::new_test(pool);
+        db.manage_library_entry(1, 15, "listening", None, None)
+            .await
+            .unwrap();
+
+        let entry = db
+            .get_latest_library_entry(1, "listening")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(entry.book_id, 15);
+        assert_eq!(entry.file_id, 336);
+
+        let entry = db.get_latest_library_entry(1, "finished").await.unwrap();
+        assert!(entry.is_none());
+    }
+}
@@ -1,9 +1,16 @@
+pub mod api_token;
+pub mod audit;
 pub mod book;
 pub mod chapter;
 pub mod file;
+pub mod image;
 pub mod library;
+pub mod role;
 pub mod session;
+pub mod setting;
+pub mod two_factor;
 pub mod user;
+pub mod waveform;
 
 use sqlx::Sqlite;
 
@@ -0,0 +1,362 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Deserializer, Serialize, Serializer, ser::SerializeSeq};
+use time::OffsetDateTime;
+
+use super::Database;
+
+/// Role of the admin account created by the migrations, it has every permission and can't be
+/// changed or deleted.
+pub const ADMIN_ROLE: i64 = 1;
+
+/// Role of new users if none is given, allows listening and keeping track of progress.
+pub const DEFAULT_ROLE: i64 = 4;
+
+/// Something a role allows its users to do.
+/// Reading books and the library is allowed for every user.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum Permission {
+    /// Update progress and library lists.
+    Progress = 1,
+    /// Add, edit and delete books, browse the files of the server and run the admin book tools.
+    ManageLibrary = 2,
+    /// Create and edit users and roles, see the sessions and the audit log.
+    ManageUsers = 4,
+}
+
+impl Permission {
+    const ALL: [Permission; 3] = [
+        Permission::Progress,
+        Permission::ManageLibrary,
+        Permission::ManageUsers,
+    ];
+}
+
+/// Set of permissions, stored as bit set in the database and sent as list of names.
+#[derive(sqlx::Type, Clone, Copy, PartialEq, Eq, Default, Debug)]
+#[sqlx(transparent)]
+pub struct Permissions(i64);
+
+impl Permissions {
+    /// Check if a permission is in the set.
+    pub fn has(self, permission: Permission) -> bool {
+        self.0 & permission as i64 != 0
+    }
+
+    /// Check if every permission of `other` is in the set.
+    pub fn contains(self, other: Permissions) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl FromIterator<Permission> for Permissions {
+    fn from_iter<T: IntoIterator<Item = Permission>>(iter: T) -> Self {
+        Self(
+            iter.into_iter()
+                .fold(0, |bits, permission| bits | permission as i64),
+        )
+    }
+}
+
+impl Serialize for Permissions {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let permissions = Permission::ALL
+            .into_iter()
+            .filter(|permission| self.has(*permission));
+
+        let mut seq = serializer.serialize_seq(None)?;
+        for permission in permissions {
+            seq.serialize_element(&permission)?;
+        }
+        seq.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Permissions {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        Ok(Vec::<Permission>::deserialize(deserializer)?
+            .into_iter()
+            .collect())
+    }
+}
+
+/// Role of a user whose admin rights are mapped from a group of an external source like LDAP,
+/// `admin` is `None` if they aren't mapped. Members of the group get the admin role, admins that
+/// left it get the default role and other roles are managed in fela.
+pub fn group_role(role_id: i64, admin: Option<bool>) -> i64 {
+    match admin {
+        Some(true) => ADMIN_ROLE,
+        Some(false) if role_id == ADMIN_ROLE => DEFAULT_ROLE,
+        _ => role_id,
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Role {
+    pub id: i64,
+    pub name: String,
+    pub permissions: Permissions,
+
+    #[serde(with = "time::serde::iso8601")]
+    pub created: OffsetDateTime,
+    #[serde(with = "time::serde::iso8601")]
+    pub modified: OffsetDateTime,
+}
+
+impl Database {
+    // Get all roles.
+    pub async fn get_roles(&self) -> Result<Vec<Role>> {
+        sqlx::query_as!(
+            Role,
+            r#"
+                SELECT
+                    id,
+                    name,
+                    permissions as "permissions: Permissions",
+                    created,
+                    modified
+                FROM roles
+                ORDER BY id
+            "#
+        )
+        .fetch_all(&self.pool)
+        .await
+        .context("Unable to get roles")
+    }
+
+    // Get role by id.
+    pub async fn get_role(&self, role_id: i64) -> Result<Option<Role>> {
+        sqlx::query_as!(
+            Role,
+            r#"
+                SELECT
+                    id,
+                    name,
+                    permissions as "permissions: Permissions",
+                    created,
+                    modified
+                FROM roles
+                WHERE id = ?
+            "#,
+            role_id
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .context("Unable to get role")
+    }
+
+    // Create role and return its id.
+    pub async fn create_role(&self, name: &str, permissions: Permissions) -> Result<i64> {
+        sqlx::query_scalar!(
+            r#"
+                INSERT INTO roles (name, permissions)
+                VALUES (?, ?)
+                RETURNING id
+            "#,
+            name,
+            permissions
+        )
+        .fetch_one(&self.pool)
+        .await
+        .context("Unable to create role")
+    }
+
+    // Update role, returns false if it doesn't exist or the new permissions would leave no user
+    // that can manage users.
+    pub async fn update_role(
+        &self,
+        role_id: i64,
+        name: Option<String>,
+        permissions: Option<Permissions>,
+    ) -> Result<bool> {
+        let manage_users = Permission::ManageUsers as i64;
+        sqlx::query!(
+            r#"
+                UPDATE roles
+                SET
+                    name = COALESCE(?, name),
+                    permissions = COALESCE(?, permissions)
+                WHERE id = ?
+                    AND (
+                        COALESCE(?, permissions) & ? != 0
+                        OR EXISTS (
+                            SELECT 1
+                            FROM users
+                            JOIN roles AS others ON others.id = users.role_id
+                            WHERE others.id != ? AND others.permissions & ? != 0
+                        )
+                    )
+            "#,
+            name,
+            permissions,
+            role_id,
+            permissions,
+            manage_users,
+            role_id,
+            manage_users
+        )
+        .execute(&self.pool)
+        .await
+        .context("Unable to update role")
+        .map(|result| result.rows_affected() > 0)
+    }
+
+    // Delete role, returns false if it doesn't exist.
+    pub async fn delete_role(&self, role_id: i64) -> Result<bool> {
+        sqlx::query!(
+            r#"
+                DELETE FROM roles
+                WHERE id = ?
+            "#,
+            role_id
+        )
+        .execute(&self.pool)
+        .await
+        .context("Unable to delete role")
+        .map(|result| result.rows_affected() > 0)
+    }
+
+    // Count the users of a role.
+    pub async fn count_role_users(&self, role_id: i64) -> Result<i64> {
+        sqlx::query_scalar!(
+            r#"
+                SELECT COUNT(*)
+                FROM users
+                WHERE role_id = ?
+            "#,
+            role_id
+        )
+        .fetch_one(&self.pool)
+        .await
+        .context("Unable to count users of role")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use sqlx::{Pool, Sqlite};
+
+    use super::*;
+
+    #[test]
+    fn test_permissions() {
+        // Test case: Verify that permission sets are compared and sent as list of names
+        let all: Permissions = Permission::ALL.into_iter().collect();
+        let progress: Permissions = [Permission::Progress].into_iter().collect();
+
+        assert!(all.has(Permission::ManageUsers));
+        assert!(!progress.has(Permission::ManageLibrary));
+        assert!(all.contains(progress));
+        assert!(!progress.contains(all));
+        assert!(progress.contains(Permissions::default()));
+
+        assert_eq!(
+            serde_json::to_string(&all).unwrap(),
+            r#"["progress","manageLibrary","manageUsers"]"#
+        );
+        assert_eq!(
+            serde_json::from_str::<Permissions>(r#"["progress","progress"]"#).unwrap(),
+            progress
+        );
+        assert!(serde_json::from_str::<Permissions>(r#"["everything"]"#).is_err());
+    }
+
+    #[test]
+    fn test_group_role() {
+        // Test case: Verify that only the admin role follows the admin group
+        assert_eq!(group_role(DEFAULT_ROLE, Some(true)), ADMIN_ROLE);
+        assert_eq!(group_role(ADMIN_ROLE, Some(false)), DEFAULT_ROLE);
+        assert_eq!(group_role(2, Some(false)), 2);
+        assert_eq!(group_role(ADMIN_ROLE, None), ADMIN_ROLE);
+        assert_eq!(group_role(5, None), 5);
+    }
+
+    #[sqlx::test(fixtures("user"))]
+    async fn test_default_roles(pool: Pool<Sqlite>) {
+        // Test case: Verify that the migration seeds the roles and keeps the admin
+        let db = Database::new_test(pool);
+
+        let roles = db.get_roles().await.unwrap();
+        let names: Vec<_> = roles.iter().map(|role| role.name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "admin",
+                "library_manager",
+                "user_manager",
+                "listener",
+                "guest"
+            ]
+        );
+        assert_eq!(roles[0].permissions, Permission::ALL.into_iter().collect());
+        assert_eq!(roles[4].permissions, Permissions::default());
+
+        assert_eq!(db.get_user(1).await.unwrap().role_id, ADMIN_ROLE);
+        assert_eq!(db.get_user(2).await.unwrap().role_id, DEFAULT_ROLE);
+        assert_eq!(db.count_role_users(ADMIN_ROLE).await.unwrap(), 1);
+    }
+
+    #[sqlx::test]
+    async fn test_create_update_delete_role(pool: Pool<Sqlite>) {
+        // Test case: Verify that roles can be created, changed and deleted
+        let db = Database::new_test(pool);
+        let library: Permissions = [Permission::ManageLibrary].into_iter().collect();
+
+        let id = db.create_role("librarian", library).await.unwrap();
+        let role = db.get_role(id).await.unwrap().unwrap();
+        assert_eq!(role.name, "librarian");
+        assert_eq!(role.permissions, library);
+
+        assert!(
+            db.update_role(id, None, Some(Permissions::default()))
+                .await
+                .unwrap()
+        );
+        let role = db.get_role(id).await.unwrap().unwrap();
+        assert_eq!(role.name, "librarian");
+        assert_eq!(role.permissions, Permissions::default());
+
+        assert!(db.delete_role(id).await.unwrap());
+        assert!(db.get_role(id).await.unwrap().is_none());
+        assert!(!db.delete_role(id).await.unwrap());
+        assert!(
+            !db.update_role(id, Some("x".to_string()), None)
+                .await
+                .unwrap()
+        );
+    }
+
+    #[sqlx::test]
+    async fn test_role_constraints(pool: Pool<Sqlite>) {
+        // Test case: Verify that role names are unique and the last user manager keeps the permission
+        let db = Database::new_test(pool);
+
+        let error = db.create_role("listener", Permissions::default()).await;
+        assert!(matches!(
+            error.unwrap_err().downcast_ref::<sqlx::Error>(),
+            Some(sqlx::Error::Database(error)) if error.is_unique_violation()
+        ));
+        let error = db.update_role(5, Some("listener".to_string()), None).await;
+        assert!(matches!(
+            error.unwrap_err().downcast_ref::<sqlx::Error>(),
+            Some(sqlx::Error::Database(error)) if error.is_unique_violation()
+        ));
+
+        // The seeded admin is the only user, move them to the user manager role.
+        db.update_user(1, None, None, Some(3), None).await.unwrap();
+        let listener: Permissions = [Permission::Progress].into_iter().collect();
+        assert!(!db.update_role(3, None, Some(listener)).await.unwrap());
+        assert!(
+            db.update_role(3, Some("managers".to_string()), None)
+                .await
+                .unwrap()
+        );
+        assert_eq!(
+            db.get_role(3).await.unwrap().unwrap().permissions,
+            [Permission::Progress, Permission::ManageUsers]
+                .into_iter()
+                .collect()
+        );
+    }
+}
@@ -1,4 +1,4 @@
-use super::Database;
+use super::{Database, api_token::TokenScope, role::Permissions};
 use anyhow::{Context, Result};
 use serde::Serialize;
 
@@ -12,7 +12,35 @@ pub struct SessionInfo {
     #[serde(with = "time::serde::iso8601")]
     pub last_accessed: time::OffsetDateTime,
     pub username: String,
-    pub admin: bool,
+    pub role_id: i64,
+    pub permissions: Permissions,
+    pub scope: TokenScope,
+    pub must_change_password: bool,
+}
+
+/// Session of a user as shown in the list of their devices.
+/// The session id itself is a secret, sessions are identified by a random public id instead.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UserSession {
+    pub id: String,
+    #[serde(skip)]
+    pub session_id: String,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    #[serde(with = "time::serde::iso8601")]
+    pub created: time::OffsetDateTime,
+    #[serde(with = "time::serde::iso8601")]
+    pub last_accessed: time::OffsetDateTime,
+}
+
+/// Number of stored sessions, for user managers.
+#[derive(Serialize, PartialEq, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionCounts {
+    pub active: i64,
+    pub expired: i64,
+    pub users: i64,
 }
 
 impl Database {
@@ -26,9 +54,13 @@ impl Database {
                     sessions.user_id,
                     sessions.last_accessed,
                     users.name as username,
-                    users.admin
+                    users.role_id,
+                    roles.permissions AS "permissions: Permissions",
+                    'admin' AS "scope!: TokenScope",
+                    users.must_change_password
                 FROM sessions
                 INNER JOIN users ON sessions.user_id = users.id
+                INNER JOIN roles ON users.role_id = roles.id
                 WHERE sessions.id = $1
             "#,
             session_id,
@@ -38,15 +70,24 @@ impl Database {
         .context("Unable to get session info")
     }
 
-    // Create session.
-    pub async fn create_session(&self, user_id: i64, session_id: &str) -> Result<()> {
+    // Create session, with the user agent and IP address of the client.
+    // The public id shown in the list of sessions is generated by the database.
+    pub async fn create_session(
+        &self,
+        user_id: i64,
+        session_id: &str,
+        user_agent: Option<&str>,
+        ip: Option<&str>,
+    ) -> Result<()> {
         sqlx::query!(
             r#"
-            INSERT INTO sessions (id, user_id)
-            VALUES ($1, $2)
+            INSERT INTO sessions (id, user_id, user_agent, ip, public_id)
+            VALUES ($1, $2, $3, $4, lower(hex(randomblob(16))))
         "#,
             session_id,
-            user_id
+            user_id,
+            user_agent,
+            ip
         )
         .execute(&self.pool)
         .await
@@ -84,11 +125,107 @@ impl Database {
         .context("Unable to delete session")
         .map(|_| ())
     }
+
+    // Delete sessions last accessed before `expired_before`, returns the number of deleted sessions.
+    pub async fn delete_expired_sessions(
+        &self,
+        expired_before: time::OffsetDateTime,
+    ) -> Result<u64> {
+        sqlx::query!(
+            r#"
+                DELETE FROM sessions
+                WHERE datetime(last_accessed) < datetime($1)
+            "#,
+            expired_before,
+        )
+        .execute(&self.pool)
+        .await
+        .context("Unable to delete expired sessions")
+        .map(|result| result.rows_affected())
+    }
+
+    // Count active and expired sessions, and the users with an active session.
+    pub async fn count_sessions(
+        &self,
+        expired_before: time::OffsetDateTime,
+    ) -> Result<SessionCounts> {
+        sqlx::query_as!(
+            SessionCounts,
+            r#"
+                SELECT
+                    COUNT(*) FILTER (WHERE datetime(last_accessed) >= datetime($1)) AS "active!: i64",
+                    COUNT(*) FILTER (WHERE datetime(last_accessed) < datetime($1)) AS "expired!: i64",
+                    COUNT(DISTINCT user_id) FILTER (WHERE datetime(last_accessed) >= datetime($1))
+                        AS "users!: i64"
+                FROM sessions
+            "#,
+            expired_before,
+        )
+        .fetch_one(&self.pool)
+        .await
+        .context("Unable to count sessions")
+    }
+
+    // Get all sessions of a user, most recently used first.
+    pub async fn get_user_sessions(&self, user_id: i64) -> Result<Vec<UserSession>> {
+        sqlx::query_as!(
+            UserSession,
+            r#"
+                SELECT
+                    public_id AS id,
+                    id AS session_id,
+                    user_agent,
+                    ip,
+                    created,
+                    last_accessed
+                FROM sessions
+                WHERE user_id = $1
+                ORDER BY last_accessed DESC, rowid DESC
+            "#,
+            user_id,
+        )
+        .fetch_all(&self.pool)
+        .await
+        .context("Unable to get sessions of user")
+    }
+
+    // Delete a session of a user by its public id, returns false if the user has no such session.
+    pub async fn delete_user_session(&self, user_id: i64, id: &str) -> Result<bool> {
+        sqlx::query!(
+            r#"
+                DELETE FROM sessions
+                WHERE public_id = $1 AND user_id = $2
+            "#,
+            id,
+            user_id,
+        )
+        .execute(&self.pool)
+        .await
+        .context("Unable to delete session")
+        .map(|result| result.rows_affected() > 0)
+    }
+
+    // Delete all sessions of a user, except the one with the id `keep`.
+    pub async fn delete_user_sessions(&self, user_id: i64, keep: Option<&str>) -> Result<()> {
+        sqlx::query!(
+            r#"
+                DELETE FROM sessions
+                WHERE user_id = $1 AND id IS NOT $2
+            "#,
+            user_id,
+            keep,
+        )
+        .execute(&self.pool)
+        .await
+        .context("Unable to delete sessions of user")
+        .map(|_| ())
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::database::role::{DEFAULT_ROLE, Permission};
 
     #[sqlx::test(fixtures("user"))]
     async fn test_get_session(pool: sqlx::Pool<sqlx::Sqlite>) {
@@ -98,7 +235,7 @@ mod tests {
         // Create a new session for the user
         let session_id = "test_session_id";
         let user_id = 2;
-        db.create_session(user_id, session_id)
+        db.create_session(user_id, session_id, None, None)
             .await
             .expect("Should be able to create a new session");
 
@@ -110,7 +247,9 @@ mod tests {
         assert_eq!(session_info.session_id, session_id);
         assert_eq!(session_info.user_id, user_id);
         assert_eq!(session_info.username, "user");
-        assert!(!session_info.admin);
+        assert_eq!(session_info.role_id, DEFAULT_ROLE);
+        assert!(session_info.permissions.has(Permission::Progress));
+        assert!(!session_info.permissions.has(Permission::ManageLibrary));
     }
 
     #[sqlx::test(fixtures("user"))]
@@ -120,7 +259,7 @@ mod tests {
 
         let session_id = "new_session_id";
         let user_id = 2;
-        db.create_session(user_id, session_id)
+        db.create_session(user_id, session_id, None, None)
             .await
             .expect("Should be able to create a new session");
 
@@ -140,7 +279,7 @@ mod tests {
 
         let session_id = "test_session_id";
         let user_id = 2;
-        db.create_session(user_id, session_id)
+        db.create_session(user_id, session_id, None, None)
             .await
             .expect("Should be able to create a new session");
 
@@ -171,7 +310,7 @@ mod tests {
 
         let session_id = "test_session_id";
         let user_id = 2;
-        db.create_session(user_id, session_id)
+        db.create_session(user_id, session_id, None, None)
             .await
             .expect("Should be able to create a new session");
 
@@ -182,4 +321,89 @@ mod tests {
         let result = db.get_session(session_id).await;
         assert!(result.is_err(), "Session should be deleted");
     }
+
+    #[sqlx::test(fixtures("user"))]
+    async fn test_user_sessions(pool: sqlx::Pool<sqlx::Sqlite>) {
+        // Test case: Verify that sessions are listed with their client and can be revoked
+        let db = Database::new_test(pool);
+
+        db.create_session(2, "first", Some("Firefox"), Some("192.168.1.2"))
+            .await
+            .unwrap();
+        db.create_session(2, "second", None, None).await.unwrap();
+        db.create_session(1, "admin", None, None).await.unwrap();
+
+        let sessions = db.get_user_sessions(2).await.unwrap();
+        assert_eq!(sessions.len(), 2);
+        let first = sessions
+            .iter()
+            .find(|session| session.session_id == "first")
+            .unwrap();
+        assert_eq!(first.user_agent.as_deref(), Some("Firefox"));
+        assert_eq!(first.ip.as_deref(), Some("192.168.1.2"));
+        assert_eq!(first.id.len(), 32);
+        assert_ne!(sessions[0].id, sessions[1].id);
+
+        // Sessions of other users can't be revoked.
+        assert!(!db.delete_user_session(1, &first.id).await.unwrap());
+        assert!(db.delete_user_session(2, &first.id).await.unwrap());
+        assert!(db.get_session("first").await.is_err());
+
+        // Ids of revoked sessions are never given to new sessions.
+        db.create_session(2, "third", None, None).await.unwrap();
+        let sessions = db.get_user_sessions(2).await.unwrap();
+        assert!(sessions.iter().all(|session| session.id != first.id));
+        assert!(!db.delete_user_session(2, &first.id).await.unwrap());
+    }
+
+    #[sqlx::test(fixtures("user"))]
+    async fn test_delete_user_sessions(pool: sqlx::Pool<sqlx::Sqlite>) {
+        // Test case: Verify that all sessions of a user are revoked, except the kept one
+        let db = Database::new_test(pool);
+
+        for session_id in ["first", "second", "third"] {
+            db.create_session(2, session_id, None, None).await.unwrap();
+        }
+        db.create_session(1, "admin", None, None).await.unwrap();
+
+        db.delete_user_sessions(2, Some("first")).await.unwrap();
+        let sessions = db.get_user_sessions(2).await.unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].session_id, "first");
+
+        db.delete_user_sessions(2, None).await.unwrap();
+        assert!(db.get_user_sessions(2).await.unwrap().is_empty());
+        assert!(db.get_session("admin").await.is_ok());
+    }
+
+    #[sqlx::test(fixtures("user"))]
+    async fn test_delete_expired_sessions(pool: sqlx::Pool<sqlx::Sqlite>) {
+        // Test case: Verify that only sessions accessed before the cutoff are counted as expired and deleted
+        let db = Database::new_test(pool);
+
+        db.create_session(2, "fresh", None, None).await.unwrap();
+        db.create_session(2, "stale", None, None).await.unwrap();
+        db.create_session(1, "admin", None, None).await.unwrap();
+        sqlx::query!(
+            "UPDATE sessions SET last_accessed = datetime('now', '-40 days') WHERE id = 'stale'"
+        )
+        .execute(&db.pool)
+        .await
+        .unwrap();
+
+        let cutoff = time::OffsetDateTime::now_utc() - time::Duration::days(30);
+        assert_eq!(
+            db.count_sessions(cutoff).await.unwrap(),
+            SessionCounts {
+                active: 2,
+                expired: 1,
+                users: 2
+            }
+        );
+
+        assert_eq!(db.delete_expired_sessions(cutoff).await.unwrap(), 1);
+        assert!(db.get_session("stale").await.is_err());
+        assert!(db.get_session("fresh").await.is_ok());
+        assert_eq!(db.count_sessions(cutoff).await.unwrap().expired, 0);
+    }
 }
@@ -0,0 +1,61 @@
+use super::Database;
+use anyhow::{Context, Result};
+
+impl Database {
+    // Get the value of a setting.
+    pub async fn get_setting(&self, key: &str) -> Result<Option<String>> {
+        sqlx::query_scalar!(
+            r#"
+                SELECT value
+                FROM settings
+                WHERE key = ?
+            "#,
+            key
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .context("Unable to get setting")
+    }
+
+    // Get the value of a setting, storing `default` if it isn't set yet.
+    // If two callers race, both get the value stored first.
+    pub async fn get_or_insert_setting(&self, key: &str, default: &str) -> Result<String> {
+        sqlx::query!(
+            r#"
+                INSERT OR IGNORE INTO settings (key, value)
+                VALUES (?, ?)
+            "#,
+            key,
+            default
+        )
+        .execute(&self.pool)
+        .await
+        .context("Unable to insert setting")?;
+
+        self.get_setting(key)
+            .await?
+            .context("Setting should exist after insert")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[sqlx::test]
+    async fn test_get_or_insert_setting(pool: sqlx::Pool<sqlx::Sqlite>) {
+        // Test case: Verify that get_or_insert_setting keeps the first stored value
+        let db = Database::new_test(pool);
+        assert_eq!(db.get_setting("key").await.unwrap(), None);
+
+        let value = db.get_or_insert_setting("key", "first").await.unwrap();
+        assert_eq!(value, "first");
+
+        let value = db.get_or_insert_setting("key", "second").await.unwrap();
+        assert_eq!(value, "first");
+        assert_eq!(
+            db.get_setting("key").await.unwrap().as_deref(),
+            Some("first")
+        );
+    }
+}
@@ -0,0 +1,229 @@
+use anyhow::{Context, Result};
+
+use super::Database;
+
+/// TOTP secret of a user.
+pub struct TwoFactor {
+    /// Base32 encoded secret.
+    pub secret: String,
+    pub confirmed: bool,
+    pub last_step: Option<i64>,
+}
+
+impl Database {
+    // Get the TOTP secret of a user.
+    pub async fn get_two_factor(&self, user_id: i64) -> Result<Option<TwoFactor>> {
+        sqlx::query_as!(
+            TwoFactor,
+            r#"
+                SELECT secret, confirmed, last_step
+                FROM two_factor
+                WHERE user_id = ?
+            "#,
+            user_id
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .context("Unable to get two-factor authentication")
+    }
+
+    // Check if a user has to enter a code to log in.
+    pub async fn has_two_factor(&self, user_id: i64) -> Result<bool> {
+        sqlx::query_scalar!(
+            r#"
+                SELECT EXISTS (
+                    SELECT 1 FROM two_factor WHERE user_id = ? AND confirmed
+                ) AS "exists!: bool"
+            "#,
+            user_id
+        )
+        .fetch_one(&self.pool)
+        .await
+        .context("Unable to check two-factor authentication")
+    }
+
+    // Store a new unconfirmed secret, replacing an unconfirmed one.
+    // Returns false if the user already confirmed a secret.
+    pub async fn create_two_factor(&self, user_id: i64, secret: &str) -> Result<bool> {
+        sqlx::query!(
+            r#"
+                INSERT INTO two_factor (user_id, secret)
+                VALUES (?, ?)
+                ON CONFLICT (user_id) DO UPDATE
+                SET secret = excluded.secret, last_step = NULL, created = CURRENT_TIMESTAMP
+                WHERE NOT confirmed
+            "#,
+            user_id,
+            secret
+        )
+        .execute(&self.pool)
+        .await
+        .context("Unable to create two-factor authentication")
+        .map(|result| result.rows_affected() > 0)
+    }
+
+    // Confirm the secret and store the time step of the code that confirmed it.
+    pub async fn confirm_two_factor(&self, user_id: i64, step: i64) -> Result<()> {
+        sqlx::query!(
+            r#"
+                UPDATE two_factor
+                SET confirmed = TRUE, last_step = ?
+                WHERE user_id = ?
+            "#,
+            step,
+            user_id
+        )
+        .execute(&self.pool)
+        .await
+        .context("Unable to confirm two-factor authentication")
+        .map(|_| ())
+    }
+
+    // Store the time step of a used code, returns false if a later code was used in the meantime.
+    pub async fn use_totp_step(&self, user_id: i64, step: i64) -> Result<bool> {
+        sqlx::query!(
+            r#"
+                UPDATE two_factor
+                SET last_step = ?
+                WHERE user_id = ? AND (last_step IS NULL OR last_step < ?)
+            "#,
+            step,
+            user_id,
+            step
+        )
+        .execute(&self.pool)
+        .await
+        .context("Unable to store used code")
+        .map(|result| result.rows_affected() > 0)
+    }
+
+    // Remove two-factor authentication and the recovery codes of a user.
+    pub async fn delete_two_factor(&self, user_id: i64) -> Result<()> {
+        let mut trx = self
+            .pool
+            .begin()
+            .await
+            .context("Failed to start transaction")?;
+
+        sqlx::query!("DELETE FROM two_factor WHERE user_id = ?", user_id)
+            .execute(&mut *trx)
+            .await
+            .context("Unable to delete two-factor authentication")?;
+        sqlx::query!("DELETE FROM recovery_codes WHERE user_id = ?", user_id)
+            .execute(&mut *trx)
+            .await
+            .context("Unable to delete recovery codes")?;
+
+        trx.commit().await.context("Failed to commit transaction")?;
+        Ok(())
+    }
+
+    // Replace the recovery codes of a user.
+    pub async fn set_recovery_codes(&self, user_id: i64, hashes: &[String]) -> Result<()> {
+        let mut trx = self
+            .pool
+            .begin()
+            .await
+            .context("Failed to start transaction")?;
+
+        sqlx::query!("DELETE FROM recovery_codes WHERE user_id = ?", user_id)
+            .execute(&mut *trx)
+            .await
+            .context("Unable to delete recovery codes")?;
+        for hash in hashes {
+            sqlx::query!(
+                "INSERT INTO recovery_codes (user_id, hash) VALUES (?, ?)",
+                user_id,
+                hash
+            )
+            .execute(&mut *trx)
+            .await
+            .context("Unable to store recovery code")?;
+        }
+
+        trx.commit().await.context("Failed to commit transaction")?;
+        Ok(())
+    }
+
+    // Mark a recovery code as used, returns false if it doesn't exist or was used before.
+    pub async fn use_recovery_code(&self, user_id: i64, hash: &str) -> Result<bool> {
+        sqlx::query!(
+            r#"
+                UPDATE recovery_codes
+                SET used = CURRENT_TIMESTAMP
+                WHERE user_id = ? AND hash = ? AND used IS NULL
+            "#,
+            user_id,
+            hash
+        )
+        .execute(&self.pool)
+        .await
+        .context("Unable to use recovery code")
+        .map(|result| result.rows_affected() > 0)
+    }
+
+    // Count the unused recovery codes of a user.
+    pub async fn count_recovery_codes(&self, user_id: i64) -> Result<i64> {
+        sqlx::query_scalar!(
+            r#"
+                SELECT COUNT(*) AS "count!: i64"
+                FROM recovery_codes
+                WHERE user_id = ? AND used IS NULL
+            "#,
+            user_id
+        )
+        .fetch_one(&self.pool)
+        .await
+        .context("Unable to count recovery codes")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[sqlx::test(fixtures("user"))]
+    async fn test_two_factor_enrolment(pool: sqlx::Pool<sqlx::Sqlite>) {
+        // Test case: Verify that secrets can be replaced until they are confirmed
+        let db = Database::new_test(pool);
+
+        assert!(db.create_two_factor(2, "FIRST").await.unwrap());
+        assert!(db.create_two_factor(2, "SECOND").await.unwrap());
+        assert!(!db.has_two_factor(2).await.unwrap());
+
+        db.confirm_two_factor(2, 100).await.unwrap();
+        assert!(db.has_two_factor(2).await.unwrap());
+        assert!(!db.create_two_factor(2, "THIRD").await.unwrap());
+
+        let two_factor = db.get_two_factor(2).await.unwrap().unwrap();
+        assert_eq!(two_factor.secret, "SECOND");
+        assert_eq!(two_factor.last_step, Some(100));
+
+        // Steps can't go backwards.
+        assert!(!db.use_totp_step(2, 100).await.unwrap());
+        assert!(db.use_totp_step(2, 101).await.unwrap());
+
+        db.delete_two_factor(2).await.unwrap();
+        assert!(db.get_two_factor(2).await.unwrap().is_none());
+    }
+
+    #[sqlx::test(fixtures("user"))]
+    async fn test_recovery_codes(pool: sqlx::Pool<sqlx::Sqlite>) {
+        // Test case: Verify that recovery codes only work once and are replaced together
+        let db = Database::new_test(pool);
+
+        db.set_recovery_codes(2, &["a".to_string(), "b".to_string()])
+            .await
+            .unwrap();
+        assert_eq!(db.count_recovery_codes(2).await.unwrap(), 2);
+
+        assert!(db.use_recovery_code(2, "a").await.unwrap());
+        assert!(!db.use_recovery_code(2, "a").await.unwrap());
+        assert!(!db.use_recovery_code(1, "b").await.unwrap());
+        assert_eq!(db.count_recovery_codes(2).await.unwrap(), 1);
+
+        db.set_recovery_codes(2, &["c".to_string()]).await.unwrap();
+        assert!(!db.use_recovery_code(2, "b").await.unwrap());
+        assert_eq!(db.count_recovery_codes(2).await.unwrap(), 1);
+    }
+}
@@ -2,7 +2,29 @@ use anyhow::{Context, Result};
 use serde::Serialize;
 use time::OffsetDateTime;
 
-use super::Database;
+use super::{
+    Database,
+    role::{Permission, Permissions},
+};
+
+/// Hash of the password `admin` the default admin account is created with.
+const DEFAULT_ADMIN_PASSWORD_HASH: &str =
+    "$argon2i$v=19$m=4096,t=3,p=1$c2FsdEl0V2l0aFNhbHQ$xTGvQNICqetaNA0Wu1GwFmYhQjAreRcjBz6ornhaFXA";
+
+/// Where a user authenticates.
+#[derive(sqlx::Type, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[sqlx(rename_all = "snake_case")]
+#[serde(rename_all = "camelCase")]
+pub enum AuthSource {
+    /// Password stored in fela.
+    Local,
+    /// OpenID Connect provider, identified by the `sub` claim.
+    Oidc,
+    /// Header set by a trusted reverse proxy.
+    Proxy,
+    /// LDAP directory, the password is checked with a bind.
+    Ldap,
+}
 
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
@@ -14,7 +36,10 @@ pub struct User {
     #[serde(skip)]
     pub password: Option<String>,
 
-    pub admin: bool,
+    pub role_id: i64,
+    pub permissions: Permissions,
+    pub must_change_password: bool,
+    pub auth_source: AuthSource,
 
     #[serde(with = "time::serde::iso8601")]
     pub created: OffsetDateTime,
@@ -32,7 +57,10 @@ impl Database {
                     id,
                     name,
                     NULL as "password: String",
-                    admin,
+                    role_id,
+                    (SELECT permissions FROM roles WHERE roles.id = users.role_id) as "permissions!: Permissions",
+                    must_change_password,
+                    auth_source as "auth_source: AuthSource",
                     created,
                     modified
                 FROM users
@@ -52,7 +80,10 @@ impl Database {
                     id,
                     name,
                     NULL as "password: String",
-                    admin,
+                    role_id,
+                    (SELECT permissions FROM roles WHERE roles.id = users.role_id) as "permissions!: Permissions",
+                    must_change_password,
+                    auth_source as "auth_source: AuthSource",
                     created,
                     modified
                 FROM users
@@ -74,7 +105,10 @@ impl Database {
                     id,
                     name,
                     password,
-                    admin,
+                    role_id,
+                    (SELECT permissions FROM roles WHERE roles.id = users.role_id) as "permissions!: Permissions",
+                    must_change_password,
+                    auth_source as "auth_source: AuthSource",
                     created,
                     modified
                 FROM users
@@ -88,15 +122,22 @@ impl Database {
     }
 
     // Create user.
-    pub async fn create_user(&self, username: &str, password: &str, admin: bool) -> Result<()> {
+    pub async fn create_user(
+        &self,
+        username: &str,
+        password: &str,
+        role_id: i64,
+        must_change_password: bool,
+    ) -> Result<()> {
         sqlx::query!(
             r#"
-                INSERT INTO users (name, password, admin)
-                VALUES ($1, $2, $3)
+                INSERT INTO users (name, password, role_id, must_change_password)
+                VALUES ($1, $2, $3, $4)
             "#,
             username,
             password,
-            admin
+            role_id,
+            must_change_password
         )
         .execute(&self.pool)
         .await
@@ -105,33 +146,303 @@ impl Database {
     }
 
     // Update user.
+    // Changing the password or role also invalidates the signed URLs of the user.
+    // Returns false if the new role would leave no user that can manage users.
     pub async fn update_user(
         &self,
         user_id: i64,
         username: Option<String>,
         password: Option<String>,
-        admin: Option<bool>,
-    ) -> Result<()> {
-        sqlx::query_as!(
-            User,
+        role_id: Option<i64>,
+        must_change_password: Option<bool>,
+    ) -> Result<bool> {
+        let manage_users = Permission::ManageUsers as i64;
+        sqlx::query!(
             r#"
                 UPDATE users
                 SET
                     name = COALESCE(?, name),
                     password = COALESCE(?, password),
-                    admin = COALESCE(?, admin)
+                    role_id = COALESCE(?, role_id),
+                    must_change_password = COALESCE(?, must_change_password),
+                    signing_generation = signing_generation + (? IS NOT NULL OR ? IS NOT NULL)
                 WHERE id = ?
+                    AND (
+                        ? IS NULL
+                        OR (SELECT permissions FROM roles WHERE id = ?) & ? != 0
+                        OR EXISTS (
+                            SELECT 1
+                            FROM users AS others
+                            JOIN roles ON roles.id = others.role_id
+                            WHERE others.id != ? AND roles.permissions & ? != 0
+                        )
+                    )
             "#,
             username,
             password,
-            admin,
-            user_id
+            role_id,
+            must_change_password,
+            password,
+            role_id,
+            user_id,
+            role_id,
+            role_id,
+            manage_users,
+            user_id,
+            manage_users
         )
         .execute(&self.pool)
         .await
         .context("Unable to update user")
+        .map(|result| result.rows_affected() > 0)
+    }
+
+    // Replace the hash of an unchanged password with a new hash of the same password.
+    // Returns false if the password was changed in the meantime.
+    // Signed URLs stay valid, unlike with password changes in `update_user`.
+    pub async fn replace_password_hash(
+        &self,
+        user_id: i64,
+        old_hash: &str,
+        new_hash: &str,
+    ) -> Result<bool> {
+        sqlx::query!(
+            r#"
+                UPDATE users
+                SET password = ?
+                WHERE id = ? AND password = ?
+            "#,
+            new_hash,
+            user_id,
+            old_hash
+        )
+        .execute(&self.pool)
+        .await
+        .context("Unable to replace password hash")
+        .map(|result| result.rows_affected() > 0)
+    }
+
+    // Replace the name and password of the default admin account, if it still has the default
+    // password. Returns false if the account was already changed.
+    pub async fn replace_default_admin(&self, username: &str, password: &str) -> Result<bool> {
+        sqlx::query!(
+            r#"
+                UPDATE users
+                SET
+                    name = ?,
+                    password = ?,
+                    must_change_password = FALSE
+                WHERE name = 'admin' AND password = ?
+            "#,
+            username,
+            password,
+            DEFAULT_ADMIN_PASSWORD_HASH
+        )
+        .execute(&self.pool)
+        .await
+        .context("Unable to replace default admin")
+        .map(|result| result.rows_affected() > 0)
+    }
+
+    // Get the podcast feed token of a user.
+    pub async fn get_feed_token(&self, user_id: i64) -> Result<Option<String>> {
+        sqlx::query_scalar!(
+            r#"
+                SELECT feed_token
+                FROM users
+                WHERE id = ?
+            "#,
+            user_id
+        )
+        .fetch_one(&self.pool)
+        .await
+        .context("Unable to get feed token")
+    }
+
+    // Set the podcast feed token of a user, replacing the old one.
+    pub async fn set_feed_token(&self, user_id: i64, token: &str) -> Result<()> {
+        sqlx::query!(
+            r#"
+                UPDATE users
+                SET feed_token = ?
+                WHERE id = ?
+            "#,
+            token,
+            user_id
+        )
+        .execute(&self.pool)
+        .await
+        .context("Unable to set feed token")
+        .map(|_| ())
+    }
+
+    // Get the generation signed URLs of a user are created with.
+    pub async fn get_signing_generation(&self, user_id: i64) -> Result<i64> {
+        sqlx::query_scalar!(
+            r#"
+                SELECT signing_generation
+                FROM users
+                WHERE id = ?
+            "#,
+            user_id
+        )
+        .fetch_one(&self.pool)
+        .await
+        .context("Unable to get signing generation")
+    }
+
+    // Invalidate all signed URLs of a user by starting a new generation.
+    pub async fn rotate_signing_generation(&self, user_id: i64) -> Result<()> {
+        sqlx::query!(
+            r#"
+                UPDATE users
+                SET signing_generation = signing_generation + 1
+                WHERE id = ?
+            "#,
+            user_id
+        )
+        .execute(&self.pool)
+        .await
+        .context("Unable to rotate signing generation")
+        .map(|_| ())
+    }
+
+    // Get the Subsonic password of a user.
+    pub async fn get_subsonic_password(&self, user_id: i64) -> Result<Option<String>> {
+        sqlx::query_scalar!(
+            r#"
+                SELECT subsonic_password
+                FROM users
+                WHERE id = ?
+            "#,
+            user_id
+        )
+        .fetch_one(&self.pool)
+        .await
+        .context("Unable to get Subsonic password")
+    }
+
+    // Set the Subsonic password of a user, replacing the old one.
+    pub async fn set_subsonic_password(&self, user_id: i64, password: &str) -> Result<()> {
+        sqlx::query!(
+            r#"
+                UPDATE users
+                SET subsonic_password = ?
+                WHERE id = ?
+            "#,
+            password,
+            user_id
+        )
+        .execute(&self.pool)
+        .await
+        .context("Unable to set Subsonic password")
         .map(|_| ())
     }
+
+    // Get the user a podcast feed token belongs to.
+    pub async fn get_user_by_feed_token(&self, token: &str) -> Result<Option<User>> {
+        sqlx::query_as!(
+            User,
+            r#"
+                SELECT
+                    id,
+                    name,
+                    NULL as "password: String",
+                    role_id,
+                    (SELECT permissions FROM roles WHERE roles.id = users.role_id) as "permissions!: Permissions",
+                    must_change_password,
+                    auth_source as "auth_source: AuthSource",
+                    created,
+                    modified
+                FROM users
+                WHERE feed_token = ?
+            "#,
+            token,
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .context("Unable to get user by feed token")
+    }
+
+    // Get user by name, without password.
+    pub async fn get_user_by_name(&self, username: &str) -> Result<Option<User>> {
+        sqlx::query_as!(
+            User,
+            r#"
+                SELECT
+                    id,
+                    name,
+                    NULL as "password: String",
+                    role_id,
+                    (SELECT permissions FROM roles WHERE roles.id = users.role_id) as "permissions!: Permissions",
+                    must_change_password,
+                    auth_source as "auth_source: AuthSource",
+                    created,
+                    modified
+                FROM users
+                WHERE name = ?
+            "#,
+            username,
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .context("Unable to get user by name")
+    }
+
+    // Get the user of an external authentication source.
+    pub async fn get_external_user(
+        &self,
+        auth_source: AuthSource,
+        external_id: &str,
+    ) -> Result<Option<User>> {
+        sqlx::query_as!(
+            User,
+            r#"
+                SELECT
+                    id,
+                    name,
+                    NULL as "password: String",
+                    role_id,
+                    (SELECT permissions FROM roles WHERE roles.id = users.role_id) as "permissions!: Permissions",
+                    must_change_password,
+                    auth_source as "auth_source: AuthSource",
+                    created,
+                    modified
+                FROM users
+                WHERE auth_source = ? AND external_id = ?
+            "#,
+            auth_source,
+            external_id,
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .context("Unable to get external user")
+    }
+
+    // Create a user of an external authentication source and return its id.
+    // The password is empty, which is no valid hash, so password logins always fail.
+    pub async fn create_external_user(
+        &self,
+        username: &str,
+        auth_source: AuthSource,
+        external_id: &str,
+        role_id: i64,
+    ) -> Result<i64> {
+        sqlx::query_scalar!(
+            r#"
+                INSERT INTO users (name, password, role_id, auth_source, external_id)
+                VALUES (?, '', ?, ?, ?)
+                RETURNING id
+            "#,
+            username,
+            role_id,
+            auth_source,
+            external_id
+        )
+        .fetch_one(&self.pool)
+        .await
+        .context("Unable to create external user")
+    }
 }
 
 #[cfg(test)]
@@ -139,6 +450,7 @@ mod tests {
     use sqlx::{Pool, Sqlite};
 
     use super::*;
+    use crate::database::role::{ADMIN_ROLE, DEFAULT_ROLE, Permission};
 
     #[sqlx::test]
     async fn test_create_user(pool: Pool<Sqlite>) {
@@ -146,9 +458,9 @@ mod tests {
 
         let username = "test_user";
         let password = "password123";
-        let admin = false;
+        let role_id = DEFAULT_ROLE;
 
-        db.create_user(username, password, admin)
+        db.create_user(username, password, role_id, false)
             .await
             .expect("Should be able to create user");
 
@@ -159,7 +471,7 @@ mod tests {
 
         assert_eq!(username, user.name);
         assert_eq!(user.password, Some(password.to_string()));
-        assert_eq!(admin, user.admin);
+        assert_eq!(role_id, user.role_id);
     }
 
     #[sqlx::test(fixtures("user"))]
@@ -185,7 +497,8 @@ mod tests {
 
         assert_eq!(user.id, 2);
         assert_eq!(user.name, "user");
-        assert!(!user.admin);
+        assert_eq!(user.role_id, DEFAULT_ROLE);
+        assert!(!user.permissions.has(Permission::ManageUsers));
     }
 
     #[sqlx::test(fixtures("user"))]
@@ -206,7 +519,8 @@ mod tests {
             user.id,
             Some(updated_username.to_string()),
             Some(updated_password.to_string()),
-            Some(true),
+            Some(ADMIN_ROLE),
+            None,
         )
         .await
         .expect("Should be able to update user");
@@ -218,7 +532,7 @@ mod tests {
 
         assert_eq!(updated.name, updated_username);
         assert_eq!(updated.password, Some(updated_password.to_string()));
-        assert!(updated.admin);
+        assert_eq!(updated.role_id, ADMIN_ROLE);
     }
 
     #[sqlx::test(fixtures("user"))]
@@ -233,9 +547,15 @@ mod tests {
             .await
             .expect("Should be able to get user");
 
-        db.update_user(user.id, Some(updated_username.to_string()), None, None)
-            .await
-            .expect("Should be able to only update username");
+        db.update_user(
+            user.id,
+            Some(updated_username.to_string()),
+            None,
+            None,
+            None,
+        )
+        .await
+        .expect("Should be able to only update username");
         let updated = db
             .get_user_with_password(updated_username)
             .await
@@ -243,7 +563,7 @@ mod tests {
 
         assert_eq!(updated.name, updated_username);
         assert_eq!(updated.password, user.password);
-        assert_eq!(updated.admin, user.admin);
+        assert_eq!(updated.role_id, user.role_id);
     }
 
     #[sqlx::test(fixtures("user"))]
@@ -258,9 +578,15 @@ mod tests {
             .await
             .expect("Should be able to get user");
 
-        db.update_user(user.id, None, Some(updated_password.to_string()), None)
-            .await
-            .expect("Should be able to only update password");
+        db.update_user(
+            user.id,
+            None,
+            Some(updated_password.to_string()),
+            None,
+            None,
+        )
+        .await
+        .expect("Should be able to only update password");
         let updated = db
             .get_user_with_password(username)
             .await
@@ -268,11 +594,11 @@ mod tests {
 
         assert_eq!(updated.name, user.name);
         assert_eq!(updated.password, Some(updated_password.to_string()));
-        assert_eq!(updated.admin, user.admin);
+        assert_eq!(updated.role_id, user.role_id);
     }
 
     #[sqlx::test(fixtures("user"))]
-    async fn test_update_user_admin(pool: Pool<Sqlite>) {
+    async fn test_update_user_role(pool: Pool<Sqlite>) {
         let db = Database::new_test(pool);
 
         let username = "user";
@@ -282,9 +608,9 @@ mod tests {
             .await
             .expect("Should be able to get user");
 
-        db.update_user(user.id, None, None, Some(true))
+        db.update_user(user.id, None, None, Some(ADMIN_ROLE), None)
             .await
-            .expect("Should be able to only update admin");
+            .expect("Should be able to only update role");
         let updated = db
             .get_user_with_password(username)
             .await
@@ -292,6 +618,145 @@ mod tests {
 
         assert_eq!(updated.name, user.name);
         assert_eq!(updated.password, user.password);
-        assert!(updated.admin);
+        assert_eq!(updated.role_id, ADMIN_ROLE);
+        assert!(updated.permissions.has(Permission::ManageUsers));
+    }
+
+    #[sqlx::test]
+    async fn test_feed_token(pool: Pool<Sqlite>) {
+        // Test case: Verify that feed tokens identify their user and can be replaced
+        let db = Database::new_test(pool);
+        assert_eq!(db.get_feed_token(1).await.unwrap(), None);
+
+        db.set_feed_token(1, "first").await.unwrap();
+        assert_eq!(
+            db.get_feed_token(1).await.unwrap().as_deref(),
+            Some("first")
+        );
+        let user = db.get_user_by_feed_token("first").await.unwrap().unwrap();
+        assert_eq!(user.id, 1);
+
+        db.set_feed_token(1, "second").await.unwrap();
+        assert!(db.get_user_by_feed_token("first").await.unwrap().is_none());
+        assert!(db.get_user_by_feed_token("second").await.unwrap().is_some());
+    }
+
+    #[sqlx::test]
+    async fn test_subsonic_password(pool: Pool<Sqlite>) {
+        // Test case: Verify that Subsonic passwords are stored per user and can be replaced
+        let db = Database::new_test(pool);
+        assert_eq!(db.get_subsonic_password(1).await.unwrap(), None);
+
+        db.set_subsonic_password(1, "first").await.unwrap();
+        db.set_subsonic_password(1, "second").await.unwrap();
+        assert_eq!(
+            db.get_subsonic_password(1).await.unwrap().as_deref(),
+            Some("second")
+        );
+    }
+
+    #[sqlx::test(fixtures("user"))]
+    async fn test_signing_generation(pool: Pool<Sqlite>) {
+        // Test case: Verify that password and role changes start a new generation, name changes don't
+        let db = Database::new_test(pool);
+        assert_eq!(db.get_signing_generation(2).await.unwrap(), 0);
+
+        db.update_user(2, Some("renamed".to_string()), None, None, None)
+            .await
+            .unwrap();
+        assert_eq!(db.get_signing_generation(2).await.unwrap(), 0);
+
+        db.update_user(2, None, Some("hash".to_string()), None, None)
+            .await
+            .unwrap();
+        db.update_user(2, None, None, Some(1), None).await.unwrap();
+        assert_eq!(db.get_signing_generation(2).await.unwrap(), 2);
+
+        db.rotate_signing_generation(2).await.unwrap();
+        assert_eq!(db.get_signing_generation(2).await.unwrap(), 3);
+        assert_eq!(db.get_signing_generation(1).await.unwrap(), 0);
+    }
+
+    #[sqlx::test(fixtures("user"))]
+    async fn test_last_user_manager(pool: Pool<Sqlite>) {
+        // Test case: Verify that the last user that can manage users keeps a role allowing it
+        let db = Database::new_test(pool);
+
+        assert!(!db.update_user(1, None, None, Some(4), None).await.unwrap());
+        assert_eq!(db.get_user(1).await.unwrap().role_id, ADMIN_ROLE);
+
+        // Other changes and roles that still manage users are fine.
+        assert!(
+            db.update_user(1, Some("root".to_string()), None, None, None)
+                .await
+                .unwrap()
+        );
+        assert!(db.update_user(1, None, None, Some(3), None).await.unwrap());
+
+        // Once another user can manage users, the first one can give it up.
+        assert!(
+            db.update_user(2, None, None, Some(ADMIN_ROLE), None)
+                .await
+                .unwrap()
+        );
+        assert!(db.update_user(1, None, None, Some(4), None).await.unwrap());
+        assert!(!db.update_user(2, None, None, Some(4), None).await.unwrap());
+    }
+
+    #[sqlx::test(fixtures("user"))]
+    async fn test_default_admin_must_change_password(pool: Pool<Sqlite>) {
+        // Test case: Verify that only the seeded admin has to change its password
+        let db = Database::new_test(pool);
+
+        assert!(db.get_user(1).await.unwrap().must_change_password);
+        assert!(!db.get_user(2).await.unwrap().must_change_password);
+
+        db.update_user(1, None, None, None, Some(false))
+            .await
+            .unwrap();
+        assert!(!db.get_user(1).await.unwrap().must_change_password);
+    }
+
+    #[sqlx::test(fixtures("user"))]
+    async fn test_replace_password_hash(pool: Pool<Sqlite>) {
+        // Test case: Verify that rehashing keeps the signing generation and never overwrites a changed password
+        let db = Database::new_test(pool);
+        let old_hash = db
+            .get_user_with_password("user")
+            .await
+            .unwrap()
+            .password
+            .unwrap();
+
+        assert!(
+            db.replace_password_hash(2, &old_hash, "rehashed")
+                .await
+                .unwrap()
+        );
+        assert_eq!(db.get_signing_generation(2).await.unwrap(), 0);
+
+        // The password was changed since the old hash was read.
+        assert!(
+            !db.replace_password_hash(2, &old_hash, "stale")
+                .await
+                .unwrap()
+        );
+        let user = db.get_user_with_password("user").await.unwrap();
+        assert_eq!(user.password.as_deref(), Some("rehashed"));
+    }
+
+    #[sqlx::test]
+    async fn test_replace_default_admin(pool: Pool<Sqlite>) {
+        // Test case: Verify that the default admin is only replaced while it has the default password
+        let db = Database::new_test(pool);
+
+        assert!(db.replace_default_admin("root", "hash").await.unwrap());
+        let user = db.get_user_with_password("root").await.unwrap();
+        assert_eq!(user.id, 1);
+        assert_eq!(user.password.as_deref(), Some("hash"));
+        assert_eq!(user.role_id, ADMIN_ROLE);
+        assert!(!user.must_change_password);
+
+        assert!(!db.replace_default_admin("other", "hash").await.unwrap());
     }
 }
@@ -0,0 +1,78 @@
+use super::Database;
+use anyhow::{Context, Result};
+
+impl Database {
+    // Get waveform peaks of a file, if they were generated from the file with `file_hash`.
+    pub async fn get_waveform(&self, file_id: i64, file_hash: &str) -> Result<Option<Vec<u8>>> {
+        sqlx::query!(
+            r#"
+                SELECT peaks
+                FROM waveforms
+                WHERE file_id = ? AND file_hash = ?
+            "#,
+            file_id,
+            file_hash
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .context("Unable to get waveform")
+        .map(|result| result.map(|result| result.peaks))
+    }
+
+    // Store waveform peaks of a file.
+    pub async fn save_waveform(&self, file_id: i64, file_hash: &str, peaks: &[u8]) -> Result<()> {
+        sqlx::query!(
+            r#"
+                INSERT OR REPLACE INTO waveforms (file_id, file_hash, peaks)
+                VALUES (?, ?, ?)
+            "#,
+            file_id,
+            file_hash,
+            peaks
+        )
+        .execute(&self.pool)
+        .await
+        .context("Unable to save waveform")
+        .map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[sqlx::test(fixtures("book"))]
+    async fn test_save_waveform(pool: sqlx::Pool<sqlx::Sqlite>) {
+        // Test case: Verify that a saved waveform can be read again
+        let db = Database::new_test(pool);
+
+        db.save_waveform(336, "hash", &[0, 128, 255])
+            .await
+            .expect("Should be able to save waveform");
+
+        let peaks = db
+            .get_waveform(336, "hash")
+            .await
+            .expect("Should be able to get waveform");
+        assert_eq!(peaks, Some(vec![0, 128, 255]));
+
+        // Peaks of a replaced file are outdated.
+        let peaks = db
+            .get_waveform(336, "other")
+            .await
+            .expect("Should be able to get waveform");
+        assert!(peaks.is_none());
+    }
+
+    #[sqlx::test(fixtures("book"))]
+    async fn test_get_waveform_not_found(pool: sqlx::Pool<sqlx::Sqlite>) {
+        // Test case: Verify that get_waveform returns None for a file without waveform
+        let db = Database::new_test(pool);
+
+        let peaks = db
+            .get_waveform(336, "hash")
+            .await
+            .expect("Should be able to get waveform");
+        assert!(peaks.is_none());
+    }
+}
@@ -0,0 +1,163 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use zip::{CompressionMethod, ZipWriter, write::SimpleFileOptions};
+
+use crate::media::ffmpeg::Chapters;
+
+/// Entry of a zip archive.
+pub enum ArchiveEntry {
+    /// File that is read from disk.
+    File { name: String, path: PathBuf },
+    /// Data that is already in memory.
+    Data { name: String, data: Vec<u8> },
+}
+
+/// Write a zip archive containing the given entries to `output`.
+/// Entries are stored without compression, audio and images don't get any smaller by deflating
+/// them.
+pub async fn create_zip(entries: Vec<ArchiveEntry>, output: PathBuf) -> Result<()> {
+    tokio::task::spawn_blocking(move || write_zip(&entries, &output))
+        .await
+        .context("Zip task panicked")?
+}
+
+fn write_zip(entries: &[ArchiveEntry], output: &Path) -> Result<()> {
+    let file = File::create(output)
+        .with_context(|| format!("Failed to create archive: {}", output.display()))?;
+    let mut zip = ZipWriter::new(BufWriter::new(file));
+
+    // Audiobooks easily exceed 4 GiB, so always write Zip64 headers.
+    let options = SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Stored)
+        .large_file(true);
+
+    for entry in entries {
+        match entry {
+            ArchiveEntry::File { name, path } => {
+                zip.start_file(name, options)?;
+                let mut file = File::open(path)
+                    .with_context(|| format!("Failed to open file: {}", path.display()))?;
+                std::io::copy(&mut file, &mut zip).with_context(|| {
+                    format!("Failed to add file to archive: {}", path.display())
+                })?;
+            }
+            ArchiveEntry::Data { name, data } => {
+                zip.start_file(name, options)?;
+                zip.write_all(data)?;
+            }
+        }
+    }
+
+    zip.finish()?.flush()?;
+
+    Ok(())
+}
+
+/// Create a plain text chapter list.
+/// Every line contains the start of a chapter as `HH:MM:SS.mmm` followed by its name.
+pub fn chapter_list(chapters: &[Chapters]) -> String {
+    chapters
+        .iter()
+        .map(|chapter| {
+            let milliseconds = (chapter.start * 1000.0).round() as u64;
+            format!(
+                "{:02}:{:02}:{:02}.{:03} {}\n",
+                milliseconds / 3_600_000,
+                milliseconds / 60_000 % 60,
+                milliseconds / 1000 % 60,
+                milliseconds % 1000,
+                chapter.name
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use tempfile::tempdir;
+    use zip::ZipArchive;
+
+    #[tokio::test]
+    async fn test_create_zip() {
+        // Test case: Verify that create_zip writes files and in-memory data in order
+        let temp_dir = tempdir().unwrap();
+        let file_path = temp_dir.path().join("1.mp3");
+        std::fs::write(&file_path, b"audio").unwrap();
+        let output = temp_dir.path().join("book.zip");
+
+        create_zip(
+            vec![
+                ArchiveEntry::File {
+                    name: "01 - One.mp3".to_string(),
+                    path: file_path,
+                },
+                ArchiveEntry::Data {
+                    name: "chapters.txt".to_string(),
+                    data: b"chapters".to_vec(),
+                },
+            ],
+            output.clone(),
+        )
+        .await
+        .unwrap();
+
+        let mut archive = ZipArchive::new(File::open(&output).unwrap()).unwrap();
+        assert_eq!(archive.len(), 2);
+
+        let mut content = String::new();
+        archive
+            .by_index(0)
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        assert_eq!(content, "audio");
+        assert_eq!(archive.by_index(1).unwrap().name(), "chapters.txt");
+    }
+
+    #[tokio::test]
+    async fn test_create_zip_missing_file() {
+        // Test case: Verify that create_zip fails if a file doesn't exist
+        let temp_dir = tempdir().unwrap();
+
+        let result = create_zip(
+            vec![ArchiveEntry::File {
+                name: "missing.mp3".to_string(),
+                path: temp_dir.path().join("missing.mp3"),
+            }],
+            temp_dir.path().join("book.zip"),
+        )
+        .await;
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_chapter_list() {
+        // Test case: Verify that chapter_list formats the start of every chapter
+        let chapters = vec![
+            Chapters {
+                name: "Opening Credits".to_string(),
+                start: 0.0,
+                end: 18.506,
+            },
+            Chapters {
+                name: "Chapter 1".to_string(),
+                start: 3723.5,
+                end: 4000.0,
+            },
+        ];
+
+        let result = chapter_list(&chapters);
+        assert_eq!(
+            result,
+            "00:00:00.000 Opening Credits\n01:02:03.500 Chapter 1\n"
+        );
+    }
+}
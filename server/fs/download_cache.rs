@@ -0,0 +1,175 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::LazyLock,
+    time::SystemTime,
+};
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
+
+use super::storage::{TMP_PATH, file_fingerprint};
+use crate::database::{book::Book, file::File};
+
+/// Directory zipped and merged book downloads are cached in.
+pub static DOWNLOAD_CACHE_PATH: LazyLock<PathBuf> = LazyLock::new(|| {
+    let path = TMP_PATH.join("downloads");
+    std::fs::create_dir_all(&path).expect("Should be able to create download cache directory");
+    path
+});
+
+/// Size the download cache may grow to in bytes.
+/// Read DOWNLOAD_CACHE_SIZE from environment variable, in MiB.
+/// Default to 4 GiB.
+pub static DOWNLOAD_CACHE_SIZE: LazyLock<u64> = LazyLock::new(|| {
+    std::env::var("DOWNLOAD_CACHE_SIZE").map_or(4096, |size| {
+        size.parse()
+            .expect("DOWNLOAD_CACHE_SIZE environment variable should be an integer")
+    }) * 1024
+        * 1024
+});
+
+/// Key of the content of a book's download.
+/// Covers the book itself and the files on disk, so downloads are created again when the book
+/// is edited or a file is replaced, even outside of fela.
+pub async fn download_key(book: &Book, files: &[File]) -> Result<String> {
+    let mut hasher = Sha256::new();
+    hasher.update(format!("{}:{}\n", book.id, book.modified.unix_timestamp()));
+
+    for file in files {
+        hasher.update(format!(
+            "{}:{}:{}\n",
+            file.position,
+            file.modified.unix_timestamp(),
+            file_fingerprint(Path::new(&file.path)).await?
+        ));
+    }
+
+    Ok(hasher
+        .finalize()
+        .iter()
+        .take(8)
+        .map(|byte| format!("{byte:02x}"))
+        .collect())
+}
+
+/// Name of a cached download, `variant` distinguishes downloads of the same content like
+/// different gains.
+pub fn cached_download_name(book_id: i64, key: &str, variant: &str, extension: &str) -> String {
+    format!("book-{book_id}-{key}{variant}.{extension}")
+}
+
+/// Remove outdated downloads of a book and the oldest downloads until the cache fits into
+/// `max_size` bytes. The new download `keep` is never removed.
+pub async fn evict_downloads(
+    directory: &Path,
+    book_id: i64,
+    keep: &Path,
+    max_size: u64,
+) -> Result<()> {
+    let keep_name = keep.file_name().map(|name| name.to_string_lossy());
+    let book_prefix = format!("book-{book_id}-");
+    let current_prefix = keep_name
+        .as_deref()
+        .and_then(|name| name.get(..book_prefix.len() + 16))
+        .unwrap_or_default()
+        .to_string();
+
+    let mut entries = Vec::new();
+    let mut directory_entries = tokio::fs::read_dir(directory)
+        .await
+        .context("Failed to read download cache")?;
+    while let Some(entry) = directory_entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if Some(name.as_str()) == keep_name.as_deref() {
+            continue;
+        }
+        let metadata = entry.metadata().await?;
+        if !metadata.is_file() {
+            continue;
+        }
+
+        // Downloads of older versions of the book are never requested again.
+        if name.starts_with(&book_prefix) && !name.starts_with(&current_prefix) {
+            remove_download(&entry.path()).await;
+            continue;
+        }
+
+        let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+        entries.push((modified, metadata.len(), entry.path()));
+    }
+
+    let keep_size = tokio::fs::metadata(keep)
+        .await
+        .map_or(0, |metadata| metadata.len());
+    let mut size = keep_size + entries.iter().map(|(_, len, _)| len).sum::<u64>();
+    entries.sort_by_key(|(modified, _, _)| *modified);
+    for (_, len, path) in entries {
+        if size <= max_size {
+            break;
+        }
+        remove_download(&path).await;
+        size -= len;
+    }
+
+    Ok(())
+}
+
+async fn remove_download(path: &Path) {
+    match tokio::fs::remove_file(path).await {
+        Ok(()) => tracing::debug!("Removed cached download {}", path.display()),
+        Err(err) => tracing::warn!(
+            "Failed to remove cached download {}: {}",
+            path.display(),
+            err
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use tempfile::tempdir;
+
+    fn key(n: u8) -> String {
+        format!("{n:016x}")
+    }
+
+    #[tokio::test]
+    async fn test_evict_downloads() {
+        // Test case: Verify that outdated downloads of the book and the oldest downloads are removed
+        let directory = tempdir().unwrap();
+        let path = |name: String| directory.path().join(name);
+        let write = |name: String, size: usize| {
+            std::fs::write(path(name), vec![0; size]).unwrap();
+            // Modification times need to differ for the order.
+            std::thread::sleep(Duration::from_millis(10));
+        };
+
+        write(cached_download_name(2, &key(1), "", "zip"), 100);
+        write(cached_download_name(1, &key(1), "", "m4b"), 100);
+        write(cached_download_name(3, &key(1), "", "zip"), 100);
+        write(cached_download_name(1, &key(2), "-1.00dB", "m4b"), 100);
+        write(cached_download_name(1, &key(2), "", "m4b"), 100);
+
+        let keep = path(cached_download_name(1, &key(2), "", "m4b"));
+        evict_downloads(directory.path(), 1, &keep, 300)
+            .await
+            .unwrap();
+
+        // The outdated download of book 1 and the oldest download are gone.
+        assert!(!path(cached_download_name(1, &key(1), "", "m4b")).exists());
+        assert!(!path(cached_download_name(2, &key(1), "", "zip")).exists());
+        assert!(path(cached_download_name(3, &key(1), "", "zip")).exists());
+        assert!(path(cached_download_name(1, &key(2), "-1.00dB", "m4b")).exists());
+        assert!(keep.exists());
+
+        // The new download is kept even if it alone exceeds the limit.
+        evict_downloads(directory.path(), 1, &keep, 50)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read_dir(directory.path()).unwrap().count(), 1);
+        assert!(keep.exists());
+    }
+}
@@ -0,0 +1,172 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result, bail};
+use sha2::{Digest, Sha256};
+
+use crate::auth::random::random_string;
+
+/// Directory of resized variants inside the image store.
+const VARIANT_DIRECTORY: &str = "variants";
+
+/// Content-addressed store for images on disk.
+/// Images are named by the SHA-256 of their data and sharded by the first two hex digits,
+/// so storing the same image twice only keeps one copy.
+#[derive(Clone)]
+pub struct ImageStore {
+    root: PathBuf,
+}
+
+impl ImageStore {
+    /// Open the image store at `root`, creating the directory if needed.
+    pub fn new(root: impl Into<PathBuf>) -> Result<Self> {
+        let root = root.into();
+        std::fs::create_dir_all(root.join(VARIANT_DIRECTORY))
+            .with_context(|| format!("Failed to create image store at {}", root.display()))?;
+
+        Ok(Self { root })
+    }
+
+    /// Open the image store configured by the `IMAGE_DIRECTORY` environment variable.
+    /// Defaults to `images` next to the working directory.
+    pub fn from_env() -> Result<Self> {
+        let root = std::env::var("IMAGE_DIRECTORY").unwrap_or_else(|_| "images".to_string());
+        Self::new(root)
+    }
+
+    /// Hash of image data, used as its name in the store.
+    pub fn hash(data: &[u8]) -> String {
+        format!("{:x}", Sha256::digest(data))
+    }
+
+    fn path(&self, hash: &str) -> Result<PathBuf> {
+        if hash.len() != 64 || !hash.bytes().all(|c| c.is_ascii_hexdigit()) {
+            bail!("Invalid image hash: {}", hash)
+        }
+
+        Ok(self.root.join(&hash[..2]).join(hash))
+    }
+
+    /// Path of a derived variant of an image, e.g. a resized cover.
+    pub fn variant_path(&self, hash: &str, variant: &str) -> Result<PathBuf> {
+        self.path(hash)?;
+
+        Ok(self
+            .root
+            .join(VARIANT_DIRECTORY)
+            .join(format!("{hash}-{variant}")))
+    }
+
+    /// Store image data and return its hash.
+    pub async fn store(&self, data: &[u8]) -> Result<String> {
+        let hash = Self::hash(data);
+        let path = self.path(&hash)?;
+
+        if !tokio::fs::try_exists(&path).await.unwrap_or(false) {
+            write_atomic(&path, data).await?;
+        }
+
+        Ok(hash)
+    }
+
+    /// Read the image with the given hash.
+    pub async fn read(&self, hash: &str) -> Result<Vec<u8>> {
+        let path = self.path(hash)?;
+
+        tokio::fs::read(&path)
+            .await
+            .with_context(|| format!("Failed to read image {}", hash))
+    }
+
+    /// Remove an image and all of its variants.
+    pub async fn remove(&self, hash: &str) -> Result<()> {
+        let path = self.path(hash)?;
+        if let Err(err) = tokio::fs::remove_file(&path).await
+            && err.kind() != std::io::ErrorKind::NotFound
+        {
+            return Err(err).with_context(|| format!("Failed to remove image {}", hash));
+        }
+
+        // Variants are named `<hash>-<variant>`.
+        let mut variants = tokio::fs::read_dir(self.root.join(VARIANT_DIRECTORY)).await?;
+        while let Some(entry) = variants.next_entry().await? {
+            if entry
+                .file_name()
+                .to_str()
+                .is_some_and(|name| name.starts_with(&format!("{hash}-")))
+            {
+                tokio::fs::remove_file(entry.path()).await.ok();
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Write a file through a temporary file in the same directory,
+/// so readers never see a partially written file.
+pub async fn write_atomic(path: &Path, data: &[u8]) -> Result<()> {
+    let directory = path.parent().context("Path has no parent directory")?;
+    tokio::fs::create_dir_all(directory).await?;
+
+    let partial_path = directory.join(format!(".{}.part", random_string(12)));
+    tokio::fs::write(&partial_path, data)
+        .await
+        .with_context(|| format!("Failed to write {}", partial_path.display()))?;
+    tokio::fs::rename(&partial_path, path)
+        .await
+        .with_context(|| format!("Failed to move file to {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_store_and_read() {
+        // Test case: Verify that stored images can be read back by their hash
+        let directory = tempfile::tempdir().unwrap();
+        let store = ImageStore::new(directory.path()).unwrap();
+
+        let hash = store.store(b"image data").await.unwrap();
+        assert_eq!(hash, ImageStore::hash(b"image data"));
+        assert!(directory.path().join(&hash[..2]).join(&hash).exists());
+
+        assert_eq!(store.read(&hash).await.unwrap(), b"image data");
+    }
+
+    #[tokio::test]
+    async fn test_store_deduplicates() {
+        // Test case: Verify that storing the same data twice returns the same hash
+        let directory = tempfile::tempdir().unwrap();
+        let store = ImageStore::new(directory.path()).unwrap();
+
+        let first = store.store(b"image data").await.unwrap();
+        let second = store.store(b"image data").await.unwrap();
+        assert_eq!(first, second);
+    }
+
+    #[tokio::test]
+    async fn test_remove() {
+        // Test case: Verify that remove deletes the image and its variants
+        let directory = tempfile::tempdir().unwrap();
+        let store = ImageStore::new(directory.path()).unwrap();
+
+        let hash = store.store(b"image data").await.unwrap();
+        let variant = store.variant_path(&hash, "128.jpg").unwrap();
+        tokio::fs::write(&variant, b"variant").await.unwrap();
+
+        store.remove(&hash).await.unwrap();
+        assert!(store.read(&hash).await.is_err());
+        assert!(!variant.exists());
+    }
+
+    #[tokio::test]
+    async fn test_invalid_hash() {
+        // Test case: Verify that hashes can't be used to escape the store
+        let directory = tempfile::tempdir().unwrap();
+        let store = ImageStore::new(directory.path()).unwrap();
+
+        assert!(store.read("../../etc/passwd").await.is_err());
+        assert!(store.variant_path("abc", "128.jpg").is_err());
+    }
+}
@@ -6,6 +6,23 @@ use tokio::io;
 // Audio file extensions (.mp3, .flac, .wav, .ogg, .m4a, .m4b, .opus)
 pub const AUDIO_EXTENSIONS: [&str; 7] = ["mp3", "flac", "wav", "ogg", "m4a", "m4b", "opus"];
 
+// Content type of an audio file, based on its extension.
+pub fn audio_content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+
+    match extension.as_str() {
+        "m4a" | "m4b" => "audio/mp4",
+        "flac" => "audio/flac",
+        "wav" => "audio/wav",
+        "ogg" | "opus" => "audio/ogg",
+        _ => "audio/mpeg",
+    }
+}
+
 // image file extensions (.jpg, .jpeg, .png, .webp)
 pub const IMAGE_EXTENSIONS: [&str; 4] = ["jpg", "jpeg", "png", "webp"];
 
@@ -125,6 +142,15 @@ mod tests {
         fs::write(&hidden_file_path, b"").await.unwrap();
     }
 
+    #[test]
+    fn test_audio_content_type() {
+        // Test case: Verify that audio_content_type maps extensions to content types
+        assert_eq!(audio_content_type(Path::new("book.M4B")), "audio/mp4");
+        assert_eq!(audio_content_type(Path::new("book.opus")), "audio/ogg");
+        assert_eq!(audio_content_type(Path::new("book.mp3")), "audio/mpeg");
+        assert_eq!(audio_content_type(Path::new("book")), "audio/mpeg");
+    }
+
     #[tokio::test]
     async fn test_entry_count() {
         // Test case: Verify that get_file_system_list returns the correct number of entries
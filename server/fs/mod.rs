@@ -1,3 +1,6 @@
+pub mod archive;
+pub mod download_cache;
+pub mod image_store;
 pub mod list_fs;
 pub mod path;
 pub mod send_file;
@@ -7,10 +7,14 @@ use axum::{
 };
 use regex::Regex;
 use std::sync::LazyLock;
-use tokio::{fs::File, io::AsyncSeekExt};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt},
+};
 use tokio_util::io::ReaderStream;
 
-static RANGE_REGEX: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^bytes=(\d+)-(\d+)?$").unwrap());
+static RANGE_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^bytes=(\d+)-(\d+)?$").unwrap());
 
 pub struct RangeHeader {
     pub start: u64,
@@ -72,24 +76,42 @@ pub async fn send_file(path: &str, header: Option<&HeaderMap>) -> impl IntoRespo
         Ok(metadata) => metadata.len(),
         Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR).into_response(),
     };
-    // create stream body
-    let body = Body::from_stream(ReaderStream::new(file));
 
     // create response
-    let res =
-        Response::builder().header(header::CONTENT_TYPE, HeaderValue::from_static("audio/mpeg"));
+    let res = Response::builder()
+        .header(header::CONTENT_TYPE, HeaderValue::from_static("audio/mpeg"))
+        .header(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
 
-    let res = match range {
+    let (res, body) = match range {
         Some(range) => {
-            let end = range.end.unwrap_or(file_size - 1);
+            // reject ranges that lie outside of the file
+            let end = range
+                .end
+                .unwrap_or(u64::MAX)
+                .min(file_size.saturating_sub(1));
+            if range.start >= file_size || end < range.start {
+                return (
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                    [(header::CONTENT_RANGE, format!("bytes */{file_size}"))],
+                )
+                    .into_response();
+            }
+
+            // only stream the requested part of the file
+            let length = end - range.start + 1;
             let content_range = format!("bytes {}-{}/{}", range.start, end, file_size);
-            res.header(header::CONTENT_RANGE, content_range)
-                .header(header::CONTENT_LENGTH, end - range.start + 1)
-                .status(StatusCode::PARTIAL_CONTENT)
+            let res = res
+                .header(header::CONTENT_RANGE, content_range)
+                .header(header::CONTENT_LENGTH, length)
+                .status(StatusCode::PARTIAL_CONTENT);
+            (res, Body::from_stream(ReaderStream::new(file.take(length))))
+        }
+        None => {
+            let res = res
+                .header(header::CONTENT_LENGTH, file_size)
+                .status(StatusCode::OK);
+            (res, Body::from_stream(ReaderStream::new(file)))
         }
-        None => res
-            .header(header::CONTENT_LENGTH, file_size)
-            .status(StatusCode::OK),
     };
 
     res.body(body).unwrap().into_response()
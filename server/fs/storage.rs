@@ -1,5 +1,9 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::LazyLock;
+use std::time::SystemTime;
+
+use anyhow::{Context, Result};
+use sha2::{Digest, Sha256};
 
 use crate::auth::random::random_string;
 
@@ -18,6 +22,103 @@ pub static TMP_PATH: LazyLock<PathBuf> = LazyLock::new(|| {
     path
 });
 
+/// Hash of the path, size and modification time of a file.
+/// Changes whenever the file is replaced or written to, without reading its content.
+pub async fn file_fingerprint(path: &Path) -> Result<String> {
+    let metadata = tokio::fs::metadata(path)
+        .await
+        .with_context(|| format!("Failed to read metadata of {}", path.display()))?;
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(SystemTime::UNIX_EPOCH).ok())
+        .map_or(0, |modified| modified.as_nanos());
+
+    let hash = Sha256::digest(format!(
+        "{}\n{}\n{}",
+        path.display(),
+        metadata.len(),
+        modified
+    ));
+    Ok(hash
+        .iter()
+        .take(16)
+        .map(|byte| format!("{byte:02x}"))
+        .collect())
+}
+
+/// File written by a job into the temporary directory or next to its destination, and moved into
+/// place once it is complete. It is removed when dropped before that, so failed or cancelled jobs
+/// never leave partial files behind.
+pub struct PartialFile {
+    path: PathBuf,
+    persisted: bool,
+}
+
+impl PartialFile {
+    /// Partial file with a random name in the temporary directory.
+    pub fn temporary() -> Self {
+        Self::new(TMP_PATH.join(format!("{}.part", random_string(12))))
+    }
+
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            persisted: false,
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Move the complete file to `target`.
+    pub async fn persist(mut self, target: &Path) -> Result<()> {
+        tokio::fs::rename(&self.path, target)
+            .await
+            .with_context(|| format!("Failed to move file into place: {}", target.display()))?;
+        self.persisted = true;
+
+        Ok(())
+    }
+}
+
+impl Drop for PartialFile {
+    fn drop(&mut self) {
+        if !self.persisted {
+            // The file may not have been created yet.
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Directory in the temporary directory for inputs of a job, removed with everything in it when
+/// dropped.
+pub struct ScratchDirectory(PathBuf);
+
+impl ScratchDirectory {
+    pub async fn create() -> Result<Self> {
+        let path = TMP_PATH.join(random_string(12));
+        tokio::fs::create_dir(&path)
+            .await
+            .context("Failed to create scratch directory")?;
+
+        Ok(Self(path))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for ScratchDirectory {
+    fn drop(&mut self) {
+        if let Err(err) = std::fs::remove_dir_all(&self.0) {
+            tracing::warn!("Failed to remove scratch directory: {}", err);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -49,4 +150,55 @@ mod tests {
         // Delete created directory.
         fs::remove_dir_all(path).unwrap();
     }
+
+    #[tokio::test]
+    async fn test_partial_file() {
+        // Test case: Verify that partial files are removed unless they were moved into place
+        let partial = PartialFile::temporary();
+        let path = partial.path().to_path_buf();
+        tokio::fs::write(&path, b"data").await.unwrap();
+        drop(partial);
+        assert!(!path.exists());
+
+        let partial = PartialFile::temporary();
+        tokio::fs::write(partial.path(), b"data").await.unwrap();
+        let target = TMP_PATH.join(random_string(12));
+        partial.persist(&target).await.unwrap();
+        assert_eq!(std::fs::read(&target).unwrap(), b"data");
+        std::fs::remove_file(target).unwrap();
+
+        // Dropping a partial file that was never written does nothing.
+        drop(PartialFile::temporary());
+    }
+
+    #[tokio::test]
+    async fn test_scratch_directory() {
+        // Test case: Verify that scratch directories are removed with their content
+        let scratch = ScratchDirectory::create().await.unwrap();
+        let path = scratch.path().to_path_buf();
+        std::fs::write(path.join("file"), b"data").unwrap();
+
+        drop(scratch);
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn test_file_fingerprint() {
+        // Test case: Verify that the fingerprint changes when the file is written to
+        let directory = tempfile::tempdir().unwrap();
+        let path = directory.path().join("file.mp3");
+        fs::write(&path, b"audio").unwrap();
+
+        let fingerprint = file_fingerprint(&path).await.unwrap();
+        assert_eq!(fingerprint, file_fingerprint(&path).await.unwrap());
+
+        fs::write(&path, b"other audio").unwrap();
+        assert_ne!(fingerprint, file_fingerprint(&path).await.unwrap());
+
+        assert!(
+            file_fingerprint(&directory.path().join("missing"))
+                .await
+                .is_err()
+        );
+    }
 }
@@ -38,6 +38,23 @@ async fn main() {
     // Build application.
     let state: state::FelaState = state::FelaState::new().await;
 
+    // Compute cover colors of books added before colors were stored.
+    tokio::spawn(media::cover::backfill_cover_colors(
+        state.database.clone(),
+        state.images.clone(),
+    ));
+
+    // Delete expired sessions in the background.
+    tokio::spawn(auth::session::cleanup_expired_sessions(
+        state.database.clone(),
+    ));
+
+    // Prune failed logins and the audit log in the background.
+    tokio::spawn(auth::throttle::cleanup_login_records(
+        state.database.clone(),
+        state.login_throttle.clone(),
+    ));
+
     // Include the frontend in the release profile.
     #[cfg(profile = "release")]
     let app = Router::new()
@@ -60,10 +77,13 @@ async fn main() {
     let listener = tokio::net::TcpListener::bind(format!("0.0.0.0:{port}"))
         .await
         .expect("Should be able to bind to port");
-    axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal())
-        .await
-        .unwrap();
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<std::net::SocketAddr>(),
+    )
+    .with_graceful_shutdown(shutdown_signal())
+    .await
+    .unwrap();
 }
 
 async fn shutdown_signal() {
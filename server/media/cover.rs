@@ -1,13 +1,102 @@
 use anyhow::{Context, Result, bail};
 use axum::body::Bytes;
 use axum_typed_multipart::FieldData;
+use image::{
+    DynamicImage, ExtendedColorType, ImageDecoder, ImageEncoder, ImageFormat, ImageReader,
+    codecs::jpeg::JpegEncoder, metadata::Orientation,
+};
+use serde::Deserialize;
+use std::io::Cursor;
+use thiserror::Error;
 
+use crate::api::response::ApiError;
+
+use crate::database::{Database, image::ImageKind};
+use crate::fs::image_store::{ImageStore, write_atomic};
 use crate::fs::path::{FileSchemes, resolve_scheme_path, validate_path_within_bounds};
 use crate::fs::storage::{FELA_MEDIA_ROOT, TMP_PATH};
 
 pub static RANDOM_FILE_NAME_LENGTH: usize = 12;
 
-pub async fn get_cover_bytes(data: FieldData<Bytes>) -> Result<Vec<u8>> {
+/// JPEG quality of stored and resized covers.
+const JPEG_QUALITY: u8 = 85;
+
+/// Quality of resized covers encoded as lossy WebP.
+const WEBP_QUALITY: f32 = 80.0;
+
+/// Maximum size of a cover before it is decoded.
+pub const MAX_COVER_BYTES: u64 = 20 * 1024 * 1024;
+
+/// Maximum width and height of a cover, larger images are rejected.
+pub const MAX_COVER_DIMENSION: u32 = 10_000;
+
+/// Covers are scaled down to fit into a square of this size when stored.
+const COVER_MAX_EDGE: u32 = 2048;
+
+/// File extension and content type of an image.
+pub struct ImageType {
+    pub extension: &'static str,
+    pub content_type: &'static str,
+}
+
+/// Detect the type of an image by looking at its magic bytes.
+pub fn detect_image_type(data: &[u8]) -> Option<ImageType> {
+    if data.starts_with(&[0xFF, 0xD8, 0xFF]) {
+        Some(ImageType {
+            extension: "jpg",
+            content_type: "image/jpeg",
+        })
+    } else if data.starts_with(&[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A]) {
+        Some(ImageType {
+            extension: "png",
+            content_type: "image/png",
+        })
+    } else if data.len() >= 12 && data.starts_with(b"RIFF") && &data[8..12] == b"WEBP" {
+        Some(ImageType {
+            extension: "webp",
+            content_type: "image/webp",
+        })
+    } else {
+        None
+    }
+}
+
+/// Reasons a cover image is rejected.
+#[derive(Debug, Error)]
+pub enum CoverRejection {
+    #[error("cover is {size} bytes, at most {MAX_COVER_BYTES} bytes are allowed")]
+    TooLarge { size: u64 },
+
+    #[error("cover is not a JPEG, PNG or WebP image")]
+    UnsupportedFormat,
+
+    #[error(
+        "cover is {width}x{height} pixels, at most {MAX_COVER_DIMENSION}x{MAX_COVER_DIMENSION} are allowed"
+    )]
+    TooManyPixels { width: u32, height: u32 },
+
+    #[error("cover could not be decoded: {0}")]
+    Malformed(String),
+}
+
+/// Get the cover image from an upload, either the image itself or a path to it.
+/// The cover is validated and normalized, see `sanitize_cover`.
+pub async fn get_cover_bytes(data: FieldData<Bytes>) -> Result<Vec<u8>, ApiError> {
+    // Rejected covers are reported with their reason, whether uploaded or read from a path.
+    let cover = read_cover_data(data).map_err(|err| match err.downcast::<CoverRejection>() {
+        Ok(rejection) => ApiError::InvalidCoverImage(rejection.to_string()),
+        Err(err) => {
+            tracing::debug!("Failed to get cover image bytes: {:?}", err);
+            ApiError::FailedToGetCoverImage
+        }
+    })?;
+
+    sanitize_cover(cover)
+        .await
+        .map_err(|rejection| ApiError::InvalidCoverImage(rejection.to_string()))
+}
+
+fn read_cover_data(data: FieldData<Bytes>) -> Result<Vec<u8>> {
     // Check if data is an image or string.
     match &data.metadata.content_type {
         Some(_) => {
@@ -18,10 +107,8 @@ pub async fn get_cover_bytes(data: FieldData<Bytes>) -> Result<Vec<u8>> {
                 .as_ref()
                 .context("Failed to get content type from data")?;
             if !content_type.starts_with("image/") {
-                bail!(
-                    "Data does not contain an image.\nContent type: {}",
-                    content_type
-                )
+                tracing::debug!("Data does not contain an image. Content type: {content_type}");
+                bail!(CoverRejection::UnsupportedFormat)
             }
 
             Ok(data.contents.to_vec())
@@ -35,7 +122,8 @@ pub async fn get_cover_bytes(data: FieldData<Bytes>) -> Result<Vec<u8>> {
     }
 }
 
-fn read_image(path: &str) -> Result<Vec<u8>> {
+/// Read an image from a `file://` or `extracted-file://` path.
+pub fn read_image(path: &str) -> Result<Vec<u8>> {
     // Resolve the scheme-prefixed path, then validate it stays within its allowed root.
     let (scheme, resolved_path) = resolve_scheme_path(path)?;
     let validated_path = match scheme {
@@ -43,10 +131,276 @@ fn read_image(path: &str) -> Result<Vec<u8>> {
         FileSchemes::ExtractedFile => validate_path_within_bounds(&resolved_path, &TMP_PATH)?,
     };
 
+    // Don't read files into memory that would be rejected anyway.
+    let size = std::fs::metadata(&validated_path)
+        .with_context(|| format!("Failed to read image file: {}", validated_path.display()))?
+        .len();
+    if size > MAX_COVER_BYTES {
+        bail!(CoverRejection::TooLarge { size })
+    }
+
     std::fs::read(&validated_path)
         .with_context(|| format!("Failed to read image file: {}", validated_path.display()))
 }
 
+/// Validate a cover and re-encode it as JPEG.
+/// Re-encoding drops EXIF and other metadata, the EXIF orientation is applied to the pixels
+/// first. Covers larger than `COVER_MAX_EDGE` are scaled down.
+pub async fn sanitize_cover(data: Vec<u8>) -> Result<Vec<u8>, CoverRejection> {
+    tokio::task::spawn_blocking(move || sanitize_cover_blocking(&data))
+        .await
+        .map_err(|err| CoverRejection::Malformed(err.to_string()))?
+}
+
+fn sanitize_cover_blocking(data: &[u8]) -> Result<Vec<u8>, CoverRejection> {
+    let malformed = |err: image::ImageError| CoverRejection::Malformed(err.to_string());
+
+    if data.len() as u64 > MAX_COVER_BYTES {
+        return Err(CoverRejection::TooLarge {
+            size: data.len() as u64,
+        });
+    }
+    let Some(image_type) = detect_image_type(data) else {
+        return Err(CoverRejection::UnsupportedFormat);
+    };
+    let format = ImageFormat::from_extension(image_type.extension)
+        .ok_or(CoverRejection::UnsupportedFormat)?;
+
+    // Check the dimensions from the header before decoding any pixels.
+    let mut decoder = ImageReader::with_format(Cursor::new(data), format)
+        .into_decoder()
+        .map_err(malformed)?;
+    let (width, height) = decoder.dimensions();
+    if width > MAX_COVER_DIMENSION || height > MAX_COVER_DIMENSION {
+        return Err(CoverRejection::TooManyPixels { width, height });
+    }
+
+    let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+    let mut image = DynamicImage::from_decoder(decoder).map_err(malformed)?;
+    image.apply_orientation(orientation);
+
+    if image.width() > COVER_MAX_EDGE || image.height() > COVER_MAX_EDGE {
+        image = image.thumbnail(COVER_MAX_EDGE, COVER_MAX_EDGE);
+    }
+
+    encode_jpeg(&image).map_err(|err| CoverRejection::Malformed(err.to_string()))
+}
+
+/// Sizes a cover can be requested in.
+#[derive(Deserialize, Default, Clone, Copy)]
+pub enum CoverSize {
+    #[serde(rename = "128")]
+    Small,
+    #[serde(rename = "256")]
+    Medium,
+    #[serde(rename = "512")]
+    Large,
+    #[default]
+    #[serde(rename = "original")]
+    Original,
+}
+
+impl CoverSize {
+    /// Edge length of the square the cover is fit into, `None` for the original image.
+    pub fn pixels(self) -> Option<u32> {
+        match self {
+            CoverSize::Small => Some(128),
+            CoverSize::Medium => Some(256),
+            CoverSize::Large => Some(512),
+            CoverSize::Original => None,
+        }
+    }
+
+    /// Smallest size that covers `pixels`, for clients that request arbitrary sizes.
+    pub fn fit(pixels: u32) -> Self {
+        match pixels {
+            0..=128 => CoverSize::Small,
+            129..=256 => CoverSize::Medium,
+            257..=512 => CoverSize::Large,
+            _ => CoverSize::Original,
+        }
+    }
+}
+
+/// Formats resized covers are encoded in.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CoverFormat {
+    Webp,
+    Jpeg,
+}
+
+impl CoverFormat {
+    /// Pick WebP if the client accepts it, JPEG otherwise.
+    pub fn from_accept(accept: Option<&str>) -> Self {
+        if accept.is_some_and(|accept| accept.contains("image/webp")) {
+            CoverFormat::Webp
+        } else {
+            CoverFormat::Jpeg
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            CoverFormat::Webp => "webp",
+            CoverFormat::Jpeg => "jpg",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            CoverFormat::Webp => "image/webp",
+            CoverFormat::Jpeg => "image/jpeg",
+        }
+    }
+}
+
+/// Resize a cover to fit into a square of `size` pixels. Covers are never scaled up.
+pub fn resize_cover(data: &[u8], size: u32, format: CoverFormat) -> Result<Vec<u8>> {
+    let image = image::load_from_memory(data).context("Failed to decode cover image")?;
+    let image = if image.width() > size || image.height() > size {
+        image.thumbnail(size, size)
+    } else {
+        image
+    };
+
+    match format {
+        CoverFormat::Jpeg => encode_jpeg(&image),
+        CoverFormat::Webp => encode_webp(&image),
+    }
+}
+
+fn encode_jpeg(image: &DynamicImage) -> Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    let image = image.to_rgb8();
+    JpegEncoder::new_with_quality(&mut buffer, JPEG_QUALITY)
+        .write_image(
+            &image,
+            image.width(),
+            image.height(),
+            ExtendedColorType::Rgb8,
+        )
+        .context("Failed to encode cover image")?;
+
+    Ok(buffer)
+}
+
+/// Encode as lossy WebP with libwebp, the image crate only encodes lossless WebP, which is
+/// larger than the JPEG.
+fn encode_webp(image: &DynamicImage) -> Result<Vec<u8>> {
+    let image = image.to_rgb8();
+    let webp = webp::Encoder::from_rgb(&image, image.width(), image.height())
+        .encode_simple(false, WEBP_QUALITY)
+        .map_err(|err| anyhow::anyhow!("Failed to encode cover image: {err:?}"))?;
+
+    Ok(webp.to_vec())
+}
+
+/// Get a resized cover in `format`, creating it in the image store on first request.
+pub async fn cached_cover_variant(
+    images: &ImageStore,
+    hash: &str,
+    data: Vec<u8>,
+    size: u32,
+    format: CoverFormat,
+) -> Result<Vec<u8>> {
+    let path = images.variant_path(hash, &format!("{size}.{}", format.extension()))?;
+
+    if let Ok(variant) = tokio::fs::read(&path).await {
+        return Ok(variant);
+    }
+
+    let variant = tokio::task::spawn_blocking(move || resize_cover(&data, size, format))
+        .await
+        .context("Cover resize task panicked")??;
+    write_atomic(&path, &variant).await?;
+
+    Ok(variant)
+}
+
+/// Dominant color of a cover as CSS hex color, e.g. `#1a2b3c`.
+/// Uses the average color of the image, which is good enough for a placeholder.
+pub fn dominant_color(data: &[u8]) -> Result<String> {
+    let image = image::load_from_memory(data).context("Failed to decode cover image")?;
+    let pixel = *image.thumbnail_exact(1, 1).to_rgb8().get_pixel(0, 0);
+
+    Ok(format!("#{:02x}{:02x}{:02x}", pixel[0], pixel[1], pixel[2]))
+}
+
+/// Read an image of a book from the image store.
+pub async fn get_book_image(
+    database: &Database,
+    images: &ImageStore,
+    book_id: i64,
+    kind: ImageKind,
+) -> Result<Option<Vec<u8>>> {
+    match database.get_book_image(book_id, kind).await? {
+        Some(hash) => Ok(Some(images.read(&hash).await?)),
+        None => Ok(None),
+    }
+}
+
+/// Remove an image from the store once no book references it anymore.
+pub async fn remove_unused_image(database: &Database, images: &ImageStore, hash: &str) {
+    match database.is_image_referenced(hash).await {
+        Ok(false) => {
+            if let Err(err) = images.remove(hash).await {
+                tracing::warn!("Failed to remove image {}: {:?}", hash, err);
+            }
+        }
+        Ok(true) => {}
+        Err(err) => tracing::error!("{:?}", err),
+    }
+}
+
+/// Move covers stored as BLOB in the database into the image store.
+/// Shrinks the database afterwards, as SQLite doesn't release the freed pages on its own.
+pub async fn move_cover_blobs(database: &Database, images: &ImageStore) -> Result<()> {
+    let books = database.get_books_with_cover_blob().await?;
+    if books.is_empty() {
+        return Ok(());
+    }
+
+    tracing::info!("Moving {} covers into the image store", books.len());
+    for book_id in books {
+        let Some(cover) = database.get_book_cover(book_id).await? else {
+            continue;
+        };
+
+        let hash = images.store(&cover).await?;
+        database.move_cover_blob(book_id, &hash).await?;
+    }
+
+    database.vacuum().await
+}
+
+/// Compute the dominant color for books that have a front cover but no color yet.
+pub async fn backfill_cover_colors(database: Database, images: ImageStore) {
+    let books = match database.get_books_without_cover_color().await {
+        Ok(books) => books,
+        Err(err) => {
+            tracing::error!("{:?}", err);
+            return;
+        }
+    };
+
+    for book_id in books {
+        let Ok(Some(cover)) = get_book_image(&database, &images, book_id, ImageKind::Front).await
+        else {
+            continue;
+        };
+
+        let color = tokio::task::spawn_blocking(move || dominant_color(&cover)).await;
+        match color {
+            Ok(Ok(color)) => {
+                if let Err(err) = database.set_cover_color(book_id, Some(&color)).await {
+                    tracing::error!("{:?}", err);
+                }
+            }
+            _ => tracing::warn!("Failed to compute cover color of book {}", book_id),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -58,6 +412,184 @@ mod tests {
 
     const TEST_IMAGE_PATH: &str = "placeholder-cover.jpg";
 
+    // Sanitized covers are re-encoded, so only the dimensions stay the same.
+    fn assert_same_image(result: &[u8], original: &[u8]) {
+        let result = image::load_from_memory(result).unwrap();
+        let original = image::load_from_memory(original).unwrap();
+        assert_eq!(
+            (result.width(), result.height()),
+            (original.width(), original.height())
+        );
+    }
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut data = Vec::new();
+        DynamicImage::new_rgb8(width, height)
+            .write_to(&mut Cursor::new(&mut data), ImageFormat::Png)
+            .unwrap();
+        data
+    }
+
+    #[tokio::test]
+    async fn test_sanitize_cover_reencodes_as_jpeg() {
+        // Test case: Verify that sanitize_cover turns a valid image into a JPEG
+        let result = sanitize_cover(png(300, 400)).await.unwrap();
+
+        assert_eq!(detect_image_type(&result).unwrap().extension, "jpg");
+        assert_same_image(&result, &png(300, 400));
+    }
+
+    #[tokio::test]
+    async fn test_sanitize_cover_scales_down() {
+        // Test case: Verify that sanitize_cover scales large covers down
+        let result = sanitize_cover(png(4096, 1024)).await.unwrap();
+
+        let image = image::load_from_memory(&result).unwrap();
+        assert_eq!((image.width(), image.height()), (2048, 512));
+    }
+
+    #[tokio::test]
+    async fn test_sanitize_cover_rejects_dimensions() {
+        // Test case: Verify that sanitize_cover rejects images with too many pixels per side
+        let result = sanitize_cover(png(MAX_COVER_DIMENSION + 1, 1)).await;
+
+        assert!(matches!(
+            result,
+            Err(CoverRejection::TooManyPixels { width: 10_001, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_sanitize_cover_rejects_size() {
+        // Test case: Verify that sanitize_cover rejects covers above the byte limit
+        let result = sanitize_cover(vec![0; MAX_COVER_BYTES as usize + 1]).await;
+
+        assert!(matches!(result, Err(CoverRejection::TooLarge { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_sanitize_cover_rejects_malformed() {
+        // Test case: Verify that sanitize_cover rejects unknown and truncated images
+        let result = sanitize_cover(b"not an image".to_vec()).await;
+        assert!(matches!(result, Err(CoverRejection::UnsupportedFormat)));
+
+        let mut data = png(64, 64);
+        data.truncate(40);
+        let result = sanitize_cover(data).await;
+        assert!(matches!(result, Err(CoverRejection::Malformed(_))));
+    }
+
+    #[tokio::test]
+    async fn test_get_cover_bytes_with_invalid_image() {
+        // Test case: FieldData claims to be an image but isn't one
+        let field_data = FieldData {
+            contents: Bytes::from("not an image"),
+            metadata: axum_typed_multipart::FieldMetadata {
+                content_type: Some("image/jpeg".to_string()),
+                ..Default::default()
+            },
+        };
+
+        let result = get_cover_bytes(field_data).await;
+        assert!(matches!(result, Err(ApiError::InvalidCoverImage(_))));
+    }
+
+    #[test]
+    fn test_cover_size_fit() {
+        // Test case: Verify that fit picks the smallest size covering the requested pixels
+        assert_eq!(CoverSize::fit(64).pixels(), Some(128));
+        assert_eq!(CoverSize::fit(256).pixels(), Some(256));
+        assert_eq!(CoverSize::fit(300).pixels(), Some(512));
+        assert_eq!(CoverSize::fit(1024).pixels(), None);
+    }
+
+    #[test]
+    fn test_resize_cover() {
+        // Test case: Verify that resize_cover fits the cover into the requested size
+        let image_data = fs::read(TEST_IMAGE_PATH).unwrap();
+
+        let result = resize_cover(&image_data, 128, CoverFormat::Jpeg).unwrap();
+        let image = image::load_from_memory(&result).unwrap();
+        assert!(image.width() <= 128 && image.height() <= 128);
+        assert!(image.width() == 128 || image.height() == 128);
+        assert_eq!(detect_image_type(&result).unwrap().extension, "jpg");
+
+        let result = resize_cover(&image_data, 128, CoverFormat::Webp).unwrap();
+        let image = image::load_from_memory(&result).unwrap();
+        assert!(image.width() == 128 || image.height() == 128);
+        assert_eq!(detect_image_type(&result).unwrap().extension, "webp");
+    }
+
+    #[test]
+    fn test_resize_cover_smaller() {
+        // Test case: Verify that resized covers are smaller than the source
+        let image_data = fs::read(TEST_IMAGE_PATH).unwrap();
+
+        for size in [128, 256, 512] {
+            for format in [CoverFormat::Jpeg, CoverFormat::Webp] {
+                let result = resize_cover(&image_data, size, format).unwrap();
+                assert!(result.len() < image_data.len());
+            }
+        }
+    }
+
+    #[test]
+    fn test_resize_cover_no_upscale() {
+        // Test case: Verify that resize_cover doesn't scale up small covers
+        let image = DynamicImage::new_rgb8(32, 16);
+        let data = encode_jpeg(&image).unwrap();
+
+        let result = resize_cover(&data, 512, CoverFormat::Webp).unwrap();
+        let image = image::load_from_memory(&result).unwrap();
+        assert_eq!((image.width(), image.height()), (32, 16));
+    }
+
+    #[test]
+    fn test_resize_cover_invalid() {
+        // Test case: Verify that resize_cover fails for data that isn't an image
+        let result = resize_cover(b"not an image", 128, CoverFormat::Jpeg);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_dominant_color() {
+        // Test case: Verify that dominant_color returns the color of a single colored image
+        let image =
+            DynamicImage::ImageRgb8(image::RgbImage::from_pixel(8, 8, image::Rgb([255, 0, 128])));
+        let mut data = Vec::new();
+        image
+            .write_to(&mut Cursor::new(&mut data), ImageFormat::Png)
+            .unwrap();
+
+        assert_eq!(dominant_color(&data).unwrap(), "#ff0080");
+    }
+
+    #[test]
+    fn test_cover_format_from_accept() {
+        // Test case: Verify that WebP is only picked if the client accepts it
+        assert_eq!(
+            CoverFormat::from_accept(Some("image/avif,image/webp,*/*")),
+            CoverFormat::Webp
+        );
+        assert_eq!(CoverFormat::from_accept(Some("image/*")), CoverFormat::Jpeg);
+        assert_eq!(CoverFormat::from_accept(None), CoverFormat::Jpeg);
+    }
+
+    #[test]
+    fn test_detect_image_type() {
+        // Test case: Verify that detect_image_type recognizes jpeg, png and webp
+        let jpeg = fs::read(TEST_IMAGE_PATH).unwrap();
+        assert_eq!(detect_image_type(&jpeg).unwrap().content_type, "image/jpeg");
+
+        let png = b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR";
+        assert_eq!(detect_image_type(png).unwrap().extension, "png");
+
+        let webp = b"RIFF\0\0\0\0WEBPVP8 ";
+        assert_eq!(detect_image_type(webp).unwrap().extension, "webp");
+
+        assert!(detect_image_type(b"not an image").is_none());
+    }
+
     #[tokio::test]
     async fn test_get_cover_bytes_with_image() {
         // Test case: FieldData contains a valid image
@@ -71,7 +603,7 @@ mod tests {
         };
 
         let result = get_cover_bytes(field_data).await.unwrap();
-        assert_eq!(result, image_data);
+        assert_same_image(&result, &image_data);
     }
 
     #[tokio::test]
@@ -86,7 +618,7 @@ mod tests {
         };
 
         let result = get_cover_bytes(field_data).await;
-        assert!(result.is_err());
+        assert!(matches!(result, Err(ApiError::InvalidCoverImage(_))));
     }
 
     #[tokio::test]
@@ -103,7 +635,7 @@ mod tests {
         };
 
         let result = get_cover_bytes(field_data).await.unwrap();
-        assert_eq!(result, image_data);
+        assert_same_image(&result, &image_data);
     }
 
     #[tokio::test]
@@ -120,7 +652,7 @@ mod tests {
         };
 
         let result = get_cover_bytes(field_data).await.unwrap();
-        assert_eq!(result, image_data);
+        assert_same_image(&result, &image_data);
     }
 
     #[tokio::test]
@@ -147,4 +679,29 @@ mod tests {
         let result = get_cover_bytes(field_data).await;
         assert!(result.is_err());
     }
+
+    #[tokio::test]
+    async fn test_get_cover_bytes_with_too_large_file_path() {
+        // Test case: FieldData contains a path to a file larger than the cover limit
+        let temp_file = NamedTempFile::new().unwrap();
+        temp_file.as_file().set_len(MAX_COVER_BYTES + 1).unwrap();
+        let file_path = temp_file.path().to_str().unwrap();
+
+        let field_data = FieldData {
+            contents: Bytes::from(format!("file://{file_path}")),
+            metadata: axum_typed_multipart::FieldMetadata::default(),
+        };
+
+        let result = get_cover_bytes(field_data).await;
+        let Err(ApiError::InvalidCoverImage(reason)) = result else {
+            panic!("Expected the cover to be rejected");
+        };
+        assert_eq!(
+            reason,
+            CoverRejection::TooLarge {
+                size: MAX_COVER_BYTES + 1
+            }
+            .to_string()
+        );
+    }
 }
@@ -0,0 +1,271 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use super::cover::{read_image, sanitize_cover};
+use super::ffmpeg::ffmpeg_extract_cover;
+use crate::fs::{
+    image_store::ImageStore,
+    list_fs::{AUDIO_EXTENSIONS, IMAGE_EXTENSIONS},
+    path::{FileSchemes, resolve_scheme_path},
+};
+
+/// File names, without extension, that mark an image as cover. Earlier names win.
+const COVER_NAMES: [&str; 3] = ["cover", "folder", "front"];
+
+/// Maximum number of audio files embedded covers are extracted from.
+/// Books split into many files usually embed the same cover in every file.
+const MAX_EMBEDDED_COVER_FILES: usize = 5;
+
+fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| extensions.contains(&extension.to_lowercase().as_str()))
+}
+
+/// Position of the file name in `COVER_NAMES`, images with other names rank last.
+fn cover_name_rank(path: &Path) -> usize {
+    let stem = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+
+    COVER_NAMES
+        .iter()
+        .position(|name| *name == stem)
+        .unwrap_or(COVER_NAMES.len())
+}
+
+/// Images in a directory that could be a cover, best candidates first.
+/// Images named like a cover come first, the others follow from largest to smallest.
+pub async fn folder_covers(directory: &Path) -> Vec<PathBuf> {
+    let mut images = Vec::new();
+
+    let Ok(mut reader) = tokio::fs::read_dir(directory).await else {
+        return Vec::new();
+    };
+    while let Ok(Some(entry)) = reader.next_entry().await {
+        let path = entry.path();
+        if !has_extension(&path, &IMAGE_EXTENSIONS) {
+            continue;
+        }
+
+        match entry.metadata().await {
+            Ok(metadata) if metadata.is_file() => {
+                images.push((cover_name_rank(&path), metadata.len(), path))
+            }
+            _ => continue,
+        }
+    }
+
+    images.sort_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(&a.1)));
+    images.into_iter().map(|(_, _, path)| path).collect()
+}
+
+/// Audio files in the directory of `path`, starting with `path` itself.
+/// Used as the files of a book when only a single file is known.
+pub async fn sibling_audio_files(path: &Path) -> Vec<PathBuf> {
+    let mut files = vec![path.to_path_buf()];
+    let Some(directory) = path.parent() else {
+        return files;
+    };
+
+    let Ok(mut reader) = tokio::fs::read_dir(directory).await else {
+        return files;
+    };
+    let mut siblings = Vec::new();
+    while let Ok(Some(entry)) = reader.next_entry().await {
+        let sibling = entry.path();
+        if sibling != path && has_extension(&sibling, &AUDIO_EXTENSIONS) {
+            siblings.push(sibling);
+        }
+    }
+
+    siblings.sort();
+    files.extend(siblings);
+    files
+}
+
+/// Directories of the files, in order of first appearance.
+fn book_directories(files: &[PathBuf]) -> Vec<&Path> {
+    let mut directories = Vec::new();
+    for directory in files.iter().filter_map(|file| file.parent()) {
+        if !directories.contains(&directory) {
+            directories.push(directory);
+        }
+    }
+
+    directories
+}
+
+/// Find cover candidates for the audio files of a book, best candidates first.
+/// Images next to the files come before artwork embedded in the files. Candidates are
+/// `file://` and `extracted-file://` paths as accepted by `get_cover_bytes`.
+pub async fn discover_covers(files: &[PathBuf]) -> Vec<String> {
+    let mut candidates = Vec::new();
+
+    for directory in book_directories(files) {
+        let images = folder_covers(directory).await;
+        candidates.extend(
+            images
+                .into_iter()
+                .map(|image| format!("file://{}", image.display())),
+        );
+    }
+
+    for file in files.iter().take(MAX_EMBEDDED_COVER_FILES) {
+        match ffmpeg_extract_cover(file).await {
+            Ok(cover) => candidates.extend(cover),
+            Err(err) => tracing::debug!("Failed to extract cover: {:?}", err),
+        }
+    }
+
+    dedup_covers(candidates).await
+}
+
+/// Pick a cover for a book that was registered without one.
+/// Stops at the first valid image, so embedded artwork is only extracted if needed.
+pub async fn discover_cover(files: &[PathBuf]) -> Option<Vec<u8>> {
+    for directory in book_directories(files) {
+        for image in folder_covers(directory).await {
+            if let Some(cover) = read_valid_cover(&format!("file://{}", image.display())).await {
+                return Some(cover);
+            }
+        }
+    }
+
+    for file in files.iter().take(MAX_EMBEDDED_COVER_FILES) {
+        if let Ok(Some(candidate)) = ffmpeg_extract_cover(file).await {
+            let cover = read_valid_cover(&candidate).await;
+            remove_extracted_cover(&candidate).await;
+            if cover.is_some() {
+                return cover;
+            }
+        }
+    }
+
+    None
+}
+
+/// Read and sanitize a candidate, `None` if it isn't a usable cover.
+async fn read_valid_cover(candidate: &str) -> Option<Vec<u8>> {
+    let cover = read_image(candidate).ok()?;
+    match sanitize_cover(cover).await {
+        Ok(cover) => Some(cover),
+        Err(rejection) => {
+            tracing::debug!("Skipping cover {}: {}", candidate, rejection);
+            None
+        }
+    }
+}
+
+/// Remove candidates with the same content as an earlier candidate.
+async fn dedup_covers(candidates: Vec<String>) -> Vec<String> {
+    let mut seen = HashSet::new();
+    let mut unique = Vec::new();
+
+    for candidate in candidates {
+        let Ok(data) = read_image(&candidate) else {
+            continue;
+        };
+
+        if seen.insert(ImageStore::hash(&data)) {
+            unique.push(candidate);
+        } else {
+            remove_extracted_cover(&candidate).await;
+        }
+    }
+
+    unique
+}
+
+/// Delete a cover extracted into the temporary directory, other candidates are left alone.
+async fn remove_extracted_cover(candidate: &str) {
+    if let Ok((FileSchemes::ExtractedFile, path)) = resolve_scheme_path(candidate) {
+        tokio::fs::remove_file(path).await.ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::fs;
+
+    #[tokio::test]
+    async fn test_folder_covers_order() {
+        // Test case: Verify that named covers come first and other images are ordered by size
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path();
+        fs::write(path.join("small.png"), b"1").await.unwrap();
+        fs::write(path.join("large.webp"), b"123").await.unwrap();
+        fs::write(path.join("Folder.jpg"), b"1").await.unwrap();
+        fs::write(path.join("cover.JPG"), b"1").await.unwrap();
+        fs::write(path.join("notes.txt"), b"12345").await.unwrap();
+
+        let result = folder_covers(path).await;
+
+        assert_eq!(
+            result,
+            vec![
+                path.join("cover.JPG"),
+                path.join("Folder.jpg"),
+                path.join("large.webp"),
+                path.join("small.png"),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sibling_audio_files() {
+        // Test case: Verify that the probed file comes first, followed by other audio files
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path();
+        fs::write(path.join("1.mp3"), b"").await.unwrap();
+        fs::write(path.join("2.mp3"), b"").await.unwrap();
+        fs::write(path.join("3.m4a"), b"").await.unwrap();
+        fs::write(path.join("cover.jpg"), b"").await.unwrap();
+
+        let result = sibling_audio_files(&path.join("2.mp3")).await;
+
+        assert_eq!(
+            result,
+            vec![path.join("2.mp3"), path.join("1.mp3"), path.join("3.m4a")]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_discover_covers_dedup() {
+        // Test case: Verify that images with the same content are only offered once
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path();
+        fs::write(path.join("cover.jpg"), b"same").await.unwrap();
+        fs::write(path.join("folder.jpg"), b"same").await.unwrap();
+        fs::write(path.join("back.jpg"), b"other").await.unwrap();
+
+        let result = discover_covers(&[path.join("book.mp3")]).await;
+
+        assert_eq!(
+            result,
+            vec![
+                format!("file://{}", path.join("cover.jpg").display()),
+                format!("file://{}", path.join("back.jpg").display()),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_discover_cover() {
+        // Test case: Verify that discover_cover reads the best valid folder image
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path();
+        let image = fs::read("placeholder-cover.jpg").await.unwrap();
+        fs::write(path.join("cover.jpg"), b"not an image")
+            .await
+            .unwrap();
+        fs::write(path.join("folder.jpg"), &image).await.unwrap();
+
+        let result = discover_cover(&[path.join("book.mp3")]).await.unwrap();
+
+        assert!(image::load_from_memory(&result).is_ok());
+    }
+}
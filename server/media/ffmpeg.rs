@@ -1,12 +1,13 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::{collections::HashMap, process::Command};
 
 use anyhow::{Context, Result, bail};
 use serde::{Deserialize, Serialize};
 
-use super::cover::RANDOM_FILE_NAME_LENGTH;
+use super::cover::{RANDOM_FILE_NAME_LENGTH, detect_image_type};
 use crate::auth::random::random_string;
-use crate::fs::storage::TMP_PATH;
+use crate::database::file::File;
+use crate::fs::storage::{ScratchDirectory, TMP_PATH};
 
 pub fn is_ffmpeg_installed() -> Result<()> {
     let ffmpeg_output = Command::new("ffmpeg").arg("-version").output()?;
@@ -108,6 +109,14 @@ pub async fn ffprobe_book_details(path: &Path) -> Result<FileInfo> {
     }
 
     // Extract cover image.
+    info.cover = ffmpeg_extract_cover(path).await?;
+
+    Ok(info)
+}
+
+/// Extract the embedded cover of an audio file into the temporary directory.
+/// Returns an `extracted-file://` path to the cover, `None` if the file has no cover.
+pub async fn ffmpeg_extract_cover(path: &Path) -> Result<Option<String>> {
     let tmp_file_name = random_string(RANDOM_FILE_NAME_LENGTH);
     // ffmpeg -i ${filePath} -v quiet -an -vcodec copy data/tmp/${random}.jpg
     let output = tokio::process::Command::new("ffmpeg")
@@ -121,11 +130,12 @@ pub async fn ffprobe_book_details(path: &Path) -> Result<FileInfo> {
         .arg(TMP_PATH.join(format!("{tmp_file_name}.jpg")))
         .output()
         .await?;
+
     if output.status.success() {
-        info.cover = Some(format!("extracted-file://{tmp_file_name}.jpg"));
+        Ok(Some(format!("extracted-file://{tmp_file_name}.jpg")))
+    } else {
+        Ok(None)
     }
-
-    Ok(info)
 }
 
 pub async fn ffprobe_duration(path: &Path) -> Result<f64> {
@@ -233,6 +243,366 @@ pub async fn ffprobe_chapters(path: &Path) -> Result<Vec<Chapters>> {
         .collect::<Result<Vec<Chapters>>>()
 }
 
+/// Derive chapter markers from file boundaries.
+/// Every file becomes one chapter spanning its duration on the book timeline.
+pub fn chapters_from_files(files: &[File]) -> Vec<Chapters> {
+    let mut start = 0.0;
+    files
+        .iter()
+        .map(|file| {
+            let chapter = Chapters {
+                name: file.name.clone(),
+                start,
+                end: start + file.duration,
+            };
+            start = chapter.end;
+            chapter
+        })
+        .collect()
+}
+
+/// Escape special characters of a value in the ffmetadata format.
+/// See https://ffmpeg.org/ffmpeg-formats.html#Metadata-2
+fn escape_ffmetadata(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '=' | ';' | '#' | '\\' | '\n') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Tags naming title and author of a book.
+pub fn book_tags(title: &str, author: &str) -> Vec<(&'static str, String)> {
+    vec![
+        ("title", title.to_string()),
+        ("album", title.to_string()),
+        ("artist", author.to_string()),
+        ("album_artist", author.to_string()),
+    ]
+}
+
+/// Create an ffmetadata file containing title, author and chapters of a book.
+pub fn ffmetadata(title: &str, author: &str, chapters: &[Chapters]) -> String {
+    let mut metadata = ";FFMETADATA1\n".to_string();
+    for (key, value) in book_tags(title, author) {
+        metadata.push_str(&format!("{key}={}\n", escape_ffmetadata(&value)));
+    }
+
+    // Chapter markers are written with millisecond precision.
+    for chapter in chapters {
+        metadata.push_str(&format!(
+            "\n[CHAPTER]\nTIMEBASE=1/1000\nSTART={}\nEND={}\ntitle={}\n",
+            (chapter.start * 1000.0).round() as i64,
+            (chapter.end * 1000.0).round() as i64,
+            escape_ffmetadata(&chapter.name)
+        ));
+    }
+
+    metadata
+}
+
+/// Write a cover image into a scratch directory.
+async fn write_scratch_cover(scratch_path: &Path, cover: &[u8]) -> Result<PathBuf> {
+    let extension = detect_image_type(cover).map_or("jpg", |image| image.extension);
+    let path = scratch_path.join(format!("cover.{extension}"));
+    tokio::fs::write(&path, cover).await?;
+    Ok(path)
+}
+
+/// Create an input list for ffmpeg's concat demuxer.
+fn concat_list(files: &[PathBuf]) -> String {
+    files
+        .iter()
+        .map(|file| {
+            let path = file.to_string_lossy().replace('\'', r"'\''");
+            format!("file '{path}'\n")
+        })
+        .collect()
+}
+
+/// Extensions of files that already contain audio which can be copied into an M4B container.
+const M4B_COPY_EXTENSIONS: [&str; 2] = ["m4a", "m4b"];
+
+/// Merge audio files into a single M4B file.
+/// `metadata` is an ffmetadata file as created by `ffmetadata` and is used for tags and chapters.
+/// Files that already contain AAC audio are copied, everything else is re-encoded. Applying a
+/// `gain` in dB always re-encodes. ffmpeg is killed if the future is dropped.
+pub async fn ffmpeg_merge_m4b(
+    files: &[PathBuf],
+    metadata: &str,
+    cover: Option<&[u8]>,
+    gain: Option<f64>,
+    output: &Path,
+) -> Result<()> {
+    let scratch = ScratchDirectory::create().await?;
+    merge_m4b(files, metadata, cover, gain, output, scratch.path()).await
+}
+
+async fn merge_m4b(
+    files: &[PathBuf],
+    metadata: &str,
+    cover: Option<&[u8]>,
+    gain: Option<f64>,
+    output: &Path,
+    scratch_path: &Path,
+) -> Result<()> {
+    let list_path = scratch_path.join("files.txt");
+    tokio::fs::write(&list_path, concat_list(files)).await?;
+
+    let metadata_path = scratch_path.join("metadata.txt");
+    tokio::fs::write(&metadata_path, metadata).await?;
+
+    // ffmpeg -v error -y -f concat -safe 0 -i ${list} -i ${metadata} [-i ${cover}] -map 0:a
+    //        -map_metadata 1 -map_chapters 1 [-map 2:v -c:v mjpeg -disposition:v attached_pic]
+    //        [-af volume=${gain}dB] -c:a ${copy|aac} -f mp4 -movflags +faststart ${output}
+    let mut command = tokio::process::Command::new("ffmpeg");
+    command
+        .arg("-v")
+        .arg("error")
+        .arg("-y")
+        .arg("-f")
+        .arg("concat")
+        .arg("-safe")
+        .arg("0")
+        .arg("-i")
+        .arg(&list_path)
+        .arg("-i")
+        .arg(&metadata_path);
+
+    if let Some(cover) = cover {
+        let cover_path = write_scratch_cover(scratch_path, cover).await?;
+        command.arg("-i").arg(cover_path);
+    }
+
+    command
+        .arg("-map")
+        .arg("0:a")
+        .arg("-map_metadata")
+        .arg("1")
+        .arg("-map_chapters")
+        .arg("1");
+
+    if cover.is_some() {
+        command
+            .arg("-map")
+            .arg("2:v")
+            .arg("-c:v")
+            .arg("mjpeg")
+            .arg("-disposition:v")
+            .arg("attached_pic");
+    }
+
+    if let Some(gain) = gain {
+        command.arg("-af").arg(format!("volume={gain:.2}dB"));
+    }
+
+    let copy_audio = gain.is_none()
+        && files.iter().all(|file| {
+            file.extension()
+                .and_then(|extension| extension.to_str())
+                .is_some_and(|extension| M4B_COPY_EXTENSIONS.contains(&extension))
+        });
+    let output = command
+        .arg("-c:a")
+        .arg(if copy_audio { "copy" } else { "aac" })
+        .arg("-f")
+        .arg("mp4")
+        .arg("-movflags")
+        .arg("+faststart")
+        .arg(output)
+        .kill_on_drop(true)
+        .output()
+        .await?;
+
+    if !output.status.success() {
+        bail!(
+            "ffmpeg failed to merge files: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    Ok(())
+}
+
+/// Extensions of files that ffmpeg can embed cover images into.
+const COVER_EXTENSIONS: [&str; 4] = ["mp3", "m4a", "m4b", "flac"];
+
+/// Metadata written into an audio file by `ffmpeg_write_metadata`.
+pub struct MetadataUpdate<'a> {
+    /// ffmetadata file as created by `ffmetadata`, only its chapters are used.
+    pub metadata: &'a str,
+    /// Tags that replace the tags of the same name, all other tags of the file are kept.
+    pub tags: Vec<(&'static str, String)>,
+    pub cover: Option<&'a [u8]>,
+    /// Replace the chapters of the file with the chapters in `metadata`.
+    pub replace_chapters: bool,
+}
+
+/// Write metadata into an audio file by remuxing it to `output`.
+/// Audio streams are copied as-is, nothing is re-encoded. Existing cover images are kept if no
+/// new cover is given or the format doesn't support them. ffmpeg is killed if the future is
+/// dropped.
+pub async fn ffmpeg_write_metadata(
+    path: &Path,
+    output: &Path,
+    update: &MetadataUpdate<'_>,
+) -> Result<()> {
+    let scratch = ScratchDirectory::create().await?;
+    write_metadata(path, output, update, scratch.path()).await
+}
+
+async fn write_metadata(
+    path: &Path,
+    output: &Path,
+    update: &MetadataUpdate<'_>,
+    scratch_path: &Path,
+) -> Result<()> {
+    let metadata_path = scratch_path.join("metadata.txt");
+    tokio::fs::write(&metadata_path, update.metadata).await?;
+
+    let supports_cover = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| COVER_EXTENSIONS.contains(&extension));
+    let cover = update.cover.filter(|_| supports_cover);
+
+    // ffmpeg -v error -y -i ${path} -i ${metadata} [-i ${cover}] -map 0:a
+    //        [-map 2:v -c:v ${copy|mjpeg} -disposition:v attached_pic | -map 0:v?]
+    //        -map_metadata 0 -map_chapters ${1|0} -c:a copy [-metadata ${tag}] ${output}
+    let mut command = tokio::process::Command::new("ffmpeg");
+    command
+        .kill_on_drop(true)
+        .arg("-v")
+        .arg("error")
+        .arg("-y")
+        .arg("-i")
+        .arg(path)
+        .arg("-i")
+        .arg(&metadata_path);
+
+    match cover {
+        Some(cover) => {
+            let cover_path = write_scratch_cover(scratch_path, cover).await?;
+            // JPEG and PNG can be embedded as they are, anything else is converted to JPEG.
+            let codec = match detect_image_type(cover) {
+                Some(image) if image.extension != "webp" => "copy",
+                _ => "mjpeg",
+            };
+            command
+                .arg("-i")
+                .arg(cover_path)
+                .arg("-map")
+                .arg("0:a")
+                .arg("-map")
+                .arg("2:v")
+                .arg("-c:v")
+                .arg(codec)
+                .arg("-disposition:v")
+                .arg("attached_pic");
+        }
+        None => {
+            command
+                .arg("-map")
+                .arg("0:a")
+                .arg("-map")
+                .arg("0:v?")
+                .arg("-c:v")
+                .arg("copy");
+        }
+    }
+
+    // Keep the existing tags of the file, the edited ones are replaced below.
+    command
+        .arg("-map_metadata")
+        .arg("0")
+        .arg("-map_chapters")
+        .arg(if update.replace_chapters { "1" } else { "0" })
+        .arg("-c:a")
+        .arg("copy");
+
+    for (key, value) in &update.tags {
+        command.arg("-metadata").arg(format!("{key}={value}"));
+    }
+
+    let output = command.arg(output).output().await?;
+
+    if !output.status.success() {
+        bail!(
+            "ffmpeg failed to write metadata: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    Ok(())
+}
+
+/// Bitrate of transcoded segments, enough for speech.
+const SEGMENT_BITRATE: &str = "96k";
+
+/// Find the part of a book's time span `start..end` in its files.
+/// Returns the file containing `start`, the offset into that file and the duration, clamped to
+/// the end of the file. Segments spanning multiple files are cut at the file boundary.
+pub fn locate_segment(files: &[File], start: f64, end: f64) -> Option<(&File, f64, f64)> {
+    let mut file_start = 0.0;
+    for file in files {
+        let file_end = file_start + file.duration;
+        if start < file_end {
+            let offset = (start - file_start).max(0.0);
+            let duration = end.min(file_end) - file_start - offset;
+            return (duration > 0.0).then_some((file, offset, duration));
+        }
+        file_start = file_end;
+    }
+
+    None
+}
+
+/// Transcode `duration` seconds of an audio file starting at `start` into an AAC M4A file.
+pub async fn ffmpeg_transcode_segment(
+    path: &Path,
+    start: f64,
+    duration: f64,
+    output: &Path,
+) -> Result<()> {
+    // ffmpeg -v error -y -ss ${start} -i ${path} -t ${duration} -vn -c:a aac -b:a 96k
+    //        -movflags +faststart -f mp4 ${output}
+    let output = tokio::process::Command::new("ffmpeg")
+        .arg("-v")
+        .arg("error")
+        .arg("-y")
+        .arg("-ss")
+        .arg(format!("{start:.3}"))
+        .arg("-i")
+        .arg(path)
+        .arg("-t")
+        .arg(format!("{duration:.3}"))
+        .arg("-vn")
+        .arg("-c:a")
+        .arg("aac")
+        .arg("-b:a")
+        .arg(SEGMENT_BITRATE)
+        .arg("-movflags")
+        .arg("+faststart")
+        .arg("-f")
+        .arg("mp4")
+        .arg(output)
+        .kill_on_drop(true)
+        .output()
+        .await?;
+
+    if !output.status.success() {
+        bail!(
+            "ffmpeg failed to transcode segment: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -350,4 +720,71 @@ title=End
         assert_eq!(result[1].start, 2.5);
         assert_eq!(result[1].end, 5.0);
     }
+    #[test]
+    fn test_chapters_from_files() {
+        // Test case: Verify that chapters_from_files creates one chapter per file
+        let files = ["One", "Two"]
+            .iter()
+            .enumerate()
+            .map(|(index, name)| File::test(index as i64 + 1, name, 10.0))
+            .collect::<Vec<_>>();
+
+        let result = chapters_from_files(&files);
+        assert_eq!(result.len(), 2);
+        assert_eq!(result[0].name, "One");
+        assert_eq!(result[0].start, 0.0);
+        assert_eq!(result[0].end, 10.0);
+        assert_eq!(result[1].name, "Two");
+        assert_eq!(result[1].start, 10.0);
+        assert_eq!(result[1].end, 20.0);
+    }
+
+    #[test]
+    fn test_locate_segment() {
+        // Test case: Verify that locate_segment finds the file and offset of a time span
+        let files = [10.0, 20.0]
+            .iter()
+            .enumerate()
+            .map(|(index, duration)| File::test(index as i64 + 1, &index.to_string(), *duration))
+            .collect::<Vec<_>>();
+
+        let (file, offset, duration) = locate_segment(&files, 2.0, 5.0).unwrap();
+        assert_eq!((file.id, offset, duration), (0, 2.0, 3.0));
+
+        let (file, offset, duration) = locate_segment(&files, 15.0, 25.0).unwrap();
+        assert_eq!((file.id, offset, duration), (1, 5.0, 10.0));
+
+        // Segments spanning files are cut at the end of the first file.
+        let (file, offset, duration) = locate_segment(&files, 8.0, 12.0).unwrap();
+        assert_eq!((file.id, offset, duration), (0, 8.0, 2.0));
+
+        assert!(locate_segment(&files, 30.0, 40.0).is_none());
+    }
+
+    #[test]
+    fn test_ffmetadata() {
+        // Test case: Verify that ffmetadata escapes values and writes chapters in milliseconds
+        let chapters = vec![Chapters {
+            name: "Part 1; Start".to_string(),
+            start: 0.0,
+            end: 2.5,
+        }];
+
+        let result = ffmetadata("A=B", "Author #1", &chapters);
+        assert!(result.starts_with(";FFMETADATA1\n"));
+        assert!(result.contains("title=A\\=B\n"));
+        assert!(result.contains("artist=Author \\#1\n"));
+        assert!(!result.contains("genre="));
+        assert!(result.contains("[CHAPTER]\nTIMEBASE=1/1000\nSTART=0\nEND=2500\n"));
+        assert!(result.contains("title=Part 1\\; Start\n"));
+    }
+
+    #[test]
+    fn test_concat_list() {
+        // Test case: Verify that concat_list quotes paths for the concat demuxer
+        let files = vec![PathBuf::from("/a.mp3"), PathBuf::from("/it's.mp3")];
+
+        let result = concat_list(&files);
+        assert_eq!(result, "file '/a.mp3'\nfile '/it'\\''s.mp3'\n");
+    }
 }
@@ -0,0 +1,249 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+/// How long the result of a finished job is kept for clients polling it.
+const FINISHED_JOB_LIFETIME: Duration = Duration::from_secs(60 * 60);
+
+/// Status of a background job as reported to clients.
+#[derive(Serialize, Clone, PartialEq, Debug)]
+#[serde(rename_all = "camelCase", tag = "status")]
+pub enum JobStatus {
+    Running,
+    Finished,
+    Failed { error: String },
+}
+
+struct Job {
+    status: JobStatus,
+    /// When the job finished, `None` while it is running.
+    finished: Option<Instant>,
+}
+
+/// Long running work like merging books, which would exceed proxy timeouts if the request waited
+/// for it. Jobs run in their own task, so they aren't cancelled when the client disconnects, and
+/// clients poll their status instead.
+/// Jobs are identified by a key, a job is never started twice at the same time.
+#[derive(Clone, Default)]
+pub struct Jobs {
+    jobs: Arc<Mutex<HashMap<String, Job>>>,
+    /// Locks of books whose files are rewritten, see `lock_book`.
+    books: Arc<Mutex<HashMap<i64, Arc<tokio::sync::Mutex<()>>>>>,
+    /// Notified whenever a job finishes, see `wait`.
+    finished: Arc<tokio::sync::Notify>,
+}
+
+impl Jobs {
+    /// Lock the files of a book, so different jobs like merging and writing metadata never
+    /// rewrite them at the same time. Waits until other jobs of the book released it.
+    pub async fn lock_book(&self, book_id: i64) -> tokio::sync::OwnedMutexGuard<()> {
+        let lock = {
+            let mut books = self
+                .books
+                .lock()
+                .expect("Book locks should not be poisoned");
+            // Locks only referenced by the map aren't held by any job.
+            books.retain(|_, lock| Arc::strong_count(lock) > 1);
+            books.entry(book_id).or_default().clone()
+        };
+
+        lock.lock_owned().await
+    }
+
+    /// Status of the job with `key`, `None` if there is none or its result expired.
+    pub fn status(&self, key: &str) -> Option<JobStatus> {
+        let jobs = self.jobs.lock().expect("Jobs lock should not be poisoned");
+        jobs.get(key).map(|job| job.status.clone())
+    }
+
+    /// Wait until the job with `key` is no longer running and return its status, `None` if there
+    /// is none or its result expired.
+    pub async fn wait(&self, key: &str) -> Option<JobStatus> {
+        loop {
+            // Register for the notification before checking, so a job finishing in between isn't
+            // missed.
+            let finished = self.finished.notified();
+            tokio::pin!(finished);
+            finished.as_mut().enable();
+
+            match self.status(key) {
+                Some(JobStatus::Running) => finished.await,
+                status => return status,
+            }
+        }
+    }
+
+    /// Remove the result of a finished job, so the next request starts it again.
+    pub fn forget(&self, key: &str) {
+        let mut jobs = self.jobs.lock().expect("Jobs lock should not be poisoned");
+        if jobs.get(key).is_some_and(|job| job.finished.is_some()) {
+            jobs.remove(key);
+        }
+    }
+
+    /// Start `job` in a background task, unless a job with the same key is still running.
+    /// Returns false if it is already running.
+    pub fn start<F>(&self, key: &str, job: F) -> bool
+    where
+        F: Future<Output = anyhow::Result<()>> + Send + 'static,
+    {
+        {
+            let mut jobs = self.jobs.lock().expect("Jobs lock should not be poisoned");
+            let now = Instant::now();
+            jobs.retain(|_, job| {
+                job.finished
+                    .is_none_or(|finished| now - finished < FINISHED_JOB_LIFETIME)
+            });
+
+            if jobs.get(key).is_some_and(|job| job.finished.is_none()) {
+                return false;
+            }
+            jobs.insert(
+                key.to_string(),
+                Job {
+                    status: JobStatus::Running,
+                    finished: None,
+                },
+            );
+        }
+
+        let this = self.clone();
+        let key = key.to_string();
+        tokio::spawn(async move {
+            // Run the job in its own task, so a panic is reported as failure instead of leaving
+            // the job running forever.
+            let status = match tokio::spawn(job).await {
+                Ok(Ok(())) => JobStatus::Finished,
+                Ok(Err(err)) => {
+                    tracing::error!("Job {} failed: {:?}", key, err);
+                    JobStatus::Failed {
+                        error: err.to_string(),
+                    }
+                }
+                Err(err) => {
+                    tracing::error!("Job {} panicked: {}", key, err);
+                    JobStatus::Failed {
+                        error: "Job panicked".to_string(),
+                    }
+                }
+            };
+
+            let mut jobs = this.jobs.lock().expect("Jobs lock should not be poisoned");
+            jobs.insert(
+                key,
+                Job {
+                    status,
+                    finished: Some(Instant::now()),
+                },
+            );
+            drop(jobs);
+            this.finished.notify_waiters();
+        });
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Wait until the job with `key` is no longer running.
+    async fn finished(jobs: &Jobs, key: &str) -> JobStatus {
+        loop {
+            match jobs.status(key) {
+                Some(JobStatus::Running) => tokio::time::sleep(Duration::from_millis(5)).await,
+                Some(status) => return status,
+                None => panic!("Job {key} not found"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_jobs() {
+        // Test case: Verify that jobs run once at a time and report their result
+        let jobs = Jobs::default();
+        let (sender, receiver) = tokio::sync::oneshot::channel::<()>();
+
+        assert!(jobs.start("merge", async move {
+            receiver.await?;
+            Ok(())
+        }));
+        assert_eq!(jobs.status("merge"), Some(JobStatus::Running));
+        assert!(!jobs.start("merge", async { Ok(()) }));
+
+        sender.send(()).unwrap();
+        assert_eq!(finished(&jobs, "merge").await, JobStatus::Finished);
+
+        // Finished jobs can be started again.
+        assert!(jobs.start("merge", async { anyhow::bail!("ffmpeg failed") }));
+        assert_eq!(
+            finished(&jobs, "merge").await,
+            JobStatus::Failed {
+                error: "ffmpeg failed".to_string()
+            }
+        );
+
+        jobs.forget("merge");
+        assert_eq!(jobs.status("merge"), None);
+        assert_eq!(jobs.status("other"), None);
+    }
+
+    #[tokio::test]
+    async fn test_wait() {
+        // Test case: Verify that waiting returns once the job finished
+        let jobs = Jobs::default();
+        let (sender, receiver) = tokio::sync::oneshot::channel::<()>();
+        assert!(jobs.start("download", async move {
+            receiver.await?;
+            Ok(())
+        }));
+
+        let waiting = tokio::spawn({
+            let jobs = jobs.clone();
+            async move { jobs.wait("download").await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!waiting.is_finished());
+
+        sender.send(()).unwrap();
+        let status = tokio::time::timeout(Duration::from_secs(1), waiting)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(status, Some(JobStatus::Finished));
+        assert_eq!(jobs.wait("other").await, None);
+    }
+
+    #[tokio::test]
+    async fn test_lock_book() {
+        // Test case: Verify that jobs of the same book wait for each other, other books don't
+        let jobs = Jobs::default();
+
+        let guard = jobs.lock_book(1).await;
+        let waiting = tokio::spawn({
+            let jobs = jobs.clone();
+            async move {
+                let _guard = jobs.lock_book(1).await;
+            }
+        });
+        tokio::time::timeout(Duration::from_secs(1), jobs.lock_book(2))
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!waiting.is_finished());
+
+        drop(guard);
+        tokio::time::timeout(Duration::from_secs(1), waiting)
+            .await
+            .unwrap()
+            .unwrap();
+        jobs.lock_book(3).await;
+        assert_eq!(jobs.books.lock().unwrap().keys().collect::<Vec<_>>(), [&3]);
+    }
+}
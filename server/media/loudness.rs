@@ -0,0 +1,207 @@
+use std::path::Path;
+
+use anyhow::{Context, Result, bail};
+
+use crate::database::{Database, file::File};
+
+/// Reference loudness in LUFS that the suggested gain aims for.
+/// ReplayGain 2.0 uses -18 LUFS as its reference level.
+pub const LOUDNESS_TARGET: f64 = -18.0;
+
+/// Maximum gain in dB that is suggested in either direction.
+/// Keeps (nearly) silent files from being amplified into noise.
+pub const MAX_GAIN: f64 = 20.0;
+
+pub struct Loudness {
+    /// Integrated loudness in LUFS.
+    pub integrated: f64,
+    /// Gain in dB to reach `LOUDNESS_TARGET`.
+    pub gain: f64,
+}
+
+impl Loudness {
+    fn new(integrated: f64) -> Self {
+        Self {
+            integrated,
+            gain: suggested_gain(integrated),
+        }
+    }
+}
+
+/// Gain in dB needed to bring audio with the given integrated loudness to `LOUDNESS_TARGET`.
+pub fn suggested_gain(integrated: f64) -> f64 {
+    (LOUDNESS_TARGET - integrated).clamp(-MAX_GAIN, MAX_GAIN)
+}
+
+/// Measure the integrated loudness of a file with ffmpeg's ebur128 filter.
+pub async fn ffmpeg_loudness(path: &Path) -> Result<Loudness> {
+    // ffmpeg -hide_banner -nostats -i ${path} -vn -af ebur128=framelog=quiet -f null -
+    let output = tokio::process::Command::new("ffmpeg")
+        .arg("-hide_banner")
+        .arg("-nostats")
+        .arg("-i")
+        .arg(path)
+        .arg("-vn")
+        .arg("-af")
+        .arg("ebur128=framelog=quiet")
+        .arg("-f")
+        .arg("null")
+        .arg("-")
+        .output()
+        .await?;
+
+    // The summary of the ebur128 filter is written to stderr.
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        bail!("ffmpeg failed to analyze loudness: {}", stderr);
+    }
+
+    parse_integrated_loudness(&stderr)
+        .map(Loudness::new)
+        .context("ffmpeg output does not contain integrated loudness")
+}
+
+/// Parse the integrated loudness from the summary of the ebur128 filter.
+///
+/// ```text
+/// [Parsed_ebur128_0 @ 0x5581c8a0e8c0] Summary:
+///
+///   Integrated loudness:
+///     I:         -19.9 LUFS
+///     Threshold: -30.2 LUFS
+/// ```
+fn parse_integrated_loudness(output: &str) -> Option<f64> {
+    let summary = &output[output.rfind("Summary:")?..];
+    summary.lines().find_map(|line| {
+        line.trim()
+            .strip_prefix("I:")?
+            .trim()
+            .strip_suffix("LUFS")?
+            .trim()
+            .parse::<f64>()
+            .ok()
+    })
+}
+
+/// Gain in dB to bring a whole book to `LOUDNESS_TARGET`, similar to ReplayGain's album gain.
+/// Files are weighted by their duration. Returns `None` if any file hasn't been analyzed yet.
+pub fn book_gain(files: &[File]) -> Option<f64> {
+    let mut energy = 0.0;
+    let mut duration = 0.0;
+    for file in files {
+        energy += file.duration * 10f64.powf(file.loudness? / 10.0);
+        duration += file.duration;
+    }
+
+    if duration <= 0.0 {
+        return None;
+    }
+
+    Some(suggested_gain(10.0 * (energy / duration).log10()))
+}
+
+/// Analyze the loudness of files and store the results.
+/// Failures are logged and don't stop the analysis of the remaining files.
+pub async fn analyze_files(database: &Database, files: &[File]) {
+    for file in files {
+        match ffmpeg_loudness(Path::new(&file.path)).await {
+            Ok(loudness) => {
+                if let Err(err) = database
+                    .update_file_loudness(file.id, loudness.integrated, loudness.gain)
+                    .await
+                {
+                    tracing::error!("{:?}", err);
+                }
+            }
+            Err(err) => tracing::warn!("Failed to analyze loudness of {}: {:?}", file.path, err),
+        }
+    }
+}
+
+/// Analyze the loudness of all files of a book in a background task.
+pub fn analyze_book_in_background(database: Database, book_id: i64) {
+    tokio::spawn(async move {
+        match database.get_files_for_book(book_id).await {
+            Ok(files) => analyze_files(&database, &files).await,
+            Err(err) => tracing::error!("{:?}", err),
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn file(duration: f64, loudness: Option<f64>) -> File {
+        File {
+            loudness,
+            ..File::test(1, "", duration)
+        }
+    }
+
+    #[test]
+    fn test_parse_integrated_loudness() {
+        // Test case: Verify that the integrated loudness is read from the summary
+        let output = r#"
+[Parsed_ebur128_0 @ 0x5581c8a0e8c0] Summary:
+
+  Integrated loudness:
+    I:         -19.9 LUFS
+    Threshold: -30.2 LUFS
+
+  Loudness range:
+    LRA:         5.3 LU
+"#;
+
+        assert_eq!(parse_integrated_loudness(output), Some(-19.9));
+        assert_eq!(parse_integrated_loudness("no summary"), None);
+    }
+
+    #[test]
+    fn test_suggested_gain() {
+        // Test case: Verify that the gain reaches the target and is clamped
+        assert_eq!(suggested_gain(-23.0), 5.0);
+        assert_eq!(suggested_gain(-10.0), -8.0);
+        assert_eq!(suggested_gain(-70.0), MAX_GAIN);
+    }
+
+    #[test]
+    fn test_book_gain() {
+        // Test case: Verify that files with the same loudness result in the same gain
+        let files = vec![file(100.0, Some(-23.0)), file(300.0, Some(-23.0))];
+        let gain = book_gain(&files).unwrap();
+        assert!((gain - 5.0).abs() < 1e-9);
+
+        // Test case: Verify that louder files weigh more
+        let files = vec![file(100.0, Some(-13.0)), file(100.0, Some(-23.0))];
+        let gain = book_gain(&files).unwrap();
+        assert!(gain < 0.0 && gain > -5.0);
+
+        // Test case: Verify that unanalyzed files result in no gain
+        let files = vec![file(100.0, Some(-23.0)), file(100.0, None)];
+        assert!(book_gain(&files).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_ffmpeg_loudness() {
+        // Test case: Verify that ffmpeg_loudness measures a generated tone
+        let temp_dir = tempdir().unwrap();
+        let file_path = temp_dir.path().join("test.mp3");
+
+        // ffmpeg -f lavfi -i sine=frequency=1000:duration=5 ${file_path}
+        let _ = tokio::process::Command::new("ffmpeg")
+            .arg("-f")
+            .arg("lavfi")
+            .arg("-i")
+            .arg("sine=frequency=1000:duration=5")
+            .arg(&file_path)
+            .output()
+            .await
+            .unwrap();
+
+        let result = ffmpeg_loudness(&file_path).await.unwrap();
+        assert!(result.integrated < 0.0);
+        assert_eq!(result.gain, suggested_gain(result.integrated));
+    }
+}
@@ -1,2 +1,6 @@
 pub mod cover;
+pub mod cover_candidates;
 pub mod ffmpeg;
+pub mod jobs;
+pub mod loudness;
+pub mod waveform;
@@ -0,0 +1,298 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    process::Stdio,
+    sync::{Arc, Mutex, Weak},
+};
+
+use anyhow::{Context, Result, bail};
+use tokio::io::AsyncReadExt;
+
+use crate::{database::Database, fs::storage::file_fingerprint};
+
+/// Sample rate audio is decoded at for waveform generation.
+/// Peaks don't need the full bandwidth of the audio, which keeps decoding cheap.
+const SAMPLE_RATE: usize = 8000;
+
+/// Number of peaks per second of audio.
+pub const PEAKS_PER_SECOND: usize = 2;
+
+/// Collects the peak amplitude of consecutive windows of samples.
+pub struct PeakAccumulator {
+    samples_per_peak: usize,
+    count: usize,
+    peak: u16,
+    peaks: Vec<u8>,
+}
+
+impl PeakAccumulator {
+    pub fn new(samples_per_peak: usize) -> Self {
+        Self {
+            samples_per_peak,
+            count: 0,
+            peak: 0,
+            peaks: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, sample: i16) {
+        self.peak = self.peak.max(sample.unsigned_abs());
+        self.count += 1;
+
+        if self.count == self.samples_per_peak {
+            self.flush();
+        }
+    }
+
+    /// Return the peaks, including a partial window at the end.
+    pub fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.flush();
+        }
+        self.peaks
+    }
+
+    fn flush(&mut self) {
+        // Scale 0..=32768 down to 0..=255.
+        self.peaks.push((u32::from(self.peak) * 255 / 32768) as u8);
+        self.peak = 0;
+        self.count = 0;
+    }
+}
+
+/// Locks held while generating the waveform of a file, so concurrent requests for the same file
+/// wait for the first one instead of decoding it again.
+#[derive(Clone, Default)]
+pub struct WaveformLocks {
+    locks: Arc<Mutex<HashMap<i64, Weak<tokio::sync::Mutex<()>>>>>,
+}
+
+impl WaveformLocks {
+    /// Lock of a file, locks no request holds anymore are dropped.
+    pub fn get(&self, file_id: i64) -> Arc<tokio::sync::Mutex<()>> {
+        let mut locks = self
+            .locks
+            .lock()
+            .expect("Waveform locks should not be poisoned");
+        locks.retain(|_, lock| lock.strong_count() > 0);
+
+        if let Some(lock) = locks.get(&file_id).and_then(Weak::upgrade) {
+            return lock;
+        }
+        let lock = Arc::new(tokio::sync::Mutex::new(()));
+        locks.insert(file_id, Arc::downgrade(&lock));
+        lock
+    }
+}
+
+/// Generate waveform peaks for an audio file.
+/// Every byte is the peak amplitude of `1 / PEAKS_PER_SECOND` seconds of audio scaled to 0-255.
+/// ffmpeg is killed if the future is dropped.
+pub async fn ffmpeg_waveform(path: &Path) -> Result<Vec<u8>> {
+    // Decode to mono 16 bit PCM and stream it through stdout.
+    // ffmpeg -v error -i ${path} -vn -ac 1 -ar 8000 -f s16le -
+    let mut child = tokio::process::Command::new("ffmpeg")
+        .kill_on_drop(true)
+        .arg("-v")
+        .arg("error")
+        .arg("-i")
+        .arg(path)
+        .arg("-vn")
+        .arg("-ac")
+        .arg("1")
+        .arg("-ar")
+        .arg(SAMPLE_RATE.to_string())
+        .arg("-f")
+        .arg("s16le")
+        .arg("-")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let mut stdout = child
+        .stdout
+        .take()
+        .context("Failed to get stdout of ffmpeg")?;
+
+    // Read stderr alongside stdout, ffmpeg blocks once the pipe is full.
+    let mut stderr = child
+        .stderr
+        .take()
+        .context("Failed to get stderr of ffmpeg")?;
+    let errors = tokio::spawn(async move {
+        let mut errors = Vec::new();
+        let _ = stderr.read_to_end(&mut errors).await;
+        errors
+    });
+
+    let mut accumulator = PeakAccumulator::new(SAMPLE_RATE / PEAKS_PER_SECOND);
+    let mut buffer = vec![0u8; 64 * 1024];
+    // Reads aren't aligned to samples, a trailing byte is carried over into the next read.
+    let mut carry: Option<u8> = None;
+    loop {
+        let read = stdout.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+
+        let mut bytes = &buffer[..read];
+        if let Some(low) = carry.take() {
+            accumulator.push(i16::from_le_bytes([low, bytes[0]]));
+            bytes = &bytes[1..];
+        }
+
+        let mut samples = bytes.chunks_exact(2);
+        for sample in &mut samples {
+            accumulator.push(i16::from_le_bytes([sample[0], sample[1]]));
+        }
+        carry = samples.remainder().first().copied();
+    }
+
+    let status = child.wait().await?;
+    let errors = errors.await.unwrap_or_default();
+    if !status.success() {
+        bail!(
+            "ffmpeg failed to decode audio: {}",
+            String::from_utf8_lossy(&errors)
+        );
+    }
+
+    Ok(accumulator.finish())
+}
+
+/// Waveform peaks of a file with the fingerprint `file_hash`, generated on first use and stored
+/// in the database until the file changes.
+/// Concurrent calls for the same file wait for the first one, which stores the peaks for the
+/// others.
+pub async fn file_waveform(
+    database: &Database,
+    locks: &WaveformLocks,
+    file_id: i64,
+    path: &Path,
+    file_hash: &str,
+) -> Result<Vec<u8>> {
+    let lock = locks.get(file_id);
+    let _guard = lock.lock().await;
+    if let Some(peaks) = database.get_waveform(file_id, file_hash).await? {
+        return Ok(peaks);
+    }
+
+    let peaks = ffmpeg_waveform(path)
+        .await
+        .with_context(|| format!("Failed to generate waveform for {}", path.display()))?;
+    database.save_waveform(file_id, file_hash, &peaks).await?;
+    Ok(peaks)
+}
+
+/// Key of the job generating the waveforms of a book.
+pub fn waveform_job_key(book_id: i64) -> String {
+    format!("waveforms-book-{book_id}")
+}
+
+/// Generate the waveforms of all files of a book, so players don't wait for them.
+/// Failures are logged and don't stop the remaining files.
+pub async fn generate_book_waveforms(
+    database: Database,
+    locks: WaveformLocks,
+    book_id: i64,
+) -> Result<()> {
+    for file in database.get_files_for_book(book_id).await? {
+        let path = Path::new(&file.path);
+        let result = async {
+            let file_hash = file_fingerprint(path).await?;
+            file_waveform(&database, &locks, file.id, path, &file_hash).await
+        }
+        .await;
+        if let Err(err) = result {
+            tracing::warn!("Failed to generate waveform of {}: {:?}", file.path, err);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_peak_accumulator() {
+        // Test case: Verify that peaks are taken per window and scaled to a byte
+        let mut accumulator = PeakAccumulator::new(2);
+        for sample in [0, -32768, 100, 16384, 0] {
+            accumulator.push(sample);
+        }
+
+        assert_eq!(accumulator.finish(), vec![255, 127, 0]);
+    }
+
+    #[test]
+    fn test_peak_accumulator_empty() {
+        // Test case: Verify that no samples result in no peaks
+        let accumulator = PeakAccumulator::new(2);
+        assert!(accumulator.finish().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_waveform_locks() {
+        // Test case: Verify that requests for the same file share a lock until none holds it
+        let locks = WaveformLocks::default();
+
+        let first = locks.get(1);
+        let guard = first.lock().await;
+        assert!(Arc::ptr_eq(&first, &locks.get(1)));
+        assert!(locks.get(1).try_lock().is_err());
+        assert!(locks.get(2).try_lock().is_ok());
+
+        drop(guard);
+        drop(first);
+        locks.get(3);
+        let locks = locks.locks.lock().unwrap();
+        assert_eq!(locks.keys().collect::<Vec<_>>(), [&3]);
+    }
+
+    #[sqlx::test(fixtures(path = "../database/fixtures", scripts("book")))]
+    async fn test_file_waveform_cached(pool: sqlx::Pool<sqlx::Sqlite>) {
+        // Test case: Verify that stored peaks are used while the file is unchanged
+        let db = Database::new_test(pool);
+        let locks = WaveformLocks::default();
+        db.save_waveform(336, "fingerprint", &[1, 2, 3])
+            .await
+            .unwrap();
+
+        let peaks = file_waveform(&db, &locks, 336, Path::new("/missing.m4b"), "fingerprint")
+            .await
+            .unwrap();
+        assert_eq!(peaks, [1, 2, 3]);
+
+        // A changed file is decoded again, which fails for a missing file.
+        assert!(
+            file_waveform(&db, &locks, 336, Path::new("/missing.m4b"), "changed")
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ffmpeg_waveform() {
+        // Test case: Verify that ffmpeg_waveform creates peaks for the whole duration
+        let temp_dir = tempdir().unwrap();
+        let file_path = temp_dir.path().join("test.wav");
+
+        // ffmpeg -f lavfi -i sine=frequency=1000:duration=5 ${file_path}
+        let _ = tokio::process::Command::new("ffmpeg")
+            .arg("-f")
+            .arg("lavfi")
+            .arg("-i")
+            .arg("sine=frequency=1000:duration=5")
+            .arg(&file_path)
+            .output()
+            .await
+            .unwrap();
+
+        let result = ffmpeg_waveform(&file_path).await.unwrap();
+        assert_eq!(result.len(), 5 * PEAKS_PER_SECOND);
+        assert!(result.iter().all(|peak| *peak > 0));
+    }
+}
@@ -1,8 +1,29 @@
+use std::sync::{Arc, LazyLock};
+
+use crate::auth::ldap::LdapConfig;
+use crate::auth::oidc::OIDC_CONFIG;
+use crate::auth::password::{PASSWORD_POLICY, hash_password};
+use crate::auth::password_cache::PasswordCache;
+use crate::auth::proxy::{TRUSTED_PROXIES, TRUSTED_PROXY};
+use crate::auth::signed_url::UrlSigner;
+use crate::auth::throttle::LoginThrottle;
 use crate::database::Database;
+use crate::fs::image_store::ImageStore;
+use crate::media::cover::move_cover_blobs;
+use crate::media::jobs::Jobs;
+use crate::media::waveform::WaveformLocks;
 
 #[derive(Clone)]
 pub struct FelaState {
     pub database: Database,
+    pub images: ImageStore,
+    pub signer: UrlSigner,
+    pub login_throttle: LoginThrottle,
+    pub password_cache: PasswordCache,
+    pub jobs: Jobs,
+    pub waveforms: WaveformLocks,
+    /// LDAP login, `None` if `LDAP_URL` isn't set.
+    pub ldap: Option<Arc<LdapConfig>>,
 }
 
 impl FelaState {
@@ -19,6 +40,69 @@ impl FelaState {
         if std::env::var("NO_MIGRATE").is_err() {
             database.migrate().await;
         };
-        Self { database }
+
+        // Load the password policy now, so a missing blocklist file is noticed on start.
+        LazyLock::force(&PASSWORD_POLICY);
+
+        // Load the external logins, so incomplete configurations are noticed on start too.
+        LazyLock::force(&OIDC_CONFIG);
+        LazyLock::force(&TRUSTED_PROXY);
+        LazyLock::force(&TRUSTED_PROXIES);
+        let ldap = LdapConfig::from_env()
+            .expect("Should be able to load LDAP configuration")
+            .map(Arc::new);
+
+        // Replace the default admin account with one from the environment.
+        bootstrap_admin(&database)
+            .await
+            .expect("Should be able to create admin account from environment");
+
+        // Open the image store, covers are stored there instead of the database.
+        let images = ImageStore::from_env().expect("Should be able to open image store");
+        move_cover_blobs(&database, &images)
+            .await
+            .expect("Should be able to move covers into the image store");
+
+        // Load the key signing URLs for players without a session.
+        let signer = UrlSigner::from_database(&database)
+            .await
+            .expect("Should be able to load URL signing key");
+
+        Self {
+            database,
+            images,
+            signer,
+            login_throttle: LoginThrottle::default(),
+            password_cache: PasswordCache::default(),
+            jobs: Jobs::default(),
+            waveforms: WaveformLocks::default(),
+            ldap,
+        }
     }
 }
+
+/// Set name and password of the default admin account from `ADMIN_USERNAME` and
+/// `ADMIN_PASSWORD`, so fresh installs never run with `admin`/`admin`.
+/// Does nothing once the default password was changed.
+async fn bootstrap_admin(database: &Database) -> anyhow::Result<()> {
+    let Ok(password) = std::env::var("ADMIN_PASSWORD") else {
+        return Ok(());
+    };
+    if password.is_empty() {
+        anyhow::bail!("ADMIN_PASSWORD should not be empty");
+    }
+    let username = std::env::var("ADMIN_USERNAME")
+        .map(|name| name.trim().to_lowercase())
+        .unwrap_or_else(|_| "admin".to_string());
+
+    PASSWORD_POLICY.check(&password, &username)?;
+
+    if database
+        .replace_default_admin(&username, &hash_password(&password)?)
+        .await?
+    {
+        tracing::info!("Created admin account {} from environment", username);
+    }
+
+    Ok(())
+}